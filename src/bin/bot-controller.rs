@@ -1,8 +1,21 @@
 extern crate tucoflyer;
-use tucoflyer::{SharedConfigFile, BotSocket, Controller, interface};
+use tucoflyer::{SharedConfigFile, BotSocket, Controller, interface, simulator_config};
+use std::env;
 
 fn main() {
-    let config = SharedConfigFile::load("config.yaml").expect("Failed to read configuration");
+    let mut args = env::args().skip(1).peekable();
+    let simulated = args.peek().map_or(false, |arg| arg == "--sim");
+    if simulated {
+        args.next();
+    }
+    let path = args.next().unwrap_or("config.yaml".into());
+    let config = SharedConfigFile::load(path).expect("Failed to read configuration");
+    let config = if simulated {
+        // Talk to bot-simulator over loopback, without saving its addresses into our file
+        SharedConfigFile::in_memory(simulator_config(&config.get_latest()))
+    } else {
+        config
+    };
     let socket = BotSocket::new(&config.get_latest()).expect("Failed to start bot networking");
 
     let controller = Controller::new(&config, &socket);
@@ -12,6 +25,7 @@ fn main() {
     interface::web::start(&config, &port);
     interface::gamepad::start(&config, &port);
     interface::metrics::start(&config, &port);
+    interface::recorder::start(&config, &port);
 
     controller.run(gimbal);
 }
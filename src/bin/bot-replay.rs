@@ -0,0 +1,27 @@
+extern crate tucoflyer;
+use tucoflyer::{SharedConfigFile, BotSocket, BusLogReader, Controller, interface};
+use std::env;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().expect("Usage: bot-replay <log file> [speed] [start seconds]");
+    let speed : f32 = args.next().map_or(1.0, |s| s.parse().expect("Bad replay speed"));
+    let start : f64 = args.next().map_or(0.0, |s| s.parse().expect("Bad start time"));
+
+    let mut log = BusLogReader::open(&path).expect("Failed to open bus log");
+    let local_config = SharedConfigFile::load("config.yaml").expect("Failed to read configuration");
+    let config = interface::replay::replay_config(&local_config.get_latest(), log.recorded_config());
+    log.seek((start * 1e6) as u64).expect("Failed to seek in bus log");
+
+    let config = SharedConfigFile::in_memory(config);
+    let socket = BotSocket::new(&config.get_latest()).expect("Failed to start bot networking");
+
+    let controller = Controller::new(&config, &socket);
+    let port = controller.port();
+    let gimbal = socket.start_receiver(&port);
+
+    interface::web::start(&config, &port);
+    interface::replay::start(log, speed, &port);
+
+    controller.run(gimbal);
+}
@@ -0,0 +1,10 @@
+extern crate tucoflyer;
+use tucoflyer::{SharedConfigFile, Simulator, simulator_config};
+use std::env;
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or("config.yaml".into());
+    let config = SharedConfigFile::load(path).expect("Failed to read configuration");
+    let simulator = Simulator::new(&simulator_config(&config.get_latest())).expect("Failed to start simulated bot networking");
+    simulator.run();
+}
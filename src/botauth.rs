@@ -0,0 +1,323 @@
+//! Authenticated framing for the bot messages that move things or light them up. With a
+//! key configured, those messages travel wrapped as
+//!
+//!   MSG_AUTHENTICATED, sequence (u64 little endian), inner code, inner body, HMAC-SHA256
+//!
+//! where the HMAC is keyed with a secret shared with the firmware, and covers the module's
+//! session nonce followed by everything before the tag. Each module draws a random nonce
+//! when it boots, and answers every hello with a MSG_AUTH_SESSION carrying that nonce and
+//! the highest sequence it has accepted since, signed the same way:
+//!
+//!   MSG_AUTH_SESSION, nonce (u64 little endian), last sequence (u64 little endian), HMAC-SHA256
+//!
+//! A module only accepts sequence numbers above any it has accepted under its current
+//! nonce, so captured packets can't be replayed, not even after the module restarts and
+//! forgets what it accepted. The controller picks up from the sequence the module reports,
+//! so nothing needs storing across controller restarts either, and until a module has
+//! reported a session we send it nothing that needs signing.
+//!
+//! The key lives in a file of its own, named by the config, so it never goes out with the
+//! config to websocket clients or into bus logs.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+use config::BotAuthConfig;
+use botcomm::{MSG_AUTHENTICATED, MSG_AUTH_SESSION, MSG_WINCH_COMMAND, MSG_LEDS};
+
+const NONCE_LEN : usize = 8;
+const SEQUENCE_LEN : usize = 8;
+const MAC_LEN : usize = 32;
+
+/// Only sent, and only accepted, inside authenticated framing when it's enabled
+const AUTHENTICATED_MESSAGES : &[u8] = &[ MSG_WINCH_COMMAND, MSG_LEDS ];
+
+pub fn requires_authentication(code: u8) -> bool {
+    AUTHENTICATED_MESSAGES.contains(&code)
+}
+
+/// The shared secret, which never appears in debug output
+#[derive(Clone)]
+pub struct BotKey(Vec<u8>);
+
+impl BotKey {
+    pub fn new(key: &[u8]) -> BotKey {
+        BotKey(key.to_vec())
+    }
+
+    /// Read the key file, ignoring trailing whitespace
+    pub fn load(config: &BotAuthConfig) -> io::Result<BotKey> {
+        let mut contents = String::new();
+        File::open(&config.key_path)?.read_to_string(&mut contents)?;
+        let key = contents.trim_right();
+        if key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bot auth key file {} is empty", config.key_path)));
+        }
+        Ok(BotKey::new(key.as_bytes()))
+    }
+
+    fn mac(&self, nonce: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new(&self.0);
+        let mut bytes = [0; NONCE_LEN];
+        LittleEndian::write_u64(&mut bytes, nonce);
+        mac.input(&bytes);
+        mac
+    }
+
+    fn session_mac(&self) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new(&self.0);
+        mac.input(&[MSG_AUTH_SESSION]);
+        mac
+    }
+}
+
+impl fmt::Debug for BotKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BotKey({} bytes)", self.0.len())
+    }
+}
+
+/// The controller's side, one per module
+#[derive(Debug)]
+pub struct BotSigner {
+    key: Arc<BotKey>,
+    /// The module's current nonce and the next sequence to send under it
+    session: Option<(u64, u64)>,
+}
+
+impl BotSigner {
+    pub fn new(key: Arc<BotKey>) -> BotSigner {
+        BotSigner {
+            key,
+            session: None,
+        }
+    }
+
+    /// Follow the module's session, from the body of its MSG_AUTH_SESSION
+    pub fn session_received(&mut self, msg: &[u8]) -> Result<(), String> {
+        if msg.len() != NONCE_LEN + SEQUENCE_LEN + MAC_LEN {
+            return Err(format!("Auth session is {} bytes", msg.len()));
+        }
+        let (signed, tag) = msg.split_at(NONCE_LEN + SEQUENCE_LEN);
+        let mut mac = self.key.session_mac();
+        mac.input(signed);
+        if !mac.verify(tag) {
+            return Err("Auth session has a bad signature".into());
+        }
+        let nonce = LittleEndian::read_u64(&signed[.. NONCE_LEN]);
+        let first_unused = LittleEndian::read_u64(&signed[NONCE_LEN ..]).saturating_add(1);
+        self.session = Some(match self.session {
+            // Packets we sent may still be on their way, don't reuse their numbers
+            Some((current, next_sequence)) if current == nonce => (nonce, next_sequence.max(first_unused)),
+            _ => (nonce, first_unused),
+        });
+        Ok(())
+    }
+
+    /// The complete datagram, header byte included
+    pub fn seal(&mut self, code: u8, body: &[u8]) -> Result<Vec<u8>, String> {
+        let (nonce, sequence) = match self.session {
+            Some(session) => session,
+            None => return Err("No auth session with this module yet".into()),
+        };
+        self.session = Some((nonce, sequence.saturating_add(1)));
+
+        let mut buf = Vec::with_capacity(2 + SEQUENCE_LEN + body.len() + MAC_LEN);
+        let mut sequence_bytes = [0; SEQUENCE_LEN];
+        LittleEndian::write_u64(&mut sequence_bytes, sequence);
+        buf.push(MSG_AUTHENTICATED);
+        buf.extend_from_slice(&sequence_bytes);
+        buf.push(code);
+        buf.extend_from_slice(body);
+
+        let mut mac = self.key.mac(nonce);
+        mac.input(&buf);
+        buf.extend_from_slice(mac.result().code().as_slice());
+        Ok(buf)
+    }
+}
+
+/// The firmware's side, used by the simulator
+#[derive(Debug)]
+pub struct BotVerifier {
+    key: BotKey,
+    nonce: u64,
+    last_sequence: u64,
+}
+
+impl BotVerifier {
+    /// A freshly booted module, with a nonce it should never have used before
+    pub fn new(key: BotKey, nonce: u64) -> BotVerifier {
+        BotVerifier {
+            key,
+            nonce,
+            last_sequence: 0,
+        }
+    }
+
+    /// Body of the MSG_AUTH_SESSION that answers a hello
+    pub fn session(&self) -> Vec<u8> {
+        let mut buf = vec![0; NONCE_LEN + SEQUENCE_LEN];
+        LittleEndian::write_u64(&mut buf[.. NONCE_LEN], self.nonce);
+        LittleEndian::write_u64(&mut buf[NONCE_LEN ..], self.last_sequence);
+        let mut mac = self.key.session_mac();
+        mac.input(&buf);
+        buf.extend_from_slice(mac.result().code().as_slice());
+        buf
+    }
+
+    /// Check the body of a MSG_AUTHENTICATED packet, returning the code and body inside it
+    pub fn open<'a>(&mut self, msg: &'a [u8]) -> Result<(u8, &'a [u8]), String> {
+        if msg.len() < SEQUENCE_LEN + 1 + MAC_LEN {
+            return Err(format!("Authenticated packet too short, {} bytes", msg.len()));
+        }
+        let (signed, tag) = msg.split_at(msg.len() - MAC_LEN);
+
+        let mut mac = self.key.mac(self.nonce);
+        mac.input(&[MSG_AUTHENTICATED]);
+        mac.input(signed);
+        if !mac.verify(tag) {
+            return Err("Authenticated packet has a bad signature".into());
+        }
+
+        let sequence = LittleEndian::read_u64(&signed[.. SEQUENCE_LEN]);
+        if sequence <= self.last_sequence {
+            return Err(format!("Authenticated packet replays sequence {}, already at {}", sequence, self.last_sequence));
+        }
+        self.last_sequence = sequence;
+        Ok((signed[SEQUENCE_LEN], &signed[SEQUENCE_LEN + 1 ..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY : &[u8] = b"test key";
+
+    /// A signer and a module that have completed the hello
+    fn pair(nonce: u64) -> (BotSigner, BotVerifier) {
+        let mut signer = BotSigner::new(Arc::new(BotKey::new(KEY)));
+        let verifier = BotVerifier::new(BotKey::new(KEY), nonce);
+        signer.session_received(&verifier.session()).unwrap();
+        (signer, verifier)
+    }
+
+    /// What the module's receiver sees, without the header byte
+    fn seal(signer: &mut BotSigner, body: &[u8]) -> Vec<u8> {
+        let packet = signer.seal(MSG_WINCH_COMMAND, body).unwrap();
+        assert_eq!(packet[0], MSG_AUTHENTICATED);
+        packet[1 ..].to_vec()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut signer, mut verifier) = pair(1234);
+        for body in [ &b""[..], &b"winch command"[..], &[0xff; 200][..] ].iter() {
+            let packet = seal(&mut signer, body);
+            assert_eq!(packet.len(), SEQUENCE_LEN + 1 + body.len() + MAC_LEN);
+            assert_eq!(verifier.open(&packet), Ok((MSG_WINCH_COMMAND, *body)));
+        }
+    }
+
+    #[test]
+    fn sequence_starts_above_what_the_module_accepted() {
+        let (mut signer, _) = pair(1234);
+        assert_eq!(LittleEndian::read_u64(&seal(&mut signer, b"")[.. SEQUENCE_LEN]), 1);
+        assert_eq!(LittleEndian::read_u64(&seal(&mut signer, b"")[.. SEQUENCE_LEN]), 2);
+    }
+
+    #[test]
+    fn flipped_bits_are_refused() {
+        let (mut signer, mut verifier) = pair(1234);
+        let packet = seal(&mut signer, b"winch command");
+        for byte in 0 .. packet.len() {
+            for bit in 0 .. 8 {
+                let mut damaged = packet.clone();
+                damaged[byte] ^= 1 << bit;
+                assert!(verifier.open(&damaged).is_err(), "Accepted a flip of bit {} in byte {}", bit, byte);
+            }
+        }
+        // The damage didn't use up the sequence number
+        assert!(verifier.open(&packet).is_ok());
+    }
+
+    #[test]
+    fn replayed_and_reordered_packets_are_refused() {
+        let (mut signer, mut verifier) = pair(1234);
+        let first = seal(&mut signer, b"first");
+        let second = seal(&mut signer, b"second");
+        let third = seal(&mut signer, b"third");
+        assert!(verifier.open(&second).is_ok());
+        assert!(verifier.open(&second).is_err());
+        // Arriving late is as good as a replay
+        assert!(verifier.open(&first).is_err());
+        assert!(verifier.open(&third).is_ok());
+    }
+
+    #[test]
+    fn truncated_packets_are_refused() {
+        let (mut signer, mut verifier) = pair(1234);
+        let packet = seal(&mut signer, b"winch command");
+        for len in 0 .. packet.len() {
+            assert!(verifier.open(&packet[.. len]).is_err(), "Accepted {} of {} bytes", len, packet.len());
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_refused() {
+        let mut signer = BotSigner::new(Arc::new(BotKey::new(b"some other key")));
+        let mut verifier = BotVerifier::new(BotKey::new(KEY), 1234);
+        // The module's session doesn't check out under the wrong key, and neither do our packets
+        assert!(signer.session_received(&verifier.session()).is_err());
+        signer.session = Some((1234, 1));
+        let packet = seal(&mut signer, b"winch command");
+        assert!(verifier.open(&packet).is_err());
+    }
+
+    #[test]
+    fn nothing_is_signed_before_a_session() {
+        let mut signer = BotSigner::new(Arc::new(BotKey::new(KEY)));
+        assert!(signer.seal(MSG_WINCH_COMMAND, b"").is_err());
+        let mut session = BotVerifier::new(BotKey::new(KEY), 1234).session();
+        session[0] ^= 1;
+        assert!(signer.session_received(&session).is_err());
+        assert!(signer.session_received(&session[1 ..]).is_err());
+        assert!(signer.seal(MSG_WINCH_COMMAND, b"").is_err());
+    }
+
+    #[test]
+    fn packets_from_before_a_module_reboot_are_refused() {
+        let (mut signer, mut verifier) = pair(1234);
+        let captured = seal(&mut signer, b"winch command");
+        assert!(verifier.open(&captured).is_ok());
+
+        // Rebooted with a new nonce and no memory of what it accepted
+        let mut verifier = BotVerifier::new(BotKey::new(KEY), 5678);
+        assert!(verifier.open(&captured).is_err());
+        signer.session_received(&verifier.session()).unwrap();
+        assert!(verifier.open(&seal(&mut signer, b"winch command")).is_ok());
+    }
+
+    #[test]
+    fn restarted_controller_continues_the_module_sequence() {
+        let (mut signer, mut verifier) = pair(1234);
+        for _ in 0 .. 5 {
+            assert!(verifier.open(&seal(&mut signer, b"")).is_ok());
+        }
+        let mut restarted = BotSigner::new(Arc::new(BotKey::new(KEY)));
+        restarted.session_received(&verifier.session()).unwrap();
+        assert!(verifier.open(&seal(&mut restarted, b"")).is_ok());
+
+        // A session report that's behind what we sent doesn't wind our own sequence back
+        let stale = verifier.session();
+        let ahead = seal(&mut restarted, b"");
+        restarted.session_received(&stale).unwrap();
+        assert!(verifier.open(&ahead).is_ok());
+        assert!(verifier.open(&seal(&mut restarted, b"")).is_ok());
+    }
+}
@@ -1,43 +1,125 @@
 //! This module is about communicating with our many robot
 //! modules via a custom UDP protocol.
+//!
+//! Message bodies are raw structs on the firmware side, so both ends must agree on their
+//! layout. We greet each module with a hello, and it answers with its protocol version and
+//! a hash of the layout it was built with for every message it speaks. Modules that never
+//! answer are assumed to be older firmware and trusted as before; modules that answer with
+//! a different layout have those messages refused in both directions.
+//!
+//! Modules are addressed through a table shared by every clone of the socket, rebuilt
+//! when the configured winches change. The controller address can't change at runtime,
+//! since the socket is already bound to it.
+//!
+//! With bot_auth configured, winch commands and LED data are signed and numbered as
+//! described in botauth, for firmware that refuses them otherwise. Each module's signing
+//! session comes from its answers to our hellos, and the key is read once at startup.
+//!
+//! Everything the receiver drops is counted, and the netmonitor turns those counts along
+//! with packet rates, counter gaps and loopback round trips into a periodic NetworkStatus.
 
-use message::{Message, WinchCommand};
+use message::*;
 use controller::ControllerPort;
-use std::thread;
+use supervisor;
 use bincode;
 use config::Config;
 use std::net::{SocketAddr, UdpSocket};
 use std::io;
 use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::mem;
+use std::time::{Duration, Instant};
 use serde::Serialize;
 use fygimbal::{GimbalPoller, GimbalPort};
+use netmonitor::NetworkMonitor;
+use botauth::{BotKey, BotSigner, requires_authentication};
 
-const MSG_LOOPBACK          : u8 = 0x20;    // copy data
-const MSG_GIMBAL            : u8 = 0x01;    // fygimbal protocol data
-const MSG_FLYER_SENSORS     : u8 = 0x02;    // struct flyer_sensors
+pub const MSG_LOOPBACK      : u8 = 0x20;    // copy data
+pub const MSG_GIMBAL        : u8 = 0x01;    // fygimbal protocol data
+pub const MSG_FLYER_SENSORS : u8 = 0x02;    // struct flyer_sensors
 //                                 0x03        old version of winch_status
 //                                 0x04        old version of winch_command
-const MSG_LEDS              : u8 = 0x05;    // apa102 data, 32 bits/pixel
-const MSG_WINCH_STATUS      : u8 = 0x06;    // struct winch_status
-const MSG_WINCH_COMMAND     : u8 = 0x07;    // struct winch_command
+pub const MSG_LEDS          : u8 = 0x05;    // apa102 data, 32 bits/pixel
+pub const MSG_WINCH_STATUS  : u8 = 0x06;    // struct winch_status
+pub const MSG_WINCH_COMMAND : u8 = 0x07;    // struct winch_command
+pub const MSG_HELLO         : u8 = 0x08;    // struct bot_hello
+pub const MSG_CAPABILITIES  : u8 = 0x09;    // struct bot_capabilities
+pub const MSG_AUTHENTICATED : u8 = 0x0a;    // sequence, message, hmac; see botauth
+pub const MSG_AUTH_SESSION  : u8 = 0x0b;    // nonce, last sequence, hmac; see botauth
+
+pub const BOT_PROTOCOL_VERSION : u16 = 1;
+
+/// Each message body as the flat sequence of fields bincode writes. The firmware keeps the
+/// same strings beside its structs, and any edit to a struct must edit its string too.
+const MESSAGE_LAYOUTS : &[(u8, &str)] = &[
+    (MSG_GIMBAL,        "fygimbal"),
+    (MSG_FLYER_SENSORS, "u32*3 u32*4 u32*4 u32*8 u32 i16*3 i16*3 i16*3 i16*3 i16*4 i16*3 i16*3 i8 i8 u32"),
+    (MSG_LEDS,          "apa102"),
+    (MSG_WINCH_STATUS,  "u32*2 i32 f32*5 f32*6 i32*2 f32*2 f32*3 i32 f32 u32 i32 f32 f32*5 i16 u16 u32 i32 f32*4"),
+    (MSG_WINCH_COMMAND, "i32 f32*5 f32*6 i32*2 f32*2 f32*3"),
+];
+
+const FLYER_MESSAGES : &[u8] = &[ MSG_GIMBAL, MSG_FLYER_SENSORS, MSG_LEDS ];
+const WINCH_MESSAGES : &[u8] = &[ MSG_WINCH_STATUS, MSG_WINCH_COMMAND, MSG_LEDS ];
+
+/// Hello again this often until a module answers, then less often to notice firmware changes
+const HELLO_RETRY_MILLIS : u64 = 1000;
+const HELLO_REFRESH_MILLIS : u64 = 10000;
+
+/// Status updates that only change counters are sent at most this often
+const STATUS_INTERVAL_MILLIS : u64 = 1000;
+
+fn layout_hash(layout: &str) -> u32 {
+    // 32-bit FNV-1a, simple enough to carry in the firmware
+    layout.bytes().fold(0x811c9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+fn expected_layout_hash(code: u8) -> Option<u32> {
+    MESSAGE_LAYOUTS.iter().find(|&&(c, _)| c == code).map(|&(_, layout)| layout_hash(layout))
+}
+
+/// What a module built from this tree would answer to a hello
+pub fn capabilities(module: BotModule, firmware_version: u32) -> BotCapabilities {
+    BotCapabilities {
+        protocol_version: BOT_PROTOCOL_VERSION,
+        firmware_version,
+        messages: module_messages(module).iter().map(|&code| BotMessageLayout {
+            code,
+            layout_hash: expected_layout_hash(code).unwrap(),
+        }).collect(),
+    }
+}
+
+fn module_messages(module: BotModule) -> &'static [u8] {
+    match module {
+        BotModule::Flyer => FLYER_MESSAGES,
+        BotModule::Winch(_) => WINCH_MESSAGES,
+    }
+}
 
 #[derive(Debug)]
 pub struct BotSocket {
     udp: UdpSocket,
-    addrs: BotAddrs,
+    /// Shared between clones, so senders respect what the receiver learned and every
+    /// clone sees the same modules after a config change
+    peers: Arc<Mutex<BotPeers>>,
 }
 
 impl BotSocket {
     pub fn new(config: &Config) -> Result<BotSocket, io::Error> {
-        let addrs = BotAddrs::new(config);
-        let udp = UdpSocket::bind(addrs.controller)?;
-        Ok(BotSocket { udp, addrs })
+        let key = match config.bot_auth {
+            Some(ref auth) => Some(Arc::new(BotKey::load(auth)?)),
+            None => None,
+        };
+        let udp = UdpSocket::bind(config.controller_addr)?;
+        let peers = Arc::new(Mutex::new(BotPeers::new(config, key)));
+        Ok(BotSocket { udp, peers })
     }
 
     pub fn try_clone(&self) -> Result<BotSocket, io::Error> {
         let udp = self.udp.try_clone()?;
-        let addrs = self.addrs.clone();
-        Ok(BotSocket { udp, addrs })
+        let peers = self.peers.clone();
+        Ok(BotSocket { udp, peers })
     }
 
     pub fn start_receiver(&self, controller: &ControllerPort) -> GimbalPort {
@@ -47,21 +129,46 @@ impl BotSocket {
         gimbal_port
     }
 
-    fn send_bytes(&self, addr: &SocketAddr, header: u8, body: &[u8]) -> io::Result<()> {
-        let mut buf = vec![header];
-        buf.write(body)?;
-        self.udp.send_to(&buf, &addr)?;
+    /// Follow changes to the flyer and winch addresses, keeping what we know about any
+    /// module whose address didn't change
+    pub fn reconfigure(&self, config: &Config) {
+        self.peers().reconfigure(config);
+    }
+
+    /// The table stays usable after a thread panics while holding it. Everything in it is
+    /// only what modules last told us, and a poisoned lock would otherwise panic every
+    /// thread that sends, including the supervised receiver after each restart.
+    fn peers(&self) -> MutexGuard<BotPeers> {
+        self.peers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn send_bytes(&self, module: BotModule, header: u8, body: &[u8]) -> io::Result<()> {
+        // Send while holding the lock, so sequence numbers leave in order
+        let mut peers = self.peers();
+        let peer = peers.peer_for(module, header)?;
+        let addr = peer.addr;
+        match peer.signer {
+            Some(ref mut signer) if requires_authentication(header) => {
+                let buf = signer.seal(header, body).map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+                self.udp.send_to(&buf, &addr)?;
+            },
+            _ => {
+                let mut buf = vec![header];
+                buf.write(body)?;
+                self.udp.send_to(&buf, &addr)?;
+            },
+        }
         Ok(())
     }
 
-    fn send<T: Serialize>(&self, addr: &SocketAddr, header: u8, body: &T) -> io::Result<()> {
+    fn send<T: Serialize>(&self, module: BotModule, header: u8, body: &T) -> io::Result<()> {
         let limit = bincode::Bounded(2048);
         let bytes = bincode::serialize(body, limit).unwrap();
-        self.send_bytes(addr, header, &bytes)
+        self.send_bytes(module, header, &bytes)
     }
 
     pub fn winch_command(&self, id: usize, cmd: WinchCommand) -> io::Result<()> {
-        self.send(&self.addrs.winches[id], MSG_WINCH_COMMAND, &cmd)
+        self.send(BotModule::Winch(id), MSG_WINCH_COMMAND, &cmd)
     }
 
     fn gimbal<'a>(&'a self) -> GimbalWriter<'a> {
@@ -73,31 +180,31 @@ impl BotSocket {
     pub fn winch_leds<'a>(&'a self, id: usize) -> LEDWriter<'a> {
         LEDWriter {
             socket: &self,
-            addr: &self.addrs.winches[id]
+            module: BotModule::Winch(id),
         }
     }
 
     pub fn flyer_leds<'a>(&'a self) -> LEDWriter<'a> {
         LEDWriter {
             socket: &self,
-            addr: &self.addrs.flyer
+            module: BotModule::Flyer,
         }
     }
 
     pub fn num_winches(&self) -> usize {
-        self.addrs.winches.len()
+        self.peers().num_winches()
     }
 }
 
 #[derive(Debug)]
 pub struct LEDWriter<'a> {
     socket: &'a BotSocket,
-    addr: &'a SocketAddr,
+    module: BotModule,
 }
 
 impl<'a> io::Write for LEDWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.socket.send_bytes(self.addr, MSG_LEDS, buf)?;
+        self.socket.send_bytes(self.module, MSG_LEDS, buf)?;
         Ok(buf.len())
     }
 
@@ -113,7 +220,7 @@ pub struct GimbalWriter<'a> {
 
 impl<'a> io::Write for GimbalWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.socket.send_bytes(&self.socket.addrs.flyer, MSG_GIMBAL, buf)?;
+        self.socket.send_bytes(BotModule::Flyer, MSG_GIMBAL, buf)?;
         Ok(buf.len())
     }
 
@@ -122,19 +229,137 @@ impl<'a> io::Write for GimbalWriter<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
-struct BotAddrs {
-    controller: SocketAddr,
-    flyer: SocketAddr,
-    winches: Vec<SocketAddr>,
+fn module_addrs(config: &Config) -> Vec<(BotModule, SocketAddr)> {
+    let mut addrs = vec![ (BotModule::Flyer, config.flyer_addr) ];
+    for (id, winch) in config.winches.iter().enumerate() {
+        addrs.push((BotModule::Winch(id), winch.addr));
+    }
+    addrs
+}
+
+#[derive(Debug)]
+struct BotPeer {
+    module: BotModule,
+    addr: SocketAddr,
+    capabilities: Option<BotCapabilities>,
+    last_hello: Option<Instant>,
+    refused_messages: u32,
+    malformed_messages: u32,
+    published: Option<(Instant, BotModuleStatus)>,
+    /// With bot_auth configured, following the session the module reported
+    signer: Option<BotSigner>,
+}
+
+impl BotPeer {
+    fn new(module: BotModule, addr: SocketAddr, key: &Option<Arc<BotKey>>) -> BotPeer {
+        BotPeer {
+            module,
+            addr,
+            capabilities: None,
+            last_hello: None,
+            refused_messages: 0,
+            malformed_messages: 0,
+            published: None,
+            signer: key.as_ref().map(|key| BotSigner::new(key.clone())),
+        }
+    }
+
+    fn accepts(&self, code: u8) -> bool {
+        match code {
+            MSG_HELLO | MSG_CAPABILITIES | MSG_LOOPBACK => true,
+            _ => match self.capabilities {
+                None => true,
+                Some(ref caps) => caps.protocol_version == BOT_PROTOCOL_VERSION && caps.messages.iter().any(|layout| {
+                    layout.code == code && Some(layout.layout_hash) == expected_layout_hash(code)
+                }),
+            }
+        }
+    }
+
+    fn status(&self) -> BotModuleStatus {
+        let refused_codes : Vec<u8> = module_messages(self.module).iter().cloned().filter(|&code| !self.accepts(code)).collect();
+        let state = match self.capabilities {
+            None => BotModuleState::Unknown,
+            Some(ref caps) if caps.protocol_version != BOT_PROTOCOL_VERSION => BotModuleState::Incompatible,
+            Some(_) if !refused_codes.is_empty() => BotModuleState::Mismatched,
+            Some(_) => BotModuleState::Compatible,
+        };
+        BotModuleStatus {
+            module: self.module,
+            state,
+            capabilities: self.capabilities.clone(),
+            refused_codes,
+            refused_messages: self.refused_messages,
+            malformed_messages: self.malformed_messages,
+        }
+    }
+
+    fn hello_due(&self, now: Instant) -> bool {
+        let interval = if self.capabilities.is_some() { HELLO_REFRESH_MILLIS } else { HELLO_RETRY_MILLIS };
+        self.last_hello.map_or(true, |timestamp| now > timestamp + Duration::from_millis(interval))
+    }
+
+    /// The status, if it changed in a way worth announcing
+    fn status_update(&mut self, now: Instant) -> Option<BotModuleStatus> {
+        let status = self.status();
+        let changed = match self.published {
+            None => true,
+            Some((timestamp, ref published)) => {
+                if published.state != status.state || published.capabilities != status.capabilities {
+                    true
+                } else {
+                    *published != status && now > timestamp + Duration::from_millis(STATUS_INTERVAL_MILLIS)
+                }
+            }
+        };
+        if changed {
+            self.published = Some((now, status.clone()));
+            Some(status)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BotPeers {
+    peers: Vec<BotPeer>,
+    key: Option<Arc<BotKey>>,
 }
 
-impl BotAddrs {
-    fn new(config: &Config) -> BotAddrs {
-        BotAddrs {
-            controller: config.controller_addr,
-            flyer: config.flyer_addr,
-            winches: config.winches.iter().map(|winch| { winch.addr }).collect()
+impl BotPeers {
+    fn new(config: &Config, key: Option<Arc<BotKey>>) -> BotPeers {
+        BotPeers {
+            peers: module_addrs(config).into_iter().map(|(module, addr)| BotPeer::new(module, addr, &key)).collect(),
+            key,
+        }
+    }
+
+    fn reconfigure(&mut self, config: &Config) {
+        let mut previous = mem::replace(&mut self.peers, Vec::new());
+        for (module, addr) in module_addrs(config) {
+            let peer = match previous.iter().position(|peer| peer.module == module && peer.addr == addr) {
+                Some(index) => previous.swap_remove(index),
+                None => BotPeer::new(module, addr, &self.key),
+            };
+            self.peers.push(peer);
+        }
+    }
+
+    fn find(&mut self, addr: &SocketAddr) -> Option<&mut BotPeer> {
+        self.peers.iter_mut().find(|peer| peer.addr == *addr)
+    }
+
+    fn num_winches(&self) -> usize {
+        self.peers.iter().filter(|peer| peer.module != BotModule::Flyer).count()
+    }
+
+    /// Who to send a message to, if that module exists and speaks it
+    fn peer_for(&mut self, module: BotModule, code: u8) -> io::Result<&mut BotPeer> {
+        match self.peers.iter_mut().find(|peer| peer.module == module) {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No such bot module in the config")),
+            Some(ref peer) if !peer.accepts(code) => Err(io::Error::new(io::ErrorKind::InvalidInput, "Bot module doesn't speak this message layout")),
+            Some(peer) => Ok(peer),
         }
     }
 }
@@ -142,72 +367,329 @@ impl BotAddrs {
 struct BotReceiver {
     socket: BotSocket,
     gimbal: GimbalPoller,
+    network: NetworkMonitor,
     controller: ControllerPort,
 }
 
 impl BotReceiver {
     fn new(socket: BotSocket, controller: &ControllerPort) -> BotReceiver {
+        let network = NetworkMonitor::new(socket.num_winches(), Instant::now());
         BotReceiver {
             socket,
             gimbal: GimbalPoller::new(),
+            network,
             controller: controller.clone(),
         }
     }
 
+    /// Safety-critical, since the controller can't see the winches without it
     fn start(mut self) {
-        thread::Builder::new().name("BotReceiver".into()).spawn(move || {
-            let mut buf = [0; 2048];
-            self.socket.udp.set_read_timeout(Some(GimbalPoller::read_timeout())).unwrap();
-            loop {
-                match self.socket.udp.recv_from(&mut buf) {
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
-                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
-                    Err(ref e) => panic!("Receiver thread failed: {:?}", e),
-
-                    Ok((num_bytes, src_addr)) => {
-                        if num_bytes >= 1 {
-                            self.bot_message(src_addr, buf[0], &buf[1..num_bytes]);
-                        }
+        let controller = self.controller.clone();
+        supervisor::spawn("BotReceiver", true, &controller, move || self.run());
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        let mut buf = [0; 2048];
+        self.socket.udp.set_read_timeout(Some(GimbalPoller::read_timeout())).map_err(|e| format!("Can't set bot socket timeout, {}", e))?;
+        loop {
+            match self.socket.udp.recv_from(&mut buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                // An earlier send found nobody listening, the netmonitor will notice the silence
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => (),
+                Err(e) => return Err(format!("Bot socket receive failed, {}", e)),
+
+                Ok((num_bytes, src_addr)) => {
+                    if num_bytes >= 1 {
+                        self.bot_message(src_addr, buf[0], &buf[1..num_bytes]);
                     }
                 }
-                self.gimbal.check_for_timeout(&mut self.socket.gimbal(), &self.controller);
             }
-        }).unwrap();
+            self.gimbal.check_for_timeout(&mut self.socket.gimbal(), &self.controller);
+            self.poll_peers();
+            self.poll_network();
+        }
+    }
+
+    /// Send any hellos that are due, and announce changes in what each module speaks
+    fn poll_peers(&mut self) {
+        let now = Instant::now();
+        let mut hellos = Vec::new();
+        let mut updates = Vec::new();
+        {
+            let mut peers = self.socket.peers();
+            for peer in peers.peers.iter_mut() {
+                if peer.hello_due(now) {
+                    peer.last_hello = Some(now);
+                    hellos.push(peer.module);
+                }
+                if let Some(status) = peer.status_update(now) {
+                    updates.push(status);
+                }
+            }
+        }
+        let hello = BotHello { protocol_version: BOT_PROTOCOL_VERSION };
+        for module in hellos {
+            drop(self.socket.send(module, MSG_HELLO, &hello));
+        }
+        for status in updates {
+            self.controller.send(Message::BotModuleStatus(status).timestamp());
+        }
     }
 
-    fn bot_message(&mut self, addr: SocketAddr, code: u8, msg: &[u8]) {
-        match code {
+    /// Publish link quality once per interval, and probe each module's latency
+    fn poll_network(&mut self) {
+        let now = Instant::now();
+        if !self.network.report_due(now) {
+            return;
+        }
+        self.network.resize(self.socket.num_winches());
+        let (round_trip_ms, loss) = self.gimbal.link_quality();
+        let status = self.network.report(round_trip_ms, loss, now);
+        self.controller.send(Message::NetworkStatus(status).timestamp());
+
+        let modules : Vec<BotModule> = self.socket.peers().peers.iter().map(|peer| peer.module).collect();
+        for module in modules {
+            let sequence = self.network.probe_sent(module, now);
+            drop(self.socket.send(module, MSG_LOOPBACK, &sequence));
+        }
+    }
 
-            MSG_WINCH_STATUS => {
-                let winches = &self.socket.addrs.winches;
-                for (id, winch_addr) in winches.iter().enumerate() {
-                    if *winch_addr == addr {
-                        match bincode::deserialize(msg) {
-                            Err(_) => (),
-                            Ok(status) => self.controller.send(Message::WinchStatus(id, status).timestamp()),
-                        }
+    fn bot_message(&mut self, addr: SocketAddr, code: u8, msg: &[u8]) {
+        let now = Instant::now();
+        let module = {
+            let mut peers = self.socket.peers();
+            let peer = match peers.find(&addr) {
+                Some(peer) => peer,
+                None => {
+                    self.network.unknown_source(addr);
+                    return;
+                }
+            };
+            self.network.packet(peer.module, now);
+            if code == MSG_CAPABILITIES {
+                match bincode::deserialize(msg) {
+                    Ok(caps) => peer.capabilities = Some(caps),
+                    Err(_) => {
+                        peer.malformed_messages += 1;
+                        self.network.malformed(peer.module);
                     }
                 }
+                return;
             }
-
-            MSG_FLYER_SENSORS => {
-                if self.socket.addrs.flyer == addr {
-                    match bincode::deserialize(msg) {
-                        Err(_) => (),
-                        Ok(sensors) => self.controller.send(Message::FlyerSensors(sensors).timestamp()),
+            if code == MSG_AUTH_SESSION {
+                // Without a key of our own there's nothing to check it against
+                if let Some(ref mut signer) = peer.signer {
+                    if let Err(e) = signer.session_received(msg) {
+                        println!("Ignoring auth session from {:?}, {}", peer.module, e);
+                        peer.malformed_messages += 1;
+                        self.network.malformed(peer.module);
                     }
                 }
+                return;
+            }
+            if !peer.accepts(code) {
+                peer.refused_messages += 1;
+                self.network.refused(peer.module);
+                return;
             }
+            peer.module
+        };
 
-            MSG_GIMBAL => {
-                if self.socket.addrs.flyer == addr {
-                    self.gimbal.received(msg, &mut self.socket.gimbal(), &self.controller);
-                }
+        let decoded = match (module, code) {
+
+            (BotModule::Winch(id), MSG_WINCH_STATUS) => match bincode::deserialize::<WinchStatus>(msg) {
+                Err(_) => false,
+                Ok(status) => {
+                    self.network.sequence(module, status.tick_counter);
+                    self.controller.send(Message::WinchStatus(id, status).timestamp());
+                    true
+                },
+            },
+
+            (BotModule::Flyer, MSG_FLYER_SENSORS) => match bincode::deserialize::<FlyerSensors>(msg) {
+                Err(_) => false,
+                Ok(sensors) => {
+                    // The analog channels are sampled once per packet, so their counter numbers packets
+                    self.network.sequence(module, sensors.analog.counter);
+                    self.controller.send(Message::FlyerSensors(sensors).timestamp());
+                    true
+                },
+            },
+
+            (BotModule::Flyer, MSG_GIMBAL) => {
+                self.network.gimbal_packet(now);
+                self.gimbal.received(msg, &mut self.socket.gimbal(), &self.controller);
+                true
             },
 
-            MSG_LOOPBACK => (),
-            _ => (),
+            (_, MSG_LOOPBACK) => match bincode::deserialize(msg) {
+                Err(_) => false,
+                Ok(sequence) => {
+                    self.network.probe_answered(module, sequence, now);
+                    true
+                },
+            },
+
+            _ => true,
+        };
+
+        if !decoded {
+            self.network.malformed(module);
+            if let Some(peer) = self.socket.peers().find(&addr) {
+                peer.malformed_messages += 1;
+            }
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use config::ControllerMode;
+    use controller::tests::{test_config, TestPort};
+    use std::sync::mpsc;
+
+    /// Bytes in a layout string, where each field is a type with an optional repeat count
+    fn layout_size(layout: &str) -> usize {
+        layout.split_whitespace().map(|field| {
+            let mut parts = field.split('*');
+            let size = match parts.next().unwrap() {
+                "u8" | "i8" => 1,
+                "u16" | "i16" => 2,
+                "u32" | "i32" | "f32" => 4,
+                other => panic!("Unknown field type {} in layout", other),
+            };
+            let count : usize = parts.next().map_or(1, |count| count.parse().unwrap());
+            size * count
+        }).sum()
+    }
+
+    fn layout(code: u8) -> &'static str {
+        MESSAGE_LAYOUTS.iter().find(|&&(c, _)| c == code).unwrap().1
+    }
+
+    /// The struct must decode from exactly as many bytes as the layout describes
+    fn check_layout<T: Serialize + DeserializeOwned>(code: u8) {
+        let size = layout_size(layout(code));
+        let decoded : T = bincode::deserialize(&vec![0; size]).unwrap();
+        assert_eq!(bincode::serialized_size(&decoded), size as u64);
+        assert!(bincode::deserialize::<T>(&vec![0; size - 1]).is_err());
+    }
+
+    #[test]
+    fn layouts_match_struct_sizes() {
+        check_layout::<FlyerSensors>(MSG_FLYER_SENSORS);
+        check_layout::<WinchStatus>(MSG_WINCH_STATUS);
+        check_layout::<WinchCommand>(MSG_WINCH_COMMAND);
+    }
+
+    #[test]
+    fn every_module_message_has_a_layout() {
+        for &code in FLYER_MESSAGES.iter().chain(WINCH_MESSAGES.iter()) {
+            assert!(expected_layout_hash(code).is_some(), "No layout for message {}", code);
+        }
+        assert_eq!(layout_hash(""), 0x811c9dc5);
+        assert_eq!(layout_hash("a"), 0xe40c292c);
+    }
+
+    fn winch_peer(capabilities: Option<BotCapabilities>) -> BotPeer {
+        let mut peer = BotPeer::new(BotModule::Winch(0), "127.0.0.1:9".parse().unwrap(), &None);
+        peer.capabilities = capabilities;
+        peer
+    }
+
+    #[test]
+    fn silent_module_is_trusted() {
+        let peer = winch_peer(None);
+        assert!(peer.accepts(MSG_WINCH_COMMAND));
+        assert!(peer.accepts(MSG_WINCH_STATUS));
+        let status = peer.status();
+        assert_eq!(status.state, BotModuleState::Unknown);
+        assert_eq!(status.refused_codes, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn matching_layouts_are_compatible() {
+        let peer = winch_peer(Some(capabilities(BotModule::Winch(0), 7)));
+        for &code in WINCH_MESSAGES.iter() {
+            assert!(peer.accepts(code));
+        }
+        let status = peer.status();
+        assert_eq!(status.state, BotModuleState::Compatible);
+        assert_eq!(status.refused_codes, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn mismatched_layout_refuses_only_that_message() {
+        let mut caps = capabilities(BotModule::Winch(0), 7);
+        for layout in caps.messages.iter_mut() {
+            if layout.code == MSG_WINCH_COMMAND {
+                layout.layout_hash ^= 1;
+            }
+        }
+        let peer = winch_peer(Some(caps));
+        assert!(!peer.accepts(MSG_WINCH_COMMAND));
+        assert!(peer.accepts(MSG_WINCH_STATUS));
+        assert!(peer.accepts(MSG_LEDS));
+        let status = peer.status();
+        assert_eq!(status.state, BotModuleState::Mismatched);
+        assert_eq!(status.refused_codes, vec![MSG_WINCH_COMMAND]);
+
+        let mut peers = BotPeers { peers: vec![peer], key: None };
+        assert_eq!(peers.peer_for(BotModule::Winch(0), MSG_WINCH_COMMAND).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(peers.peer_for(BotModule::Winch(0), MSG_LEDS).is_ok());
+    }
+
+    #[test]
+    fn unlisted_message_is_refused() {
+        let mut caps = capabilities(BotModule::Winch(0), 7);
+        caps.messages.retain(|layout| layout.code != MSG_LEDS);
+        let peer = winch_peer(Some(caps));
+        assert!(!peer.accepts(MSG_LEDS));
+        assert_eq!(peer.status().refused_codes, vec![MSG_LEDS]);
+    }
+
+    #[test]
+    fn other_protocol_version_is_incompatible() {
+        let mut caps = capabilities(BotModule::Winch(0), 7);
+        caps.protocol_version = BOT_PROTOCOL_VERSION + 1;
+        let peer = winch_peer(Some(caps));
+        for &code in WINCH_MESSAGES.iter() {
+            assert!(!peer.accepts(code));
+        }
+        // Still reachable for hellos and probes, so we notice new firmware
+        assert!(peer.accepts(MSG_HELLO));
+        assert!(peer.accepts(MSG_LOOPBACK));
+        let status = peer.status();
+        assert_eq!(status.state, BotModuleState::Incompatible);
+        assert_eq!(status.refused_codes, WINCH_MESSAGES.to_vec());
+    }
+
+    #[test]
+    fn peers_survive_a_panic_while_locked() {
+        let config = test_config(ControllerMode::Halted);
+        let socket = BotSocket::new(&config).unwrap();
+        let clone = socket.try_clone().unwrap();
+        let test_port = TestPort::new();
+        let (done, finished) = mpsc::channel();
+
+        let mut runs = 0;
+        supervisor::spawn("Peers test", false, &test_port.port, move || {
+            runs += 1;
+            let peers = clone.peers();
+            if runs == 1 { panic!("Panicked holding the peers lock") }
+            done.send(peers.num_winches()).unwrap();
+            Ok(())
+        });
+
+        // The restarted body gets the lock, and so does everyone else
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)).unwrap(), config.winches.len());
+        assert!(socket.peers.is_poisoned());
+        assert_eq!(socket.num_winches(), config.winches.len());
+        socket.reconfigure(&config);
+        assert!(socket.send(BotModule::Winch(0), MSG_LEDS, &[0u8; 4]).is_ok());
+    }
+}
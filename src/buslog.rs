@@ -0,0 +1,403 @@
+//! Compact on-disk log of the controller message bus, for post-flight analysis and replay.
+//!
+//! The log file holds a short header followed by length-prefixed frames, one per message,
+//! each tagged with the time since recording started. Alongside it, an index file holds
+//! fixed-size (time, offset) entries at regular intervals so readers can seek without
+//! scanning. Both files are only ever appended to, so a log cut short by a crash is still
+//! readable up to its last complete frame.
+
+use message::*;
+use config::Config;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LittleEndian};
+use bincode;
+use serde_json;
+
+const MAGIC : &[u8; 8] = b"TFBUSLOG";
+const VERSION : u32 = 1;
+const HEADER_LEN : u64 = 12;
+
+/// Frame length, not including the length itself
+const FRAME_HEADER_LEN : u32 = 9;
+const MAX_FRAME_LEN : u32 = 16 * 1024 * 1024;
+
+/// Log time between index entries
+const INDEX_INTERVAL_MICROS : u64 = 1_000_000;
+
+const ENCODING_BINCODE : u8 = 0;
+const ENCODING_JSON : u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusLogRecord {
+    /// Microseconds since recording started
+    pub micros: u64,
+    pub message: Message,
+}
+
+pub struct BusLogWriter {
+    file: BufWriter<File>,
+    index: BufWriter<File>,
+    start: Instant,
+    offset: u64,
+    next_index_micros: u64,
+}
+
+pub struct BusLogReader {
+    file: BufReader<File>,
+    index: Vec<(u64, u64)>,
+    recorded_config: Option<Config>,
+    pending: Option<BusLogRecord>,
+}
+
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+pub fn duration_to_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_micros() as u64
+}
+
+pub fn micros_to_duration(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000)
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl BusLogWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<BusLogWriter> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(File::create(path)?);
+        let index = BufWriter::new(File::create(index_path(path))?);
+        file.write_all(MAGIC)?;
+        file.write_u32::<LittleEndian>(VERSION)?;
+        Ok(BusLogWriter {
+            file,
+            index,
+            start: Instant::now(),
+            offset: HEADER_LEN,
+            next_index_micros: 0,
+        })
+    }
+
+    pub fn write(&mut self, tsm: &TimestampedMessage) -> io::Result<()> {
+        let micros = if tsm.timestamp > self.start {
+            duration_to_micros(tsm.timestamp - self.start)
+        } else {
+            0
+        };
+
+        // Freeform JSON values can't round-trip through bincode
+        let (encoding, payload) = match &tsm.message {
+            &Message::UpdateConfig(_) => (ENCODING_JSON, serde_json::to_vec(&tsm.message).map_err(invalid_data)?),
+            message => (ENCODING_BINCODE, bincode::serialize(message, bincode::Infinite).map_err(invalid_data)?),
+        };
+
+        if micros >= self.next_index_micros {
+            self.index.write_u64::<LittleEndian>(micros)?;
+            self.index.write_u64::<LittleEndian>(self.offset)?;
+            self.next_index_micros = (micros / INDEX_INTERVAL_MICROS + 1) * INDEX_INTERVAL_MICROS;
+            self.flush()?;
+        }
+
+        let frame_len = FRAME_HEADER_LEN + payload.len() as u32;
+        self.file.write_u32::<LittleEndian>(frame_len)?;
+        self.file.write_u64::<LittleEndian>(micros)?;
+        self.file.write_u8(encoding)?;
+        self.file.write_all(&payload)?;
+        self.offset += 4 + frame_len as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.index.flush()
+    }
+}
+
+impl BusLogReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<BusLogReader> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a bus log file"));
+        }
+        let version = file.read_u32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(invalid_data(format!("Unsupported bus log version {}", version)));
+        }
+
+        let mut reader = BusLogReader {
+            file,
+            index: Vec::new(),
+            recorded_config: None,
+            pending: None,
+        };
+
+        reader.index = match File::open(index_path(path)) {
+            Ok(index_file) => read_index(index_file)?,
+            Err(_) => reader.scan_index()?,
+        };
+
+        // Recordings start with a snapshot of the configuration in use
+        if let Some(record) = reader.read_record()? {
+            if let Message::ConfigIsCurrent(ref config) = record.message {
+                reader.recorded_config = Some(config.clone());
+            }
+            reader.pending = Some(record);
+        }
+        Ok(reader)
+    }
+
+    /// Configuration in effect when recording started, if the log has one
+    pub fn recorded_config(&self) -> Option<&Config> {
+        self.recorded_config.as_ref()
+    }
+
+    /// Time of the last indexed point in the log, a lower bound on its duration
+    pub fn indexed_micros(&self) -> u64 {
+        self.index.last().map_or(0, |&(micros, _)| micros)
+    }
+
+    /// Position the reader at the first record at or after a time
+    pub fn seek(&mut self, micros: u64) -> io::Result<()> {
+        let offset = match self.index.iter().rev().find(|&&(entry_micros, _)| entry_micros <= micros) {
+            Some(&(_, offset)) => offset,
+            None => HEADER_LEN,
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.pending = None;
+        while let Some(record) = self.read_record()? {
+            if record.micros >= micros {
+                self.pending = Some(record);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_index(&mut self) -> io::Result<Vec<(u64, u64)>> {
+        // No index file, rebuild it by walking the frame headers
+        let mut index = Vec::new();
+        let mut next_index_micros = 0;
+        let mut offset = self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+        loop {
+            let frame_len = match self.file.read_u32::<LittleEndian>() {
+                Ok(len) if len >= FRAME_HEADER_LEN && len <= MAX_FRAME_LEN => len,
+                _ => break,
+            };
+            let micros = match self.file.read_u64::<LittleEndian>() {
+                Ok(micros) => micros,
+                Err(_) => break,
+            };
+            if micros >= next_index_micros {
+                index.push((micros, offset));
+                next_index_micros = (micros / INDEX_INTERVAL_MICROS + 1) * INDEX_INTERVAL_MICROS;
+            }
+            offset = self.file.seek(SeekFrom::Current(frame_len as i64 - 8))?;
+        }
+        self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+        Ok(index)
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<BusLogRecord>> {
+        let frame_len = match self.file.read_u32::<LittleEndian>() {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+            Ok(len) => len,
+        };
+        if frame_len < FRAME_HEADER_LEN || frame_len > MAX_FRAME_LEN {
+            return Err(invalid_data(format!("Bad frame length {}", frame_len)));
+        }
+
+        let mut frame = vec![0; frame_len as usize];
+        match self.file.read_exact(&mut frame) {
+            // The recorder stopped partway through writing this frame
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        }
+
+        let (header, payload) = frame.split_at(FRAME_HEADER_LEN as usize);
+        let micros = LittleEndian::read_u64(&header[0..8]);
+        let message = match header[8] {
+            ENCODING_BINCODE => bincode::deserialize(payload).map_err(invalid_data)?,
+            ENCODING_JSON => serde_json::from_slice(payload).map_err(invalid_data)?,
+            other => return Err(invalid_data(format!("Unknown frame encoding {}", other))),
+        };
+        Ok(Some(BusLogRecord { micros, message }))
+    }
+}
+
+impl Iterator for BusLogReader {
+    type Item = io::Result<BusLogRecord>;
+
+    fn next(&mut self) -> Option<io::Result<BusLogRecord>> {
+        if let Some(record) = self.pending.take() {
+            return Some(Ok(record));
+        }
+        match self.read_record() {
+            Ok(None) => None,
+            Ok(Some(record)) => Some(Ok(record)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn read_index(file: File) -> io::Result<Vec<(u64, u64)>> {
+    let mut file = BufReader::new(file);
+    let mut index = Vec::new();
+    loop {
+        let micros = match file.read_u64::<LittleEndian>() {
+            Ok(micros) => micros,
+            Err(_) => break,
+        };
+        match file.read_u64::<LittleEndian>() {
+            Ok(offset) => index.push((micros, offset)),
+            Err(_) => break,
+        }
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::test_config;
+    use std::env;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use rand;
+
+    /// A log file and its index, removed afterward
+    struct TempLog {
+        path: PathBuf,
+    }
+
+    impl TempLog {
+        fn new() -> TempLog {
+            TempLog { path: env::temp_dir().join(format!("tucoflyer-buslog-test-{:08x}.log", rand::random::<u32>())) }
+        }
+
+        /// Write each message at its time in microseconds
+        fn write(&self, records: &[(u64, Message)]) {
+            let mut log = BusLogWriter::create(&self.path).unwrap();
+            for &(micros, ref message) in records {
+                let timestamp = log.start + micros_to_duration(micros);
+                log.write(&TimestampedMessage { timestamp, message: message.clone() }).unwrap();
+            }
+            log.flush().unwrap();
+        }
+
+        fn read_all(&self) -> Vec<BusLogRecord> {
+            BusLogReader::open(&self.path).unwrap().map(|record| record.unwrap()).collect()
+        }
+
+        fn truncate_by(&self, bytes: u64) {
+            let file = OpenOptions::new().write(true).open(&self.path).unwrap();
+            let len = file.metadata().unwrap().len();
+            file.set_len(len - bytes).unwrap();
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            drop(fs::remove_file(&self.path));
+            drop(fs::remove_file(index_path(&self.path)));
+        }
+    }
+
+    fn set_mode(mode: ControllerMode) -> Message {
+        Message::Command(Command::SetMode(mode))
+    }
+
+    /// One record every 400 ms, for 3.6 seconds
+    fn numbered_records() -> Vec<(u64, Message)> {
+        (0 .. 10).map(|i| (i * 400_000, set_mode(ControllerMode::ManualWinch(i as usize)))).collect()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let log = TempLog::new();
+        let config = test_config(ControllerMode::Halted);
+        let edit : serde_json::Value = serde_json::from_str(r#"{ "mode": "Normal" }"#).unwrap();
+        log.write(&[
+            (0, Message::ConfigIsCurrent(config.clone())),
+            (500_000, set_mode(ControllerMode::Normal)),
+            (1_500_000, Message::UpdateConfig(edit.clone())),
+        ]);
+
+        assert_eq!(BusLogReader::open(&log.path).unwrap().recorded_config(), Some(&config));
+        assert_eq!(log.read_all(), vec![
+            BusLogRecord { micros: 0, message: Message::ConfigIsCurrent(config) },
+            BusLogRecord { micros: 500_000, message: set_mode(ControllerMode::Normal) },
+            BusLogRecord { micros: 1_500_000, message: Message::UpdateConfig(edit) },
+        ]);
+    }
+
+    #[test]
+    fn seek_uses_the_index() {
+        let log = TempLog::new();
+        log.write(&numbered_records());
+
+        let mut reader = BusLogReader::open(&log.path).unwrap();
+        assert_eq!(reader.index.iter().map(|&(micros, _)| micros).collect::<Vec<_>>(), vec![0, 1_200_000, 2_000_000, 3_200_000]);
+        assert_eq!(reader.indexed_micros(), 3_200_000);
+
+        reader.seek(2_100_000).unwrap();
+        let record = reader.next().unwrap().unwrap();
+        assert_eq!(record, BusLogRecord { micros: 2_400_000, message: set_mode(ControllerMode::ManualWinch(6)) });
+        reader.seek(400_000).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().micros, 400_000);
+        reader.seek(3_700_000).unwrap();
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn missing_index_is_rebuilt() {
+        let log = TempLog::new();
+        log.write(&numbered_records());
+        let indexed = BusLogReader::open(&log.path).unwrap().index;
+
+        fs::remove_file(index_path(&log.path)).unwrap();
+        let mut reader = BusLogReader::open(&log.path).unwrap();
+        assert_eq!(reader.index, indexed);
+        reader.seek(3_000_000).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().micros, 3_200_000);
+    }
+
+    #[test]
+    fn truncated_final_frame_ends_the_log() {
+        let log = TempLog::new();
+        let records = numbered_records();
+        log.write(&records);
+
+        // Cut off partway through the last payload
+        log.truncate_by(1);
+        let expected : Vec<BusLogRecord> = records[.. 9].iter().map(|&(micros, ref message)| {
+            BusLogRecord { micros, message: message.clone() }
+        }).collect();
+        assert_eq!(log.read_all(), expected);
+
+        // Cut off partway through the last length prefix. Every frame here is the same size.
+        let frame_len = {
+            let mut reader = BusLogReader::open(&log.path).unwrap();
+            reader.file.seek(SeekFrom::Start(HEADER_LEN)).unwrap();
+            reader.file.read_u32::<LittleEndian>().unwrap() as u64
+        };
+        log.truncate_by(4 + frame_len - 1 - 2);
+        assert_eq!(log.read_all(), expected);
+    }
+}
@@ -16,16 +16,25 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use chrono::NaiveTime;
+use controller::ControllerPort;
+use supervisor;
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Config {
     pub mode: ControllerMode,
     pub controller_addr: SocketAddr,
     pub flyer_addr: SocketAddr,
+    pub bot_auth: Option<BotAuthConfig>,
+    pub flyer_home_loc: Vector3<f32>,
     pub web: WebConfig,
     pub metrics: Option<MetricsConfig>,
+    pub recorder: Option<RecorderConfig>,
     pub params: BotParams,
+    pub estimator: EstimatorConfig,
+    pub geofence: GeofenceConfig,
+    pub winch_autotune: WinchAutotuneConfig,
     pub gimbal: GimbalConfig,
+    pub gimbal_firmware: Option<GimbalFirmwareConfig>,
     pub overlay: OverlayConfig,
     pub vision: VisionConfig,
     pub winches: Vec<WinchConfig>,
@@ -36,6 +45,8 @@ pub struct Config {
 pub struct SharedConfigFile {
     config: Arc<Mutex<Config>>,
     async_save_channel: Sender<Config>,
+    /// Where to save, and the saves waiting for a thread to write them
+    pending_save_thread: Arc<Mutex<Option<(PathBuf, Receiver<Config>)>>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -44,6 +55,14 @@ pub enum ControllerMode {
     Normal,
     ManualFlyer,
     ManualWinch(usize),
+    Trajectory,
+}
+
+/// Sign winch commands and LED data, for firmware built with the same key. The key is kept
+/// in its own file, since the config is shared with every websocket client and bus log.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BotAuthConfig {
+    pub key_path: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -171,6 +190,8 @@ pub struct OverlayConfig {
     pub gimbal_cursor_color: Vector4<f32>,
     pub gimbal_cursor_size: f32,
     pub gimbal_cursor_sprite: Vector4<i32>,
+    pub geofence_violation_color: Vector4<f32>,
+    pub geofence_slowdown_color: Vector4<f32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -188,6 +209,16 @@ pub struct VisionConfig {
     pub snap_tracked_region_to: Vec<(String, f32)>,
 }
 
+/// Firmware updates stay off unless this is configured. Our bootloader framing (256 byte
+/// blocks each led by a little endian block number, acknowledgements echoing that number,
+/// bootloader version 3) hasn't been checked against a capture of the vendor's updater, so
+/// only enable this for a gimbal you can recover if the update goes wrong.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GimbalFirmwareConfig {
+    /// One image per MCU, in yaw, roll, pitch order, read when an update starts
+    pub image_paths: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct GimbalConfig {
     pub values: BTreeMap<u8, Vector3<Option<i16>>>,
@@ -212,6 +243,38 @@ pub struct GimbalConfig {
     pub error_duration_for_poweroff: f32,
     pub motor_voltage_min: f32,
     pub motor_voltage_max: f32,
+    pub tuning: GimbalTuningConfig,
+    pub look_at: GimbalLookAtConfig,
+    /// Where full snapshots of the gimbal's values are saved
+    pub profile_directory: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GimbalLookAtConfig {
+    /// Room coordinates to keep the camera aimed at, or None for vision tracking
+    pub point: Option<Vector3<f32>>,
+    /// Gimbal pivot relative to the flyer's IMU, in the flyer body frame
+    pub pivot_offset_m: Vector3<f32>,
+    /// Body frame direction of the yaw motor's axis
+    pub yaw_axis: Vector3<f32>,
+    /// Body frame direction the camera faces with both encoder angles at zero
+    pub zero_direction: Vector3<f32>,
+    /// Signed encoder counts per radian, for each of yaw and pitch
+    pub counts_per_radian: Vector2<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GimbalTuningConfig {
+    pub settle_sec: f32,
+    pub step_rate: f32,
+    pub step_sec: f32,
+    pub chirp_rate: f32,
+    pub chirp_start_hz: f32,
+    pub chirp_end_hz: f32,
+    pub chirp_sec: f32,
+    pub max_delay_sec: f32,
+    pub hold_phase_margin_deg: f32,
+    pub limiter_phase_margin_deg: f32,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -226,6 +289,9 @@ pub struct BotParams {
     pub gimbal_max_control_rate: f32,
     pub manual_control_velocity_m_per_sec: f32,
     pub accel_limit_m_per_sec2: f32,
+    pub jerk_limit_m_per_sec3: f32,
+    pub trajectory_velocity_limit_m_per_sec: f32,
+    pub trajectory_position_gain: f32,
     pub force_neg_motion_min_kg: f32,
     pub force_pos_motion_max_kg: f32,
     pub force_lockout_below_kg: f32,
@@ -249,6 +315,45 @@ pub struct BotParams {
     pub pwm_hz_filter_param: f32,
     pub pwm_velocity_threshold: f32,
     pub winch_watchdog_millis: u64,
+    /// Fraction of winch status packets lost over a NetworkStatus interval that halts motion
+    pub winch_watchdog_max_loss: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EstimatorConfig {
+    pub initial_position_sigma_m: f32,
+    pub rope_length_sigma_m: f32,
+    pub imu_accel_sigma_m_per_sec2: f32,
+    pub imu_accel_m_per_sec2_per_count: f32,
+    pub lidar_sigma_m: f32,
+    pub lidar_m_per_count: f32,
+    pub lidar_max_range_m: f32,
+    pub lidar_min_down_component: f32,
+    pub lidar_directions: Vec<Vector3<f32>>,
+    pub floor_z: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GeofenceConfig {
+    pub box_min: Vector3<f32>,
+    pub box_max: Vector3<f32>,
+    pub planes: Vec<Vector4<f32>>,
+    pub floor_margin_m: f32,
+    pub ceiling_margin_m: f32,
+    pub anchor_hull_margin_m: f32,
+    pub slowdown_extent_m: f32,
+    pub slowdown_velocity_m_per_sec: f32,
+    pub spring_gain: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct WinchAutotuneConfig {
+    pub relay_pwm: f32,
+    pub relay_hysteresis_m: f32,
+    pub max_excursion_m: f32,
+    pub settle_cycles: u32,
+    pub measure_cycles: u32,
+    pub timeout_sec: f32,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -259,6 +364,11 @@ pub struct MetricsConfig {
     pub max_sample_hz: f32,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RecorderConfig {
+    pub directory: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct WebConfig {
     pub http_addr: SocketAddr,
@@ -312,8 +422,18 @@ impl SharedConfigFile {
         err_string(file.read_to_string(&mut buffer))?;
         let config = Arc::new(Mutex::new(err_string(serde_yaml::from_str(&buffer))?));
         let (async_save_channel, save_thread_receiver) = channel();
-        SharedConfigFile::start_save_thread(path, save_thread_receiver);
-        Ok(SharedConfigFile { config, async_save_channel })
+        let pending_save_thread = Arc::new(Mutex::new(Some((path, save_thread_receiver))));
+        Ok(SharedConfigFile { config, async_save_channel, pending_save_thread })
+    }
+
+    /// Shared configuration that lives only in memory, changes are never saved
+    pub fn in_memory(config: Config) -> SharedConfigFile {
+        let (async_save_channel, _) = channel();
+        SharedConfigFile {
+            config: Arc::new(Mutex::new(config)),
+            async_save_channel,
+            pending_save_thread: Arc::new(Mutex::new(None)),
+        }
     }
 
     pub fn get_latest(&self) -> Config {
@@ -325,30 +445,36 @@ impl SharedConfigFile {
         drop(self.async_save_channel.send(config));
     }
 
-    fn start_save_thread(path: PathBuf, receiver: Receiver<Config>) {
-        const CONSOLIDATION_MILLIS : u64 = 1000;
-        thread::Builder::new().name("SharedConfigFile".into()).spawn(move || {
-            loop {
-                // Block until any config save at all shows up
-                let config = match receiver.recv() {
-                    Ok(config) => config,
-                    Err(_) => return,
-                };
-
-                // Wait a bit to see if something newer shows up
-                thread::sleep(Duration::from_millis(CONSOLIDATION_MILLIS));
-                let config = match receiver.try_iter().last() {
-                    Some(config) => config,
-                    None => config,
-                };
-
-                let string = serde_yaml::to_string(&config).unwrap();
-                let af = atomicwrites::AtomicFile::new(&path, atomicwrites::AllowOverwrite);
-                af.write( |f| {
-                    f.write_all(string.as_bytes())
-                }).expect("Failed to write new configuration file");
-            }
-        }).unwrap();
+    /// Start writing changes back to the file, with failures reported to the controller.
+    /// Anything set before this is saved once it starts.
+    pub fn start_saving(&self, controller: &ControllerPort) {
+        if let Some((path, receiver)) = self.pending_save_thread.lock().unwrap().take() {
+            supervisor::spawn("SharedConfigFile", false, controller, move || save_changes(&path, &receiver));
+        }
+    }
+}
+
+fn save_changes(path: &Path, receiver: &Receiver<Config>) -> Result<(), String> {
+    const CONSOLIDATION_MILLIS : u64 = 1000;
+    loop {
+        // Block until any config save at all shows up
+        let config = match receiver.recv() {
+            Ok(config) => config,
+            Err(_) => return Ok(()),
+        };
+
+        // Wait a bit to see if something newer shows up
+        thread::sleep(Duration::from_millis(CONSOLIDATION_MILLIS));
+        let config = match receiver.try_iter().last() {
+            Some(config) => config,
+            None => config,
+        };
+
+        let string = err_string(serde_yaml::to_string(&config))?;
+        let af = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+        af.write( |f| {
+            f.write_all(string.as_bytes())
+        }).map_err(|err| format!("Failed to write new configuration file, {}", err))?;
     }
 }
 
@@ -392,15 +518,25 @@ fn merge_map_into_array(base: &mut Vec<Value>, update: &Map<String, Value>) -> b
     // Updating an array with a map, fails unless all keys
     // in the map are numeric and already exist in the array.
     // The possibility for failure means we take a ref to update and copy inserted items.
+    // As with maps, nulls delete elements. This is how winches are removed.
+    let mut removed = Vec::new();
     for (key, item) in update.iter() {
         if let Ok(index) = key.parse::<usize>() {
             if index < base.len() {
-                merge_values(&mut base[index], item.clone());
+                match *item {
+                    Value::Null => removed.push(index),
+                    ref item => merge_values(&mut base[index], item.clone()),
+                }
                 continue;
             }
         }
         return false;
     }
+    // Highest index first, so removals don't shift the ones still to come
+    removed.sort();
+    for index in removed.into_iter().rev() {
+        base.remove(index);
+    }
     true
 }
 
@@ -0,0 +1,369 @@
+//! Relay feedback autotuning for the winch position loop. While the operator has manual
+//! control of a winch, we replace its PID loop with a bang-bang relay around the current
+//! position and watch the resulting limit cycle. Its period and amplitude give the ultimate
+//! gain and period of the loop, from which a few classic tuning rules propose gains in the
+//! same meter-based units as BotParams.
+
+use message::*;
+use config::{Config, ControllerMode};
+use controller::winch::MechStatus;
+use std::f32::consts::PI;
+
+struct RelayTest {
+    center: i32,
+    start_tick: u32,
+    output_positive: bool,
+    last_cycle_tick: Option<u32>,
+    cycle_min: i32,
+    cycle_max: i32,
+    period_ticks: Vec<u32>,
+    amplitude_counts: Vec<f32>,
+}
+
+pub struct WinchAutotuner {
+    status: Option<WinchAutotuneStatus>,
+    relay: Option<RelayTest>,
+}
+
+impl WinchAutotuner {
+    pub fn new() -> WinchAutotuner {
+        WinchAutotuner {
+            status: None,
+            relay: None,
+        }
+    }
+
+    pub fn status(&self) -> Option<WinchAutotuneStatus> {
+        self.status.clone()
+    }
+
+    pub fn is_running(&self, id: usize) -> bool {
+        match self.status {
+            Some(ref status) => status.running && status.id == id,
+            None => false,
+        }
+    }
+
+    /// Handle an operator command, returning the gains to save if they were just confirmed
+    pub fn command(&mut self, config: &Config, command: WinchAutotuneCommand) -> Option<WinchPIDProposal> {
+        let result = match command {
+
+            WinchAutotuneCommand::Start(id) => {
+                self.start(config, id);
+                return None;
+            },
+
+            WinchAutotuneCommand::Cancel => {
+                if self.status.as_ref().map_or(false, |status| status.running) {
+                    self.stop(Some("Cancelled".into()));
+                    return None;
+                }
+                Err("No winch autotune in progress".into())
+            },
+
+            WinchAutotuneCommand::Confirm(rule) => match self.status {
+                None => Err("No winch autotune results".into()),
+                Some(ref status) if status.running => Err("Wait for the autotune to finish".into()),
+                Some(ref status) => match status.proposals.iter().find(|proposal| proposal.rule == rule) {
+                    Some(proposal) => Ok(proposal.clone()),
+                    None => Err("Autotune has no proposal for this rule".into()),
+                },
+            },
+        };
+
+        match result {
+            Ok(proposal) => {
+                self.set_error(None);
+                Some(proposal)
+            },
+            Err(e) => {
+                self.set_error(Some(e));
+                None
+            }
+        }
+    }
+
+    /// Run the relay for one status packet from the winch under test. Returns the command to
+    /// send instead of the normal one, or None if the test isn't running on this winch.
+    pub fn relay_tick(&mut self, config: &Config, id: usize, status: &WinchStatus, mech_status: MechStatus, base_command: WinchCommand) -> Option<WinchCommand> {
+        if !self.is_running(id) {
+            return None;
+        }
+        let result = self.relay_step(config, id, status, mech_status);
+        match result {
+            Err(e) => {
+                self.stop(Some(e));
+                None
+            },
+            Ok(false) => {
+                self.finish(config, id);
+                None
+            },
+            Ok(true) => {
+                // The relay is built from the firmware's P term. Its target sits at the edge of
+                // the allowed excursion, so a command left standing after we stop can't carry the
+                // winch any further than the test would have. The gain is rescaled each packet
+                // to keep the PWM output at a constant relay amplitude.
+                let relay = self.relay.as_ref().unwrap();
+                let excursion = config.winches[id].calibration.dist_from_m(config.winch_autotune.max_excursion_m).abs().round() as i32;
+                let direction = if relay.output_positive { 1 } else { -1 };
+                let target = relay.center.wrapping_add(direction * excursion);
+                let error = target.wrapping_sub(status.sensors.position).abs().max(1);
+                let mut command = base_command;
+                command.position = target;
+                command.pid = PIDGains {
+                    gain_p: config.winch_autotune.relay_pwm / error as f32,
+                    gain_i: 0.0,
+                    gain_d: 0.0,
+                    p_filter_param: 1.0,
+                    i_decay_param: 1.0,
+                    d_filter_param: config.params.vel_err_filter_param,
+                };
+                Some(command)
+            }
+        }
+    }
+
+    fn start(&mut self, config: &Config, id: usize) {
+        let error = if id >= config.winches.len() {
+            Some(format!("No winch with id {}", id))
+        } else if config.mode != ControllerMode::ManualWinch(id) {
+            Some("Autotune requires manual control of this winch".into())
+        } else {
+            None
+        };
+        self.relay = None;
+        self.status = Some(WinchAutotuneStatus {
+            id,
+            running: error.is_none(),
+            cycles_completed: 0,
+            ultimate_gain: None,
+            ultimate_period_sec: None,
+            amplitude_m: None,
+            proposals: Vec::new(),
+            error,
+        });
+    }
+
+    /// Returns Ok(true) while the relay should keep running
+    fn relay_step(&mut self, config: &Config, id: usize, status: &WinchStatus, mech_status: MechStatus) -> Result<bool, String> {
+        if config.mode != ControllerMode::ManualWinch(id) {
+            return Err("Left manual control of this winch".into());
+        }
+        if mech_status != MechStatus::Normal {
+            return Err(format!("Winch reports {:?}", mech_status));
+        }
+
+        let tune = &config.winch_autotune;
+        let cal = &config.winches[id].calibration;
+        let position = status.sensors.position;
+        let relay = self.relay.get_or_insert(RelayTest {
+            center: position,
+            start_tick: status.tick_counter,
+            output_positive: true,
+            last_cycle_tick: None,
+            cycle_min: 0,
+            cycle_max: 0,
+            period_ticks: Vec::new(),
+            amplitude_counts: Vec::new(),
+        });
+
+        let elapsed_ticks = status.tick_counter.wrapping_sub(relay.start_tick);
+        if elapsed_ticks as f32 > tune.timeout_sec * TICK_HZ as f32 {
+            return Err("No steady oscillation before the timeout".into());
+        }
+
+        let offset = position.wrapping_sub(relay.center);
+        if cal.dist_to_m(offset as f32).abs() > tune.max_excursion_m {
+            return Err("Winch moved too far from its starting position".into());
+        }
+        relay.cycle_min = relay.cycle_min.min(offset);
+        relay.cycle_max = relay.cycle_max.max(offset);
+
+        let hysteresis = cal.dist_from_m(tune.relay_hysteresis_m).abs();
+        if relay.output_positive && offset as f32 > hysteresis {
+            relay.output_positive = false;
+        } else if !relay.output_positive && (offset as f32) < -hysteresis {
+            // Each switch back to positive output completes one cycle
+            relay.output_positive = true;
+            if let Some(last_tick) = relay.last_cycle_tick {
+                let completed = self.status.as_mut().unwrap();
+                completed.cycles_completed += 1;
+                if completed.cycles_completed > tune.settle_cycles {
+                    relay.period_ticks.push(status.tick_counter.wrapping_sub(last_tick));
+                    relay.amplitude_counts.push((relay.cycle_max - relay.cycle_min) as f32 / 2.0);
+                }
+            }
+            relay.last_cycle_tick = Some(status.tick_counter);
+            relay.cycle_min = offset;
+            relay.cycle_max = offset;
+        }
+
+        Ok(relay.period_ticks.len() < tune.measure_cycles.max(1) as usize)
+    }
+
+    fn finish(&mut self, config: &Config, id: usize) {
+        let relay = self.relay.take().unwrap();
+        let tune = &config.winch_autotune;
+        let cal = &config.winches[id].calibration;
+
+        let period_sec = relay.period_ticks.iter().sum::<u32>() as f32 / relay.period_ticks.len() as f32 / TICK_HZ as f32;
+        let amplitude_counts = relay.amplitude_counts.iter().sum::<f32>() / relay.amplitude_counts.len() as f32;
+        let amplitude_m = cal.dist_to_m(amplitude_counts).abs();
+        let hysteresis_m = tune.relay_hysteresis_m;
+
+        let status = self.status.as_mut().unwrap();
+        status.running = false;
+        status.amplitude_m = Some(amplitude_m);
+        status.ultimate_period_sec = Some(period_sec);
+        if amplitude_m <= hysteresis_m || period_sec <= 0.0 {
+            status.error = Some("Oscillation is smaller than the relay hysteresis".into());
+            return;
+        }
+
+        // Describing function of a relay with hysteresis, in PWM per meter
+        let ku = 4.0 * tune.relay_pwm / (PI * (amplitude_m * amplitude_m - hysteresis_m * hysteresis_m).sqrt());
+        let tu = period_sec;
+        status.ultimate_gain = Some(ku);
+        status.proposals = vec![
+            proposal(WinchTuningRule::ZieglerNichols, 0.6 * ku, 0.5 * tu, 0.125 * tu),
+            proposal(WinchTuningRule::TyreusLuyben, ku / 2.2, 2.2 * tu, tu / 6.3),
+            proposal(WinchTuningRule::NoOvershoot, 0.2 * ku, 0.5 * tu, tu / 3.0),
+        ];
+        status.error = None;
+    }
+
+    fn stop(&mut self, error: Option<String>) {
+        self.relay = None;
+        if let Some(ref mut status) = self.status {
+            status.running = false;
+            status.error = error;
+        }
+    }
+
+    fn set_error(&mut self, error: Option<String>) {
+        if let Some(ref mut status) = self.status {
+            status.error = error;
+        }
+    }
+}
+
+/// Convert ideal-form PID parameters to the parallel gains BotParams uses
+fn proposal(rule: WinchTuningRule, kp: f32, ti: f32, td: f32) -> WinchPIDProposal {
+    WinchPIDProposal {
+        rule,
+        pwm_gain_p: kp,
+        pwm_gain_i: kp / ti,
+        pwm_gain_d: kp * td,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::tests::{test_config, winch_status, assert_near};
+
+    /// Triangle wave limit cycle with a 100 tick period, peaking at +/- 1000 counts
+    fn triangle_offset(tick: u32) -> i32 {
+        let phase = (tick % 100) as i32;
+        if phase < 25 {
+            40 * phase
+        } else if phase < 75 {
+            40 * (50 - phase)
+        } else {
+            40 * (phase - 100)
+        }
+    }
+
+    fn status_at(tick: u32, position: i32) -> WinchStatus {
+        let mut status = winch_status(tick);
+        status.sensors.position = position;
+        status
+    }
+
+    #[test]
+    fn relay_output_follows_hysteresis() {
+        let config = test_config(ControllerMode::ManualWinch(0));
+        let mut tuner = WinchAutotuner::new();
+        tuner.command(&config, WinchAutotuneCommand::Start(0));
+        let base = winch_status(0).command;
+        let relay_pwm = config.winch_autotune.relay_pwm;
+
+        // Targets sit at the edge of the allowed excursion, 0.3 m or about 9050 counts
+        let excursion = config.winches[0].calibration.dist_from_m(config.winch_autotune.max_excursion_m).abs().round() as i32;
+        assert!(excursion > 9000 && excursion < 9100);
+
+        let command = tuner.relay_tick(&config, 0, &status_at(0, 5000), MechStatus::Normal, base.clone()).unwrap();
+        assert_eq!(command.position, 5000 + excursion);
+        assert_near(command.pid.gain_p * excursion as f32, relay_pwm, 1e-6);
+
+        // Hysteresis is 0.005 m, about 151 counts; inside it the output holds
+        let command = tuner.relay_tick(&config, 0, &status_at(1, 5150), MechStatus::Normal, base.clone()).unwrap();
+        assert_eq!(command.position, 5000 + excursion);
+        assert_near(command.pid.gain_p * (excursion - 150) as f32, relay_pwm, 1e-6);
+        let command = tuner.relay_tick(&config, 0, &status_at(2, 5152), MechStatus::Normal, base.clone()).unwrap();
+        assert_eq!(command.position, 5000 - excursion);
+        assert_near(command.pid.gain_p * (excursion + 152) as f32, relay_pwm, 1e-6);
+        let command = tuner.relay_tick(&config, 0, &status_at(3, 4850), MechStatus::Normal, base.clone()).unwrap();
+        assert_eq!(command.position, 5000 - excursion);
+        let command = tuner.relay_tick(&config, 0, &status_at(4, 4848), MechStatus::Normal, base.clone()).unwrap();
+        assert_eq!(command.position, 5000 + excursion);
+    }
+
+    #[test]
+    fn relay_measures_limit_cycle() {
+        let config = test_config(ControllerMode::ManualWinch(0));
+        let mut tuner = WinchAutotuner::new();
+        tuner.command(&config, WinchAutotuneCommand::Start(0));
+        let base = winch_status(0).command;
+
+        // Cycles complete at ticks 154, 254, ...; two settle and four are measured
+        let mut tick = 0;
+        while tuner.relay_tick(&config, 0, &status_at(tick, 5000 + triangle_offset(tick)), MechStatus::Normal, base.clone()).is_some() {
+            tick += 1;
+            assert!(tick < 1000);
+        }
+        assert_eq!(tick, 654);
+
+        let status = tuner.status().unwrap();
+        assert!(!status.running);
+        assert_eq!(status.error, None);
+        assert_eq!(status.cycles_completed, 6);
+        assert_near(status.ultimate_period_sec.unwrap(), 0.4, 1e-6);
+        assert_near(status.amplitude_m.unwrap(), 1000.0 * config.winches[0].calibration.m_dist_per_count, 1e-7);
+
+        // Ku = 4 d / (pi sqrt(a^2 - h^2)) for relay amplitude d, oscillation a, hysteresis h
+        assert_near(status.ultimate_gain.unwrap(), 11.6557, 1e-3);
+        let ziegler_nichols = status.proposals.iter().find(|p| p.rule == WinchTuningRule::ZieglerNichols).unwrap();
+        assert_near(ziegler_nichols.pwm_gain_p, 6.9934, 1e-3);
+        assert_near(ziegler_nichols.pwm_gain_i, 34.967, 5e-3);
+        assert_near(ziegler_nichols.pwm_gain_d, 0.34967, 1e-4);
+
+        let proposal = tuner.command(&config, WinchAutotuneCommand::Confirm(WinchTuningRule::ZieglerNichols));
+        assert_eq!(proposal.as_ref(), Some(ziegler_nichols));
+    }
+
+    #[test]
+    fn relay_stops_on_large_excursion() {
+        let config = test_config(ControllerMode::ManualWinch(0));
+        let mut tuner = WinchAutotuner::new();
+        tuner.command(&config, WinchAutotuneCommand::Start(0));
+        let base = winch_status(0).command;
+
+        assert!(tuner.relay_tick(&config, 0, &status_at(0, 5000), MechStatus::Normal, base.clone()).is_some());
+        let too_far = (config.winch_autotune.max_excursion_m / config.winches[0].calibration.m_dist_per_count) as i32 + 10;
+        assert!(tuner.relay_tick(&config, 0, &status_at(1, 5000 + too_far), MechStatus::Normal, base.clone()).is_none());
+        let status = tuner.status().unwrap();
+        assert!(!status.running);
+        assert!(status.error.is_some());
+    }
+
+    #[test]
+    fn relay_requires_manual_winch_mode() {
+        let config = test_config(ControllerMode::Normal);
+        let mut tuner = WinchAutotuner::new();
+        tuner.command(&config, WinchAutotuneCommand::Start(0));
+        assert!(!tuner.status().unwrap().running);
+        assert!(tuner.relay_tick(&config, 0, &status_at(0, 0), MechStatus::Normal, winch_status(0).command).is_none());
+    }
+}
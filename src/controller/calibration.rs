@@ -0,0 +1,360 @@
+//! Guided winch calibration. The operator tares the load cell, hangs known masses, and reels
+//! out a measured length of rope; we average the winch's own readings at each step and propose
+//! a new WinchCalibration, which only reaches the config file once the operator confirms it.
+
+use message::*;
+use config::{Config, ControllerMode, WinchCalibration};
+
+/// Status packets averaged for each load cell measurement
+const FORCE_SAMPLE_COUNT : u32 = TICK_HZ;
+
+/// Shortest reel measurement we trust, in encoder counts
+const MIN_REEL_COUNTS : i32 = 100;
+
+enum ForceMeasurement {
+    Tare,
+    Mass(f32),
+}
+
+struct PendingMeasurement {
+    kind: ForceMeasurement,
+    sum: f64,
+    count: u32,
+}
+
+pub struct WinchCalibrator {
+    status: Option<WinchCalibrationStatus>,
+    pending: Option<PendingMeasurement>,
+    position: Option<i32>,
+}
+
+impl WinchCalibrator {
+    pub fn new() -> WinchCalibrator {
+        WinchCalibrator {
+            status: None,
+            pending: None,
+            position: None,
+        }
+    }
+
+    pub fn status(&self) -> Option<WinchCalibrationStatus> {
+        self.status.clone()
+    }
+
+    /// Handle an operator command, returning a calibration to save if it was just confirmed
+    pub fn command(&mut self, config: &Config, command: WinchCalibrationCommand) -> Option<(usize, WinchCalibration)> {
+        if let WinchCalibrationCommand::Start(id) = command {
+            self.start(config, id);
+            return None;
+        }
+
+        let result = if self.is_active() {
+            self.session_command(config, command)
+        } else {
+            Err("No winch calibration in progress".into())
+        };
+
+        match result {
+            Ok(saved) => {
+                self.set_error(None);
+                saved
+            },
+            Err(e) => {
+                self.set_error(Some(e));
+                None
+            }
+        }
+    }
+
+    /// Observe a status packet from any winch, returning true if the calibration status changed
+    pub fn winch_status(&mut self, id: usize, winch_status: &WinchStatus) -> bool {
+        let is_calibrating = match self.status {
+            Some(ref status) => status.active && status.id == id,
+            None => false,
+        };
+        if !is_calibrating {
+            return false;
+        }
+        self.position = Some(winch_status.sensors.position);
+
+        let finished = match self.pending {
+            None => return false,
+            Some(ref mut pending) => {
+                pending.sum += winch_status.sensors.force.measure as f64;
+                pending.count += 1;
+                pending.count >= FORCE_SAMPLE_COUNT
+            }
+        };
+        if !finished {
+            return false;
+        }
+
+        let pending = self.pending.take().unwrap();
+        let average = (pending.sum / pending.count as f64) as f32;
+        if let Some(ref mut status) = self.status {
+            status.sampling = false;
+            match pending.kind {
+                ForceMeasurement::Tare => {
+                    status.tare_count = Some(average);
+                    status.proposed.force_zero_count = average;
+                },
+                ForceMeasurement::Mass(kg) => {
+                    status.masses.push(WinchMassSample { kg, force_count: average, residual_kg: 0.0 });
+                },
+            }
+            if let Err(e) = fit_force_scale(status) {
+                status.error = Some(e);
+            }
+        }
+        true
+    }
+
+    fn is_active(&self) -> bool {
+        match self.status {
+            Some(ref status) => status.active,
+            None => false,
+        }
+    }
+
+    fn start(&mut self, config: &Config, id: usize) {
+        self.pending = None;
+        self.position = None;
+
+        let mode_ok = match config.mode {
+            ControllerMode::Halted => true,
+            ControllerMode::ManualWinch(manual_id) => manual_id == id,
+            _ => false,
+        };
+        let error = if id >= config.winches.len() {
+            Some(format!("No winch with id {}", id))
+        } else if !mode_ok {
+            Some("Calibration requires Halted mode or manual control of this winch".into())
+        } else {
+            None
+        };
+
+        let current = match config.winches.get(id) {
+            Some(winch) => winch.calibration.clone(),
+            None => WinchCalibration { force_zero_count: 0.0, kg_force_per_count: 0.0, m_dist_per_count: 0.0 },
+        };
+        self.status = Some(WinchCalibrationStatus {
+            id,
+            active: error.is_none(),
+            sampling: false,
+            proposed: current.clone(),
+            current,
+            tare_count: None,
+            masses: Vec::new(),
+            mass_rms_residual_kg: None,
+            reel_mark: None,
+            reel_counts: None,
+            error,
+        });
+    }
+
+    fn session_command(&mut self, config: &Config, command: WinchCalibrationCommand) -> Result<Option<(usize, WinchCalibration)>, String> {
+        let status = self.status.as_mut().unwrap();
+        match command {
+
+            WinchCalibrationCommand::Start(_) => unreachable!(),
+
+            WinchCalibrationCommand::Tare => {
+                self.pending = Some(PendingMeasurement { kind: ForceMeasurement::Tare, sum: 0.0, count: 0 });
+                status.sampling = true;
+                Ok(None)
+            },
+
+            WinchCalibrationCommand::KnownMass(kg) => {
+                if status.tare_count.is_none() {
+                    return Err("Tare the load cell before measuring known masses".into());
+                }
+                self.pending = Some(PendingMeasurement { kind: ForceMeasurement::Mass(kg), sum: 0.0, count: 0 });
+                status.sampling = true;
+                Ok(None)
+            },
+
+            WinchCalibrationCommand::MarkReel => {
+                match self.position {
+                    None => Err("No status from this winch yet".into()),
+                    Some(position) => {
+                        status.reel_mark = Some(position);
+                        status.reel_counts = None;
+                        Ok(None)
+                    }
+                }
+            },
+
+            WinchCalibrationCommand::KnownReelLength(m) => {
+                let counts = match (status.reel_mark, self.position) {
+                    (Some(mark), Some(position)) => position.wrapping_sub(mark).abs(),
+                    _ => return Err("Mark the starting position before measuring a length".into()),
+                };
+                if counts < MIN_REEL_COUNTS {
+                    return Err(format!("Only {} encoder counts since the mark, reel a longer length", counts));
+                }
+                status.reel_counts = Some(counts);
+                status.proposed.m_dist_per_count = m / counts as f32;
+                Ok(None)
+            },
+
+            WinchCalibrationCommand::Confirm => {
+                if status.sampling {
+                    return Err("Wait for the load cell measurement to finish".into());
+                }
+                if status.id >= config.winches.len() {
+                    return Err(format!("No winch with id {}", status.id));
+                }
+                status.current = status.proposed.clone();
+                status.active = false;
+                Ok(Some((status.id, status.proposed.clone())))
+            },
+
+            WinchCalibrationCommand::Cancel => {
+                self.pending = None;
+                status.sampling = false;
+                status.active = false;
+                Ok(None)
+            },
+        }
+    }
+
+    fn set_error(&mut self, error: Option<String>) {
+        if let Some(ref mut status) = self.status {
+            status.error = error;
+        }
+    }
+}
+
+fn fit_force_scale(status: &mut WinchCalibrationStatus) -> Result<(), String> {
+    // Least squares fit of kg = scale * (count - zero), with the zero fixed by the tare
+    let zero = match status.tare_count {
+        Some(zero) => zero,
+        None => return Ok(()),
+    };
+    if status.masses.is_empty() {
+        return Ok(());
+    }
+
+    let mut num = 0.0;
+    let mut den = 0.0;
+    for sample in &status.masses {
+        let x = (sample.force_count - zero) as f64;
+        num += sample.kg as f64 * x;
+        den += x * x;
+    }
+    if den <= 0.0 {
+        return Err("Load cell readings don't change with the hanging mass".into());
+    }
+    let scale = (num / den) as f32;
+
+    let mut sum_sq = 0.0;
+    for sample in status.masses.iter_mut() {
+        sample.residual_kg = sample.kg - scale * (sample.force_count - zero);
+        sum_sq += sample.residual_kg * sample.residual_kg;
+    }
+    status.proposed.kg_force_per_count = scale;
+    status.mass_rms_residual_kg = Some((sum_sq / status.masses.len() as f32).sqrt());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::tests::{test_config, winch_status, assert_near};
+
+    fn status_at(position: i32, measure: i32) -> WinchStatus {
+        let mut status = winch_status(0);
+        status.sensors.position = position;
+        status.sensors.force.measure = measure;
+        status
+    }
+
+    /// Feed one full measurement, alternating around the given reading to check the averaging
+    fn measure(calibrator: &mut WinchCalibrator, count: i32) {
+        for tick in 0 .. FORCE_SAMPLE_COUNT {
+            let noise = if tick % 2 == 0 { 10 } else { -10 };
+            calibrator.winch_status(0, &status_at(0, count + noise));
+        }
+    }
+
+    fn started() -> (Config, WinchCalibrator) {
+        let config = test_config(ControllerMode::Halted);
+        let mut calibrator = WinchCalibrator::new();
+        calibrator.command(&config, WinchCalibrationCommand::Start(0));
+        (config, calibrator)
+    }
+
+    #[test]
+    fn tare_and_masses_fit_force_scale() {
+        let (config, mut calibrator) = started();
+        calibrator.command(&config, WinchCalibrationCommand::Tare);
+        assert!(calibrator.status().unwrap().sampling);
+        measure(&mut calibrator, 1000);
+        let status = calibrator.status().unwrap();
+        assert!(!status.sampling);
+        assert_eq!(status.tare_count, Some(1000.0));
+        assert_eq!(status.proposed.force_zero_count, 1000.0);
+
+        // A single mass fits exactly
+        calibrator.command(&config, WinchCalibrationCommand::KnownMass(1.0));
+        measure(&mut calibrator, 1100);
+        let status = calibrator.status().unwrap();
+        assert_near(status.proposed.kg_force_per_count, 0.01, 1e-7);
+        assert_near(status.mass_rms_residual_kg.unwrap(), 0.0, 1e-6);
+
+        // Readings of 100 and 300 counts for 1 and 2 kg: scale = 700 / 100000, residuals 0.3 and -0.1
+        calibrator.command(&config, WinchCalibrationCommand::KnownMass(2.0));
+        measure(&mut calibrator, 1300);
+        let status = calibrator.status().unwrap();
+        assert_eq!(status.error, None);
+        assert_near(status.proposed.kg_force_per_count, 0.007, 1e-7);
+        assert_near(status.masses[0].residual_kg, 0.3, 1e-5);
+        assert_near(status.masses[1].residual_kg, -0.1, 1e-5);
+        assert_near(status.mass_rms_residual_kg.unwrap(), 0.05f32.sqrt(), 1e-5);
+    }
+
+    #[test]
+    fn masses_need_a_tare_and_a_changing_reading() {
+        let (config, mut calibrator) = started();
+        calibrator.command(&config, WinchCalibrationCommand::KnownMass(1.0));
+        assert!(calibrator.status().unwrap().error.is_some());
+
+        calibrator.command(&config, WinchCalibrationCommand::Tare);
+        measure(&mut calibrator, 1000);
+        calibrator.command(&config, WinchCalibrationCommand::KnownMass(1.0));
+        measure(&mut calibrator, 1000);
+        let status = calibrator.status().unwrap();
+        assert!(status.error.is_some());
+        assert_eq!(status.proposed.kg_force_per_count, config.winches[0].calibration.kg_force_per_count);
+    }
+
+    #[test]
+    fn reel_mark_measures_distance_per_count() {
+        let (config, mut calibrator) = started();
+        calibrator.command(&config, WinchCalibrationCommand::MarkReel);
+        assert!(calibrator.status().unwrap().error.is_some());
+
+        calibrator.winch_status(0, &status_at(5000, 0));
+        calibrator.command(&config, WinchCalibrationCommand::MarkReel);
+        assert_eq!(calibrator.status().unwrap().reel_mark, Some(5000));
+
+        // Too short to trust
+        calibrator.winch_status(0, &status_at(4950, 0));
+        calibrator.command(&config, WinchCalibrationCommand::KnownReelLength(0.002));
+        let status = calibrator.status().unwrap();
+        assert!(status.error.is_some());
+        assert_eq!(status.reel_counts, None);
+
+        // Either direction counts
+        calibrator.winch_status(0, &status_at(2000, 0));
+        calibrator.command(&config, WinchCalibrationCommand::KnownReelLength(0.1));
+        let status = calibrator.status().unwrap();
+        assert_eq!(status.error, None);
+        assert_eq!(status.reel_counts, Some(3000));
+        assert_near(status.proposed.m_dist_per_count, 0.1 / 3000.0, 1e-10);
+
+        let saved = calibrator.command(&config, WinchCalibrationCommand::Confirm);
+        assert_eq!(saved, Some((0, status.proposed.clone())));
+        assert!(!calibrator.status().unwrap().active);
+    }
+}
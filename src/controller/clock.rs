@@ -0,0 +1,65 @@
+//! Time source for the controller. Control logic takes its idea of "now" from here instead
+//! of asking the system directly, so tests and replays can step time deterministically.
+
+use chrono::prelude::*;
+use chrono;
+use message::TICK_HZ;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+    fn local_time(&self) -> NaiveTime;
+}
+
+/// The real time, for normal operation
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_time(&self) -> NaiveTime {
+        Local::now().time()
+    }
+}
+
+/// A clock that only moves when it's told to. Clones all share the same time.
+#[derive(Clone)]
+pub struct ManualClock {
+    time: Arc<Mutex<(Instant, NaiveTime)>>,
+}
+
+impl ManualClock {
+    pub fn new(local_time: NaiveTime) -> ManualClock {
+        ManualClock {
+            time: Arc::new(Mutex::new((Instant::now(), local_time))),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.0 += duration;
+        time.1 = time.1 + chrono::Duration::from_std(duration).unwrap();
+    }
+
+    /// Advance by exactly one controller tick
+    pub fn tick(&self) {
+        self.advance(tick_duration());
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.time.lock().unwrap().0
+    }
+
+    fn local_time(&self) -> NaiveTime {
+        self.time.lock().unwrap().1
+    }
+}
+
+pub fn tick_duration() -> Duration {
+    Duration::new(0, 1_000_000_000 / TICK_HZ)
+}
@@ -3,6 +3,7 @@ use vecmath::*;
 use config::{Config, ControllerMode};
 use overlay::DrawingContext;
 use controller::manual::ManualControls;
+use std::time::Instant;
 
 pub fn mode_indicator(config: &Config, draw: &mut DrawingContext) {
     if config.mode == ControllerMode::Halted {
@@ -47,11 +48,11 @@ pub fn detected_objects(config: &Config, draw: &mut DrawingContext, detected: &C
     }
 }
 
-pub fn tracking_rect(config: &Config, draw: &mut DrawingContext, tracked: &CameraTrackedRegion, manual: &ManualControls) {
+pub fn tracking_rect(config: &Config, draw: &mut DrawingContext, tracked: &CameraTrackedRegion, manual: &ManualControls, now: Instant) {
     if !tracked.is_empty() {
         draw.current.outline_thickness = config.overlay.tracked_region_outline_thickness;
 
-        if manual.camera_control_active() {
+        if manual.camera_control_active(now) {
             draw.current.outline_color = config.overlay.tracked_region_manual_color;
             draw.outline_rect(tracked.rect);
 
@@ -137,3 +138,29 @@ pub fn gimbal_status(config: &Config, draw: &mut DrawingContext, gimbal: &Option
         draw.outline_rect(rect);
     }
 }
+
+pub fn geofence_status(config: &Config, draw: &mut DrawingContext, geofence: &Option<GeofenceStatus>) {
+    if let &Some(ref geofence) = geofence {
+        let (color, boundaries) = if !geofence.violations.is_empty() {
+            (config.overlay.geofence_violation_color, &geofence.violations)
+        } else if !geofence.slowdowns.is_empty() {
+            (config.overlay.geofence_slowdown_color, &geofence.slowdowns)
+        } else {
+            return;
+        };
+
+        // Inside the halt indicator, so both can be seen at once
+        let thickness = config.overlay.border_thickness;
+        let rect = rect_offset(config.vision.border_rect, -thickness * 2.0);
+        draw.current.outline_color = color;
+        draw.current.outline_thickness = thickness;
+        draw.outline_rect(rect);
+
+        let label = format!("geofence {:?}", boundaries);
+        draw.current.text_height = config.overlay.label_text_size;
+        draw.current.color = color;
+        draw.current.background_color = config.overlay.label_background_color;
+        draw.current.outline_thickness = 0.0;
+        draw.text(rect_bottomleft(rect), [0.0, 1.0], &label).unwrap();
+    }
+}
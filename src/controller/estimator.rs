@@ -0,0 +1,330 @@
+//! Flyer pose estimator, fusing rope lengths from the winch encoders
+//! with the IMU and LIDAR sensors on the flyer itself.
+
+use message::*;
+use vecmath::*;
+use config::Config;
+use controller::kinematics::{FlyerKinematics, forward_kinematics};
+use std::time::{Duration, Instant};
+
+/// IMU acceleration older than this is no longer used for prediction
+const MAX_IMU_AGE_MILLIS : u64 = 100;
+
+pub struct FlyerEstimator {
+    axes: [AxisFilter; 3],
+    orientation: Vector4<f32>,
+    accel: Option<(Instant, Vector3<f32>)>,
+    winches: Vec<RopeSensor>,
+    lidar_counters: Vec<u32>,
+    pending_lidar_heights: Vec<f32>,
+    is_initialized: bool,
+}
+
+/// Rope length measurement for one winch, from its encoder
+struct RopeSensor {
+    /// Latest encoder reading and the tick counter it came with
+    last_status: Option<(i32, u32)>,
+    /// Rope length at encoder position zero; unknown until referenced against a pose
+    length_offset_m: Option<f32>,
+}
+
+/// Constant-velocity Kalman filter for one Cartesian axis, state is [position, velocity]
+#[derive(Debug, Clone, Copy)]
+struct AxisFilter {
+    x: Vector2<f32>,
+    p: Matrix2<f32>,
+}
+
+impl FlyerEstimator {
+    pub fn new() -> FlyerEstimator {
+        FlyerEstimator {
+            axes: [AxisFilter::new(0.0, 0.0); 3],
+            orientation: [1.0, 0.0, 0.0, 0.0],
+            accel: None,
+            winches: Vec::new(),
+            lidar_counters: Vec::new(),
+            pending_lidar_heights: Vec::new(),
+            is_initialized: false,
+        }
+    }
+
+    pub fn position(&self) -> Vector3<f32> {
+        [ self.axes[0].x[0], self.axes[1].x[0], self.axes[2].x[0] ]
+    }
+
+    pub fn pose(&self) -> Option<FlyerPose> {
+        if !self.is_initialized {
+            return None;
+        }
+        Some(FlyerPose {
+            position: self.position(),
+            velocity: [ self.axes[0].x[1], self.axes[1].x[1], self.axes[2].x[1] ],
+            orientation: self.orientation,
+            position_variance: [ self.axes[0].p[0][0], self.axes[1].p[0][0], self.axes[2].p[0][0] ],
+            velocity_variance: [ self.axes[0].p[1][1], self.axes[1].p[1][1], self.axes[2].p[1][1] ],
+            num_ropes: self.winches.iter().filter(|w| w.length_offset_m.is_some()).count(),
+        })
+    }
+
+    fn resize(&mut self, num_winches: usize) {
+        self.winches.truncate(num_winches);
+        while self.winches.len() < num_winches {
+            self.winches.push(RopeSensor {
+                last_status: None,
+                length_offset_m: None,
+            });
+        }
+    }
+
+    pub fn winch_status_update(&mut self, config: &Config, id: usize, status: &WinchStatus) {
+        self.resize(config.winches.len());
+        if id >= self.winches.len() {
+            return;
+        }
+
+        let is_discontinuous = match self.winches[id].last_status {
+            None => true,
+            Some((_, tick_counter)) => status.tick_counter.wrapping_sub(tick_counter) > 2,
+        };
+        if is_discontinuous {
+            // The winch restarted or we lost track of it; its encoder needs a new reference
+            self.winches[id].length_offset_m = None;
+        }
+        self.winches[id].last_status = Some((status.sensors.position, status.tick_counter));
+    }
+
+    pub fn flyer_sensor_update(&mut self, config: &Config, sensors: &FlyerSensors, now: Instant) {
+        let q = sensors.imu.quaternion;
+        self.orientation = quat_normalized([ q[0] as f32, q[1] as f32, q[2] as f32, q[3] as f32 ]);
+
+        let scale = config.estimator.imu_accel_m_per_sec2_per_count;
+        let a = sensors.imu.linear_accel;
+        let body_accel = [ a[0] as f32 * scale, a[1] as f32 * scale, a[2] as f32 * scale ];
+        self.accel = Some((now, quat_rotate_vec3(self.orientation, body_accel)));
+
+        self.lidar_counters.resize(sensors.lidar.counters.len(), 0);
+        for (index, direction) in config.estimator.lidar_directions.iter().enumerate() {
+            if index < sensors.lidar.counters.len() && sensors.lidar.counters[index] != self.lidar_counters[index] {
+                self.lidar_counters[index] = sensors.lidar.counters[index];
+                let range = sensors.lidar.ranges[index] as f32 * config.estimator.lidar_m_per_count;
+                if let Some(height) = self.lidar_height(config, *direction, range) {
+                    self.pending_lidar_heights.push(height);
+                }
+            }
+        }
+    }
+
+    fn lidar_height(&self, config: &Config, body_direction: Vector3<f32>, range: f32) -> Option<f32> {
+        lidar_height(config, self.orientation, body_direction, range)
+    }
+
+    /// Replace every rope's length reference, for winches that have reported
+    pub fn set_rope_offsets(&mut self, config: &Config, offsets_m: &[f32]) {
+        self.resize(config.winches.len());
+        for (winch, offset) in self.winches.iter_mut().zip(offsets_m.iter()) {
+            if winch.last_status.is_some() {
+                winch.length_offset_m = Some(*offset);
+            }
+        }
+    }
+
+    fn rope_lengths(&self, config: &Config) -> Vec<Option<f32>> {
+        self.winches.iter().enumerate().map(|(id, winch)| {
+            match (winch.last_status, winch.length_offset_m) {
+                (Some((counts, _)), Some(offset)) => {
+                    // Increasing encoder counts reel in rope
+                    Some(offset - config.winches[id].calibration.dist_to_m(counts as f32))
+                },
+                _ => None,
+            }
+        }).collect()
+    }
+
+    fn reference_ropes(&mut self, config: &Config) {
+        // Any winch without a length reference gets one from the current pose estimate
+        let kinematics = FlyerKinematics { position: self.position() };
+        for (id, winch) in self.winches.iter_mut().enumerate() {
+            if let (Some((counts, _)), None) = (winch.last_status, winch.length_offset_m) {
+                let counts_m = config.winches[id].calibration.dist_to_m(counts as f32);
+                winch.length_offset_m = Some(kinematics.rope_length(config, id) + counts_m);
+            }
+        }
+    }
+
+    fn initialize(&mut self, config: &Config) {
+        let sigma = config.estimator.initial_position_sigma_m;
+        let home = config.flyer_home_loc;
+        for axis in 0..3 {
+            self.axes[axis] = AxisFilter::new(home[axis], sigma * sigma);
+        }
+        self.is_initialized = true;
+    }
+
+    /// Run one controller tick of prediction, followed by updates from any new measurements
+    pub fn tick(&mut self, config: &Config, now: Instant) {
+        self.resize(config.winches.len());
+
+        if !self.is_initialized {
+            // Wait until every winch has reported at least once, then assume we start at home
+            if self.winches.is_empty() || self.winches.iter().any(|w| w.last_status.is_none()) {
+                return;
+            }
+            self.initialize(config);
+        }
+
+        let dt = 1.0 / (TICK_HZ as f32);
+        let accel_sigma = config.estimator.imu_accel_sigma_m_per_sec2;
+        let accel = match self.accel {
+            Some((timestamp, accel)) if timestamp + Duration::from_millis(MAX_IMU_AGE_MILLIS) > now => accel,
+            _ => [0.0; 3],
+        };
+        for axis in 0..3 {
+            self.axes[axis].predict(accel[axis], accel_sigma * accel_sigma, dt);
+        }
+
+        self.reference_ropes(config);
+        let rope_sigma = config.estimator.rope_length_sigma_m;
+        let lengths = self.rope_lengths(config);
+        if let Some((position, normal_inverse)) = forward_kinematics(config, &lengths, self.position()) {
+            for axis in 0..3 {
+                let variance = normal_inverse[axis][axis] * rope_sigma * rope_sigma;
+                self.axes[axis].update_position(position[axis], variance);
+            }
+        }
+
+        let lidar_sigma = config.estimator.lidar_sigma_m;
+        for height in self.pending_lidar_heights.drain(..) {
+            self.axes[2].update_position(height, lidar_sigma * lidar_sigma);
+        }
+    }
+}
+
+/// Flyer height from one LIDAR range, if the ray points down far enough to have hit the floor
+pub fn lidar_height(config: &Config, orientation: Vector4<f32>, body_direction: Vector3<f32>, range: f32) -> Option<f32> {
+    let direction = quat_rotate_vec3(orientation, body_direction);
+    if range > 0.0 && range < config.estimator.lidar_max_range_m && -direction[2] >= config.estimator.lidar_min_down_component {
+        Some(config.estimator.floor_z - range * direction[2])
+    } else {
+        None
+    }
+}
+
+impl AxisFilter {
+    fn new(position: f32, variance: f32) -> AxisFilter {
+        AxisFilter {
+            x: [position, 0.0],
+            p: [[variance, 0.0], [0.0, 0.0]],
+        }
+    }
+
+    fn predict(&mut self, accel: f32, accel_variance: f32, dt: f32) {
+        // x' = F x + B a, P' = F P F^T + B B^T q, with F = [[1, dt], [0, 1]] and B = [dt^2/2, dt]
+        let x = self.x;
+        let p = self.p;
+        let b = [0.5 * dt * dt, dt];
+        self.x = [ x[0] + x[1] * dt + b[0] * accel, x[1] + b[1] * accel ];
+        self.p = [
+            [
+                p[0][0] + dt * (p[0][1] + p[1][0]) + dt * dt * p[1][1] + b[0] * b[0] * accel_variance,
+                p[0][1] + dt * p[1][1] + b[0] * b[1] * accel_variance,
+            ],
+            [
+                p[1][0] + dt * p[1][1] + b[0] * b[1] * accel_variance,
+                p[1][1] + b[1] * b[1] * accel_variance,
+            ],
+        ];
+    }
+
+    fn update_position(&mut self, measurement: f32, variance: f32) {
+        // Measurement matrix H = [1, 0]
+        let x = self.x;
+        let p = self.p;
+        let s = p[0][0] + variance;
+        if s <= 0.0 {
+            return;
+        }
+        let k = [ p[0][0] / s, p[1][0] / s ];
+        let innovation = measurement - x[0];
+        self.x = [ x[0] + k[0] * innovation, x[1] + k[1] * innovation ];
+        self.p = [
+            [ (1.0 - k[0]) * p[0][0], (1.0 - k[0]) * p[0][1] ],
+            [ p[1][0] - k[1] * p[0][0], p[1][1] - k[1] * p[0][1] ],
+        ];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::{test_config, assert_near};
+
+    #[test]
+    fn predict_constant_velocity() {
+        let mut filter = AxisFilter { x: [2.0, 1.5], p: [[0.0; 2]; 2] };
+        filter.predict(0.0, 0.0, 0.5);
+        assert_eq!(filter.x, [2.75, 1.5]);
+        assert_eq!(filter.p, [[0.0; 2]; 2]);
+    }
+
+    #[test]
+    fn predict_with_acceleration() {
+        // B = [1/8, 1/2] at dt = 1/2, so acceleration noise adds q B B^T to the covariance
+        let mut filter = AxisFilter::new(0.0, 1.0);
+        filter.predict(2.0, 4.0, 0.5);
+        assert_eq!(filter.x, [0.25, 1.0]);
+        assert_eq!(filter.p, [[1.0625, 0.25], [0.25, 1.0]]);
+    }
+
+    #[test]
+    fn update_averages_equal_variances() {
+        let mut filter = AxisFilter::new(0.0, 1.0);
+        filter.update_position(2.0, 1.0);
+        assert_near(filter.x[0], 1.0, 1e-6);
+        assert_near(filter.p[0][0], 0.5, 1e-6);
+
+        // A second identical measurement now carries twice the weight of the estimate
+        filter.update_position(2.0, 1.0);
+        assert_near(filter.x[0], 4.0 / 3.0, 1e-6);
+        assert_near(filter.p[0][0], 1.0 / 3.0, 1e-6);
+    }
+
+    #[test]
+    fn update_corrects_velocity_through_covariance() {
+        let mut filter = AxisFilter::new(0.0, 1.0);
+        filter.predict(2.0, 4.0, 0.5);
+        // Innovation equal to S makes the state correction equal to the gain times S, P[.][0]
+        filter.update_position(0.25 + 2.0625, 1.0);
+        assert_near(filter.x[0], 1.3125, 1e-5);
+        assert_near(filter.x[1], 1.25, 1e-5);
+        assert_near(filter.p[0][0], 1.0625 / 2.0625, 1e-6);
+    }
+
+    #[test]
+    fn lidar_height_straight_down() {
+        let config = test_config(ControllerMode::Halted);
+        let height = lidar_height(&config, [1.0, 0.0, 0.0, 0.0], [0.0, 0.0, -1.0], 2.5).unwrap();
+        assert_near(height, config.estimator.floor_z + 2.5, 1e-6);
+    }
+
+    #[test]
+    fn lidar_height_tilted() {
+        // Twenty degrees about X still points down steeply enough to trust
+        let config = test_config(ControllerMode::Halted);
+        let half = 10f32.to_radians();
+        let orientation = [half.cos(), half.sin(), 0.0, 0.0];
+        let height = lidar_height(&config, orientation, [0.0, 0.0, -1.0], 2.0).unwrap();
+        assert_near(height, config.estimator.floor_z + 2.0 * 20f32.to_radians().cos(), 1e-5);
+    }
+
+    #[test]
+    fn lidar_height_rejects_sideways_and_out_of_range() {
+        let config = test_config(ControllerMode::Halted);
+        let half = 45f32.to_radians();
+        let sideways = [half.cos(), half.sin(), 0.0, 0.0];
+        assert!(lidar_height(&config, sideways, [0.0, 0.0, -1.0], 2.0).is_none());
+        assert!(lidar_height(&config, [1.0, 0.0, 0.0, 0.0], [0.0, 0.0, -1.0], 0.0).is_none());
+        let too_far = config.estimator.lidar_max_range_m + 1.0;
+        assert!(lidar_height(&config, [1.0, 0.0, 0.0, 0.0], [0.0, 0.0, -1.0], too_far).is_none());
+    }
+}
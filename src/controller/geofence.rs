@@ -0,0 +1,270 @@
+//! Workspace limits for the flyer, applied to commanded velocities before inverse kinematics
+
+use message::*;
+use vecmath::*;
+use config::Config;
+
+/// One half-space of the safe volume. Points are inside when dot(normal, p) <= offset.
+struct Boundary {
+    id: GeofenceBoundary,
+    normal: Vector3<f32>,
+    offset: f32,
+}
+
+impl Boundary {
+    fn new(id: GeofenceBoundary, normal: Vector3<f32>, offset: f32) -> Option<Boundary> {
+        let len = vec3_len(normal);
+        if len > 0.0 {
+            Some(Boundary {
+                id,
+                normal: vec3_scale(normal, 1.0 / len),
+                offset: offset / len,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Distance from the boundary, positive inside and negative outside
+    fn distance(&self, position: Vector3<f32>) -> f32 {
+        self.offset - vec3_dot(self.normal, position)
+    }
+}
+
+fn boundaries(config: &Config) -> Vec<Boundary> {
+    let fence = &config.geofence;
+    let mut result = Vec::new();
+
+    result.extend(Boundary::new(GeofenceBoundary::Floor, [0.0, 0.0, -1.0], -(config.estimator.floor_z + fence.floor_margin_m)));
+
+    let lowest_anchor = config.winches.iter().map(|w| w.loc[2]).fold(None, |acc: Option<f32>, z| {
+        Some(acc.map_or(z, |acc| acc.min(z)))
+    });
+    if let Some(z) = lowest_anchor {
+        result.extend(Boundary::new(GeofenceBoundary::Ceiling, [0.0, 0.0, 1.0], z - fence.ceiling_margin_m));
+    }
+
+    for axis in 0..3 {
+        let mut normal = [0.0; 3];
+        normal[axis] = 1.0;
+        result.extend(Boundary::new(GeofenceBoundary::Box, normal, fence.box_max[axis]));
+        normal[axis] = -1.0;
+        result.extend(Boundary::new(GeofenceBoundary::Box, normal, -fence.box_min[axis]));
+    }
+
+    for (index, plane) in fence.planes.iter().enumerate() {
+        result.extend(Boundary::new(GeofenceBoundary::Plane(index), [plane[0], plane[1], plane[2]], plane[3]));
+    }
+
+    // A flyer hanging below its anchors can only keep every rope in tension while it's
+    // inside the horizontal convex hull of the anchor points. Near the hull edges, the
+    // opposite rope's share of the load goes to zero and it will go slack.
+    let hull = convex_hull_2d(config.winches.iter().map(|w| [w.loc[0], w.loc[1]]).collect());
+    if hull.len() >= 3 {
+        for index in 0 .. hull.len() {
+            let a = hull[index];
+            let b = hull[(index + 1) % hull.len()];
+            let edge = vec2_sub(b, a);
+            let len = vec2_len(edge);
+            if len > 0.0 {
+                // Counterclockwise hull, so the outward normal is the edge rotated clockwise
+                let normal = [edge[1] / len, -edge[0] / len, 0.0];
+                let offset = normal[0] * a[0] + normal[1] * a[1] - fence.anchor_hull_margin_m;
+                result.extend(Boundary::new(GeofenceBoundary::AnchorHull(index), normal, offset));
+            }
+        }
+    }
+
+    result
+}
+
+fn convex_hull_2d(mut points: Vec<Vector2<f32>>) -> Vec<Vector2<f32>> {
+    // Andrew's monotone chain, producing a counterclockwise hull
+    points.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+    if points.len() < 3 {
+        return points;
+    }
+
+    let cross = |o: Vector2<f32>, a: Vector2<f32>, b: Vector2<f32>| {
+        (a[0] - o[0]) * (b[1] - o[1]) - (a[1] - o[1]) * (b[0] - o[0])
+    };
+
+    let mut lower: Vec<Vector2<f32>> = Vec::new();
+    for p in points.iter() {
+        while lower.len() >= 2 && cross(lower[lower.len() - 2], lower[lower.len() - 1], *p) <= 0.0 {
+            lower.pop();
+        }
+        lower.push(*p);
+    }
+
+    let mut upper: Vec<Vector2<f32>> = Vec::new();
+    for p in points.iter().rev() {
+        while upper.len() >= 2 && cross(upper[upper.len() - 2], upper[upper.len() - 1], *p) <= 0.0 {
+            upper.pop();
+        }
+        upper.push(*p);
+    }
+
+    lower.pop();
+    upper.pop();
+    lower.extend(upper);
+    lower
+}
+
+/// Passes over the boundaries before giving up on satisfying every limit at once
+const MAX_GUARD_PASSES : usize = 100;
+
+/// Outward speed allowed beyond a limit, for rounding
+const GUARD_TOLERANCE_M_PER_SEC : f32 = 1e-4;
+
+/// Limit a commanded flyer velocity at the edges of the safe volume. Inside the slowdown
+/// region, outward motion is slowed in proportion to the remaining distance; past the
+/// boundary, the flyer must head back in at least as fast as a spring would pull it.
+pub fn guard_velocity(config: &Config, position: Vector3<f32>, velocity: Vector3<f32>) -> (Vector3<f32>, GeofenceStatus) {
+    let fence = &config.geofence;
+    let mut status = GeofenceStatus {
+        violations: Vec::new(),
+        slowdowns: Vec::new(),
+    };
+
+    // Highest outward speed allowed across each nearby boundary, and whether we're past it
+    let mut limits = Vec::new();
+    for boundary in boundaries(config) {
+        let distance = boundary.distance(position);
+        if distance < 0.0 {
+            status.add_violation(boundary.id);
+            limits.push((boundary, distance * fence.spring_gain, true));
+        } else if distance < fence.slowdown_extent_m {
+            limits.push((boundary, distance / fence.slowdown_extent_m * fence.slowdown_velocity_m_per_sec, false));
+        }
+    }
+
+    // Limiting the speed across one boundary can add outward speed across a neighbor that
+    // meets it at an angle, so keep going around until every limit holds at once
+    let mut velocity = velocity;
+    for _ in 0 .. MAX_GUARD_PASSES {
+        let mut limited = false;
+        for &(ref boundary, speed_limit, outside) in limits.iter() {
+            let outward = vec3_dot(velocity, boundary.normal);
+            if outward > speed_limit + GUARD_TOLERANCE_M_PER_SEC {
+                velocity = vec3_sub(velocity, vec3_scale(boundary.normal, outward - speed_limit));
+                if !outside {
+                    status.add_slowdown(boundary.id);
+                }
+                limited = true;
+            }
+        }
+        if !limited {
+            return (velocity, status);
+        }
+    }
+
+    // Only a very sharp corner converges this slowly; stop rather than risk leaving it
+    ([0.0; 3], status)
+}
+
+impl GeofenceStatus {
+    fn add_violation(&mut self, id: GeofenceBoundary) {
+        if !self.violations.contains(&id) {
+            self.violations.push(id);
+        }
+    }
+
+    fn add_slowdown(&mut self, id: GeofenceBoundary) {
+        if !self.slowdowns.contains(&id) {
+            self.slowdowns.push(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::{test_config, assert_near};
+
+    fn assert_velocity(velocity: Vector3<f32>, expected: Vector3<f32>) {
+        for axis in 0 .. 3 {
+            assert_near(velocity[axis], expected[axis], 1e-3);
+        }
+    }
+
+    /// Anchors on a right triangle, with its 45 degree corners well inside a wide box
+    fn triangle_config() -> Config {
+        let mut config = test_config(ControllerMode::Halted);
+        config.winches.truncate(3);
+        config.winches[0].loc = [0.0, 0.0, 30.0];
+        config.winches[1].loc = [10.0, 0.0, 30.0];
+        config.winches[2].loc = [0.0, 10.0, 30.0];
+        config.geofence.box_min = [-100.0, -100.0, 0.0];
+        config.geofence.box_max = [100.0, 100.0, 28.0];
+        config.geofence.anchor_hull_margin_m = 0.0;
+        config
+    }
+
+    #[test]
+    fn slowdown_ramps_toward_the_boundary() {
+        let config = test_config(ControllerMode::Halted);
+        // The box edge is at x = 18, the anchor hull 0.5 m beyond it
+        let (velocity, status) = guard_velocity(&config, [17.0, 0.0, 10.0], [1.0, 0.0, 0.0]);
+        assert_velocity(velocity, [1.0, 0.0, 0.0]);
+        assert!(status.slowdowns.is_empty());
+
+        let (velocity, status) = guard_velocity(&config, [17.5, 0.0, 10.0], [1.0, 0.5, 0.0]);
+        assert_velocity(velocity, [0.15, 0.5, 0.0]);
+        assert_eq!(status.slowdowns, vec![ GeofenceBoundary::Box ]);
+        assert!(status.violations.is_empty());
+
+        // Heading back in is never slowed
+        let (velocity, status) = guard_velocity(&config, [17.5, 0.0, 10.0], [-1.0, 0.0, 0.0]);
+        assert_velocity(velocity, [-1.0, 0.0, 0.0]);
+        assert!(status.slowdowns.is_empty());
+    }
+
+    #[test]
+    fn spring_back_when_outside() {
+        let config = test_config(ControllerMode::Halted);
+        for &commanded in [ [0.0, 0.0, 0.0], [1.0, 0.0, 0.0] ].iter() {
+            let (velocity, status) = guard_velocity(&config, [18.2, 0.0, 10.0], commanded);
+            assert_velocity(velocity, [-0.1, 0.0, 0.0]);
+            assert_eq!(status.violations, vec![ GeofenceBoundary::Box ]);
+            assert!(status.slowdowns.is_empty());
+        }
+        // Already heading in faster than the spring
+        let (velocity, _) = guard_velocity(&config, [18.2, 0.0, 10.0], [-1.0, 0.0, 0.0]);
+        assert_velocity(velocity, [-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn hull_corner_holds_both_faces() {
+        let config = triangle_config();
+        // Near the corner at (10, 0), between the y = 0 face and the x + y = 10 face. One
+        // pass would leave 0.485 m/s of outward speed across the y = 0 face.
+        let (velocity, status) = guard_velocity(&config, [9.5, 0.2, 10.0], [1.0, -1.0, 0.0]);
+        assert_velocity(velocity, [0.15, -0.06, 0.0]);
+        assert_eq!(status.slowdowns, vec![ GeofenceBoundary::AnchorHull(0), GeofenceBoundary::AnchorHull(1) ]);
+        assert!(status.violations.is_empty());
+    }
+
+    #[test]
+    fn floor_and_ceiling() {
+        let config = test_config(ControllerMode::Halted);
+        // The floor margin puts the floor boundary at z = 0.5, ahead of the box at z = 0
+        let (velocity, status) = guard_velocity(&config, [0.0, 0.0, 0.9], [0.0, 0.0, -1.0]);
+        assert_velocity(velocity, [0.0, 0.0, -0.12]);
+        assert_eq!(status.slowdowns, vec![ GeofenceBoundary::Floor ]);
+
+        let (velocity, status) = guard_velocity(&config, [0.0, 0.0, 0.3], [0.0, 0.0, 0.0]);
+        assert_velocity(velocity, [0.0, 0.0, 0.1]);
+        assert_eq!(status.violations, vec![ GeofenceBoundary::Floor ]);
+
+        // The ceiling is 2 m below the lowest anchor, at z = 28, where the box ends too
+        let (velocity, status) = guard_velocity(&config, [0.0, 0.0, 27.8], [0.0, 0.0, 1.0]);
+        assert_velocity(velocity, [0.0, 0.0, 0.06]);
+        assert_eq!(status.slowdowns, vec![ GeofenceBoundary::Ceiling ]);
+
+        let (velocity, status) = guard_velocity(&config, [0.0, 0.0, 28.4], [0.0, 0.0, 0.5]);
+        assert_velocity(velocity, [0.0, 0.0, -0.2]);
+        assert_eq!(status.violations, vec![ GeofenceBoundary::Ceiling, GeofenceBoundary::Box ]);
+    }
+}
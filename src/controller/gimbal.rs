@@ -3,9 +3,13 @@ use message::*;
 use config::{ControllerMode, Config, GimbalTrackingGain};
 use fygimbal;
 use fygimbal::protocol::{target, values, motor_status};
+use fygimbal::registry;
 use fygimbal::util::vec2_encoder_sub;
-use fygimbal::GimbalPort;
+use fygimbal::{GimbalPort, GimbalPacket};
+use controller::gimbal_tuning::GimbalTuner;
+use controller::gimbal_calibration::GimbalCalibrator;
 use std::time::{Duration, Instant};
+use std::mem;
 
 pub struct GimbalController {
     values: Vec<Vec<GimbalValueState>>,
@@ -17,14 +21,35 @@ pub struct GimbalController {
     current_osc_detector: Vector3<f32>,
     current_peak_detector: Vector3<f32>,
     current_error_timestamp: Option<Instant>,
+    tuner: GimbalTuner,
+    calibrator: GimbalCalibrator,
+    angle_control: Option<AngleControl>,
+    /// Renewed every tick and sent to the poller together
+    requests: Vec<GimbalValueRequest>,
+    link_state: GimbalLinkState,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum RequestType {
-    Continuous,
-    Infrequent,
+struct AngleControl {
+    target: GimbalAngleTarget,
+    /// Slew speed in encoder counts per second, or None to jump straight to the target
+    speed: Option<f32>,
+    setpoint: Option<Vector2<f32>>,
 }
 
+/// Values the control loop can't run without, which keep their rate when the link is busy
+const ANGLE_POLL : GimbalPollRate = GimbalPollRate { hz: 100.0, priority: GimbalRequestPriority::High };
+const CURRENT_POLL : GimbalPollRate = GimbalPollRate { hz: 50.0, priority: GimbalRequestPriority::High };
+
+/// Slowly changing values, and the configured values we check for drift
+const STATUS_POLL : GimbalPollRate = GimbalPollRate { hz: 0.5, priority: GimbalRequestPriority::Normal };
+
+/// Motor status right after enabling or disabling the motors
+const MOTOR_ENABLE_POLL : GimbalPollRate = GimbalPollRate { hz: 20.0, priority: GimbalRequestPriority::High };
+
+/// Values are stale once this many poll periods pass, plus a margin for link jitter
+const STALE_PERIODS : f32 = 2.0;
+const STALE_MARGIN_MILLIS : f32 = 200.0;
+
 impl GimbalController {
     pub fn new() -> GimbalController {
         GimbalController {
@@ -41,20 +66,33 @@ impl GimbalController {
             current_osc_detector: [0.0; 3],
             current_peak_detector: [0.0; 3],
             current_error_timestamp: None,
+            tuner: GimbalTuner::new(),
+            calibrator: GimbalCalibrator::new(),
+            angle_control: None,
+            requests: Vec::new(),
+            link_state: GimbalLinkState::Ok,
         }
     }
 
-    pub fn tick(&mut self, config: &Config, gimbal: &GimbalPort, tracked: &CameraTrackedRegion) -> GimbalControlStatus {
+    pub fn tick(&mut self, config: &Config, gimbal: &GimbalPort, tracked: &CameraTrackedRegion, hold_target: Option<Vector2<i16>>, now: Instant) -> GimbalControlStatus {
         let mut stale_flag = false;
-        self.requests_from_config(config, gimbal, &mut stale_flag);
-        let supply_voltage = self.request(gimbal, &mut stale_flag, RequestType::Infrequent, values::SUPPLY_VOLTAGE, target::IMU_ADJACENT) as f32 * 0.1;
-        let center_cal = self.request_vec2(gimbal, &mut stale_flag, RequestType::Infrequent, values::CALIBRATION_ANGLE_0_CENTER);
-        let raw_angles = self.request_vec2(gimbal, &mut stale_flag, RequestType::Continuous, values::ENCODER_ANGLE);
-        let current = self.request_vec3(gimbal, &mut stale_flag, RequestType::Continuous, values::MOTOR_FILTERED_CURRENT);
-        let motor_status = self.request_vec3(gimbal, &mut stale_flag, RequestType::Infrequent, values::MOTOR_STATUS_FLAGS);
+        if !self.calibrator.is_active() {
+            // Configured values would overwrite the ones being captured
+            self.requests_from_config(config, gimbal, &mut stale_flag, now);
+        }
+        let supply_voltage = registry::scaled(values::SUPPLY_VOLTAGE, self.request(&mut stale_flag, STATUS_POLL, values::SUPPLY_VOLTAGE, target::IMU_ADJACENT, now));
+        let center_cal = self.request_vec2(&mut stale_flag, STATUS_POLL, values::CALIBRATION_ANGLE_0_CENTER, now);
+        let raw_angles = self.request_vec2(&mut stale_flag, ANGLE_POLL, values::ENCODER_ANGLE, now);
+        let current = self.request_vec3(&mut stale_flag, CURRENT_POLL, values::MOTOR_FILTERED_CURRENT, now);
+        let motor_status = self.request_vec3(&mut stale_flag, STATUS_POLL, values::MOTOR_STATUS_FLAGS, now);
+        gimbal.request_values(mem::replace(&mut self.requests, Vec::new()));
+        if self.link_state != GimbalLinkState::Ok {
+            // Values may still trickle in, but not reliably enough to steer by
+            stale_flag = true;
+        }
         let angles = vec2_encoder_sub(raw_angles, center_cal);
         let motor_power = vec3_nonzero(vec3_bitand(motor_status, [motor_status::POWER_ON; 3]));
-        let current_error_duration = self.update_current_error_duration(config, motor_power);
+        let current_error_duration = self.update_current_error_duration(config, motor_power, now);
 
         let mut status = GimbalControlStatus {
             angles,
@@ -72,14 +110,39 @@ impl GimbalController {
             current_osc_detector: self.current_osc_detector,
             current_peak_detector: self.current_peak_detector,
             current_error_duration,
-            supply_voltage
+            supply_voltage,
+            angle_control: None,
+            look_at: None,
         };
 
+        self.calibrator.tick(gimbal, &status, stale_flag, now);
+        let tuning_rate = self.tuner.tick(config, &status, stale_flag);
         if !stale_flag {
             self.motor_poweroff_check(config, &status, gimbal);
-            self.tracking_tick(config, &mut status, tracked);
-            self.hold_tick(config, &mut status);
-            self.gimbal_rate_tick(config, &mut status);
+            if let Some((axis, rate)) = tuning_rate {
+                // The axis under test follows the tuner's input, the other holds still
+                self.hold_tick(config, &mut status, None);
+                self.gimbal_rate_tick(config, &mut status, config.gimbal.max_rate);
+                let index = axis.index();
+                self.hold_i[index] = 0.0;
+                let mut rates = [0.0; 2];
+                rates[index] = rate as f32;
+                // The tuner's input passes through the same software endstops as everything else
+                let limited = self.limiter(config, status.angles, rates)[index];
+                status.rates[index] = limited.round() as i16;
+            } else {
+                // Commanded angles take precedence over any other hold target
+                let (hold_target, max_rate) = match self.angle_control_tick(config, &mut status) {
+                    Some((setpoint, max_rate)) => (Some(setpoint), max_rate),
+                    None => (hold_target, config.gimbal.max_rate),
+                };
+                if hold_target.is_none() {
+                    // An explicit hold target takes the place of vision tracking
+                    self.tracking_tick(config, &mut status, tracked);
+                }
+                self.hold_tick(config, &mut status, hold_target);
+                self.gimbal_rate_tick(config, &mut status, max_rate);
+            }
         }
 
         gimbal.write_control_rates(status.rates);
@@ -111,9 +174,8 @@ impl GimbalController {
         return false;
     }
 
-    fn update_current_error_duration(&mut self, config: &Config, motor_power: Vector3<bool>) -> f32 {
+    fn update_current_error_duration(&mut self, config: &Config, motor_power: Vector3<bool>, now: Instant) -> f32 {
         if self.check_for_current_error(config, motor_power) {
-            let now = Instant::now();
             let prev_timestamp = self.current_error_timestamp;
             match prev_timestamp {
                 None => {
@@ -175,7 +237,7 @@ impl GimbalController {
         status.tracking_i_rates = [xi, yi];
     }
 
-    fn hold_tick(&mut self, config: &Config, status: &mut GimbalControlStatus) {
+    fn hold_tick(&mut self, config: &Config, status: &mut GimbalControlStatus, hold_target: Option<Vector2<i16>>) {
         let is_halted = config.mode == ControllerMode::Halted;
         let is_rehoming = status.current_error_duration > config.gimbal.error_duration_for_rehome;
 
@@ -205,6 +267,11 @@ impl GimbalController {
         // Capture angles at the beginning of a hold
         if next_hold_active[0] && !self.hold_active[0] { self.hold_angles[0] = status.angles[0]; }
         if next_hold_active[1] && !self.hold_active[1] { self.hold_angles[1] = status.angles[1]; }
+        if let Some(target) = hold_target {
+            if !(is_halted || is_rehoming) {
+                self.hold_angles = target;
+            }
+        }
         self.hold_active = next_hold_active;
         status.hold_angles = self.hold_angles;
         status.hold_active = self.hold_active;
@@ -224,7 +291,67 @@ impl GimbalController {
         }
     }
 
-    fn gimbal_rate_tick(&mut self, config: &Config, status: &mut GimbalControlStatus) {
+    pub fn set_angle_target(&mut self, config: &Config, target: GimbalAngleTarget, speed: Option<f32>) {
+        let mut target = target;
+        target.angles = [
+            target.angles[0].max(config.gimbal.yaw_limits.0).min(config.gimbal.yaw_limits.1),
+            target.angles[1].max(config.gimbal.pitch_limits.0).min(config.gimbal.pitch_limits.1),
+        ];
+        // A slew starts from wherever the gimbal is on the next tick
+        let setpoint = match speed {
+            None => Some([ target.angles[0] as f32, target.angles[1] as f32 ]),
+            Some(_) => None,
+        };
+        self.angle_control = Some(AngleControl { target, speed, setpoint });
+    }
+
+    pub fn release_angle_target(&mut self) {
+        self.angle_control = None;
+    }
+
+    /// Move the commanded angle setpoint, returning it and the rate limit to use while holding it
+    fn angle_control_tick(&mut self, config: &Config, status: &mut GimbalControlStatus) -> Option<(Vector2<i16>, f32)> {
+        if config.mode == ControllerMode::Halted {
+            // Halting cancels any commanded angle
+            self.angle_control = None;
+        }
+        let control = match self.angle_control {
+            None => return None,
+            Some(ref mut control) => control,
+        };
+
+        let target = [ control.target.angles[0] as f32, control.target.angles[1] as f32 ];
+        let setpoint = match (control.setpoint, control.speed) {
+            (Some(setpoint), Some(speed)) => {
+                let remaining = vec2_sub(target, setpoint);
+                let distance = vec2_len(remaining);
+                let step = speed / TICK_HZ as f32;
+                if distance <= step {
+                    target
+                } else {
+                    vec2_add(setpoint, vec2_scale(remaining, step / distance))
+                }
+            },
+            (Some(setpoint), None) => setpoint,
+            (None, _) => [ status.angles[0] as f32, status.angles[1] as f32 ],
+        };
+        control.setpoint = Some(setpoint);
+
+        let tolerance = control.target.settle_tolerance as i32;
+        let settled = setpoint == target && (0..2).all(|axis| {
+            (status.angles[axis] as i32 - control.target.angles[axis] as i32).abs() <= tolerance
+        });
+        status.angle_control = Some(GimbalAngleStatus {
+            target: control.target.clone(),
+            setpoint,
+            settled,
+        });
+
+        let max_rate = control.target.max_rate.min(config.gimbal.max_rate);
+        Some(([ setpoint[0].round() as i16, setpoint[1].round() as i16 ], max_rate))
+    }
+
+    fn gimbal_rate_tick(&mut self, config: &Config, status: &mut GimbalControlStatus, max_rate: f32) {
         let rates = if status.current_error_duration > config.gimbal.error_duration_for_rehome {
             // Re-homing for error recovery
             [
@@ -241,35 +368,15 @@ impl GimbalController {
 
         // Software endstops
         let rates = self.limiter(config, status.angles, rates);
-        let rates = vec2_clamp_len(rates, config.gimbal.max_rate);
+        let rates = vec2_clamp_len(rates, max_rate);
         status.rates = self.dither_rates(rates);
     }
 
     fn limiter(&self, config: &Config, angles: Vector2<i16>, rates: Vector2<f32>) -> Vector2<f32> {
-        let axis = |rate: f32, angle: i16, limits: (i16, i16), slowdown_extent: f32| {
-            let angle = angle as f32;
-            let limits = (limits.0 as f32, limits.1 as f32);
-
-            if angle < limits.0 {
-                // Past lower limit; spring back
-                rate.max(0.0) + (limits.0 - angle) * config.gimbal.limiter_gain
-            } else if angle > limits.1 {
-                // Past upper limit; spring back
-                rate.min(0.0) + (limits.1 - angle) * config.gimbal.limiter_gain
-            } else if angle < limits.0 + slowdown_extent {
-                // In lower slowdown region
-                let speed_limit = (angle - limits.0) / slowdown_extent * config.gimbal.max_rate;
-                rate.max(-speed_limit)
-            } else if angle > limits.1 - slowdown_extent {
-                let speed_limit = -(angle - limits.1) / slowdown_extent * config.gimbal.max_rate;
-                rate.min(speed_limit)
-            } else {
-                rate
-            }
-        };
+        let g = &config.gimbal;
         [
-            axis(rates[0], angles[0], config.gimbal.yaw_limits, config.gimbal.limiter_slowdown_extent[0]),
-            axis(rates[1], angles[1], config.gimbal.pitch_limits, config.gimbal.limiter_slowdown_extent[1])
+            limit_axis_rate(g.max_rate, g.limiter_gain, rates[0], angles[0] as f32, g.yaw_limits, g.limiter_slowdown_extent[0]),
+            limit_axis_rate(g.max_rate, g.limiter_gain, rates[1], angles[1] as f32, g.pitch_limits, g.limiter_slowdown_extent[1])
         ]
     }
 
@@ -278,30 +385,24 @@ impl GimbalController {
         [rates[0].round() as i16, rates[1].round() as i16]
     }
 
-    fn request(&mut self, gimbal: &GimbalPort, stale_flag: &mut bool, rtype: RequestType, index: u8, target: u8) -> i16 {
-        let slot = &mut self.values[index as usize][target as usize];
-        match rtype {
-            RequestType::Continuous => {
-                gimbal.request_continuous(index, target);
-                slot.value_with_max_age(stale_flag, 250)
-            },
-            RequestType::Infrequent => {
-                if slot.poll_for_request(3000) {
-                    gimbal.request_once(index, target);
-                }
-                slot.value_with_max_age(stale_flag, 5000)
-            }
-        }
+    fn request(&mut self, stale_flag: &mut bool, rate: GimbalPollRate, index: u8, target: u8, now: Instant) -> i16 {
+        self.requests.push(GimbalValueRequest {
+            addr: GimbalValueAddress { index, target },
+            scope: GimbalRequestScope::Rate(rate),
+        });
+        let max_age_millis = STALE_PERIODS * 1000.0 / rate.hz + STALE_MARGIN_MILLIS;
+        let slot = &self.values[index as usize][target as usize];
+        slot.value_with_max_age(stale_flag, Duration::from_millis(max_age_millis as u64), now)
     }
 
-    fn requests_from_config(&mut self, config: &Config, gimbal: &GimbalPort, stale_flag: &mut bool) {
+    fn requests_from_config(&mut self, config: &Config, gimbal: &GimbalPort, stale_flag: &mut bool, now: Instant) {
         for (&index, vector) in config.gimbal.values.iter() {
             for (target, value) in vector.iter().enumerate() {
                 let target = target as u8;
                 let addr = GimbalValueAddress { target, index };
                 if let &Some(value) = value {
                     let mut item_is_stale = false;
-                    let cached_value = self.request(gimbal, &mut item_is_stale, RequestType::Infrequent, index, target);
+                    let cached_value = self.request(&mut item_is_stale, STATUS_POLL, index, target, now);
                     if item_is_stale || cached_value != value {
                         // Don't wait for the cache, send new value right away.
                         *stale_flag = true;
@@ -312,22 +413,23 @@ impl GimbalController {
         }
     }
 
-    fn request_vec2(&mut self, gimbal: &GimbalPort, stale_flag: &mut bool, rtype: RequestType, index: u8) -> Vector2<i16> {
+    fn request_vec2(&mut self, stale_flag: &mut bool, rate: GimbalPollRate, index: u8, now: Instant) -> Vector2<i16> {
         [
-            self.request(gimbal, stale_flag, rtype, index, target::YAW),
-            self.request(gimbal, stale_flag, rtype, index, target::PITCH),
+            self.request(stale_flag, rate, index, target::YAW, now),
+            self.request(stale_flag, rate, index, target::PITCH, now),
         ]
     }
 
-    fn request_vec3(&mut self, gimbal: &GimbalPort, stale_flag: &mut bool, rtype: RequestType, index: u8) -> Vector3<i16> {
+    fn request_vec3(&mut self, stale_flag: &mut bool, rate: GimbalPollRate, index: u8, now: Instant) -> Vector3<i16> {
         [
-            self.request(gimbal, stale_flag, rtype, index, target::YAW),
-            self.request(gimbal, stale_flag, rtype, index, target::ROLL),
-            self.request(gimbal, stale_flag, rtype, index, target::PITCH),
+            self.request(stale_flag, rate, index, target::YAW, now),
+            self.request(stale_flag, rate, index, target::ROLL, now),
+            self.request(stale_flag, rate, index, target::PITCH, now),
         ]
     }
 
-    pub fn value_received(&mut self, config: &Config, data: GimbalValueData) {
+    pub fn value_received(&mut self, config: &Config, data: GimbalValueData, now: Instant) {
+        self.calibrator.value_received(&data);
         let index = data.addr.index as usize;
         let target = data.addr.target as usize;
 
@@ -351,54 +453,86 @@ impl GimbalController {
                 }
             }
 
-            value.last_update = Some((Instant::now(), data));
+            value.last_update = Some((now, data));
         }
     }
 
+    pub fn tuning_command(&mut self, config: &Config, command: GimbalTuningCommand) -> Option<GimbalTuningProposal> {
+        self.tuner.command(config, command)
+    }
+
+    pub fn tuning_status(&self) -> Option<GimbalTuningStatus> {
+        self.tuner.status()
+    }
+
+    pub fn packet_received(&mut self, packet: &GimbalPacket) {
+        self.calibrator.packet_received(packet);
+    }
+
+    pub fn link_status_received(&mut self, status: &GimbalLinkStatus) {
+        self.link_state = status.state;
+    }
+
+    pub fn calibration_command(&mut self, command: GimbalCalibrationCommand) {
+        self.calibrator.command(command);
+    }
+
+    pub fn calibration_status(&self) -> Option<GimbalCalibrationStatus> {
+        self.calibrator.status()
+    }
+
+    pub fn take_calibrated_values(&mut self) -> Option<Vec<GimbalValueData>> {
+        self.calibrator.take_calibrated_values()
+    }
+
     pub fn set_motor_enable(&mut self, gimbal: &GimbalPort, en: bool) {
         gimbal.set_motor_enable(en);
 
         // The motor enable won't take effect synchronously with the command processing
         // on the gimbal, it takes a control loop tick for the request to propagate
-        // into the motor status flags. Issue a continuous request which will, for a short time,
-        // ask the poller to request these status flags at a high rate. When the continuous
+        // into the motor status flags. Issue a rate request which will, for a short time,
+        // ask the poller to request these status flags at a high rate. When the rate
         // request expires, these flags will go back to being requested only infrequently.
-        gimbal.request_continuous(values::MOTOR_STATUS_FLAGS, target::YAW);
-        gimbal.request_continuous(values::MOTOR_STATUS_FLAGS, target::ROLL);
-        gimbal.request_continuous(values::MOTOR_STATUS_FLAGS, target::PITCH);
+        gimbal.request_rate(values::MOTOR_STATUS_FLAGS, target::YAW, MOTOR_ENABLE_POLL);
+        gimbal.request_rate(values::MOTOR_STATUS_FLAGS, target::ROLL, MOTOR_ENABLE_POLL);
+        gimbal.request_rate(values::MOTOR_STATUS_FLAGS, target::PITCH, MOTOR_ENABLE_POLL);
+    }
+}
+
+/// Software endstop for one axis, slowing down near the limits and springing back past them
+pub fn limit_axis_rate(max_rate: f32, limiter_gain: f32, rate: f32, angle: f32, limits: (i16, i16), slowdown_extent: f32) -> f32 {
+    let limits = (limits.0 as f32, limits.1 as f32);
+
+    if angle < limits.0 {
+        // Past lower limit; spring back
+        rate.max(0.0) + (limits.0 - angle) * limiter_gain
+    } else if angle > limits.1 {
+        // Past upper limit; spring back
+        rate.min(0.0) + (limits.1 - angle) * limiter_gain
+    } else if angle < limits.0 + slowdown_extent {
+        // In lower slowdown region
+        let speed_limit = (angle - limits.0) / slowdown_extent * max_rate;
+        rate.max(-speed_limit)
+    } else if angle > limits.1 - slowdown_extent {
+        let speed_limit = -(angle - limits.1) / slowdown_extent * max_rate;
+        rate.min(speed_limit)
+    } else {
+        rate
     }
 }
 
 struct GimbalValueState {
-    last_requested: Option<Instant>,
     last_update: Option<(Instant, GimbalValueData)>,
 }
 
 impl GimbalValueState {
     fn new() -> GimbalValueState {
         GimbalValueState {
-            last_requested: None,
             last_update: None,
         }
     }
 
-    fn poll_for_request(&mut self, interval_millis: u64) -> bool {
-        let now = Instant::now();
-        let needs_request = match self.last_requested {
-            None => true,
-            Some(timestamp) => now > timestamp + Duration::from_millis(interval_millis),
-        };
-        if needs_request {
-            self.last_requested = Some(now);
-            true
-        } else {
-            false
-        }
-    }
-
-    fn value_with_max_age(&self, stale_flag: &mut bool, millis: u64) -> i16 {
-        let max_age = Duration::from_millis(millis);
-        let now = Instant::now();
+    fn value_with_max_age(&self, stale_flag: &mut bool, max_age: Duration, now: Instant) -> i16 {
         match self.last_update {
             None => {
                 *stale_flag = true;
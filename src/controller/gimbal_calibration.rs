@@ -0,0 +1,408 @@
+//! Guided calibration of the gimbal's IMU and encoders. Each procedure checks that the
+//! motors are off, reads the values it's about to change, asks the gimbal to capture new
+//! ones, then reads them back to verify before saving them to flash. The operator is
+//! responsible for holding the gimbal still, level, or centered as the procedure requires.
+
+use message::*;
+use fygimbal::{GimbalPacket, GimbalFraming, GimbalPort};
+use fygimbal::protocol::{cmd, target, values, pack, unpack, NUM_AXES};
+use fygimbal::util::encoder_sub;
+use std::time::{Duration, Instant};
+
+/// Reads are requested again after going unanswered this long
+const RETRY_MILLIS : u64 = 1000;
+
+/// Captures and saves are sent again if unacknowledged this long, allowing for the IMU's capture time
+const ACK_TIMEOUT_MILLIS : u64 = 3000;
+
+/// Sends of one capture or save before the phase fails
+const MAX_ACK_ATTEMPTS : u32 = 3;
+
+/// Give up on a phase that makes no progress for this long
+const PHASE_TIMEOUT_MILLIS : u64 = 10000;
+
+/// A captured center may differ this much from the encoder angle read just before
+const CENTER_TOLERANCE : i16 = 16;
+
+pub struct GimbalCalibrator {
+    status: Option<GimbalCalibrationStatus>,
+    phase_started: Option<Instant>,
+    last_request: Option<Instant>,
+    /// Targets still to acknowledge a capture or save, one at a time in order
+    pending: Vec<u8>,
+    /// When the first pending target was last sent its packet, and how many times
+    awaiting_ack: Option<(Instant, u32)>,
+    accel_corrections: Option<[i16; 3]>,
+    calibrated: Option<Vec<GimbalValueData>>,
+}
+
+impl GimbalCalibrator {
+    pub fn new() -> GimbalCalibrator {
+        GimbalCalibrator {
+            status: None,
+            phase_started: None,
+            last_request: None,
+            pending: Vec::new(),
+            awaiting_ack: None,
+            accel_corrections: None,
+            calibrated: None,
+        }
+    }
+
+    pub fn status(&self) -> Option<GimbalCalibrationStatus> {
+        self.status.clone()
+    }
+
+    pub fn is_active(&self) -> bool {
+        match self.status {
+            Some(ref status) => match status.phase {
+                GimbalCalibrationPhase::Finished | GimbalCalibrationPhase::Failed => false,
+                _ => true,
+            },
+            None => false,
+        }
+    }
+
+    /// Verified values, once, so the config can be updated to match
+    pub fn take_calibrated_values(&mut self) -> Option<Vec<GimbalValueData>> {
+        self.calibrated.take()
+    }
+
+    pub fn command(&mut self, command: GimbalCalibrationCommand) {
+        match command {
+            GimbalCalibrationCommand::Start(procedure) => {
+                if self.is_active() {
+                    self.set_error("Wait for the current calibration to finish".into());
+                    return;
+                }
+                self.status = Some(GimbalCalibrationStatus {
+                    procedure,
+                    phase: GimbalCalibrationPhase::CheckingMotors,
+                    values: calibration_addrs(procedure).into_iter().map(|addr| {
+                        GimbalCalibrationValue { addr, before: None, after: None }
+                    }).collect(),
+                    error: None,
+                });
+                self.accel_corrections = None;
+                self.enter_phase(GimbalCalibrationPhase::CheckingMotors);
+            },
+            GimbalCalibrationCommand::Cancel => {
+                if self.is_active() {
+                    self.fail("Cancelled".into());
+                } else {
+                    self.set_error("No gimbal calibration in progress".into());
+                }
+            },
+        }
+    }
+
+    pub fn value_received(&mut self, data: &GimbalValueData) {
+        if let Some(ref mut status) = self.status {
+            let reading_after = match status.phase {
+                GimbalCalibrationPhase::ReadingBefore => false,
+                GimbalCalibrationPhase::Verifying => true,
+                _ => return,
+            };
+            for value in status.values.iter_mut() {
+                if value.addr == data.addr {
+                    if reading_after {
+                        value.after = Some(data.value);
+                    } else if value.before.is_none() {
+                        value.before = Some(data.value);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn packet_received(&mut self, packet: &GimbalPacket) {
+        if packet.framing != GimbalFraming::Normal || packet.target != target::HOST || self.awaiting_ack.is_none() {
+            return;
+        }
+        let phase = match self.status {
+            Some(ref status) => status.phase,
+            None => return,
+        };
+        let expected = match phase {
+            GimbalCalibrationPhase::Capturing => capture_command(self.procedure()),
+            GimbalCalibrationPhase::Saving => cmd::SAVE_VALUES,
+            _ => return,
+        };
+        if packet.command != expected {
+            return;
+        }
+        if packet.command == cmd::GET_ACCEL_CORRECTIONS {
+            match unpack::accel_corrections(packet) {
+                Ok(corrections) => self.accel_corrections = Some(corrections),
+                Err(_) => return,
+            }
+        }
+        if packet.command == cmd::SAVE_VALUES {
+            // A late ack for a save we sent again may still be on its way
+            match unpack::save_values(packet) {
+                Ok(saved) if Some(&saved) == self.pending.first() => (),
+                _ => return,
+            }
+        }
+        self.pending.remove(0);
+        self.awaiting_ack = None;
+        // Each acknowledgement is progress, so the phase timeout starts over
+        self.phase_started = None;
+    }
+
+    /// Advance the procedure once per gimbal control tick
+    pub fn tick(&mut self, gimbal: &GimbalPort, status: &GimbalControlStatus, stale: bool, now: Instant) {
+        if !self.is_active() {
+            return;
+        }
+        if !stale && status.motor_power.iter().any(|&on| on) {
+            let checking = self.status.as_ref().unwrap().phase == GimbalCalibrationPhase::CheckingMotors;
+            self.fail(if checking {
+                "Turn off the gimbal motors before calibrating".into()
+            } else {
+                "Gimbal motors must stay off during calibration".into()
+            });
+            return;
+        }
+        let phase_started = *self.phase_started.get_or_insert(now);
+        if now > phase_started + Duration::from_millis(PHASE_TIMEOUT_MILLIS) {
+            let phase = self.status.as_ref().unwrap().phase;
+            self.fail(format!("Gimbal stopped responding during {:?}", phase));
+            return;
+        }
+        let retry = match self.last_request {
+            None => true,
+            Some(timestamp) => now > timestamp + Duration::from_millis(RETRY_MILLIS),
+        };
+
+        let procedure = self.procedure();
+        let phase = self.status.as_ref().unwrap().phase;
+        match phase {
+
+            GimbalCalibrationPhase::CheckingMotors => {
+                // Wait for motor flags we can trust
+                if !stale {
+                    self.enter_phase(GimbalCalibrationPhase::ReadingBefore);
+                }
+            },
+
+            GimbalCalibrationPhase::ReadingBefore => {
+                if self.all_read(false) {
+                    self.pending = capture_targets(procedure);
+                    self.enter_phase(GimbalCalibrationPhase::Capturing);
+                } else if retry {
+                    self.request_missing(gimbal, false);
+                    self.last_request = Some(now);
+                }
+            },
+
+            GimbalCalibrationPhase::Capturing => {
+                if let Some(&t) = self.pending.first() {
+                    self.send_until_acknowledged(gimbal, capture_packet(procedure, t), now);
+                } else {
+                    if let Some(corrections) = self.accel_corrections {
+                        // Raw packets go out ahead of the readback requests
+                        for (axis, &value) in corrections.iter().enumerate() {
+                            gimbal.send_packet(pack::set_accel_correction(axis as u8, value));
+                        }
+                    }
+                    self.enter_phase(GimbalCalibrationPhase::Verifying);
+                }
+            },
+
+            GimbalCalibrationPhase::Verifying => {
+                if !self.all_read(true) {
+                    if retry {
+                        self.request_missing(gimbal, true);
+                        self.last_request = Some(now);
+                    }
+                } else {
+                    match self.verify() {
+                        Err(e) => self.fail(e),
+                        Ok(calibrated) => {
+                            self.pending = Vec::new();
+                            for data in calibrated.iter() {
+                                if !self.pending.contains(&data.addr.target) {
+                                    self.pending.push(data.addr.target);
+                                }
+                            }
+                            self.calibrated = Some(calibrated);
+                            self.enter_phase(GimbalCalibrationPhase::Saving);
+                        }
+                    }
+                }
+            },
+
+            GimbalCalibrationPhase::Saving => {
+                if let Some(&t) = self.pending.first() {
+                    self.send_until_acknowledged(gimbal, pack::save_values(t), now);
+                } else {
+                    self.enter_phase(GimbalCalibrationPhase::Finished);
+                }
+            },
+
+            GimbalCalibrationPhase::Finished | GimbalCalibrationPhase::Failed => (),
+        }
+    }
+
+    /// Compare the readback against what the procedure asked for, returning the values to keep
+    fn verify(&self) -> Result<Vec<GimbalValueData>, String> {
+        let status = self.status.as_ref().unwrap();
+        let after = |index: u8, t: u8| -> i16 {
+            status.values.iter().find(|v| v.addr.index == index && v.addr.target == t).and_then(|v| v.after).unwrap_or(0)
+        };
+        let before = |index: u8, t: u8| -> i16 {
+            status.values.iter().find(|v| v.addr.index == index && v.addr.target == t).and_then(|v| v.before).unwrap_or(0)
+        };
+
+        match status.procedure {
+            GimbalCalibrationProcedure::GyroDrift => {
+                // Drift is measured afresh on every capture, so an exact match means it never happened
+                if GYRO_DRIFTS.iter().all(|&index| after(index, target::IMU_ADJACENT) == before(index, target::IMU_ADJACENT)) {
+                    return Err("Gyro drift reads back unchanged, the IMU didn't capture new values".into());
+                }
+            },
+            GimbalCalibrationProcedure::AccelOffsets => {
+                let corrections = match self.accel_corrections {
+                    Some(corrections) => corrections,
+                    None => return Err("IMU sent no accelerometer corrections".into()),
+                };
+                for (axis, &index) in ACCEL_OFFSETS.iter().enumerate() {
+                    if after(index, target::IMU_ADJACENT) != corrections[axis] {
+                        return Err(format!("Accelerometer offset {} reads back {}, expected {}",
+                            index, after(index, target::IMU_ADJACENT), corrections[axis]));
+                    }
+                }
+            },
+            GimbalCalibrationProcedure::EncoderCenter => {
+                for t in 0 .. NUM_AXES as u8 {
+                    let center = after(values::CALIBRATION_ANGLE_0_CENTER, t);
+                    let angle = before(values::ENCODER_ANGLE, t);
+                    if encoder_sub(center, angle).abs() > CENTER_TOLERANCE {
+                        return Err(format!("Axis {} center reads back {}, but the encoder was at {}", t, center, angle));
+                    }
+                }
+            },
+        }
+
+        // Encoder angles were only read for comparison
+        Ok(status.values.iter().filter(|v| v.addr.index != values::ENCODER_ANGLE).map(|v| {
+            GimbalValueData { addr: v.addr.clone(), value: v.after.unwrap_or(0) }
+        }).collect())
+    }
+
+    fn procedure(&self) -> GimbalCalibrationProcedure {
+        self.status.as_ref().unwrap().procedure
+    }
+
+    fn all_read(&self, reading_after: bool) -> bool {
+        self.status.as_ref().unwrap().values.iter().all(|v| {
+            if reading_after { v.after.is_some() } else { v.before.is_some() }
+        })
+    }
+
+    fn request_missing(&self, gimbal: &GimbalPort, reading_after: bool) {
+        let reqs = self.status.as_ref().unwrap().values.iter().filter(|v| {
+            if reading_after { v.after.is_none() } else { v.before.is_none() }
+        }).map(|v| GimbalValueRequest {
+            addr: v.addr.clone(),
+            scope: GimbalRequestScope::Once,
+        }).collect();
+        gimbal.request_values(reqs);
+    }
+
+    /// Send a packet for the first pending target, again whenever its acknowledgement is overdue
+    fn send_until_acknowledged(&mut self, gimbal: &GimbalPort, packet: GimbalPacket, now: Instant) {
+        let attempts = match self.awaiting_ack {
+            None => 0,
+            Some((sent, attempts)) => {
+                if now <= sent + Duration::from_millis(ACK_TIMEOUT_MILLIS) {
+                    return;
+                }
+                attempts
+            },
+        };
+        if attempts >= MAX_ACK_ATTEMPTS {
+            let error = format!("No acknowledgement from gimbal MCU {} during {:?}, after {} attempts",
+                self.pending[0], self.status.as_ref().unwrap().phase, attempts);
+            self.fail(error);
+            return;
+        }
+        gimbal.send_packet(packet);
+        self.awaiting_ack = Some((now, attempts + 1));
+    }
+
+    fn enter_phase(&mut self, phase: GimbalCalibrationPhase) {
+        if let Some(ref mut status) = self.status {
+            status.phase = phase;
+        }
+        self.phase_started = None;
+        self.last_request = None;
+        self.awaiting_ack = None;
+    }
+
+    fn fail(&mut self, error: String) {
+        self.enter_phase(GimbalCalibrationPhase::Failed);
+        self.pending.clear();
+        self.set_error(error);
+    }
+
+    fn set_error(&mut self, error: String) {
+        if let Some(ref mut status) = self.status {
+            status.error = Some(error);
+        }
+    }
+}
+
+const GYRO_DRIFTS : [u8; 3] = [
+    values::CALIBRATION_GYRO_DRIFT_Z,
+    values::CALIBRATION_GYRO_DRIFT_X,
+    values::CALIBRATION_GYRO_DRIFT_Y,
+];
+
+/// In imu_axis order, matching GET_ACCEL_CORRECTIONS
+const ACCEL_OFFSETS : [u8; 3] = [
+    values::CALIBRATION_ACCEL_OFFSET_Z,
+    values::CALIBRATION_ACCEL_OFFSET_X,
+    values::CALIBRATION_ACCEL_OFFSET_Y,
+];
+
+fn calibration_addrs(procedure: GimbalCalibrationProcedure) -> Vec<GimbalValueAddress> {
+    let imu = |index: &u8| GimbalValueAddress { index: *index, target: target::IMU_ADJACENT };
+    match procedure {
+        GimbalCalibrationProcedure::GyroDrift => GYRO_DRIFTS.iter().map(imu).collect(),
+        GimbalCalibrationProcedure::AccelOffsets => ACCEL_OFFSETS.iter().map(imu).collect(),
+        GimbalCalibrationProcedure::EncoderCenter => {
+            let mut addrs = Vec::new();
+            for t in 0 .. NUM_AXES as u8 {
+                addrs.push(GimbalValueAddress { index: values::ENCODER_ANGLE, target: t });
+                addrs.push(GimbalValueAddress { index: values::CALIBRATION_ANGLE_0_CENTER, target: t });
+            }
+            addrs
+        },
+    }
+}
+
+fn capture_targets(procedure: GimbalCalibrationProcedure) -> Vec<u8> {
+    match procedure {
+        GimbalCalibrationProcedure::GyroDrift | GimbalCalibrationProcedure::AccelOffsets => vec![ target::IMU_ADJACENT ],
+        GimbalCalibrationProcedure::EncoderCenter => (0 .. NUM_AXES as u8).collect(),
+    }
+}
+
+fn capture_command(procedure: GimbalCalibrationProcedure) -> u8 {
+    match procedure {
+        GimbalCalibrationProcedure::GyroDrift => cmd::CAPTURE_GYRO_DRIFT_COMPENSATION,
+        GimbalCalibrationProcedure::AccelOffsets => cmd::GET_ACCEL_CORRECTIONS,
+        GimbalCalibrationProcedure::EncoderCenter => cmd::CAPTURE_CALIBRATION_ANGLE,
+    }
+}
+
+fn capture_packet(procedure: GimbalCalibrationProcedure, t: u8) -> GimbalPacket {
+    match procedure {
+        GimbalCalibrationProcedure::GyroDrift => pack::capture_gyro_drift(),
+        GimbalCalibrationProcedure::AccelOffsets => pack::get_accel_corrections(),
+        // Slot 0 is CALIBRATION_ANGLE_0_CENTER
+        GimbalCalibrationProcedure::EncoderCenter => pack::capture_calibration_angle(t, 0),
+    }
+}
@@ -0,0 +1,604 @@
+//! Backup, comparison and restore of the gimbal's whole value space. The config only knows
+//! the handful of values we override, but each MCU keeps many more calibration and tuning
+//! values in flash. A snapshot reads every value through the poller into a named profile on
+//! disk, and a restore writes selected values back before asking each MCU to save them.
+
+use message::*;
+use vecmath::*;
+use config::Config;
+use fygimbal::{GimbalPacket, GimbalFraming, GimbalPort};
+use fygimbal::protocol::{cmd, target, pack, unpack, NUM_AXES, NUM_VALUES};
+use fygimbal::registry;
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Requests are sent again after going this long without any progress
+const RETRY_MILLIS : u64 = 1000;
+
+/// Give up after this long without any progress
+const STALL_TIMEOUT_MILLIS : u64 = 10000;
+
+/// Keeps restores well within the GimbalPort's write queue
+const WRITES_PER_TICK : usize = 16;
+
+const PROFILE_EXTENSION : &str = "yaml";
+
+/// Same layout as GimbalConfig::values, so profile entries can be copied into the config
+type ValueMap = BTreeMap<u8, Vector3<Option<i16>>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct GimbalProfile {
+    values: ValueMap,
+}
+
+enum Transfer {
+    /// Every value read so far
+    Read(ValueMap),
+    /// Writes not yet queued, queued writes the poller hasn't completed yet, and the MCUs to save
+    Write(Vec<GimbalValueData>, Vec<GimbalValueData>, Vec<u8>),
+    /// MCUs that haven't acknowledged saving their values
+    Save(Vec<u8>),
+}
+
+pub struct GimbalParams {
+    status: Option<GimbalParamStatus>,
+    transfer: Option<Transfer>,
+    last_sent: Instant,
+    last_progress: Instant,
+}
+
+impl GimbalParams {
+    pub fn new(now: Instant) -> GimbalParams {
+        GimbalParams {
+            status: None,
+            transfer: None,
+            last_sent: now,
+            last_progress: now,
+        }
+    }
+
+    pub fn status(&self) -> Option<GimbalParamStatus> {
+        self.status.clone()
+    }
+
+    pub fn command(&mut self, config: &Config, gimbal: &GimbalPort, command: GimbalParamCommand, now: Instant) {
+        let status = self.status.get_or_insert(GimbalParamStatus {
+            profiles: Vec::new(),
+            activity: None,
+            progress: 0,
+            total: 0,
+            diff: None,
+            error: None,
+        });
+
+        let result = if let Err(e) = command.validate() {
+            Err(e)
+        } else {
+            match command {
+                GimbalParamCommand::ListProfiles => Ok(None),
+                GimbalParamCommand::Cancel => {
+                    if status.activity.is_none() {
+                        Err("No gimbal parameter transfer in progress".into())
+                    } else {
+                        self.transfer = None;
+                        status.activity = None;
+                        Err("Cancelled".into())
+                    }
+                },
+                _ if status.activity.is_some() => Err("Wait for the current gimbal parameter transfer to finish".into()),
+                command => start(config, gimbal, status, command),
+            }
+        };
+
+        match result {
+            Ok(transfer) => {
+                status.error = None;
+                if transfer.is_some() {
+                    self.transfer = transfer;
+                    self.last_sent = now;
+                    self.last_progress = now;
+                }
+            },
+            Err(e) => status.error = Some(e),
+        }
+        match list_profiles(config) {
+            Ok(profiles) => status.profiles = profiles,
+            Err(e) => status.error = Some(e),
+        }
+    }
+
+    pub fn value_received(&mut self, data: &GimbalValueData, op: &GimbalValueOp, now: Instant) {
+        if *op == GimbalValueOp::WriteFailed {
+            let ours = match self.transfer {
+                Some(Transfer::Write(_, ref queued, _)) => queued.contains(data),
+                _ => false,
+            };
+            if ours {
+                self.finish(Err(format!("Gimbal value {} on axis {} didn't read back as written", data.addr.index, data.addr.target)));
+            }
+            return;
+        }
+        let progress = match (&mut self.transfer, op) {
+
+            (&mut Some(Transfer::Read(ref mut values)), &GimbalValueOp::ReadComplete) => {
+                let axes = values.entry(data.addr.index).or_insert([None; NUM_AXES]);
+                match axes.get_mut(data.addr.target as usize) {
+                    Some(slot) => {
+                        let is_new = slot.is_none();
+                        *slot = Some(data.value);
+                        is_new
+                    },
+                    None => false,
+                }
+            },
+
+            (&mut Some(Transfer::Write(_, ref mut queued, _)), &GimbalValueOp::WriteComplete) => {
+                // Only our own value counts, another writer to the same address could win
+                match queued.iter().position(|write| write == data) {
+                    Some(index) => {
+                        queued.remove(index);
+                        true
+                    },
+                    None => false,
+                }
+            },
+
+            _ => false,
+        };
+        if progress {
+            self.last_progress = now;
+            if let Some(ref mut status) = self.status {
+                status.progress += 1;
+            }
+        }
+    }
+
+    pub fn packet_received(&mut self, packet: &GimbalPacket, now: Instant) {
+        if packet.framing != GimbalFraming::Normal || packet.command != cmd::SAVE_VALUES || packet.target != target::HOST {
+            return;
+        }
+        if let Some(Transfer::Save(ref mut targets)) = self.transfer {
+            if let Ok(saved) = unpack::save_values(packet) {
+                if let Some(index) = targets.iter().position(|&t| t == saved) {
+                    targets.remove(index);
+                    self.last_progress = now;
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, config: &Config, gimbal: &GimbalPort, now: Instant) {
+        if self.transfer.is_none() {
+            return;
+        }
+        if now > self.last_progress + Duration::from_millis(STALL_TIMEOUT_MILLIS) {
+            self.finish(Err("Gimbal stopped responding".into()));
+            return;
+        }
+        let retry = now > self.last_progress + Duration::from_millis(RETRY_MILLIS)
+            && now > self.last_sent + Duration::from_millis(RETRY_MILLIS);
+
+        let finished = match self.transfer {
+
+            Some(Transfer::Read(ref values)) => {
+                let received = values.values().map(|axes| axes.iter().filter(|v| v.is_some()).count()).sum::<usize>();
+                let complete = received >= NUM_VALUES * NUM_AXES;
+                if retry && !complete {
+                    request_missing(gimbal, values);
+                    self.last_sent = now;
+                }
+                complete
+            },
+
+            Some(Transfer::Write(ref mut unsent, ref mut queued, _)) => {
+                if retry {
+                    unsent.extend(queued.drain(..));
+                }
+                let count = unsent.len().min(WRITES_PER_TICK);
+                for write in unsent.drain(.. count) {
+                    gimbal.write_value(write.clone());
+                    queued.push(write);
+                    self.last_sent = now;
+                }
+                unsent.is_empty() && queued.is_empty()
+            },
+
+            Some(Transfer::Save(ref targets)) => {
+                if retry {
+                    for &t in targets.iter() {
+                        gimbal.send_packet(pack::save_values(t));
+                    }
+                    self.last_sent = now;
+                }
+                targets.is_empty()
+            },
+
+            None => false,
+        };
+        if !finished {
+            return;
+        }
+
+        match self.transfer.take() {
+            Some(Transfer::Read(values)) => {
+                let result = self.finish_read(config, values);
+                self.finish(result);
+            },
+            Some(Transfer::Write(_, _, targets)) => {
+                // Written values only last until power off, unless each MCU saves them.
+                // Raw packets skip the write queue, so these wait until every write went out.
+                for &t in targets.iter() {
+                    gimbal.send_packet(pack::save_values(t));
+                }
+                self.transfer = Some(Transfer::Save(targets));
+                self.last_sent = now;
+                self.last_progress = now;
+            },
+            Some(Transfer::Save(_)) => self.finish(Ok(())),
+            None => (),
+        }
+    }
+
+    fn finish_read(&mut self, config: &Config, values: ValueMap) -> Result<(), String> {
+        let activity = self.status.as_ref().and_then(|status| status.activity.clone());
+        match activity {
+            Some(GimbalParamActivity::Snapshot(name)) => {
+                save_profile(config, &name, &GimbalProfile { values })?;
+                let profiles = list_profiles(config)?;
+                if let Some(ref mut status) = self.status {
+                    status.profiles = profiles;
+                }
+                Ok(())
+            },
+            Some(GimbalParamActivity::DiffLive(name)) => {
+                let from = load_profile(config, &name)?;
+                if let Some(ref mut status) = self.status {
+                    status.diff = Some(GimbalParamDiff {
+                        from: name,
+                        to: None,
+                        values: diff_values(&from.values, &values),
+                    });
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn finish(&mut self, result: Result<(), String>) {
+        self.transfer = None;
+        if let Some(ref mut status) = self.status {
+            status.activity = None;
+            status.error = result.err();
+        }
+    }
+}
+
+/// Begin a command, returning the transfer to run if it needs to talk to the gimbal
+fn start(config: &Config, gimbal: &GimbalPort, status: &mut GimbalParamStatus, command: GimbalParamCommand) -> Result<Option<Transfer>, String> {
+    match command {
+
+        GimbalParamCommand::Snapshot(name) => {
+            request_missing(gimbal, &BTreeMap::new());
+            status.activity = Some(GimbalParamActivity::Snapshot(name));
+            status.progress = 0;
+            status.total = NUM_VALUES * NUM_AXES;
+            Ok(Some(Transfer::Read(BTreeMap::new())))
+        },
+
+        GimbalParamCommand::Diff(from, None) => {
+            load_profile(config, &from)?;
+            request_missing(gimbal, &BTreeMap::new());
+            status.activity = Some(GimbalParamActivity::DiffLive(from));
+            status.progress = 0;
+            status.total = NUM_VALUES * NUM_AXES;
+            Ok(Some(Transfer::Read(BTreeMap::new())))
+        },
+
+        GimbalParamCommand::Diff(from, Some(to)) => {
+            let from_profile = load_profile(config, &from)?;
+            let to_profile = load_profile(config, &to)?;
+            status.diff = Some(GimbalParamDiff {
+                values: diff_values(&from_profile.values, &to_profile.values),
+                from,
+                to: Some(to),
+            });
+            Ok(None)
+        },
+
+        GimbalParamCommand::Restore(name, addrs) => {
+            let profile = load_profile(config, &name)?;
+            let mut writes = Vec::new();
+            let mut targets = Vec::new();
+            for addr in addrs {
+                let value = match profile_value(&profile.values, &addr) {
+                    Some(value) => value,
+                    None => return Err(format!("Profile {} has no value {} on axis {}", name, addr.index, addr.target)),
+                };
+                // The gimbal controller keeps writing configured values, so it would undo ours
+                if let Some(configured) = profile_value(&config.gimbal.values, &addr) {
+                    if configured != value {
+                        return Err(format!("Value {} on axis {} is set by gimbal.values in the config", addr.index, addr.target));
+                    }
+                }
+                let data = GimbalValueData { addr, value };
+                registry::validate_write(&data)?;
+                if !targets.contains(&data.addr.target) {
+                    targets.push(data.addr.target);
+                }
+                writes.push(data);
+            }
+            status.activity = Some(GimbalParamActivity::Restore(name));
+            status.progress = 0;
+            status.total = writes.len();
+            Ok(Some(Transfer::Write(writes, Vec::new(), targets)))
+        },
+
+        GimbalParamCommand::ListProfiles | GimbalParamCommand::Cancel => Ok(None),
+    }
+}
+
+fn request_missing(gimbal: &GimbalPort, values: &ValueMap) {
+    let mut reqs = Vec::new();
+    for index in 0 .. NUM_VALUES {
+        for target in 0 .. NUM_AXES {
+            let addr = GimbalValueAddress { index: index as u8, target: target as u8 };
+            if profile_value(values, &addr).is_none() {
+                reqs.push(GimbalValueRequest { addr, scope: GimbalRequestScope::Once });
+            }
+        }
+    }
+    gimbal.request_values(reqs);
+}
+
+fn profile_value(values: &ValueMap, addr: &GimbalValueAddress) -> Option<i16> {
+    values.get(&addr.index).and_then(|axes| axes.get(addr.target as usize).cloned().unwrap_or(None))
+}
+
+/// Every address where the two sets of values disagree, in index then axis order
+fn diff_values(from: &ValueMap, to: &ValueMap) -> Vec<GimbalValueDiff> {
+    let mut result = Vec::new();
+    for index in 0 .. NUM_VALUES {
+        for target in 0 .. NUM_AXES {
+            let addr = GimbalValueAddress { index: index as u8, target: target as u8 };
+            let a = profile_value(from, &addr);
+            let b = profile_value(to, &addr);
+            if a != b {
+                result.push(GimbalValueDiff { addr, from: a, to: b });
+            }
+        }
+    }
+    result
+}
+
+fn profile_path(config: &Config, name: &str) -> PathBuf {
+    PathBuf::from(&config.gimbal.profile_directory).join(format!("{}.{}", name, PROFILE_EXTENSION))
+}
+
+fn list_profiles(config: &Config) -> Result<Vec<String>, String> {
+    let entries = match fs::read_dir(&config.gimbal.profile_directory) {
+        Ok(entries) => entries,
+        // Nothing saved yet
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().map_or(false, |ext| ext == PROFILE_EXTENSION) {
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(stem.to_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn load_profile(config: &Config, name: &str) -> Result<GimbalProfile, String> {
+    let mut buffer = String::new();
+    File::open(profile_path(config, name))
+        .and_then(|mut file| file.read_to_string(&mut buffer))
+        .map_err(|e| format!("Can't read gimbal profile {}, {}", name, e))?;
+    serde_yaml::from_str(&buffer).map_err(|e| format!("Bad gimbal profile {}, {}", name, e))
+}
+
+fn save_profile(config: &Config, name: &str, profile: &GimbalProfile) -> Result<(), String> {
+    fs::create_dir_all(&config.gimbal.profile_directory).map_err(|e| e.to_string())?;
+    let string = serde_yaml::to_string(profile).map_err(|e| e.to_string())?;
+    File::create(profile_path(config, name))
+        .and_then(|mut file| file.write_all(string.as_bytes()))
+        .map_err(|e| format!("Can't write gimbal profile {}, {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::test_config;
+    use fygimbal::GimbalPoller;
+    use fygimbal::protocol::values;
+    use rand;
+    use std::env;
+
+    /// Keeps each test's profiles in a directory of its own, removed afterward
+    struct ProfileDirectory {
+        config: Config,
+    }
+
+    impl ProfileDirectory {
+        fn new() -> ProfileDirectory {
+            let mut config = test_config(ControllerMode::Halted);
+            let path = env::temp_dir().join(format!("gimbal-profiles-{:08x}", rand::random::<u32>()));
+            config.gimbal.profile_directory = path.to_str().unwrap().to_owned();
+            ProfileDirectory { config }
+        }
+    }
+
+    impl Drop for ProfileDirectory {
+        fn drop(&mut self) {
+            drop(fs::remove_dir_all(&self.config.gimbal.profile_directory));
+        }
+    }
+
+    fn addr(target: u8, index: u8) -> GimbalValueAddress {
+        GimbalValueAddress { target, index }
+    }
+
+    fn original_value(target: u8, index: u8) -> i16 {
+        index as i16 * 3 + target as i16
+    }
+
+    /// Answer every read of a snapshot or live diff
+    fn read_everything<F: Fn(u8, u8) -> i16>(params: &mut GimbalParams, now: Instant, value: F) {
+        for index in 0 .. NUM_VALUES as u8 {
+            for target in 0 .. NUM_AXES as u8 {
+                let data = GimbalValueData { addr: addr(target, index), value: value(target, index) };
+                params.value_received(&data, &GimbalValueOp::ReadComplete, now);
+            }
+        }
+    }
+
+    fn save_ack(mcu: u8) -> GimbalPacket {
+        GimbalPacket {
+            framing: GimbalFraming::Normal,
+            command: cmd::SAVE_VALUES,
+            target: target::HOST,
+            data: vec![mcu],
+        }
+    }
+
+    fn snapshot(dir: &ProfileDirectory, params: &mut GimbalParams, gimbal: &GimbalPort, now: Instant) {
+        params.command(&dir.config, gimbal, GimbalParamCommand::Snapshot("bench".into()), now);
+        read_everything(params, now, original_value);
+        params.tick(&dir.config, gimbal, now);
+    }
+
+    #[test]
+    fn snapshot_diff_and_restore_round_trip() {
+        let dir = ProfileDirectory::new();
+        let poller = GimbalPoller::new();
+        let gimbal = poller.port();
+        let now = Instant::now();
+        let mut params = GimbalParams::new(now);
+
+        snapshot(&dir, &mut params, &gimbal, now);
+        let status = params.status().unwrap();
+        assert_eq!(status.error, None);
+        assert_eq!(status.activity, None);
+        assert_eq!(status.progress, NUM_VALUES * NUM_AXES);
+        assert_eq!(status.profiles, vec!["bench".to_owned()]);
+        let profile = load_profile(&dir.config, "bench").unwrap();
+        assert_eq!(profile_value(&profile.values, &addr(2, values::FOLLOW_RATE)), Some(original_value(2, values::FOLLOW_RATE)));
+
+        // Someone changed one value since the snapshot
+        params.command(&dir.config, &gimbal, GimbalParamCommand::Diff("bench".into(), None), now);
+        read_everything(&mut params, now, |target, index| {
+            if target == 2 && index == values::FOLLOW_RATE { 7 } else { original_value(target, index) }
+        });
+        params.tick(&dir.config, &gimbal, now);
+        let status = params.status().unwrap();
+        assert_eq!(status.error, None);
+        assert_eq!(status.diff.unwrap().values, vec![GimbalValueDiff {
+            addr: addr(2, values::FOLLOW_RATE),
+            from: Some(original_value(2, values::FOLLOW_RATE)),
+            to: Some(7),
+        }]);
+
+        // Put it back, then have the MCU save it once the write is confirmed
+        params.command(&dir.config, &gimbal, GimbalParamCommand::Restore("bench".into(), vec![addr(2, values::FOLLOW_RATE)]), now);
+        assert_eq!(params.status().unwrap().activity, Some(GimbalParamActivity::Restore("bench".into())));
+        params.tick(&dir.config, &gimbal, now);
+        let written = GimbalValueData { addr: addr(2, values::FOLLOW_RATE), value: original_value(2, values::FOLLOW_RATE) };
+        params.value_received(&written, &GimbalValueOp::WriteComplete, now);
+        params.tick(&dir.config, &gimbal, now);
+        assert!(params.status().unwrap().activity.is_some());
+        params.packet_received(&save_ack(2), now);
+        params.tick(&dir.config, &gimbal, now);
+
+        let status = params.status().unwrap();
+        assert_eq!(status.error, None);
+        assert_eq!(status.activity, None);
+        assert_eq!(status.progress, 1);
+    }
+
+    #[test]
+    fn restore_fails_when_write_does_not_verify() {
+        let dir = ProfileDirectory::new();
+        let poller = GimbalPoller::new();
+        let gimbal = poller.port();
+        let now = Instant::now();
+        let mut params = GimbalParams::new(now);
+        snapshot(&dir, &mut params, &gimbal, now);
+
+        params.command(&dir.config, &gimbal, GimbalParamCommand::Restore("bench".into(), vec![addr(0, values::FOLLOW_RATE)]), now);
+        params.tick(&dir.config, &gimbal, now);
+        let written = GimbalValueData { addr: addr(0, values::FOLLOW_RATE), value: original_value(0, values::FOLLOW_RATE) };
+        params.value_received(&written, &GimbalValueOp::WriteFailed, now);
+
+        let status = params.status().unwrap();
+        assert_eq!(status.activity, None);
+        assert!(status.error.unwrap().contains("didn't read back"));
+    }
+
+    #[test]
+    fn restore_refuses_values_the_config_sets() {
+        let dir = ProfileDirectory::new();
+        let poller = GimbalPoller::new();
+        let gimbal = poller.port();
+        let now = Instant::now();
+        let mut params = GimbalParams::new(now);
+        snapshot(&dir, &mut params, &gimbal, now);
+
+        // gimbal.values in the config sets this slot to something else, and would win
+        let configured = addr(0, values::CALIBRATION_ANGLE_0_CENTER);
+        assert!(profile_value(&dir.config.gimbal.values, &configured).is_some());
+        params.command(&dir.config, &gimbal, GimbalParamCommand::Restore("bench".into(), vec![configured]), now);
+        let status = params.status().unwrap();
+        assert_eq!(status.activity, None);
+        assert!(status.error.unwrap().contains("gimbal.values"));
+
+        // Read-only values can't be restored either
+        params.command(&dir.config, &gimbal, GimbalParamCommand::Restore("bench".into(), vec![addr(0, values::ENCODER_ANGLE)]), now);
+        let status = params.status().unwrap();
+        assert_eq!(status.activity, None);
+        assert!(status.error.is_some());
+    }
+
+    #[test]
+    fn unsafe_profile_names_never_reach_the_filesystem() {
+        let dir = ProfileDirectory::new();
+        let poller = GimbalPoller::new();
+        let gimbal = poller.port();
+        let now = Instant::now();
+        let mut params = GimbalParams::new(now);
+
+        for name in ["../escape", "nested/name", "back\\slash", ".hidden", ""].iter() {
+            params.command(&dir.config, &gimbal, GimbalParamCommand::Snapshot(name.to_string()), now);
+            let status = params.status().unwrap();
+            assert_eq!(status.activity, None);
+            assert!(status.error.is_some(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn snapshot_gives_up_on_silent_gimbal() {
+        let dir = ProfileDirectory::new();
+        let poller = GimbalPoller::new();
+        let gimbal = poller.port();
+        let now = Instant::now();
+        let mut params = GimbalParams::new(now);
+
+        params.command(&dir.config, &gimbal, GimbalParamCommand::Snapshot("bench".into()), now);
+        params.tick(&dir.config, &gimbal, now + Duration::from_millis(STALL_TIMEOUT_MILLIS / 2));
+        assert!(params.status().unwrap().activity.is_some());
+        params.tick(&dir.config, &gimbal, now + Duration::from_millis(STALL_TIMEOUT_MILLIS + 1));
+        let status = params.status().unwrap();
+        assert_eq!(status.activity, None);
+        assert!(status.error.is_some());
+        assert!(status.profiles.is_empty());
+    }
+}
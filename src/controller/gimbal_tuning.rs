@@ -0,0 +1,464 @@
+//! Gimbal tuning tool. One axis at a time, we drive the control rate through a step doublet
+//! and a frequency sweep while recording the encoder angle, then fit a first order lag with
+//! dead time to the response. From the fitted models we propose hold and endstop gains, along
+//! with the overshoot a simulation of each axis predicts for them.
+
+use message::*;
+use config::{Config, ControllerMode, GimbalConfig, GimbalTuningConfig};
+use controller::gimbal::limit_axis_rate;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::f32;
+
+/// Time constants tried when fitting a model, log spaced
+const MODEL_TAU_MIN_SEC : f64 = 0.005;
+const MODEL_TAU_MAX_SEC : f64 = 2.0;
+const MODEL_TAU_STEPS : usize = 32;
+
+/// Crossover frequency search range, in radians per second
+const CROSSOVER_MIN : f32 = 1e-3;
+const CROSSOVER_MAX : f32 = 1e4;
+
+/// How far below the hold loop's crossover its integral term takes over
+const HOLD_INTEGRAL_RATIO : f32 = 10.0;
+
+/// Simulated hold step size in encoder counts, and how long predictions run
+const SIM_STEP_COUNTS : f32 = 100.0;
+const SIM_SEC : f32 = 4.0;
+
+struct AxisTest {
+    axis: GimbalTuningAxis,
+    tick: u32,
+    inputs: Vec<f32>,
+    angles: Vec<f32>,
+}
+
+pub struct GimbalTuner {
+    status: Option<GimbalTuningStatus>,
+    test: Option<AxisTest>,
+}
+
+impl GimbalTuner {
+    pub fn new() -> GimbalTuner {
+        GimbalTuner {
+            status: None,
+            test: None,
+        }
+    }
+
+    pub fn status(&self) -> Option<GimbalTuningStatus> {
+        self.status.clone()
+    }
+
+    /// Handle an operator command, returning the gains to save if they were just confirmed
+    pub fn command(&mut self, config: &Config, command: GimbalTuningCommand) -> Option<GimbalTuningProposal> {
+        let result : Result<Option<GimbalTuningProposal>, String> = match command {
+
+            GimbalTuningCommand::Start(axis) => {
+                if self.test.is_some() {
+                    Err("A gimbal tuning test is already running".into())
+                } else if config.mode != ControllerMode::Halted {
+                    Err("Gimbal tuning requires Halted mode".into())
+                } else {
+                    self.test = Some(AxisTest { axis, tick: 0, inputs: Vec::new(), angles: Vec::new() });
+                    let status = self.status.get_or_insert_with(empty_status);
+                    status.models[axis.index()] = None;
+                    status.proposal = propose(&config.gimbal, &status.models);
+                    status.testing = Some((axis, GimbalTuningPhase::Settle));
+                    Ok(None)
+                }
+            },
+
+            GimbalTuningCommand::Cancel => match self.test.take() {
+                Some(_) => Ok(None),
+                None => Err("No gimbal tuning test in progress".into()),
+            },
+
+            GimbalTuningCommand::Confirm => if self.test.is_some() {
+                Err("Wait for the test to finish".into())
+            } else {
+                match self.status.as_ref().and_then(|status| status.proposal.clone()) {
+                    Some(proposal) => Ok(Some(proposal)),
+                    None => Err("No axes have been tested yet".into()),
+                }
+            },
+        };
+
+        let status = self.status.get_or_insert_with(empty_status);
+        if self.test.is_none() {
+            status.testing = None;
+        }
+        match result {
+            Ok(saved) => {
+                status.error = None;
+                saved
+            },
+            Err(e) => {
+                status.error = Some(e);
+                None
+            }
+        }
+    }
+
+    /// Advance the test by one controller tick. Returns the axis under test and the rate to
+    /// command on it, or None if no test is running.
+    pub fn tick(&mut self, config: &Config, gimbal_status: &GimbalControlStatus, stale: bool) -> Option<(GimbalTuningAxis, i16)> {
+        let result = match self.test {
+            None => return None,
+            Some(ref mut test) => test.step(config, gimbal_status, stale),
+        };
+        let test = self.test.take().unwrap();
+        let status = self.status.get_or_insert_with(empty_status);
+
+        match result {
+            Ok(Some((phase, rate))) => {
+                status.testing = Some((test.axis, phase));
+                let axis = test.axis;
+                self.test = Some(test);
+                Some((axis, rate))
+            },
+            Ok(None) => {
+                status.testing = None;
+                match identify(&config.gimbal.tuning, &test.inputs, &test.angles) {
+                    Ok(model) => {
+                        status.models[test.axis.index()] = Some(model);
+                        status.proposal = propose(&config.gimbal, &status.models);
+                        status.error = None;
+                    },
+                    Err(e) => status.error = Some(e),
+                }
+                None
+            },
+            Err(e) => {
+                status.testing = None;
+                status.error = Some(e);
+                None
+            },
+        }
+    }
+}
+
+impl AxisTest {
+    /// Record one sample, returning the next input or None once the test is over
+    fn step(&mut self, config: &Config, gimbal_status: &GimbalControlStatus, stale: bool) -> Result<Option<(GimbalTuningPhase, i16)>, String> {
+        if config.mode != ControllerMode::Halted {
+            return Err("Left Halted mode during the test".into());
+        }
+        if stale {
+            return Err("Lost contact with the gimbal during the test".into());
+        }
+        if gimbal_status.current_error_duration > 0.0 {
+            return Err("Motor current error during the test".into());
+        }
+
+        let index = self.axis.index();
+        let angle = gimbal_status.angles[index];
+        let limits = axis_limits(&config.gimbal, index);
+        if angle < limits.0 || angle > limits.1 {
+            return Err("Axis moved past its limits, reduce the test rates".into());
+        }
+
+        let t = self.tick as f32 / TICK_HZ as f32;
+        let (phase, input) = match test_input(&config.gimbal.tuning, t) {
+            None => return Ok(None),
+            Some(input) => input,
+        };
+        let rate = input.round();
+        self.inputs.push(rate);
+        self.angles.push(angle as f32);
+        self.tick += 1;
+        Ok(Some((phase, rate as i16)))
+    }
+}
+
+fn empty_status() -> GimbalTuningStatus {
+    GimbalTuningStatus {
+        testing: None,
+        models: [None, None],
+        proposal: None,
+        error: None,
+    }
+}
+
+fn axis_limits(gimbal: &GimbalConfig, index: usize) -> (i16, i16) {
+    if index == 0 { gimbal.yaw_limits } else { gimbal.pitch_limits }
+}
+
+/// Control rate at a time since the test started: settle, step doublet, sweep, then coast
+fn test_input(tuning: &GimbalTuningConfig, t: f32) -> Option<(GimbalTuningPhase, f32)> {
+    let mut t = t;
+    if t < tuning.settle_sec {
+        return Some((GimbalTuningPhase::Settle, 0.0));
+    }
+    t -= tuning.settle_sec;
+    if t < tuning.step_sec {
+        return Some((GimbalTuningPhase::StepForward, tuning.step_rate));
+    }
+    t -= tuning.step_sec;
+    if t < tuning.step_sec {
+        return Some((GimbalTuningPhase::StepReverse, -tuning.step_rate));
+    }
+    t -= tuning.step_sec;
+    if t < tuning.chirp_sec {
+        // Linear frequency sweep
+        let sweep = (tuning.chirp_end_hz - tuning.chirp_start_hz) / tuning.chirp_sec;
+        let cycles = tuning.chirp_start_hz * t + 0.5 * sweep * t * t;
+        return Some((GimbalTuningPhase::Chirp, tuning.chirp_rate * (2.0 * PI * cycles).sin()));
+    }
+    t -= tuning.chirp_sec;
+    if t < tuning.settle_sec {
+        return Some((GimbalTuningPhase::Coast, 0.0));
+    }
+    None
+}
+
+fn identify(tuning: &GimbalTuningConfig, inputs: &[f32], angles: &[f32]) -> Result<GimbalAxisModel, String> {
+    if inputs.is_empty() {
+        return Err("Test recorded no samples".into());
+    }
+
+    // Output error fit: for each candidate time constant and delay, simulate the unit gain
+    // response and solve for the gain and angle offset by linear least squares.
+    let n = inputs.len() as f64;
+    let max_delay = (tuning.max_delay_sec * TICK_HZ as f32).round().max(0.0) as usize;
+    let mean_angle = angles.iter().map(|&a| a as f64).sum::<f64>() / n;
+    let angle_sum_sq = angles.iter().map(|&a| (a as f64 - mean_angle).powi(2)).sum::<f64>();
+    let mut response = vec![0.0; inputs.len()];
+    let mut best : Option<(f64, f64, f64, usize)> = None;
+
+    for tau_step in 0 .. MODEL_TAU_STEPS {
+        let tau = MODEL_TAU_MIN_SEC * (MODEL_TAU_MAX_SEC / MODEL_TAU_MIN_SEC).powf(tau_step as f64 / (MODEL_TAU_STEPS - 1) as f64);
+        for delay in 0 .. max_delay + 1 {
+            unit_response(inputs, tau, delay, &mut response);
+            let mean_response = response.iter().sum::<f64>() / n;
+            let mut response_sum_sq = 0.0;
+            let mut cross = 0.0;
+            for (r, &a) in response.iter().zip(angles.iter()) {
+                let r = r - mean_response;
+                response_sum_sq += r * r;
+                cross += r * (a as f64 - mean_angle);
+            }
+            if response_sum_sq <= 0.0 {
+                continue;
+            }
+            let gain = cross / response_sum_sq;
+            let residual_sum_sq = angle_sum_sq - gain * cross;
+            if best.map_or(true, |(best_sum_sq, _, _, _)| residual_sum_sq < best_sum_sq) {
+                best = Some((residual_sum_sq, gain, tau, delay));
+            }
+        }
+    }
+
+    match best {
+        Some((residual_sum_sq, gain, tau, delay)) if gain > 0.0 => Ok(GimbalAxisModel {
+            gain: gain as f32,
+            time_constant_sec: tau as f32,
+            delay_sec: delay as f32 / TICK_HZ as f32,
+            rms_residual: (residual_sum_sq.max(0.0) / n).sqrt() as f32,
+        }),
+        _ => Err("Axis did not follow the test input".into()),
+    }
+}
+
+fn unit_response(inputs: &[f32], tau: f64, delay: usize, response: &mut [f64]) {
+    let dt = 1.0 / TICK_HZ as f64;
+    let alpha = 1.0 - (-dt / tau).exp();
+    let mut velocity = 0.0;
+    let mut angle = 0.0;
+    for n in 0 .. inputs.len() {
+        response[n] = angle;
+        let input = if n >= delay { inputs[n - delay] as f64 } else { 0.0 };
+        velocity += (input - velocity) * alpha;
+        angle += velocity * dt;
+    }
+}
+
+fn propose(gimbal: &GimbalConfig, models: &Vector2<Option<GimbalAxisModel>>) -> Option<GimbalTuningProposal> {
+    // Gains are shared by both axes, so the more sensitive axis sets them
+    let mut hold_p_gain = f32::INFINITY;
+    let mut hold_i_gain = 0.0;
+    let mut limiter_gain = f32::INFINITY;
+    for model in models.iter() {
+        if let Some(ref model) = *model {
+            let (p, crossover) = p_gain_for_phase_margin(model, gimbal.tuning.hold_phase_margin_deg);
+            if p < hold_p_gain {
+                hold_p_gain = p;
+                hold_i_gain = p * crossover / HOLD_INTEGRAL_RATIO / TICK_HZ as f32;
+            }
+            limiter_gain = limiter_gain.min(p_gain_for_phase_margin(model, gimbal.tuning.limiter_phase_margin_deg).0);
+        }
+    }
+    if !limiter_gain.is_finite() {
+        return None;
+    }
+
+    let mut proposal = GimbalTuningProposal {
+        hold_p_gain,
+        hold_i_gain,
+        limiter_gain,
+        limiter_slowdown_extent: gimbal.limiter_slowdown_extent,
+        hold_overshoot_percent: [None, None],
+        limit_overshoot: [None, None],
+    };
+    for index in 0..2 {
+        if let Some(ref model) = models[index] {
+            // Slow down no more sharply than the limiter pushes back, and leave room to coast
+            let coast = model.gain * gimbal.max_rate * (model.time_constant_sec + model.delay_sec);
+            proposal.limiter_slowdown_extent[index] = (gimbal.max_rate / limiter_gain).max(coast);
+        }
+    }
+    for index in 0..2 {
+        if let Some(ref model) = models[index] {
+            proposal.hold_overshoot_percent[index] = Some(simulate_hold_step(gimbal, &proposal, model));
+            proposal.limit_overshoot[index] = Some(simulate_limit_approach(gimbal, &proposal, model, index));
+        }
+    }
+    Some(proposal)
+}
+
+/// Proportional gain whose loop with this model crosses over at the given phase margin.
+/// Returns the gain and the crossover frequency in radians per second.
+fn p_gain_for_phase_margin(model: &GimbalAxisModel, phase_margin_deg: f32) -> (f32, f32) {
+    // Integrator, lag, and delay together give a phase lag that only grows with frequency
+    let target = PI - phase_margin_deg.to_radians();
+    let lag = |w: f32| PI / 2.0 + (w * model.time_constant_sec).atan() + w * model.delay_sec;
+    let mut lo = CROSSOVER_MIN;
+    let mut hi = CROSSOVER_MAX;
+    for _ in 0..60 {
+        let mid = (lo * hi).sqrt();
+        if lag(mid) < target { lo = mid; } else { hi = mid; }
+    }
+    let crossover = lo;
+    let magnitude = model.gain / (crossover * (1.0 + (crossover * model.time_constant_sec).powi(2)).sqrt());
+    (1.0 / magnitude, crossover)
+}
+
+/// One axis of the gimbal as the fitted model sees it
+struct AxisSim {
+    alpha: f32,
+    gain: f32,
+    velocity: f32,
+    angle: f32,
+    queue: VecDeque<f32>,
+}
+
+impl AxisSim {
+    /// Start at a steady rate, already moving
+    fn new(model: &GimbalAxisModel, angle: f32, rate: f32) -> AxisSim {
+        let delay = (model.delay_sec * TICK_HZ as f32).round() as usize;
+        AxisSim {
+            alpha: 1.0 - (-1.0 / (TICK_HZ as f32 * model.time_constant_sec)).exp(),
+            gain: model.gain,
+            velocity: rate * model.gain,
+            angle,
+            queue: (0 .. delay).map(|_| rate).collect(),
+        }
+    }
+
+    fn step(&mut self, rate: f32) -> f32 {
+        self.queue.push_back(rate);
+        let input = self.queue.pop_front().unwrap();
+        self.velocity += (input * self.gain - self.velocity) * self.alpha;
+        self.angle += self.velocity / TICK_HZ as f32;
+        self.angle
+    }
+}
+
+fn simulate_hold_step(gimbal: &GimbalConfig, proposal: &GimbalTuningProposal, model: &GimbalAxisModel) -> f32 {
+    let mut sim = AxisSim::new(model, 0.0, 0.0);
+    let mut angle = 0.0;
+    let mut integral = 0.0;
+    let mut peak = 0.0f32;
+    for _ in 0 .. (SIM_SEC * TICK_HZ as f32) as usize {
+        let err = SIM_STEP_COUNTS - angle;
+        integral += err;
+        let rate = err * proposal.hold_p_gain + integral * proposal.hold_i_gain;
+        angle = sim.step(rate.max(-gimbal.max_rate).min(gimbal.max_rate));
+        peak = peak.max(angle);
+    }
+    ((peak - SIM_STEP_COUNTS) / SIM_STEP_COUNTS * 100.0).max(0.0)
+}
+
+fn simulate_limit_approach(gimbal: &GimbalConfig, proposal: &GimbalTuningProposal, model: &GimbalAxisModel, index: usize) -> f32 {
+    let limits = axis_limits(gimbal, index);
+    let extent = proposal.limiter_slowdown_extent[index];
+    let middle = (limits.0 as f32 + limits.1 as f32) / 2.0;
+    let mut sim = AxisSim::new(model, (limits.1 as f32 - 2.0 * extent).max(middle), gimbal.max_rate);
+    let mut angle = sim.angle;
+    let mut peak = angle;
+    for _ in 0 .. (SIM_SEC * TICK_HZ as f32) as usize {
+        let rate = limit_axis_rate(gimbal.max_rate, proposal.limiter_gain, gimbal.max_rate, angle, limits, extent);
+        angle = sim.step(rate);
+        peak = peak.max(angle);
+    }
+    (peak - limits.1 as f32).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::tests::{test_config, assert_near};
+
+    fn model(gain: f32, time_constant_sec: f32, delay_sec: f32) -> GimbalAxisModel {
+        GimbalAxisModel { gain, time_constant_sec, delay_sec, rms_residual: 0.0 }
+    }
+
+    /// Alternating rate steps, long enough apart for the response to settle
+    fn square_wave_inputs() -> Vec<f32> {
+        (0 .. 500).map(|n| if (n / 50) % 2 == 0 { 40.0 } else { -40.0 }).collect()
+    }
+
+    #[test]
+    fn identify_recovers_model_on_grid() {
+        let tuning = test_config(ControllerMode::Halted).gimbal.tuning;
+        let inputs = square_wave_inputs();
+        let tau = MODEL_TAU_MIN_SEC * (MODEL_TAU_MAX_SEC / MODEL_TAU_MIN_SEC).powf(16.0 / (MODEL_TAU_STEPS - 1) as f64);
+        let mut response = vec![0.0; inputs.len()];
+        unit_response(&inputs, tau, 5, &mut response);
+        let angles : Vec<f32> = response.iter().map(|r| (3.0 * r + 100.0) as f32).collect();
+
+        let fitted = identify(&tuning, &inputs, &angles).unwrap();
+        assert_near(fitted.gain, 3.0, 1e-3);
+        assert_near(fitted.time_constant_sec, tau as f32, 1e-6);
+        assert_near(fitted.delay_sec, 5.0 / TICK_HZ as f32, 1e-6);
+        assert!(fitted.rms_residual < 1e-3, "{:?}", fitted);
+    }
+
+    #[test]
+    fn identify_rejects_axis_that_did_not_move() {
+        let tuning = test_config(ControllerMode::Halted).gimbal.tuning;
+        let inputs = square_wave_inputs();
+        let angles = vec![100.0; inputs.len()];
+        assert!(identify(&tuning, &inputs, &angles).is_err());
+        assert!(identify(&tuning, &[], &[]).is_err());
+    }
+
+    #[test]
+    fn unit_response_is_delayed_first_order_rate() {
+        // A unit rate step through a lag settles to one count per second
+        let inputs = vec![1.0; 3 * TICK_HZ as usize];
+        let mut response = vec![0.0; inputs.len()];
+        unit_response(&inputs, 0.1, 10, &mut response);
+        assert_eq!(response[10], 0.0);
+        assert!(response[11] > 0.0);
+        let last = inputs.len() - 1;
+        assert!((response[last] - response[last - TICK_HZ as usize] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn phase_margin_gain_without_delay() {
+        // Lag is 90 degrees plus atan(w tau), reaching 135 degrees at w = 1 / tau.
+        // There |G| = gain / (w sqrt(2)), so unity loop gain needs P = sqrt(2) / gain.
+        let (p, crossover) = p_gain_for_phase_margin(&model(2.0, 1.0, 0.0), 45.0);
+        assert_near(crossover, 1.0, 1e-4);
+        assert_near(p, 2f32.sqrt() / 2.0, 1e-4);
+    }
+
+    #[test]
+    fn phase_margin_gain_with_delay() {
+        // Delay alone: lag is 90 degrees plus w d, so a 60 degree margin crosses at w = (pi / 6) / d
+        let (p, crossover) = p_gain_for_phase_margin(&model(4.0, 0.0, 0.1), 60.0);
+        assert_near(crossover, (PI / 6.0) / 0.1, 1e-3);
+        assert_near(p, crossover / 4.0, 1e-3);
+    }
+}
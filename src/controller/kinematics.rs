@@ -0,0 +1,164 @@
+//! Parallel cable robot kinematics, relating the flyer's position to rope geometry
+
+use vecmath::*;
+use config::Config;
+
+/// Ropes shorter than this have no meaningful direction
+const MIN_ROPE_LENGTH_M : f32 = 0.01;
+
+/// Gauss-Newton iteration limits for the forward kinematics solver
+const FK_MAX_ITERATIONS : usize = 10;
+const FK_CONVERGED_STEP_M : f32 = 1e-5;
+const FK_MIN_DETERMINANT : f32 = 1e-6;
+
+pub struct FlyerKinematics {
+    /// Estimated flyer location in the same coordinate system as the winch anchors
+    pub position: Vector3<f32>,
+}
+
+impl FlyerKinematics {
+    pub fn new(config: &Config) -> FlyerKinematics {
+        FlyerKinematics {
+            position: config.flyer_home_loc,
+        }
+    }
+
+    /// Vector from the flyer to a winch's anchor point
+    pub fn rope_vector(&self, config: &Config, id: usize) -> Vector3<f32> {
+        vec3_sub(config.winches[id].loc, self.position)
+    }
+
+    pub fn rope_length(&self, config: &Config, id: usize) -> f32 {
+        vec3_len(self.rope_vector(config, id))
+    }
+
+    /// Unit vector pointing along the rope, from the flyer toward the anchor
+    pub fn rope_direction(&self, config: &Config, id: usize) -> Vector3<f32> {
+        let v = self.rope_vector(config, id);
+        let len = vec3_len(v);
+        if len > MIN_ROPE_LENGTH_M {
+            vec3_scale(v, 1.0 / len)
+        } else {
+            [0.0, 0.0, 0.0]
+        }
+    }
+
+    /// Rope rate for one winch that moves the flyer at a Cartesian velocity.
+    /// This is one row of the inverse Jacobian; positive rates shorten the rope.
+    pub fn rope_rate(&self, config: &Config, id: usize, velocity: Vector3<f32>) -> f32 {
+        vec3_dot(velocity, self.rope_direction(config, id))
+    }
+}
+
+/// Forward kinematics: find the flyer position that best explains a set of measured rope
+/// lengths, as a least-squares fit starting from an initial guess. Winches with unknown
+/// lengths are skipped. On success, also returns the inverse of the normal matrix; scaled
+/// by the rope length variance, this is the covariance of the resulting position.
+pub fn forward_kinematics(config: &Config, lengths: &[Option<f32>], initial: Vector3<f32>) -> Option<(Vector3<f32>, Matrix3<f32>)> {
+    let mut kinematics = FlyerKinematics { position: initial };
+
+    for _ in 0 .. FK_MAX_ITERATIONS {
+        let mut normal : Matrix3<f32> = [[0.0; 3]; 3];
+        let mut gradient : Vector3<f32> = [0.0; 3];
+        let mut num_ropes = 0;
+
+        for (id, length) in lengths.iter().enumerate() {
+            if let &Some(length) = length {
+                if id < config.winches.len() {
+                    let u = kinematics.rope_direction(config, id);
+                    let residual = kinematics.rope_length(config, id) - length;
+                    for row in 0..3 {
+                        for col in 0..3 {
+                            normal[row][col] += u[row] * u[col];
+                        }
+                    }
+                    gradient = vec3_add(gradient, vec3_scale(u, residual));
+                    num_ropes += 1;
+                }
+            }
+        }
+
+        if num_ropes < 3 || mat3_det(normal).abs() < FK_MIN_DETERMINANT {
+            // Not enough ropes to constrain all three axes
+            return None;
+        }
+
+        let inverse = mat3_inv(normal);
+        let step = row_mat3_transform(inverse, gradient);
+        kinematics.position = vec3_add(kinematics.position, step);
+
+        if vec3_len(step) < FK_CONVERGED_STEP_M {
+            return Some((kinematics.position, inverse));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::{test_config, assert_near};
+
+    fn exact_lengths(config: &Config, position: Vector3<f32>) -> Vec<Option<f32>> {
+        let kinematics = FlyerKinematics { position };
+        (0 .. config.winches.len()).map(|id| Some(kinematics.rope_length(config, id))).collect()
+    }
+
+    #[test]
+    fn rope_geometry_below_square_anchors() {
+        // Anchors sit at (+/-20, +/-20, 30), so every rope from (0, 0, 10) is (+/-20, +/-20, 20)
+        let config = test_config(ControllerMode::Halted);
+        let kinematics = FlyerKinematics { position: [0.0, 0.0, 10.0] };
+        for id in 0 .. 4 {
+            assert_near(kinematics.rope_length(&config, id), 1200f32.sqrt(), 1e-4);
+            assert_near(kinematics.rope_direction(&config, id)[2], 1.0 / 3f32.sqrt(), 1e-6);
+            assert_near(kinematics.rope_rate(&config, id, [0.0, 0.0, 1.0]), 1.0 / 3f32.sqrt(), 1e-6);
+        }
+    }
+
+    #[test]
+    fn forward_kinematics_centered() {
+        // Directions are (+/-1, +/-1, 1) / sqrt(3), whose outer products sum to 4/3 I
+        let config = test_config(ControllerMode::Halted);
+        let lengths = exact_lengths(&config, [0.0, 0.0, 10.0]);
+        let (position, inverse) = forward_kinematics(&config, &lengths, [1.0, -1.0, 8.0]).unwrap();
+        for axis in 0 .. 3 {
+            assert_near(position[axis], [0.0, 0.0, 10.0][axis], 1e-3);
+            for col in 0 .. 3 {
+                assert_near(inverse[axis][col], if axis == col { 0.75 } else { 0.0 }, 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn forward_kinematics_off_center() {
+        let config = test_config(ControllerMode::Halted);
+        let lengths = exact_lengths(&config, [3.0, -2.0, 12.0]);
+        let (position, _) = forward_kinematics(&config, &lengths, config.flyer_home_loc).unwrap();
+        assert_near(position[0], 3.0, 1e-3);
+        assert_near(position[1], -2.0, 1e-3);
+        assert_near(position[2], 12.0, 1e-3);
+    }
+
+    #[test]
+    fn forward_kinematics_with_one_rope_unknown() {
+        let config = test_config(ControllerMode::Halted);
+        let mut lengths = exact_lengths(&config, [3.0, -2.0, 12.0]);
+        lengths[2] = None;
+        let (position, _) = forward_kinematics(&config, &lengths, config.flyer_home_loc).unwrap();
+        assert_near(position[0], 3.0, 1e-3);
+        assert_near(position[1], -2.0, 1e-3);
+        assert_near(position[2], 12.0, 1e-3);
+    }
+
+    #[test]
+    fn forward_kinematics_needs_three_ropes() {
+        let config = test_config(ControllerMode::Halted);
+        let mut lengths = exact_lengths(&config, [3.0, -2.0, 12.0]);
+        lengths[1] = None;
+        lengths[2] = None;
+        assert!(forward_kinematics(&config, &lengths, config.flyer_home_loc).is_none());
+    }
+}
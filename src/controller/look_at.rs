@@ -0,0 +1,118 @@
+//! Aim the camera at a fixed point in the room, using the flyer's estimated position and IMU
+//! orientation to turn that point into gimbal encoder angles.
+
+use message::*;
+use vecmath::*;
+use config::Config;
+
+pub fn look_at_angles(config: &Config, pose: &FlyerPose, point: Vector3<f32>) -> Option<GimbalLookAtStatus> {
+    let look_at = &config.gimbal.look_at;
+
+    // Direction from the gimbal pivot to the point, in the flyer body frame
+    let pivot = vec3_add(pose.position, quat_rotate_vec3(pose.orientation, look_at.pivot_offset_m));
+    let q = pose.orientation;
+    let inverse = [ q[0], -q[1], -q[2], -q[3] ];
+    let direction = quat_rotate_vec3(inverse, vec3_sub(point, pivot));
+
+    // Yaw is measured around the yaw axis from the zero direction, pitch out of that plane
+    let axis = vec3_normalized(look_at.yaw_axis);
+    let zero = vec3_sub(look_at.zero_direction, vec3_scale(axis, vec3_dot(axis, look_at.zero_direction)));
+    if vec3_len(zero) <= 0.0 || vec3_len(direction) <= 0.0 {
+        return None;
+    }
+    let x = vec3_normalized(zero);
+    let y = vec3_cross(axis, x);
+    let forward = vec3_dot(direction, x);
+    let side = vec3_dot(direction, y);
+    let yaw = side.atan2(forward);
+    let pitch = vec3_dot(direction, axis).atan2((forward * forward + side * side).sqrt());
+
+    let counts = [ yaw * look_at.counts_per_radian[0], pitch * look_at.counts_per_radian[1] ];
+    let limits = [ config.gimbal.yaw_limits, config.gimbal.pitch_limits ];
+    let mut angles = [0; 2];
+    let mut reachable = true;
+    for index in 0..2 {
+        let (lower, upper) = (limits[index].0 as f32, limits[index].1 as f32);
+        if !(counts[index] >= lower && counts[index] <= upper) {
+            reachable = false;
+        }
+        angles[index] = counts[index].max(lower).min(upper).round() as i16;
+    }
+
+    Some(GimbalLookAtStatus { point, angles, reachable })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::test_config;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    fn pose(position: Vector3<f32>, orientation: Vector4<f32>) -> FlyerPose {
+        FlyerPose {
+            position,
+            velocity: [0.0; 3],
+            orientation,
+            position_variance: [0.0; 3],
+            velocity_variance: [0.0; 3],
+            num_ropes: 4,
+        }
+    }
+
+    const LEVEL : Vector4<f32> = [1.0, 0.0, 0.0, 0.0];
+
+    // The pivot hangs 0.15 m below the flyer, yaw turns about -z from +x, and there are
+    // about 512 counts in 45 degrees
+    #[test]
+    fn straight_ahead_is_zero() {
+        let config = test_config(ControllerMode::Normal);
+        let status = look_at_angles(&config, &pose([0.0; 3], LEVEL), [10.0, 0.0, -0.15]).unwrap();
+        assert_eq!(status.angles, [0, 0]);
+        assert!(status.reachable);
+        assert_eq!(status.point, [10.0, 0.0, -0.15]);
+    }
+
+    #[test]
+    fn yaw_and_pitch_known_answers() {
+        let config = test_config(ControllerMode::Normal);
+        let level = pose([0.0; 3], LEVEL);
+
+        let status = look_at_angles(&config, &level, [1.0, -1.0, -0.15]).unwrap();
+        assert_eq!(status.angles, [512, 0]);
+        assert!(status.reachable);
+
+        let status = look_at_angles(&config, &level, [1.0, 0.0, 0.85]).unwrap();
+        assert_eq!(status.angles, [0, -512]);
+        assert!(status.reachable);
+
+        // Turned 90 degrees about z, the flyer faces +y
+        let turned = pose([2.0, 3.0, 1.0], [FRAC_1_SQRT_2, 0.0, 0.0, FRAC_1_SQRT_2]);
+        let status = look_at_angles(&config, &turned, [2.0, 5.0, 0.85]).unwrap();
+        assert_eq!(status.angles, [0, 0]);
+        let status = look_at_angles(&config, &turned, [3.0, 5.0, 0.85]).unwrap();
+        assert_eq!(status.angles, [302, 0]);
+    }
+
+    #[test]
+    fn out_of_limits_clamps_and_is_unreachable() {
+        let config = test_config(ControllerMode::Normal);
+        let level = pose([0.0; 3], LEVEL);
+
+        // Pitching up 45 degrees is well past the 11 count upper limit
+        let status = look_at_angles(&config, &level, [1.0, 0.0, -1.15]).unwrap();
+        assert_eq!(status.angles, [0, 11]);
+        assert!(!status.reachable);
+
+        // Nearly behind, about 1983 counts of yaw against a 1710 count limit
+        let status = look_at_angles(&config, &level, [-1.0, -0.1, -0.15]).unwrap();
+        assert_eq!(status.angles, [1710, 0]);
+        assert!(!status.reachable);
+    }
+
+    #[test]
+    fn point_at_the_pivot_has_no_direction() {
+        let config = test_config(ControllerMode::Normal);
+        assert!(look_at_angles(&config, &pose([0.0; 3], LEVEL), [0.0, 0.0, -0.15]).is_none());
+    }
+}
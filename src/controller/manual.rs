@@ -72,10 +72,10 @@ impl ManualControls {
         rect_constrain(rect_translate(default_rect, center), config.vision.border_rect)
     }
 
-    pub fn camera_control_active(&self) -> bool {
+    pub fn camera_control_active(&self, now: Instant) -> bool {
         match self.camera_control_active_until_timestamp {
             None => false,
-            Some(timestamp) => Instant::now() < timestamp,
+            Some(timestamp) => now < timestamp,
         }
     }
 
@@ -96,21 +96,21 @@ impl ManualControls {
         self.velocity.get()
     }
 
-    pub fn control_tick(&mut self, config: &Config) {
+    pub fn control_tick(&mut self, config: &Config, now: Instant) {
         match config.mode {
             ControllerMode::Halted => self.full_reset(),
             _ => {
                 let v = self.velocity_target(config);
                 self.velocity.tick(config, v);
-                self.camera_control_tick(config);
+                self.camera_control_tick(config, now);
             }
         };
     }
 
-    pub fn camera_control_tick(&mut self, config: &Config) {
+    pub fn camera_control_tick(&mut self, config: &Config, now: Instant) {
         if !ManualControls::camera_vector_in_deadzone(self.camera_vector(), config) {
             let timeout = Duration::from_millis((1000.0 * config.vision.manual_control_timeout_sec) as u64);
-            self.camera_control_active_until_timestamp = Some(Instant::now() + timeout);
+            self.camera_control_active_until_timestamp = Some(now + timeout);
         }
     }
 
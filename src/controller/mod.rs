@@ -3,10 +3,25 @@
 mod manual;
 mod velocity;
 mod winch;
+mod kinematics;
+mod estimator;
+mod trajectory;
+mod geofence;
 mod state;
 mod timer;
 mod gimbal;
+mod calibration;
+mod survey;
+mod autotune;
+mod gimbal_tuning;
+mod gimbal_params;
+mod gimbal_calibration;
+mod look_at;
 mod draw;
+mod clock;
+
+#[cfg(test)]
+pub mod tests;
 
 use message::*;
 use vecmath::*;
@@ -14,17 +29,30 @@ use std::sync::mpsc::{SyncSender, Receiver, sync_channel};
 use bus::{Bus, BusReader};
 use config::{SharedConfigFile, Config, ControllerMode};
 use botcomm::BotSocket;
-use fygimbal::GimbalPort;
+use fygimbal;
+use fygimbal::{GimbalPort, FirmwareCommand};
+use fygimbal::registry;
 use self::state::ControllerState;
 use self::timer::{ConfigScheduler, ControllerTimers};
 use self::gimbal::GimbalController;
+use self::calibration::WinchCalibrator;
+use self::survey::AnchorSurvey;
+use self::gimbal_params::GimbalParams;
 use led::{LightEnvironment, LightAnimator};
 use overlay::DrawingContext;
+use std::time::{Duration, Instant};
+use std::panic::{self, AssertUnwindSafe};
+use supervisor;
+pub use self::clock::{Clock, SystemClock, ManualClock};
+
+/// A panic in every poll would otherwise flood the bus and the console with faults
+const FAULT_REPORT_INTERVAL_MILLIS : u64 = 1000;
 
 pub struct Controller {
     recv: Receiver<ControllerInput>,
     bus: Bus<TimestampedMessage>,
     port_prototype: ControllerPort,
+    clock: Box<Clock>,
     socket: BotSocket,
     shared_config: SharedConfigFile,
     local_config: Config,
@@ -35,6 +63,17 @@ pub struct Controller {
     lights: LightAnimator,
     gimbal_ctrl: GimbalController,
     gimbal_status: Option<GimbalControlStatus>,
+    geofence_status: Option<GeofenceStatus>,
+    winch_calibrator: WinchCalibrator,
+    anchor_survey: AnchorSurvey,
+    winch_autotune_status: Option<WinchAutotuneStatus>,
+    gimbal_tuning_status: Option<GimbalTuningStatus>,
+    gimbal_params: GimbalParams,
+    gimbal_params_status: Option<GimbalParamStatus>,
+    gimbal_calibration_status: Option<GimbalCalibrationStatus>,
+    /// Panics caught in our own loop, and when the last one was reported
+    failures: u32,
+    last_fault_report: Option<Instant>,
 }
 
 enum ControllerInput {
@@ -54,15 +93,20 @@ impl ControllerPort {
         }
     }
 
-    pub fn add_rx(&self) -> BusReader<TimestampedMessage> {
+    pub fn add_rx(&self) -> Result<BusReader<TimestampedMessage>, String> {
         let (result_sender, result_recv) = sync_channel(1);
         drop(self.sender.try_send(ControllerInput::ReaderRequest(result_sender)));
-        result_recv.recv().unwrap()
+        // The request is dropped along with its sender if the queue is full or the controller is gone
+        result_recv.recv().map_err(|_| "Controller didn't answer a message bus reader request".to_owned())
     }
 }
 
 impl Controller {
     pub fn new(config: &SharedConfigFile, socket: &BotSocket) -> Controller {
+        Controller::with_clock(config, socket, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(config: &SharedConfigFile, socket: &BotSocket, clock: Box<Clock>) -> Controller {
 
         const DEPTH : usize = 1024;
 
@@ -70,24 +114,38 @@ impl Controller {
         let bus = Bus::new(DEPTH);
         let port_prototype = ControllerPort { sender };
 
+        config.start_saving(&port_prototype);
         let local_config = config.get_latest();
-        let lights = LightAnimator::start(&local_config.lighting.animation, &socket);
-        let state = ControllerState::new(&local_config);
+        let lights = LightAnimator::start(&local_config.lighting.animation, &socket, &port_prototype);
+        let now = clock.now();
+        let state = ControllerState::new(&local_config, now);
+        let config_scheduler = ConfigScheduler::new(&*clock);
 
         Controller {
             lights,
             recv,
             bus,
             port_prototype,
+            clock,
             state,
             local_config,
             socket: socket.try_clone().unwrap(),
             shared_config: config.clone(),
-            config_scheduler: ConfigScheduler::new(),
-            timers: ControllerTimers::new(),
+            config_scheduler,
+            timers: ControllerTimers::new(now),
             draw: DrawingContext::new(),
             gimbal_ctrl: GimbalController::new(),
-            gimbal_status: None
+            gimbal_status: None,
+            geofence_status: None,
+            winch_calibrator: WinchCalibrator::new(),
+            anchor_survey: AnchorSurvey::new(),
+            winch_autotune_status: None,
+            gimbal_tuning_status: None,
+            gimbal_params: GimbalParams::new(now),
+            gimbal_params_status: None,
+            gimbal_calibration_status: None,
+            failures: 0,
+            last_fault_report: None,
         }
     }
 
@@ -98,7 +156,37 @@ impl Controller {
     pub fn run(mut self, gimbal_port: GimbalPort) {
         println!("Running.");
         loop {
-            self.poll(&gimbal_port);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| self.poll(&gimbal_port)));
+            if let Err(cause) = result {
+                self.poll_failed(supervisor::panic_message(&cause));
+            }
+        }
+    }
+
+    /// A panic may leave motion state half-updated, so keep running but halt right away,
+    /// without waiting for our own fault report to come back around through the input queue
+    fn poll_failed(&mut self, error: String) {
+        self.failures += 1;
+        if self.local_config.mode != ControllerMode::Halted {
+            self.local_config.mode = ControllerMode::Halted;
+            self.config_changed();
+        }
+
+        let now = self.clock.now();
+        let report_due = match self.last_fault_report {
+            None => true,
+            Some(timestamp) => now >= timestamp + Duration::from_millis(FAULT_REPORT_INTERVAL_MILLIS),
+        };
+        if report_due {
+            self.last_fault_report = Some(now);
+            println!("Controller failed, halted and continuing: {}", error);
+            let fault = SubsystemFault {
+                subsystem: "Controller".into(),
+                error,
+                failures: self.failures,
+                critical: true,
+            };
+            self.broadcast(Message::SubsystemFault(fault).timestamp());
         }
     }
 
@@ -108,15 +196,99 @@ impl Controller {
         }
     }
 
+    fn broadcast_winch_calibration_status(&mut self) {
+        if let Some(status) = self.winch_calibrator.status() {
+            self.broadcast(Message::WinchCalibrationStatus(status).timestamp());
+        }
+    }
+
+    fn broadcast_anchor_survey_status(&mut self) {
+        if let Some(status) = self.anchor_survey.status() {
+            self.broadcast(Message::AnchorSurveyStatus(status).timestamp());
+        }
+    }
+
+    fn broadcast_winch_autotune_status(&mut self) {
+        let status = self.state.winch_autotune_status();
+        if status != self.winch_autotune_status {
+            if let Some(ref status) = status {
+                self.broadcast(Message::WinchAutotuneStatus(status.clone()).timestamp());
+            }
+            self.winch_autotune_status = status;
+        }
+    }
+
+    fn broadcast_gimbal_tuning_status(&mut self) {
+        let status = self.gimbal_ctrl.tuning_status();
+        if status != self.gimbal_tuning_status {
+            if let Some(ref status) = status {
+                self.broadcast(Message::GimbalTuningStatus(status.clone()).timestamp());
+            }
+            self.gimbal_tuning_status = status;
+        }
+    }
+
+    fn broadcast_gimbal_params_status(&mut self) {
+        let status = self.gimbal_params.status();
+        if status != self.gimbal_params_status {
+            if let Some(ref status) = status {
+                self.broadcast(Message::GimbalParamStatus(status.clone()).timestamp());
+            }
+            self.gimbal_params_status = status;
+        }
+    }
+
+    fn broadcast_gimbal_calibration_status(&mut self) {
+        let status = self.gimbal_ctrl.calibration_status();
+        if status != self.gimbal_calibration_status {
+            if let Some(ref status) = status {
+                self.broadcast(Message::GimbalCalibrationStatus(status.clone()).timestamp());
+            }
+            self.gimbal_calibration_status = status;
+        }
+    }
+
+    fn save_gimbal_calibration(&mut self) {
+        if let Some(calibrated) = self.gimbal_ctrl.take_calibrated_values() {
+            // Only values the config already overrides, so it doesn't undo the calibration
+            let mut changed = false;
+            for data in calibrated {
+                if let Some(axes) = self.local_config.gimbal.values.get_mut(&data.addr.index) {
+                    if let Some(slot) = axes.get_mut(data.addr.target as usize) {
+                        if slot.is_some() && *slot != Some(data.value) {
+                            *slot = Some(data.value);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            if changed {
+                println!("Saving gimbal calibration, {:?}", self.local_config.gimbal.values);
+                self.config_changed();
+            }
+        }
+    }
+
     fn config_changed(&mut self) {
         self.shared_config.set(self.local_config.clone());
         let msg = Message::ConfigIsCurrent(self.local_config.clone());
         self.broadcast(msg.timestamp());
         self.state.config_changed(&self.local_config);
+        self.socket.reconfigure(&self.local_config);
+        if self.anchor_survey.winches_changed(self.local_config.winches.len()) {
+            self.broadcast_anchor_survey_status();
+        }
     }
 
     fn poll(&mut self, gimbal_port: &GimbalPort) {
-        match self.recv.recv().unwrap() {
+        let input = self.recv.recv().unwrap();
+        let now = self.clock.now();
+        self.handle_input(input, gimbal_port, now);
+        self.poll_timers(gimbal_port, now);
+    }
+
+    fn handle_input(&mut self, input: ControllerInput, gimbal_port: &GimbalPort, now: Instant) {
+        match input {
 
             ControllerInput::ReaderRequest(result_channel) => {
                 // Never blocks, result_channel must already have room
@@ -126,44 +298,69 @@ impl Controller {
 
             ControllerInput::Message(ts_msg) => {
                 self.broadcast(ts_msg.clone());
-                self.handle_message(ts_msg, gimbal_port);
+                self.handle_message(ts_msg, gimbal_port, now);
             }
         }
+    }
 
-        if self.timers.tick.poll() {
-            self.state.every_tick(&self.local_config);
+    fn poll_timers(&mut self, gimbal_port: &GimbalPort, now: Instant) {
+        if self.timers.tick.poll(now) {
+            self.state.every_tick(&self.local_config, now);
+            if let Some(pose) = self.state.flyer_pose() {
+                self.broadcast(Message::FlyerPose(pose).timestamp());
+            }
+            if let Some(status) = self.state.trajectory_status() {
+                self.broadcast(Message::TrajectoryStatus(status).timestamp());
+            }
+            let geofence_status = self.state.geofence_status();
+            if geofence_status != self.geofence_status {
+                if let Some(ref status) = geofence_status {
+                    self.broadcast(Message::GeofenceStatus(status.clone()).timestamp());
+                }
+                self.geofence_status = geofence_status;
+            }
+            self.broadcast_winch_autotune_status();
             let light_env = self.light_environment(&self.local_config);
             self.lights.update(light_env);
 
-            let gimbal_status = self.gimbal_ctrl.tick(&self.local_config, gimbal_port, &self.state.tracked);
+            let look_at = self.state.gimbal_look_at(&self.local_config);
+            let hold_target = self.state.gimbal_hold_target(&self.local_config, &look_at);
+            let mut gimbal_status = self.gimbal_ctrl.tick(&self.local_config, gimbal_port, &self.state.tracked, hold_target, now);
+            gimbal_status.look_at = look_at;
             let reset_tracking = gimbal_status.current_error_duration > self.local_config.gimbal.error_duration_for_rehome;
             self.gimbal_status = Some(gimbal_status.clone());
             self.broadcast(Message::GimbalControlStatus(gimbal_status).timestamp());
+            self.broadcast_gimbal_tuning_status();
+            self.save_gimbal_calibration();
+            self.broadcast_gimbal_calibration_status();
+            self.gimbal_params.tick(&self.local_config, gimbal_port, now);
+            self.broadcast_gimbal_params_status();
 
-            if let Some(tracking_rect) = self.state.tracking_update(&self.local_config, 1.0 / TICK_HZ as f32, reset_tracking) {
+            if let Some(tracking_rect) = self.state.tracking_update(&self.local_config, 1.0 / TICK_HZ as f32, reset_tracking, now) {
                 self.broadcast(Message::CameraInitTrackedRegion(tracking_rect).timestamp());
             }
         }
 
-        if self.timers.video_frame.poll() {
-            self.render_overlay();
+        if self.timers.video_frame.poll(now) {
+            self.render_overlay(now);
             let scene = self.draw.scene.drain(..).collect();
             self.broadcast(Message::CameraOverlayScene(scene).timestamp());
         }
 
-        if self.config_scheduler.poll(&mut self.local_config) {
+        if self.config_scheduler.poll(&mut self.local_config, &*self.clock) {
             self.config_changed();
         }
     }
 
-    fn render_overlay(&mut self) {
+    fn render_overlay(&mut self, now: Instant) {
         let config = &self.local_config;
         self.draw.clear();
         draw::mode_indicator(config, &mut self.draw);
         draw::detected_objects(config, &mut self.draw, &self.state.detected.1);
         draw::tracking_gains(config, &mut self.draw, &self.gimbal_status);
-        draw::tracking_rect(config, &mut self.draw, &self.state.tracked, &self.state.manual);
+        draw::tracking_rect(config, &mut self.draw, &self.state.tracked, &self.state.manual, now);
         draw::gimbal_status(config, &mut self.draw, &self.gimbal_status);
+        draw::geofence_status(config, &mut self.draw, &self.geofence_status);
         draw::debug_text(config, &mut self.draw, format!("{:?}, {:?}", config.mode, self.gimbal_status));
 
         match config.mode {
@@ -199,7 +396,7 @@ impl Controller {
         }
     }
 
-    fn handle_message(&mut self, ts_msg: TimestampedMessage, gimbal_port: &GimbalPort) {
+    fn handle_message(&mut self, ts_msg: TimestampedMessage, gimbal_port: &GimbalPort, now: Instant) {
         match ts_msg.message {
 
             Message::UpdateConfig(updates) => {
@@ -215,9 +412,19 @@ impl Controller {
                 }
             }
 
+            Message::WinchStatus(id, _) if id >= self.local_config.winches.len() => {
+                // Sent before this winch was removed from the config
+            },
+
             Message::WinchStatus(id, status) => {
-                let command = self.state.winch_control_loop(&self.local_config, id, status);
-                if self.state.multi_winch_watchdog_should_halt(&self.local_config) {
+                if self.winch_calibrator.winch_status(id, &status) {
+                    self.broadcast_winch_calibration_status();
+                }
+                if self.anchor_survey.winch_status(id, &status) {
+                    self.broadcast_anchor_survey_status();
+                }
+                let command = self.state.winch_control_loop(&self.local_config, id, status, now);
+                if self.state.multi_winch_watchdog_should_halt(&self.local_config, now) {
                     println!("Halting; lost communication with one or more winches");
                     self.local_config.mode = ControllerMode::Halted;
                     self.config_changed();
@@ -226,22 +433,47 @@ impl Controller {
             },
 
             Message::FlyerSensors(sensors) => {
-                self.state.flyer_sensor_update(sensors);
+                self.anchor_survey.flyer_sensor_update(&sensors);
+                self.state.flyer_sensor_update(&self.local_config, sensors, now);
+            },
+
+            Message::GimbalValue(val, op) => {
+                self.gimbal_params.value_received(&val, &op, now);
+                if op != GimbalValueOp::WriteFailed {
+                    self.gimbal_ctrl.value_received(&self.local_config, val, now)
+                }
+            },
+
+            Message::SubsystemFault(fault) => {
+                if fault.critical && self.local_config.mode != ControllerMode::Halted {
+                    println!("Halting; {} failed", fault.subsystem);
+                    self.local_config.mode = ControllerMode::Halted;
+                    self.config_changed();
+                }
+            },
+
+            Message::NetworkStatus(status) => {
+                self.state.network_status_update(&status);
+            },
+
+            Message::GimbalLinkStatus(status) => {
+                self.gimbal_ctrl.link_status_received(&status);
             },
 
-            Message::GimbalValue(val, _) => {
-                self.gimbal_ctrl.value_received(&self.local_config, val)
+            Message::UnhandledGimbalPacket(packet) => {
+                self.gimbal_params.packet_received(&packet, now);
+                self.gimbal_ctrl.packet_received(&packet);
             },
 
             Message::Command(Command::CameraObjectDetection(obj)) => {
-                self.state.camera_object_detection_update(obj);
-                if let Some(tracking_rect) = self.state.tracking_update(&self.local_config, 0.0, false) {
+                self.state.camera_object_detection_update(obj, now);
+                if let Some(tracking_rect) = self.state.tracking_update(&self.local_config, 0.0, false, now) {
                     self.broadcast(Message::CameraInitTrackedRegion(tracking_rect).timestamp());
                 }
             },
 
             Message::Command(Command::CameraRegionTracking(tr)) => {
-                self.state.camera_region_tracking_update(tr);
+                self.state.camera_region_tracking_update(tr, now);
             },
 
             Message::Command(Command::CameraOutputStatus(outs)) => {
@@ -260,16 +492,51 @@ impl Controller {
                 self.gimbal_ctrl.set_motor_enable(gimbal_port, en);
             },
 
+            Message::Command(Command::GimbalSetAngle(target)) => {
+                match target.validate() {
+                    Err(e) => println!("Error in GimbalSetAngle from message bus: {}", e),
+                    Ok(()) => self.gimbal_ctrl.set_angle_target(&self.local_config, target, None),
+                }
+            },
+
+            Message::Command(Command::GimbalSlewTo(slew)) => {
+                match slew.validate() {
+                    Err(e) => println!("Error in GimbalSlewTo from message bus: {}", e),
+                    Ok(()) => self.gimbal_ctrl.set_angle_target(&self.local_config, slew.target, Some(slew.speed)),
+                }
+            },
+
+            Message::Command(Command::GimbalReleaseAngle) => {
+                self.gimbal_ctrl.release_angle_target();
+            },
+
+            Message::Command(Command::GimbalFirmware(GimbalFirmwareCommand::Start)) => {
+                match fygimbal::load_images(self.local_config.gimbal_firmware.as_ref()) {
+                    Ok(images) => gimbal_port.firmware_command(FirmwareCommand::Start(images)),
+                    Err(e) => println!("Not starting gimbal firmware update, {}", e),
+                }
+            },
+
+            Message::Command(Command::GimbalFirmware(GimbalFirmwareCommand::Cancel)) => {
+                gimbal_port.firmware_command(FirmwareCommand::Cancel);
+            },
+
             Message::Command(Command::GimbalPacket(packet)) => {
                 gimbal_port.send_packet(packet);
             },
 
             Message::Command(Command::GimbalValueWrite(data)) => {
-                gimbal_port.write_value(data);
+                match registry::validate_write(&data) {
+                    Ok(()) => gimbal_port.write_value(data),
+                    Err(e) => println!("Error in gimbal value write from message bus: {}", e),
+                }
             },
 
             Message::Command(Command::GimbalValueRequests(reqs)) => {
-                gimbal_port.request_values(reqs);
+                match reqs.iter().map(|req| req.validate()).collect::<Result<(), String>>() {
+                    Ok(()) => gimbal_port.request_values(reqs),
+                    Err(e) => println!("Error in GimbalValueRequests from message bus: {}", e),
+                }
             },
 
             Message::Command(Command::ManualControlValue(axis, value)) => {
@@ -280,6 +547,68 @@ impl Controller {
                 self.state.manual.control_reset();
             },
 
+            Message::Command(Command::TrajectoryUpload(trajectory)) => {
+                match trajectory.validate() {
+                    Err(e) => println!("Error in TrajectoryUpload from message bus: {}", e),
+                    Ok(()) => self.state.trajectory_upload(&self.local_config, trajectory),
+                }
+            },
+
+            Message::Command(Command::WinchCalibration(command)) => {
+                if let Some((id, calibration)) = self.winch_calibrator.command(&self.local_config, command) {
+                    println!("Saving new calibration for winch {}, {:?}", id, calibration);
+                    self.local_config.winches[id].calibration = calibration;
+                    self.config_changed();
+                }
+                self.broadcast_winch_calibration_status();
+            },
+
+            Message::Command(Command::AnchorSurvey(command)) => {
+                if let Some(fit) = self.anchor_survey.command(&self.local_config, command) {
+                    for (id, anchor) in fit.iter().enumerate() {
+                        println!("Saving surveyed location for winch {}, {:?} with rms residual {} m", id, anchor.loc, anchor.rms_residual_m);
+                        self.local_config.winches[id].loc = anchor.loc;
+                    }
+                    let offsets : Vec<f32> = fit.iter().map(|anchor| anchor.rope_offset_m).collect();
+                    self.state.anchor_survey_confirmed(&self.local_config, &offsets);
+                    self.config_changed();
+                }
+                self.broadcast_anchor_survey_status();
+            },
+
+            Message::Command(Command::WinchAutotune(command)) => {
+                if let Some(gains) = self.state.winch_autotune_command(&self.local_config, command) {
+                    println!("Saving autotuned winch gains, {:?}", gains);
+                    self.local_config.params.pwm_gain_p = gains.pwm_gain_p;
+                    self.local_config.params.pwm_gain_i = gains.pwm_gain_i;
+                    self.local_config.params.pwm_gain_d = gains.pwm_gain_d;
+                    self.config_changed();
+                }
+                self.broadcast_winch_autotune_status();
+            },
+
+            Message::Command(Command::GimbalTuning(command)) => {
+                if let Some(proposal) = self.gimbal_ctrl.tuning_command(&self.local_config, command) {
+                    println!("Saving tuned gimbal gains, {:?}", proposal);
+                    self.local_config.gimbal.hold_p_gain = proposal.hold_p_gain;
+                    self.local_config.gimbal.hold_i_gain = proposal.hold_i_gain;
+                    self.local_config.gimbal.limiter_gain = proposal.limiter_gain;
+                    self.local_config.gimbal.limiter_slowdown_extent = proposal.limiter_slowdown_extent;
+                    self.config_changed();
+                }
+                self.broadcast_gimbal_tuning_status();
+            },
+
+            Message::Command(Command::GimbalCalibration(command)) => {
+                self.gimbal_ctrl.calibration_command(command);
+                self.broadcast_gimbal_calibration_status();
+            },
+
+            Message::Command(Command::GimbalParams(command)) => {
+                self.gimbal_params.command(&self.local_config, gimbal_port, command, now);
+                self.broadcast_gimbal_params_status();
+            },
+
             _ => (),
         }
     }
@@ -5,6 +5,12 @@ use std::time::{Duration, Instant};
 use config::{Config, ControllerMode};
 use controller::manual::ManualControls;
 use controller::winch::{WinchController, MechStatus};
+use controller::kinematics::FlyerKinematics;
+use controller::estimator::FlyerEstimator;
+use controller::trajectory::TrajectoryPlayer;
+use controller::geofence;
+use controller::autotune::WinchAutotuner;
+use controller::look_at::look_at_angles;
 use overlay::ParticleDrawing;
 use led::WinchLighting;
 
@@ -13,7 +19,13 @@ pub struct ControllerState {
     pub tracked: CameraTrackedRegion,
     pub detected: (Instant, CameraDetectedObjects),
     pub tracking_particles: ParticleDrawing,
+    pub kinematics: FlyerKinematics,
+    estimator: FlyerEstimator,
+    trajectory: TrajectoryPlayer,
+    flyer_velocity: Vector3<f32>,
+    geofence_status: Option<GeofenceStatus>,
     winches: Vec<WinchController>,
+    winch_autotune: WinchAutotuner,
     flyer_sensors: Option<FlyerSensors>,
     camera_outputs: HashMap<CameraOutput, CameraOutputStatus>,
     pending_snap: bool,
@@ -21,14 +33,20 @@ pub struct ControllerState {
 }
 
 impl ControllerState {
-    pub fn new(initial_config: &Config) -> ControllerState {
+    pub fn new(initial_config: &Config, now: Instant) -> ControllerState {
         ControllerState {
             manual: ManualControls::new(),
+            kinematics: FlyerKinematics::new(initial_config),
+            estimator: FlyerEstimator::new(),
+            trajectory: TrajectoryPlayer::new(),
+            flyer_velocity: [0.0; 3],
+            geofence_status: None,
             winches: initial_config.winches.iter().enumerate().map(|(id, _config)| {
                 WinchController::new(id)
             }).collect(),
+            winch_autotune: WinchAutotuner::new(),
             flyer_sensors: None,
-            detected: (Instant::now(), CameraDetectedObjects::new()),
+            detected: (now, CameraDetectedObjects::new()),
             pending_snap: false,
             tracked: CameraTrackedRegion::new(),
             tracking_particles: ParticleDrawing::new(),
@@ -38,18 +56,60 @@ impl ControllerState {
     }
 
     pub fn config_changed(&mut self, config: &Config) {
+        self.resize_winches(config.winches.len());
         if config.mode != self.last_mode {
             self.mode_changed(&config.mode);
             self.last_mode = config.mode.clone();
         }
     }
 
-    fn mode_changed(&mut self, _mode: &ControllerMode) {
+    fn resize_winches(&mut self, num_winches: usize) {
+        self.winches.truncate(num_winches);
+        while self.winches.len() < num_winches {
+            let id = self.winches.len();
+            self.winches.push(WinchController::new(id));
+        }
+    }
+
+    fn mode_changed(&mut self, mode: &ControllerMode) {
         self.halt_motion();
+        if *mode == ControllerMode::Trajectory {
+            self.trajectory.start(self.kinematics.position);
+        }
     }
 
     fn halt_motion(&mut self) {
         self.manual.full_reset();
+        self.trajectory.stop();
+    }
+
+    pub fn trajectory_upload(&mut self, config: &Config, trajectory: Trajectory) {
+        self.trajectory.upload(trajectory);
+        if config.mode == ControllerMode::Trajectory {
+            // New trajectory replaces the one in progress, starting over from here
+            self.trajectory.start(self.kinematics.position);
+        }
+    }
+
+    pub fn trajectory_status(&self) -> Option<TrajectoryStatus> {
+        self.trajectory.status()
+    }
+
+    pub fn gimbal_hold_target(&self, config: &Config, look_at: &Option<GimbalLookAtStatus>) -> Option<Vector2<i16>> {
+        let trajectory_target = if config.mode == ControllerMode::Trajectory {
+            self.trajectory.gimbal_target()
+        } else {
+            None
+        };
+        // Without a pose estimate there's nothing to look from, and vision tracking takes over
+        trajectory_target.or_else(|| look_at.as_ref().map(|look_at| look_at.angles))
+    }
+
+    pub fn gimbal_look_at(&self, config: &Config) -> Option<GimbalLookAtStatus> {
+        match (config.gimbal.look_at.point, self.flyer_pose()) {
+            (Some(point), Some(pose)) => look_at_angles(config, &pose, point),
+            _ => None,
+        }
     }
 
     pub fn winch_lighting(&self, config: &Config) ->  Vec<WinchLighting> {
@@ -58,8 +118,8 @@ impl ControllerState {
         }).collect()
     }
 
-    pub fn tracking_update(&mut self, config: &Config, time_step: f32, reset_tracking: bool) -> Option<Vector4<f32>> {
-        if self.manual.camera_control_active() {
+    pub fn tracking_update(&mut self, config: &Config, time_step: f32, reset_tracking: bool, now: Instant) -> Option<Vector4<f32>> {
+        if self.manual.camera_control_active(now) {
             // Manual tracking control temporarily overrides other sources
             self.tracked.rect = self.manual.tracking_update(config, self.tracked.rect, time_step);
             Some(self.tracked.rect)
@@ -69,7 +129,7 @@ impl ControllerState {
             self.tracked = CameraTrackedRegion::new();
             Some(self.tracked.rect)
         }
-        else if let Some(obj) = self.find_best_snap_object(config) {
+        else if let Some(obj) = self.find_best_snap_object(config, now) {
             // Snap to a detected object
             self.pending_snap = false;
             self.tracked.rect = rect_constrain(obj.rect, config.vision.border_rect);
@@ -81,18 +141,72 @@ impl ControllerState {
         }
     }
 
-    pub fn every_tick(&mut self, config: &Config) {
-        self.manual.control_tick(config);
+    pub fn every_tick(&mut self, config: &Config, now: Instant) {
+        self.manual.control_tick(config, now);
+        self.estimator.tick(config, now);
+        let pose = self.estimator.pose();
+        if let Some(ref pose) = pose {
+            self.kinematics.position = pose.position;
+        }
+        let position = pose.map(|pose| pose.position);
+        if config.mode == ControllerMode::Trajectory {
+            self.trajectory.tick(config, position);
+        }
+        self.flyer_velocity_tick(config, position);
         self.tracking_particles.follow_rect(config, self.tracked.rect);
     }
 
-    fn find_best_snap_object(&self, config: &Config) -> Option<CameraDetectedObject> {
+    fn flyer_velocity_tick(&mut self, config: &Config, position: Option<Vector3<f32>>) {
+        let v = match config.mode {
+            ControllerMode::ManualFlyer => self.manual_flyer_velocity(),
+            ControllerMode::Normal => self.manual_flyer_velocity(),
+            ControllerMode::Trajectory => self.trajectory.velocity(),
+            _ => [0.0; 3],
+        };
+
+        // The geofence needs to know where we are. Without a pose estimate, not all
+        // winches are reporting yet and the watchdog will keep us from moving.
+        self.flyer_velocity = match position {
+            None => {
+                self.geofence_status = None;
+                v
+            },
+            Some(position) => {
+                let (v, status) = geofence::guard_velocity(config, position, v);
+                self.geofence_status = Some(status);
+                v
+            },
+        };
+    }
+
+    pub fn geofence_status(&self) -> Option<GeofenceStatus> {
+        self.geofence_status.clone()
+    }
+
+    pub fn anchor_survey_confirmed(&mut self, config: &Config, rope_offsets_m: &[f32]) {
+        // Anchors moved, so the pose estimate should follow the survey's idea of rope length
+        self.estimator.set_rope_offsets(config, rope_offsets_m);
+    }
+
+    pub fn winch_autotune_command(&mut self, config: &Config, command: WinchAutotuneCommand) -> Option<WinchPIDProposal> {
+        self.winch_autotune.command(config, command)
+    }
+
+    pub fn winch_autotune_status(&self) -> Option<WinchAutotuneStatus> {
+        self.winch_autotune.status()
+    }
+
+    pub fn flyer_pose(&self) -> Option<FlyerPose> {
+        self.estimator.pose()
+    }
+
+    fn find_best_snap_object(&self, config: &Config, now: Instant) -> Option<CameraDetectedObject> {
         if !self.pending_snap {
             // No data from the CV subsystem yet or we've already processed the latest frame
             return None;
         }
 
-        if self.detected.0 + Duration::from_millis(500) < Instant::now() {
+        if self.detected.0 + Duration::from_millis(500) < now {
             // Latest data from CV is too old to bother with
             return None;
         }
@@ -123,13 +237,13 @@ impl ControllerState {
         }
     }
 
-    pub fn camera_object_detection_update(&mut self, det: CameraDetectedObjects) {
-        self.detected = (Instant::now(), det);
+    pub fn camera_object_detection_update(&mut self, det: CameraDetectedObjects, now: Instant) {
+        self.detected = (now, det);
         self.pending_snap = true;
     }
 
-    pub fn camera_region_tracking_update(&mut self, tr: CameraTrackedRegion) {
-        if !self.manual.camera_control_active() {
+    pub fn camera_region_tracking_update(&mut self, tr: CameraTrackedRegion, now: Instant) {
+        if !self.manual.camera_control_active(now) {
             self.tracked = tr;
         }
     }
@@ -138,13 +252,24 @@ impl ControllerState {
         self.camera_outputs = outputs;
     }
 
-    pub fn flyer_sensor_update(&mut self, sensors: FlyerSensors) {
+    pub fn flyer_sensor_update(&mut self, config: &Config, sensors: FlyerSensors, now: Instant) {
+        self.estimator.flyer_sensor_update(config, &sensors, now);
         self.flyer_sensors = Some(sensors);
     }
 
-    pub fn winch_control_loop(&mut self, config: &Config, id: usize, status: WinchStatus) -> WinchCommand {
+    pub fn winch_control_loop(&mut self, config: &Config, id: usize, status: WinchStatus, now: Instant) -> WinchCommand {
         let cal = &config.winches[id].calibration;
-        self.winches[id].update(config, cal, &status);
+        self.winches[id].update(config, cal, &status, now);
+        self.estimator.winch_status_update(config, id, &status);
+
+        if self.winch_autotune.is_running(id) {
+            // The relay test owns this winch's PID loop until it finishes or gives up
+            let base_command = self.winches[id].make_command(config, cal, &status);
+            let mech_status = self.winches[id].mech_status;
+            if let Some(command) = self.winch_autotune.relay_tick(config, id, &status, mech_status, base_command) {
+                return command;
+            }
+        }
 
         let velocity = match config.mode {
 
@@ -157,11 +282,15 @@ impl ControllerState {
             },
 
             ControllerMode::ManualFlyer => {
-                self.manual_multi_winch_controller(config, id)
+                self.multi_winch_controller(config, id, self.flyer_velocity)
             }
 
             ControllerMode::Normal => {
-                self.manual_multi_winch_controller(config, id)
+                self.multi_winch_controller(config, id, self.flyer_velocity)
+            }
+
+            ControllerMode::Trajectory => {
+                self.multi_winch_controller(config, id, self.flyer_velocity)
             }
 
             ControllerMode::Halted => 0.0
@@ -184,14 +313,14 @@ impl ControllerState {
         }
     }
 
-    fn manual_multi_winch_controller(&self, config: &Config, id: usize) -> f32 {
+    fn manual_flyer_velocity(&self) -> Vector3<f32> {
         let v = self.manual.limited_velocity();
-        let v = [v[0], -v[1], v[2]];
-        self.multi_winch_controller(config, id, v)
+        [v[0], -v[1], v[2]]
     }
 
     fn multi_winch_controller(&self, config: &Config, id: usize, velocity: Vector3<f32>) -> f32 {
-        let projected_velocity = vec3_dot(velocity, self.winch_rope_direction_vector(config, id));
+        // Inverse kinematics: each winch's share of the Cartesian velocity is its projection onto the rope
+        let projected_velocity = self.kinematics.rope_rate(config, id, velocity);
         let return_v = config.params.force_return_velocity_max_m_per_sec;
         match self.winches[id].mech_status {
             MechStatus::Stuck => 0.0,
@@ -200,14 +329,15 @@ impl ControllerState {
         }
     }
 
-    fn winch_rope_direction_vector(&self, config: &Config, id: usize) -> Vector3<f32> {
-        // fix me
-        vec3_normalized(config.winches[id].loc)
+    pub fn network_status_update(&mut self, status: &NetworkStatus) {
+        for (winch, link) in self.winches.iter_mut().zip(status.winches.iter()) {
+            winch.link_status_update(link);
+        }
     }
 
-    pub fn multi_winch_watchdog_should_halt(&self, config: &Config) -> bool {
+    pub fn multi_winch_watchdog_should_halt(&self, config: &Config, now: Instant) -> bool {
         for winch in &self.winches {
-            if !winch.is_status_recent(config) {
+            if !winch.is_status_recent(config, now) || !winch.is_link_healthy(config) {
                 // This winch isn't okay, halt unless we're in manual mode
                 return match config.mode {
                     ControllerMode::Halted => false,
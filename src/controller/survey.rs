@@ -0,0 +1,404 @@
+//! Anchor location survey. The operator moves the flyer through a set of poses, telling us
+//! where it is at each one, and we record every winch's encoder position. Each winch then
+//! gets its own least-squares fit for the anchor location and the rope length at encoder
+//! zero, which is reviewed along with its residuals before being saved to the config.
+
+use message::*;
+use vecmath::*;
+use config::Config;
+use controller::estimator::lidar_height;
+use std::cmp::Ordering;
+
+/// One more pose than there are unknowns per winch, so residuals mean something
+const MIN_SURVEY_POSES : usize = 5;
+
+/// Gauss-Newton iteration limits for each winch's fit
+const FIT_MAX_ITERATIONS : usize = 50;
+const FIT_CONVERGED_STEP_M : f64 = 1e-6;
+const FIT_MIN_PIVOT : f64 = 1e-9;
+
+pub struct AnchorSurvey {
+    status: Option<AnchorSurveyStatus>,
+    /// Latest encoder position and tick counter from each winch
+    winches: Vec<Option<(i32, u32)>>,
+    flyer_sensors: Option<FlyerSensors>,
+}
+
+impl AnchorSurvey {
+    pub fn new() -> AnchorSurvey {
+        AnchorSurvey {
+            status: None,
+            winches: Vec::new(),
+            flyer_sensors: None,
+        }
+    }
+
+    pub fn status(&self) -> Option<AnchorSurveyStatus> {
+        self.status.clone()
+    }
+
+    /// Observe a status packet from any winch, returning true if the survey status changed
+    pub fn winch_status(&mut self, id: usize, winch_status: &WinchStatus) -> bool {
+        while self.winches.len() <= id {
+            self.winches.push(None);
+        }
+        let is_discontinuous = match self.winches[id] {
+            None => false,
+            Some((_, tick_counter)) => winch_status.tick_counter.wrapping_sub(tick_counter) > 2,
+        };
+        self.winches[id] = Some((winch_status.sensors.position, winch_status.tick_counter));
+
+        if !is_discontinuous || !self.is_active() {
+            return false;
+        }
+        let status = self.status.as_mut().unwrap();
+        if status.poses.is_empty() {
+            return false;
+        }
+        // Encoder positions from before a winch restart can't be compared with later ones
+        status.poses.clear();
+        status.fit = None;
+        status.error = Some(format!("Lost track of winch {}, poses must be recorded again", id));
+        true
+    }
+
+    /// Follow a change in the number of configured winches, returning true if the survey
+    /// status changed. Poses recorded with a different set of winches can't be solved.
+    pub fn winches_changed(&mut self, num_winches: usize) -> bool {
+        self.winches.truncate(num_winches);
+        if !self.is_active() {
+            return false;
+        }
+        let status = self.status.as_mut().unwrap();
+        if status.poses.iter().all(|pose| pose.winch_counts.len() == num_winches) {
+            return false;
+        }
+        status.poses.clear();
+        status.fit = None;
+        status.error = Some("Number of winches changed, poses must be recorded again".into());
+        true
+    }
+
+    pub fn flyer_sensor_update(&mut self, sensors: &FlyerSensors) {
+        self.flyer_sensors = Some(sensors.clone());
+    }
+
+    /// Handle an operator command, returning the fit to save if it was just confirmed
+    pub fn command(&mut self, config: &Config, command: AnchorSurveyCommand) -> Option<Vec<AnchorFit>> {
+        let current_locs = config.winches.iter().map(|w| w.loc).collect();
+        if let AnchorSurveyCommand::Start = command {
+            self.status = Some(AnchorSurveyStatus {
+                active: true,
+                poses: Vec::new(),
+                current_locs,
+                fit: None,
+                error: None,
+            });
+            return None;
+        }
+
+        let result = if self.is_active() {
+            self.session_command(config, command)
+        } else {
+            Err("No anchor survey in progress".into())
+        };
+
+        if let Some(ref mut status) = self.status {
+            status.current_locs = current_locs;
+            match result {
+                Ok(saved) => {
+                    status.error = None;
+                    return saved;
+                },
+                Err(e) => status.error = Some(e),
+            }
+        }
+        None
+    }
+
+    fn is_active(&self) -> bool {
+        match self.status {
+            Some(ref status) => status.active,
+            None => false,
+        }
+    }
+
+    fn session_command(&mut self, config: &Config, command: AnchorSurveyCommand) -> Result<Option<Vec<AnchorFit>>, String> {
+        match command {
+
+            AnchorSurveyCommand::Start => unreachable!(),
+
+            AnchorSurveyCommand::RecordPose(reference) => {
+                let position = self.reference_position(config, reference)?;
+                let winch_counts = self.winch_counts(config)?;
+                let status = self.status.as_mut().unwrap();
+                status.poses.push(AnchorSurveyPose { position, winch_counts });
+                status.fit = None;
+                Ok(None)
+            },
+
+            AnchorSurveyCommand::RemovePose(index) => {
+                let status = self.status.as_mut().unwrap();
+                if index >= status.poses.len() {
+                    return Err(format!("No pose {}", index));
+                }
+                status.poses.remove(index);
+                status.fit = None;
+                Ok(None)
+            },
+
+            AnchorSurveyCommand::Solve => {
+                let status = self.status.as_mut().unwrap();
+                let mut fit = Vec::new();
+                for id in 0 .. config.winches.len() {
+                    fit.push(fit_anchor(config, id, &status.poses)?);
+                }
+                status.fit = Some(fit);
+                Ok(None)
+            },
+
+            AnchorSurveyCommand::Confirm => {
+                let status = self.status.as_mut().unwrap();
+                let fit = match status.fit {
+                    Some(ref fit) if fit.len() == config.winches.len() => fit.clone(),
+                    Some(_) => return Err("Number of winches changed since the survey was solved".into()),
+                    None => return Err("Solve the survey before confirming".into()),
+                };
+                status.active = false;
+                Ok(Some(fit))
+            },
+
+            AnchorSurveyCommand::Cancel => {
+                self.status.as_mut().unwrap().active = false;
+                Ok(None)
+            },
+        }
+    }
+
+    fn reference_position(&self, config: &Config, reference: SurveyReference) -> Result<Vector3<f32>, String> {
+        match reference {
+            SurveyReference::Position(position) => Ok(position),
+            SurveyReference::HorizontalWithLidarHeight(xy) => {
+                let sensors = match self.flyer_sensors {
+                    Some(ref sensors) => sensors,
+                    None => return Err("No LIDAR data from the flyer".into()),
+                };
+                let q = sensors.imu.quaternion;
+                let orientation = quat_normalized([ q[0] as f32, q[1] as f32, q[2] as f32, q[3] as f32 ]);
+                let mut heights = Vec::new();
+                for (index, direction) in config.estimator.lidar_directions.iter().enumerate() {
+                    if index < sensors.lidar.ranges.len() {
+                        let range = sensors.lidar.ranges[index] as f32 * config.estimator.lidar_m_per_count;
+                        if let Some(height) = lidar_height(config, orientation, *direction, range) {
+                            heights.push(height);
+                        }
+                    }
+                }
+                if heights.is_empty() {
+                    return Err("No LIDAR sensor can see the floor".into());
+                }
+                let height = heights.iter().sum::<f32>() / heights.len() as f32;
+                Ok([ xy[0], xy[1], height ])
+            },
+        }
+    }
+
+    fn winch_counts(&self, config: &Config) -> Result<Vec<i32>, String> {
+        let mut counts = Vec::new();
+        for id in 0 .. config.winches.len() {
+            match self.winches.get(id) {
+                Some(&Some((position, _))) => counts.push(position),
+                _ => return Err(format!("No status from winch {} yet", id)),
+            }
+        }
+        Ok(counts)
+    }
+}
+
+fn fit_anchor(config: &Config, id: usize, poses: &[AnchorSurveyPose]) -> Result<AnchorFit, String> {
+    if poses.len() < MIN_SURVEY_POSES {
+        return Err(format!("Need at least {} poses, have {}", MIN_SURVEY_POSES, poses.len()));
+    }
+
+    // Rope length at each pose is offset - reeled_in, with increasing counts reeling in.
+    // Solve for the anchor and offset minimizing |anchor - position| - (offset - reeled_in).
+    let cal = &config.winches[id].calibration;
+    let mut reeled_in = Vec::new();
+    for pose in poses {
+        match pose.winch_counts.get(id) {
+            Some(&counts) => reeled_in.push(cal.dist_to_m(counts as f32) as f64),
+            None => return Err(format!("Poses were recorded without winch {}", id)),
+        }
+    }
+    let positions : Vec<[f64; 3]> = poses.iter().map(|pose| {
+        [ pose.position[0] as f64, pose.position[1] as f64, pose.position[2] as f64 ]
+    }).collect();
+
+    // Start from the configured anchor, with the offset that best matches it
+    let loc = config.winches[id].loc;
+    let mut x = [ loc[0] as f64, loc[1] as f64, loc[2] as f64, 0.0 ];
+    x[3] = positions.iter().zip(reeled_in.iter()).map(|(p, r)| distance(&x, p) + r).sum::<f64>() / poses.len() as f64;
+
+    let mut converged = false;
+    for _ in 0 .. FIT_MAX_ITERATIONS {
+        let mut normal = [[0.0; 4]; 4];
+        let mut gradient = [0.0; 4];
+        for (p, r) in positions.iter().zip(reeled_in.iter()) {
+            let d = distance(&x, p);
+            if d <= 0.0 {
+                return Err(format!("Winch {} anchor fit passed through a pose", id));
+            }
+            let jacobian = [ (x[0] - p[0]) / d, (x[1] - p[1]) / d, (x[2] - p[2]) / d, -1.0 ];
+            let residual = d - (x[3] - r);
+            for row in 0..4 {
+                for col in 0..4 {
+                    normal[row][col] += jacobian[row] * jacobian[col];
+                }
+                gradient[row] -= jacobian[row] * residual;
+            }
+        }
+
+        let step = match solve4(normal, gradient) {
+            Some(step) => step,
+            None => return Err(format!("Poses don't constrain winch {}, spread them out more", id)),
+        };
+        for i in 0..4 {
+            x[i] += step[i];
+        }
+        if step.iter().map(|s| s * s).sum::<f64>().sqrt() < FIT_CONVERGED_STEP_M {
+            converged = true;
+            break;
+        }
+    }
+    if !converged {
+        return Err(format!("Winch {} anchor fit did not converge", id));
+    }
+
+    let residuals_m : Vec<f32> = positions.iter().zip(reeled_in.iter()).map(|(p, r)| {
+        ((x[3] - r) - distance(&x, p)) as f32
+    }).collect();
+    let rms_residual_m = (residuals_m.iter().map(|r| r * r).sum::<f32>() / residuals_m.len() as f32).sqrt();
+
+    Ok(AnchorFit {
+        loc: [ x[0] as f32, x[1] as f32, x[2] as f32 ],
+        rope_offset_m: x[3] as f32,
+        residuals_m,
+        rms_residual_m,
+    })
+}
+
+fn distance(x: &[f64; 4], p: &[f64; 3]) -> f64 {
+    ((x[0] - p[0]).powi(2) + (x[1] - p[1]).powi(2) + (x[2] - p[2]).powi(2)).sqrt()
+}
+
+/// Solve a 4x4 linear system by Gaussian elimination with partial pivoting
+fn solve4(mut a: [[f64; 4]; 4], mut b: [f64; 4]) -> Option<[f64; 4]> {
+    for col in 0..4 {
+        let pivot = (col .. 4).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap_or(Ordering::Equal))?;
+        if a[pivot][col].abs() < FIT_MIN_PIVOT {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1 .. 4 {
+            let factor = a[row][col] / a[col][col];
+            for k in col .. 4 {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; 4];
+    for row in (0..4).rev() {
+        let mut sum = b[row];
+        for k in row + 1 .. 4 {
+            sum -= a[row][k] * x[k];
+        }
+        x[row] = sum / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::test_config;
+
+    fn assert_solution(x: [f64; 4], expected: [f64; 4]) {
+        for i in 0..4 {
+            assert!((x[i] - expected[i]).abs() < 1e-12, "{:?} is not {:?}", x, expected);
+        }
+    }
+
+    #[test]
+    fn solve4_diagonal() {
+        let a = [[2.0, 0.0, 0.0, 0.0], [0.0, 4.0, 0.0, 0.0], [0.0, 0.0, 5.0, 0.0], [0.0, 0.0, 0.0, 8.0]];
+        assert_solution(solve4(a, [2.0, 4.0, 10.0, 4.0]).unwrap(), [1.0, 1.0, 2.0, 0.5]);
+    }
+
+    #[test]
+    fn solve4_needs_pivoting() {
+        // Every diagonal element is zero, so this only works by swapping rows
+        let a = [[0.0, 1.0, 0.0, 0.0], [1.0, 0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 0.0]];
+        assert_solution(solve4(a, [1.0, 2.0, 3.0, 4.0]).unwrap(), [2.0, 1.0, 4.0, 3.0]);
+    }
+
+    #[test]
+    fn solve4_tridiagonal() {
+        let a = [[4.0, 1.0, 0.0, 0.0], [1.0, 3.0, 1.0, 0.0], [0.0, 1.0, 2.0, 1.0], [0.0, 0.0, 1.0, 2.0]];
+        assert_solution(solve4(a, [6.0, 10.0, 12.0, 11.0]).unwrap(), [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn solve4_singular() {
+        let a = [[1.0, 2.0, 3.0, 4.0], [2.0, 4.0, 6.0, 8.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+        assert!(solve4(a, [1.0, 2.0, 3.0, 4.0]).is_none());
+    }
+
+    /// Poses whose winch 0 counts come from an anchor and rope offset we know
+    fn synthetic_poses(config: &Config, anchor: [f64; 3], offset_m: f64) -> Vec<AnchorSurveyPose> {
+        let positions = [
+            [0.0, 0.0, 2.0], [6.0, 5.0, 10.0], [-7.0, 6.0, 4.0],
+            [5.0, -6.0, 15.0], [-8.0, -4.0, 8.0], [2.0, 8.0, 12.0],
+        ];
+        let m_per_count = config.winches[0].calibration.m_dist_per_count as f64;
+        positions.iter().map(|&position : &[f32; 3]| {
+            let p = [ position[0] as f64, position[1] as f64, position[2] as f64 ];
+            let reeled_in = offset_m - distance(&[anchor[0], anchor[1], anchor[2], 0.0], &p);
+            let counts = (reeled_in / m_per_count).round() as i32;
+            AnchorSurveyPose { position, winch_counts: vec![counts, 0, 0, 0] }
+        }).collect()
+    }
+
+    #[test]
+    fn fit_recovers_anchor_and_offset() {
+        // Starts from the configured anchor at (-20, -20, 30)
+        let config = test_config(ControllerMode::Halted);
+        let poses = synthetic_poses(&config, [-19.5, -20.2, 30.3], 45.0);
+        let fit = fit_anchor(&config, 0, &poses).unwrap();
+        assert!((fit.loc[0] - -19.5).abs() < 1e-2, "{:?}", fit);
+        assert!((fit.loc[1] - -20.2).abs() < 1e-2, "{:?}", fit);
+        assert!((fit.loc[2] - 30.3).abs() < 1e-2, "{:?}", fit);
+        assert!((fit.rope_offset_m - 45.0).abs() < 1e-2, "{:?}", fit);
+        // Only encoder quantization is left over
+        assert_eq!(fit.residuals_m.len(), poses.len());
+        assert!(fit.rms_residual_m < 1e-4, "{:?}", fit);
+    }
+
+    #[test]
+    fn fit_needs_enough_poses() {
+        let config = test_config(ControllerMode::Halted);
+        let poses = synthetic_poses(&config, [-19.5, -20.2, 30.3], 45.0);
+        assert!(fit_anchor(&config, 0, &poses[.. MIN_SURVEY_POSES - 1]).is_err());
+        assert!(fit_anchor(&config, 0, &poses[.. MIN_SURVEY_POSES]).is_ok());
+    }
+
+    #[test]
+    fn fit_refuses_poses_missing_the_winch() {
+        let config = test_config(ControllerMode::Halted);
+        let mut poses = synthetic_poses(&config, [-19.5, -20.2, 30.3], 45.0);
+        poses[3].winch_counts.truncate(2);
+        assert!(fit_anchor(&config, 3, &poses).is_err());
+    }
+}
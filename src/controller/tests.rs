@@ -0,0 +1,907 @@
+//! Controller behavior under a simulated clock. The harness drives a real Controller one
+//! tick at a time, feeding it messages directly and collecting everything it broadcasts.
+
+use message::*;
+use vecmath::*;
+use config::{Config, ControllerMode, SharedConfigFile};
+use botcomm::BotSocket;
+use fygimbal::{GimbalPoller, GimbalPort, GimbalPacket, GimbalFraming};
+use fygimbal::protocol::{cmd, target, values, motor_status};
+use controller::{Controller, ControllerInput, ControllerPort, ManualClock, Clock, FAULT_REPORT_INTERVAL_MILLIS};
+use controller::clock::tick_duration;
+use controller::timer::{IntervalTimer, ConfigScheduler};
+use netmonitor::NetworkMonitor;
+use supervisor;
+use bus::BusReader;
+use chrono::NaiveTime;
+use serde_yaml;
+use serde_json::{Value, Map, to_value};
+use std::time::Duration;
+use std::sync::mpsc::{Receiver, sync_channel};
+
+pub fn test_config(mode: ControllerMode) -> Config {
+    let mut config : Config = serde_yaml::from_str(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/config.yaml"))).unwrap();
+    // Nothing is listening on the discard port; outgoing packets go nowhere
+    config.controller_addr = "127.0.0.1:0".parse().unwrap();
+    config.flyer_addr = "127.0.0.1:9".parse().unwrap();
+    for winch in config.winches.iter_mut() {
+        winch.addr = "127.0.0.1:9".parse().unwrap();
+    }
+    config.mode = mode;
+    config
+}
+
+/// A controller port with only a queue behind it, for seeing what other subsystems send
+pub struct TestPort {
+    pub port: ControllerPort,
+    recv: Receiver<ControllerInput>,
+}
+
+impl TestPort {
+    pub fn new() -> TestPort {
+        let (sender, recv) = sync_channel(1024);
+        TestPort {
+            port: ControllerPort { sender },
+            recv,
+        }
+    }
+
+    /// Everything sent since last time
+    pub fn messages(&self) -> Vec<Message> {
+        self.recv.try_iter().filter_map(|input| match input {
+            ControllerInput::Message(ts_msg) => Some(ts_msg.message),
+            ControllerInput::ReaderRequest(_) => None,
+        }).collect()
+    }
+}
+
+pub fn assert_near(value: f32, expected: f32, tolerance: f32) {
+    assert!((value - expected).abs() <= tolerance, "{} is not within {} of {}", value, tolerance, expected);
+}
+
+struct Harness {
+    clock: ManualClock,
+    controller: Controller,
+    rx: BusReader<TimestampedMessage>,
+    gimbal_port: GimbalPort,
+    // Keeps the gimbal port's channels open
+    _poller: GimbalPoller,
+    winch_tick_counters: Vec<u32>,
+}
+
+impl Harness {
+    fn new(mode: ControllerMode) -> Harness {
+        let config = test_config(mode);
+        let socket = BotSocket::new(&config).unwrap();
+        let clock = ManualClock::new(NaiveTime::from_hms(12, 0, 0));
+        let mut controller = Controller::with_clock(&SharedConfigFile::in_memory(config.clone()), &socket, Box::new(clock.clone()));
+        let rx = controller.bus.add_rx();
+        let poller = GimbalPoller::new();
+        Harness {
+            clock,
+            controller,
+            rx,
+            gimbal_port: poller.port(),
+            _poller: poller,
+            winch_tick_counters: config.winches.iter().map(|_| 0).collect(),
+        }
+    }
+
+    fn config(&self) -> &Config {
+        &self.controller.local_config
+    }
+
+    fn send(&mut self, message: Message) {
+        let now = self.clock.now();
+        let ts_msg = TimestampedMessage { timestamp: now, message };
+        self.controller.handle_input(ControllerInput::Message(ts_msg), &self.gimbal_port, now);
+    }
+
+    /// Advance exactly one controller tick, returning everything broadcast along the way
+    fn step(&mut self) -> Vec<Message> {
+        self.clock.tick();
+        let now = self.clock.now();
+        self.controller.poll_timers(&self.gimbal_port, now);
+        self.messages()
+    }
+
+    fn messages(&mut self) -> Vec<Message> {
+        let mut result = Vec::new();
+        while let Ok(ts_msg) = self.rx.try_recv() {
+            result.push(ts_msg.message);
+        }
+        result
+    }
+
+    fn winch_status(&mut self, id: usize) {
+        while self.winch_tick_counters.len() <= id {
+            self.winch_tick_counters.push(0);
+        }
+        self.winch_tick_counters[id] += 1;
+        let status = winch_status(self.winch_tick_counters[id]);
+        self.send(Message::WinchStatus(id, status));
+    }
+
+    fn gimbal_value(&mut self, target: u8, index: u8, value: i16) {
+        let data = GimbalValueData { addr: GimbalValueAddress { target, index }, value };
+        self.send(Message::GimbalValue(data, GimbalValueOp::ReadComplete));
+    }
+
+    /// One round of fresh readings from a powered gimbal at the given angles
+    fn gimbal_values(&mut self, angles: [i16; 2], current: i16) {
+        self.gimbal_readings(angles, current, motor_status::POWER_ON);
+    }
+
+    /// The same readings with every motor switched off
+    fn gimbal_values_unpowered(&mut self, angles: [i16; 2]) {
+        self.gimbal_readings(angles, 0, 0);
+    }
+
+    fn gimbal_readings(&mut self, angles: [i16; 2], current: i16, motor_flags: i16) {
+        let config_values = self.config().gimbal.values.clone();
+        for (&index, vector) in config_values.iter() {
+            for (target, value) in vector.iter().enumerate() {
+                if let &Some(value) = value {
+                    self.gimbal_value(target as u8, index, value);
+                }
+            }
+        }
+        self.gimbal_value(target::IMU_ADJACENT, values::SUPPLY_VOLTAGE, 120);
+        self.gimbal_value(target::YAW, values::CALIBRATION_ANGLE_0_CENTER, 0);
+        self.gimbal_value(target::PITCH, values::CALIBRATION_ANGLE_0_CENTER, 0);
+        self.gimbal_value(target::YAW, values::ENCODER_ANGLE, angles[0]);
+        self.gimbal_value(target::PITCH, values::ENCODER_ANGLE, angles[1]);
+        for &axis in [target::YAW, target::ROLL, target::PITCH].iter() {
+            self.gimbal_value(axis, values::MOTOR_STATUS_FLAGS, motor_flags);
+            self.gimbal_value(axis, values::MOTOR_FILTERED_CURRENT, current);
+        }
+    }
+
+    fn gimbal_calibration(&self) -> GimbalCalibrationStatus {
+        self.controller.gimbal_ctrl.calibration_status().unwrap()
+    }
+}
+
+pub fn winch_status(tick_counter: u32) -> WinchStatus {
+    WinchStatus {
+        command_counter: tick_counter,
+        tick_counter,
+        command: WinchCommand {
+            position: 0,
+            force: ForceCommand {
+                filter_param: 1.0,
+                neg_motion_min: 0.0,
+                pos_motion_max: 0.0,
+                lockout_below: 0.0,
+                lockout_above: 0.0,
+            },
+            pid: PIDGains {
+                gain_p: 0.0,
+                gain_i: 0.0,
+                gain_d: 0.0,
+                p_filter_param: 1.0,
+                i_decay_param: 1.0,
+                d_filter_param: 1.0,
+            },
+            deadband: WinchDeadband {
+                position_center: 0,
+                position_width: 0,
+                velocity_center: 0.0,
+                velocity_width: 0.0,
+            },
+            pwm: WinchPWMCommand {
+                hz: 0.0,
+                minimum: 0.0,
+                bias: 0.0,
+            },
+        },
+        sensors: WinchSensors {
+            force: ForceTelemetry {
+                measure: 0,
+                filtered: 0.0,
+                counter: tick_counter,
+            },
+            position: 0,
+            velocity: 0.0,
+        },
+        motor: WinchMotorControl {
+            pwm: WinchPWMStatus {
+                total: 0.0,
+                p: 0.0,
+                i: 0.0,
+                d: 0.0,
+                hz: 0.0,
+                clocks: 0,
+                period: 0,
+                enabled: 1,
+            },
+            position_err: 0,
+            pos_err_filtered: 0.0,
+            pos_err_integral: 0.0,
+            vel_err_inst: 0.0,
+            vel_err_filtered: 0.0,
+        },
+    }
+}
+
+/// Append a copy of winch 0 at a new anchor location
+fn add_winch(h: &mut Harness, loc: Vector3<f32>) {
+    let mut winch = h.config().winches[0].clone();
+    winch.loc = loc;
+    let mut winches = vec![Value::Null; h.config().winches.len()];
+    winches.push(to_value(&winch).unwrap());
+    let mut update = Map::new();
+    update.insert("winches".into(), Value::Array(winches));
+    h.send(Message::UpdateConfig(Value::Object(update)));
+}
+
+fn remove_winch(h: &mut Harness, id: usize) {
+    let mut winches = Map::new();
+    winches.insert(id.to_string(), Value::Null);
+    let mut update = Map::new();
+    update.insert("winches".into(), Value::Object(winches));
+    h.send(Message::UpdateConfig(Value::Object(update)));
+}
+
+fn gimbal_statuses(messages: &[Message]) -> Vec<GimbalControlStatus> {
+    messages.iter().filter_map(|msg| match msg {
+        &Message::GimbalControlStatus(ref status) => Some(status.clone()),
+        _ => None,
+    }).collect()
+}
+
+/// A reply from the gimbal that no read or write was waiting for
+fn gimbal_reply(command: u8, data: Vec<u8>) -> Message {
+    Message::UnhandledGimbalPacket(GimbalPacket {
+        framing: GimbalFraming::Normal,
+        command,
+        target: target::HOST,
+        data,
+    })
+}
+
+const GYRO_DRIFTS : [u8; 3] = [
+    values::CALIBRATION_GYRO_DRIFT_Z,
+    values::CALIBRATION_GYRO_DRIFT_X,
+    values::CALIBRATION_GYRO_DRIFT_Y,
+];
+
+/// Start calibrating gyro drift on an unpowered gimbal, running until it's ready to capture
+fn start_gyro_drift_calibration(h: &mut Harness) {
+    h.send(Message::Command(Command::GimbalCalibration(GimbalCalibrationCommand::Start(GimbalCalibrationProcedure::GyroDrift))));
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    assert_eq!(h.gimbal_calibration().phase, GimbalCalibrationPhase::ReadingBefore);
+    for &index in GYRO_DRIFTS.iter() {
+        h.gimbal_value(target::IMU_ADJACENT, index, 10);
+    }
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    assert_eq!(h.gimbal_calibration().phase, GimbalCalibrationPhase::Capturing);
+}
+
+fn detection(label: &str, prob: f32, rect: Vector4<f32>) -> Command {
+    Command::CameraObjectDetection(CameraDetectedObjects {
+        frame: 1,
+        detector_nsec: 0,
+        objects: vec![ CameraDetectedObject { rect, prob, label: label.into() } ],
+    })
+}
+
+const CAT_RECT : Vector4<f32> = [ -0.2, -0.2, 0.4, 0.4 ];
+
+#[test]
+fn interval_timer_fires_once_per_period() {
+    let clock = ManualClock::new(NaiveTime::from_hms(0, 0, 0));
+    let mut timer = IntervalTimer::new(TICK_HZ, clock.now());
+    clock.advance(tick_duration() / 2);
+    assert!(!timer.poll(clock.now()));
+    clock.advance(tick_duration() / 2);
+    assert!(timer.poll(clock.now()));
+    assert!(!timer.poll(clock.now()));
+    for _ in 0 .. 100 {
+        clock.tick();
+        assert!(timer.poll(clock.now()));
+    }
+}
+
+#[test]
+fn harness_ticks_at_tick_hz() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    let mut ticks = 0;
+    for _ in 0 .. TICK_HZ {
+        ticks += gimbal_statuses(&h.step()).len();
+    }
+    assert_eq!(ticks, TICK_HZ as usize);
+}
+
+#[test]
+fn scheduled_lighting_change() {
+    let clock = ManualClock::new(NaiveTime::from_hms(21, 59, 50));
+    let mut config = test_config(ControllerMode::Halted);
+    let mut night = config.lighting.current.clone();
+    night.brightness = 0.1;
+    config.lighting.saved.insert("night".into(), night.clone());
+    config.lighting.schedule.insert(NaiveTime::from_hms(22, 0, 0), "night".into());
+
+    let mut scheduler = ConfigScheduler::new(&clock);
+    for _ in 0 .. 9 {
+        clock.advance(Duration::from_millis(1100));
+        assert!(!scheduler.poll(&mut config, &clock));
+    }
+    clock.advance(Duration::from_millis(1100));
+    assert!(scheduler.poll(&mut config, &clock));
+    assert_eq!(config.lighting.current, night);
+    clock.advance(Duration::from_millis(1100));
+    assert!(!scheduler.poll(&mut config, &clock));
+}
+
+#[test]
+fn winch_watchdog_keeps_running_while_winches_report() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    let num_winches = h.config().winches.len();
+    for id in 0 .. num_winches { h.winch_status(id); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    for _ in 0 .. TICK_HZ {
+        h.step();
+        for id in 0 .. num_winches { h.winch_status(id); }
+    }
+    assert_eq!(h.config().mode, ControllerMode::Normal);
+}
+
+#[test]
+fn winch_watchdog_halts_on_silent_winch() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    let num_winches = h.config().winches.len();
+    for id in 0 .. num_winches { h.winch_status(id); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+
+    // Winch 0 goes quiet; the rest keep reporting
+    let deadline = Duration::from_millis(h.config().params.winch_watchdog_millis);
+    let mut elapsed = Duration::new(0, 0);
+    while elapsed < deadline {
+        h.step();
+        elapsed += tick_duration();
+        for id in 1 .. num_winches { h.winch_status(id); }
+        if elapsed < deadline {
+            assert_eq!(h.config().mode, ControllerMode::Normal);
+        }
+    }
+    assert_eq!(h.config().mode, ControllerMode::Halted);
+}
+
+#[test]
+fn winch_watchdog_ignores_silent_winch_in_manual_winch_mode() {
+    let mut h = Harness::new(ControllerMode::ManualWinch(1));
+    let num_winches = h.config().winches.len();
+    for _ in 0 .. TICK_HZ {
+        h.step();
+        for id in 1 .. num_winches { h.winch_status(id); }
+    }
+    assert_eq!(h.config().mode, ControllerMode::ManualWinch(1));
+}
+
+#[test]
+fn winch_added_and_removed_mid_survey() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    for id in 0 .. 4 { h.winch_status(id); }
+    h.send(Message::Command(Command::AnchorSurvey(AnchorSurveyCommand::Start)));
+    for z in 1 .. 3 {
+        h.send(Message::Command(Command::AnchorSurvey(AnchorSurveyCommand::RecordPose(SurveyReference::Position([0.0, 0.0, z as f32])))));
+    }
+    assert_eq!(h.controller.anchor_survey.status().unwrap().poses.len(), 2);
+
+    // Poses without the new winch are thrown out, rather than solved with a missing count
+    add_winch(&mut h, [0.0, 25.0, 30.0]);
+    assert_eq!(h.config().winches.len(), 5);
+    let status = h.controller.anchor_survey.status().unwrap();
+    assert!(status.active);
+    assert!(status.poses.is_empty());
+    assert!(status.error.is_some());
+
+    for id in 0 .. 5 { h.winch_status(id); }
+    for z in 1 .. 6 {
+        h.send(Message::Command(Command::AnchorSurvey(AnchorSurveyCommand::RecordPose(SurveyReference::Position([z as f32, 0.0, z as f32])))));
+    }
+    h.step();
+    let status = h.controller.anchor_survey.status().unwrap();
+    assert_eq!(status.poses.len(), 5);
+    assert!(status.poses.iter().all(|pose| pose.winch_counts.len() == 5));
+
+    remove_winch(&mut h, 4);
+    assert_eq!(h.config().winches.len(), 4);
+    assert!(h.controller.anchor_survey.status().unwrap().poses.is_empty());
+    h.send(Message::Command(Command::AnchorSurvey(AnchorSurveyCommand::Solve)));
+    let status = h.controller.anchor_survey.status().unwrap();
+    assert!(status.fit.is_none());
+    assert!(status.error.is_some());
+    h.step();
+}
+
+#[test]
+fn winch_added_and_removed_mid_motion() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    for id in 0 .. 4 { h.winch_status(id); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    h.send(Message::Command(Command::ManualControlValue(ManualControlAxis::RelativeX, 1.0)));
+    for _ in 0 .. 10 {
+        h.step();
+        for id in 0 .. 4 { h.winch_status(id); }
+    }
+
+    // The new winch reports before the watchdog next checks on everyone
+    add_winch(&mut h, [0.0, 25.0, 30.0]);
+    for _ in 0 .. 10 {
+        h.winch_status(4);
+        for id in 0 .. 4 { h.winch_status(id); }
+        h.step();
+    }
+    assert_eq!(h.config().winches.len(), 5);
+    assert_eq!(h.controller.state.winch_lighting(h.config()).len(), 5);
+    assert_eq!(h.config().mode, ControllerMode::Normal);
+
+    // Statuses still in flight from the removed winch are ignored
+    remove_winch(&mut h, 4);
+    for _ in 0 .. 10 {
+        for id in 0 .. 5 { h.winch_status(id); }
+        h.step();
+    }
+    assert_eq!(h.config().winches.len(), 4);
+    assert_eq!(h.controller.state.winch_lighting(h.config()).len(), 4);
+    assert_eq!(h.config().mode, ControllerMode::Normal);
+}
+
+#[test]
+fn silent_new_winch_halts_motion() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    for id in 0 .. 4 { h.winch_status(id); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    h.step();
+    add_winch(&mut h, [0.0, 25.0, 30.0]);
+    for id in 0 .. 4 { h.winch_status(id); }
+    assert_eq!(h.config().mode, ControllerMode::Halted);
+}
+
+#[test]
+fn lossy_winch_link_halts_motion() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    let num_winches = h.config().winches.len();
+    let mut network = NetworkMonitor::new(num_winches, h.clock.now());
+    for id in 0 .. num_winches { h.winch_status(id); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+
+    // Winch 2 loses every other status, arriving often enough for the watchdog's deadline
+    let mut reports = 0;
+    for tick in 0 .. TICK_HZ * 3 / 2 {
+        h.step();
+        for id in 0 .. num_winches {
+            if id == 2 && tick % 2 == 0 {
+                h.winch_tick_counters[id] += 1;
+                continue;
+            }
+            h.winch_status(id);
+            network.sequence(BotModule::Winch(id), h.winch_tick_counters[id]);
+        }
+        let now = h.clock.now();
+        if network.report_due(now) {
+            assert_eq!(h.config().mode, ControllerMode::Normal);
+            let status = network.report(0.0, 0.0, now);
+            assert_near(status.winches[2].loss, 0.5, 0.01);
+            assert_eq!(status.winches[1].loss, 0.0);
+            h.send(Message::NetworkStatus(status));
+            reports += 1;
+        }
+    }
+    assert_eq!(reports, 1);
+    assert_eq!(h.config().mode, ControllerMode::Halted);
+}
+
+fn subsystem_fault(subsystem: &str, critical: bool) -> Message {
+    Message::SubsystemFault(SubsystemFault {
+        subsystem: subsystem.into(),
+        error: "Panicked".into(),
+        failures: 1,
+        critical,
+    })
+}
+
+fn controller_faults(messages: &[Message]) -> Vec<u32> {
+    messages.iter().filter_map(|msg| match msg {
+        &Message::SubsystemFault(ref fault) if fault.subsystem == "Controller" => Some(fault.failures),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn critical_fault_halts_motion() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    for id in 0 .. 4 { h.winch_status(id); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    h.step();
+    h.send(subsystem_fault("Metrics", false));
+    h.step();
+    assert_eq!(h.config().mode, ControllerMode::Normal);
+    h.send(subsystem_fault("BotReceiver", true));
+    assert_eq!(h.config().mode, ControllerMode::Halted);
+}
+
+#[test]
+fn supervised_critical_failure_halts_motion() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    for id in 0 .. 4 { h.winch_status(id); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    h.step();
+    h.messages();
+
+    let mut runs = 0;
+    supervisor::spawn("Test subsystem", true, &h.controller.port(), move || {
+        runs += 1;
+        if runs == 1 { panic!("First run fails") }
+        Ok(())
+    });
+    let input = h.controller.recv.recv_timeout(Duration::from_secs(5)).unwrap();
+    let now = h.clock.now();
+    h.controller.handle_input(input, &h.gimbal_port, now);
+
+    assert_eq!(h.config().mode, ControllerMode::Halted);
+    let faults : Vec<SubsystemFault> = h.messages().into_iter().filter_map(|msg| match msg {
+        Message::SubsystemFault(fault) => Some(fault),
+        _ => None,
+    }).collect();
+    assert_eq!(faults, vec![ SubsystemFault {
+        subsystem: "Test subsystem".into(),
+        error: "First run fails".into(),
+        failures: 1,
+        critical: true,
+    }]);
+}
+
+#[test]
+fn controller_panic_halts_and_limits_reports() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    h.messages();
+    h.controller.poll_failed("Panicked".into());
+    assert_eq!(h.config().mode, ControllerMode::Halted);
+    let messages = h.messages();
+    assert_eq!(controller_faults(&messages), vec![ 1 ]);
+    assert!(messages.iter().any(|msg| match msg {
+        &Message::ConfigIsCurrent(ref config) => config.mode == ControllerMode::Halted,
+        _ => false,
+    }));
+
+    // Failing again and again only reports once per interval, with the running count
+    h.controller.poll_failed("Panicked".into());
+    assert_eq!(controller_faults(&h.messages()), vec![]);
+    h.clock.advance(Duration::from_millis(FAULT_REPORT_INTERVAL_MILLIS));
+    h.controller.poll_failed("Panicked".into());
+    assert_eq!(controller_faults(&h.messages()), vec![ 3 ]);
+    assert_eq!(h.config().mode, ControllerMode::Halted);
+}
+
+#[test]
+fn gimbal_holds_steady_without_current_errors() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    let mut statuses = Vec::new();
+    for _ in 0 .. 50 {
+        h.gimbal_values([200, -100], 1000);
+        statuses.extend(gimbal_statuses(&h.step()));
+    }
+    let status = statuses.last().unwrap();
+    assert_eq!(status.current_error_duration, 0.0);
+    assert_eq!(status.motor_power, [true; 3]);
+}
+
+#[test]
+fn gimbal_rehomes_after_current_oscillation() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    let rehome_after = h.config().gimbal.error_duration_for_rehome;
+    let mut statuses = Vec::new();
+    let mut current = 10000;
+    for _ in 0 .. 50 {
+        current = -current;
+        h.gimbal_values([200, -100], current);
+        statuses.extend(gimbal_statuses(&h.step()));
+    }
+
+    let status = statuses.last().unwrap();
+    assert!(status.current_error_duration > rehome_after);
+    assert_eq!(status.motor_power, [true; 3]);
+    assert_eq!(status.hold_angles, [0, 0]);
+    assert_eq!(status.hold_active, [true, true]);
+    // Rates drive both axes back toward center
+    assert!(status.rates[0] < 0);
+    assert!(status.rates[1] > 0);
+}
+
+#[test]
+fn gimbal_tuning_step_respects_endstops() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    let angle = 5;
+    for _ in 0 .. 10 {
+        h.gimbal_values([0, angle], 1000);
+        h.step();
+    }
+    h.send(Message::Command(Command::GimbalTuning(GimbalTuningCommand::Start(GimbalTuningAxis::Pitch))));
+
+    // Close to the upper pitch limit, the step forward must slow down like any other input
+    let g = h.config().gimbal.clone();
+    let speed_limit = ((g.pitch_limits.1 - angle) as f32 / g.limiter_slowdown_extent[1] * g.max_rate).round() as i16;
+    assert!(speed_limit < g.tuning.step_rate as i16);
+    let mut statuses = Vec::new();
+    for _ in 0 .. 2 * TICK_HZ {
+        h.gimbal_values([0, angle], 1000);
+        statuses.extend(gimbal_statuses(&h.step()));
+    }
+    let forward = statuses.iter().map(|status| status.rates[1]).max().unwrap();
+    assert_eq!(forward, speed_limit);
+    assert_eq!(h.controller.gimbal_ctrl.tuning_status().unwrap().error, None);
+}
+
+/// One tick in Normal mode with the gimbal reading the given angles, keeping the winch watchdog fed
+fn gimbal_angle_step(h: &mut Harness, angles: [i16; 2]) -> GimbalControlStatus {
+    let num_winches = h.config().winches.len();
+    for id in 0 .. num_winches { h.winch_status(id); }
+    h.gimbal_values(angles, 1000);
+    gimbal_statuses(&h.step()).pop().unwrap()
+}
+
+fn angle_target(angles: [i16; 2], max_rate: f32) -> GimbalAngleTarget {
+    GimbalAngleTarget { angles, max_rate, settle_tolerance: 5 }
+}
+
+#[test]
+fn gimbal_angle_target_clamps_to_limits() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    h.send(Message::Command(Command::GimbalSetAngle(angle_target([5000, -5000], 100.0))));
+    let status = gimbal_angle_step(&mut h, [0, 0]);
+    let angle_control = status.angle_control.unwrap();
+    assert_eq!(angle_control.target.angles, [1710, -870]);
+    assert_eq!(angle_control.setpoint, [1710.0, -870.0]);
+    assert!(!angle_control.settled);
+    assert_eq!(status.hold_angles, [1710, -870]);
+}
+
+#[test]
+fn gimbal_angle_target_caps_control_rate() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    h.send(Message::Command(Command::GimbalSetAngle(angle_target([1000, 0], 20.0))));
+    for _ in 0 .. 10 {
+        let status = gimbal_angle_step(&mut h, [0, 0]);
+        // Dithering may round up by one count
+        assert!(status.rates[0] > 0 && status.rates[0] <= 21);
+        assert!(status.rates[1].abs() <= 1);
+    }
+}
+
+#[test]
+fn gimbal_slew_moves_setpoint_at_speed_until_arrival() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    let speed = 10.0 * TICK_HZ as f32;
+    h.send(Message::Command(Command::GimbalSlewTo(GimbalSlew { target: angle_target([200, 0], 100.0), speed })));
+
+    // The slew starts from the current angle and moves 10 counts per tick
+    for &expected in [0.0, 10.0, 20.0].iter() {
+        let angle_control = gimbal_angle_step(&mut h, [0, 0]).angle_control.unwrap();
+        assert_eq!(angle_control.setpoint, [expected, 0.0]);
+        assert!(!angle_control.settled);
+    }
+
+    // Setpoint reaches the target first, then arrival waits on the measured angles
+    for _ in 0 .. 20 {
+        gimbal_angle_step(&mut h, [100, 0]);
+    }
+    let angle_control = gimbal_angle_step(&mut h, [100, 0]).angle_control.unwrap();
+    assert_eq!(angle_control.setpoint, [200.0, 0.0]);
+    assert!(!angle_control.settled);
+    let angle_control = gimbal_angle_step(&mut h, [196, 2]).angle_control.unwrap();
+    assert!(angle_control.settled);
+}
+
+#[test]
+fn gimbal_angle_target_cancelled_by_halt() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    h.send(Message::Command(Command::GimbalSetAngle(angle_target([300, -200], 100.0))));
+    assert!(gimbal_angle_step(&mut h, [0, 0]).angle_control.is_some());
+
+    h.send(Message::Command(Command::SetMode(ControllerMode::Halted)));
+    assert_eq!(gimbal_angle_step(&mut h, [0, 0]).angle_control, None);
+
+    // Going back to Normal doesn't bring the old target back
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    assert_eq!(gimbal_angle_step(&mut h, [0, 0]).angle_control, None);
+}
+
+#[test]
+fn gimbal_look_at_falls_back_to_tracking_without_pose() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    h.controller.local_config.gimbal.look_at.point = Some([1.0, 2.0, 0.0]);
+    assert!(h.controller.state.flyer_pose().is_none());
+
+    // A tracked region past the right edge of the border steers yaw, which it only does
+    // when there's no hold target
+    h.controller.state.tracked.rect = [0.9, -0.1, 0.2, 0.2];
+    let status = gimbal_angle_step(&mut h, [100, -50]);
+    assert_eq!(status.look_at, None);
+    assert!(status.tracking_p_rates[0] < 0.0);
+}
+
+#[test]
+fn gimbal_calibration_runs_through_each_phase() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    start_gyro_drift_calibration(&mut h);
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    h.send(gimbal_reply(cmd::CAPTURE_GYRO_DRIFT_COMPENSATION, Vec::new()));
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    assert_eq!(h.gimbal_calibration().phase, GimbalCalibrationPhase::Verifying);
+
+    for &index in GYRO_DRIFTS.iter() {
+        h.gimbal_value(target::IMU_ADJACENT, index, -3);
+    }
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    let status = h.gimbal_calibration();
+    assert_eq!(status.phase, GimbalCalibrationPhase::Saving);
+    assert_eq!(status.values.len(), 3);
+    for value in status.values.iter() {
+        assert_eq!(value.before, Some(10));
+        assert_eq!(value.after, Some(-3));
+    }
+
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    // An ack for some other MCU doesn't finish the save
+    h.send(gimbal_reply(cmd::SAVE_VALUES, vec![target::YAW]));
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    assert_eq!(h.gimbal_calibration().phase, GimbalCalibrationPhase::Saving);
+    h.send(gimbal_reply(cmd::SAVE_VALUES, vec![target::IMU_ADJACENT]));
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    let status = h.gimbal_calibration();
+    assert_eq!(status.phase, GimbalCalibrationPhase::Finished);
+    assert_eq!(status.error, None);
+}
+
+#[test]
+fn gimbal_calibration_fails_on_unchanged_gyro_drift() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    start_gyro_drift_calibration(&mut h);
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    h.send(gimbal_reply(cmd::CAPTURE_GYRO_DRIFT_COMPENSATION, Vec::new()));
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    assert_eq!(h.gimbal_calibration().phase, GimbalCalibrationPhase::Verifying);
+
+    // Acknowledged, but the readback matches what was there before
+    for &index in GYRO_DRIFTS.iter() {
+        h.gimbal_value(target::IMU_ADJACENT, index, 10);
+    }
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    let status = h.gimbal_calibration();
+    assert_eq!(status.phase, GimbalCalibrationPhase::Failed);
+    assert_eq!(status.error, Some("Gyro drift reads back unchanged, the IMU didn't capture new values".into()));
+}
+
+#[test]
+fn gimbal_calibration_retries_lost_capture() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    start_gyro_drift_calibration(&mut h);
+    // Past two resends, each 3 seconds after the last
+    for _ in 0 .. TICK_HZ * 8 {
+        h.gimbal_values_unpowered([0, 0]);
+        h.step();
+    }
+    assert_eq!(h.gimbal_calibration().phase, GimbalCalibrationPhase::Capturing);
+    h.send(gimbal_reply(cmd::CAPTURE_GYRO_DRIFT_COMPENSATION, Vec::new()));
+    h.gimbal_values_unpowered([0, 0]);
+    h.step();
+    assert_eq!(h.gimbal_calibration().phase, GimbalCalibrationPhase::Verifying);
+}
+
+#[test]
+fn gimbal_calibration_fails_after_unacknowledged_captures() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    start_gyro_drift_calibration(&mut h);
+    // The third send goes unanswered at 9 seconds, before the 10 second phase timeout
+    for _ in 0 .. TICK_HZ * 19 / 2 {
+        h.gimbal_values_unpowered([0, 0]);
+        h.step();
+    }
+    let status = h.gimbal_calibration();
+    assert_eq!(status.phase, GimbalCalibrationPhase::Failed);
+    assert_eq!(status.error, Some(format!("No acknowledgement from gimbal MCU {} during Capturing, after 3 attempts", target::IMU_ADJACENT)));
+}
+
+#[test]
+fn gimbal_calibration_refuses_powered_motors() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    h.send(Message::Command(Command::GimbalCalibration(GimbalCalibrationCommand::Start(GimbalCalibrationProcedure::GyroDrift))));
+    h.gimbal_values([0, 0], 0);
+    h.step();
+    let status = h.gimbal_calibration();
+    assert_eq!(status.phase, GimbalCalibrationPhase::Failed);
+    assert_eq!(status.error, Some("Turn off the gimbal motors before calibrating".into()));
+}
+
+#[test]
+fn gimbal_calibration_aborts_when_motors_power_on() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    start_gyro_drift_calibration(&mut h);
+    h.gimbal_values([0, 0], 0);
+    h.step();
+    let status = h.gimbal_calibration();
+    assert_eq!(status.phase, GimbalCalibrationPhase::Failed);
+    assert_eq!(status.error, Some("Gimbal motors must stay off during calibration".into()));
+}
+
+#[test]
+fn tracking_snaps_to_detected_object() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    h.step();
+    h.send(Message::Command(detection("cat", 0.9, CAT_RECT)));
+    let snapped = h.messages().into_iter().any(|msg| msg == Message::CameraInitTrackedRegion(CAT_RECT));
+    assert!(snapped);
+    assert_eq!(h.controller.state.tracked.rect, CAT_RECT);
+}
+
+#[test]
+fn tracking_ignores_unlisted_objects() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    let initial_rect = h.controller.state.tracked.rect;
+    h.send(Message::Command(detection("cat", 0.1, CAT_RECT)));
+    h.send(Message::Command(detection("toaster", 1.0, CAT_RECT)));
+    h.step();
+    assert_eq!(h.controller.state.tracked.rect, initial_rect);
+}
+
+#[test]
+fn tracking_does_not_snap_while_halted() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    let initial_rect = h.controller.state.tracked.rect;
+    h.send(Message::Command(detection("cat", 0.9, CAT_RECT)));
+    for _ in 0 .. 10 { h.step(); }
+    assert_eq!(h.controller.state.tracked.rect, initial_rect);
+}
+
+#[test]
+fn tracking_snaps_to_pending_detection_after_halt() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    h.send(Message::Command(detection("cat", 0.9, CAT_RECT)));
+    for _ in 0 .. TICK_HZ / 10 { h.step(); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    h.step();
+    assert_eq!(h.controller.state.tracked.rect, CAT_RECT);
+}
+
+#[test]
+fn tracking_ignores_stale_detection() {
+    let mut h = Harness::new(ControllerMode::Halted);
+    let initial_rect = h.controller.state.tracked.rect;
+    h.send(Message::Command(detection("cat", 0.9, CAT_RECT)));
+    // Detections older than half a second are too old to snap to
+    for _ in 0 .. TICK_HZ * 6 / 10 { h.step(); }
+    h.send(Message::Command(Command::SetMode(ControllerMode::Normal)));
+    h.step();
+    assert_eq!(h.controller.state.tracked.rect, initial_rect);
+}
+
+#[test]
+fn manual_camera_control_overrides_snap() {
+    let mut h = Harness::new(ControllerMode::Normal);
+    h.send(Message::Command(Command::ManualControlValue(ManualControlAxis::CameraYaw, 1.0)));
+    h.step();
+    h.send(Message::Command(detection("cat", 0.9, CAT_RECT)));
+    h.step();
+    assert!(h.controller.state.tracked.rect != CAT_RECT);
+
+    // Once the operator lets go, control returns to vision after the timeout
+    h.send(Message::Command(Command::ManualControlReset));
+    let timeout = h.config().vision.manual_control_timeout_sec;
+    for _ in 0 .. (timeout * TICK_HZ as f32) as u32 + 2 { h.step(); }
+    h.send(Message::Command(detection("cat", 0.9, CAT_RECT)));
+    assert_eq!(h.controller.state.tracked.rect, CAT_RECT);
+}
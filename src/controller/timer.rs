@@ -3,6 +3,7 @@ use config::Config;
 use std::time::{Duration, Instant};
 use message::TICK_HZ;
 use overlay::OVERLAY_HZ;
+use controller::clock::Clock;
 
 pub struct ControllerTimers {
     pub tick: IntervalTimer,
@@ -10,10 +11,10 @@ pub struct ControllerTimers {
 }
 
 impl ControllerTimers {
-    pub fn new() -> ControllerTimers {
+    pub fn new(now: Instant) -> ControllerTimers {
         ControllerTimers {
-            tick: IntervalTimer::new(TICK_HZ),
-            video_frame: IntervalTimer::new(OVERLAY_HZ),
+            tick: IntervalTimer::new(TICK_HZ, now),
+            video_frame: IntervalTimer::new(OVERLAY_HZ, now),
         }
     }
 }
@@ -24,16 +25,15 @@ pub struct IntervalTimer {
 }
 
 impl IntervalTimer {
-    pub fn new(hz: u32) -> IntervalTimer {
+    pub fn new(hz: u32, now: Instant) -> IntervalTimer {
         IntervalTimer {
             period: Duration::new(0, 1000000000 / hz),
-            timestamp: Instant::now(),
+            timestamp: now,
         }
     }
 
-    pub fn poll(&mut self) -> bool {
-        let now = Instant::now();
-        if now > self.timestamp + self.period {
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if now >= self.timestamp + self.period {
             self.timestamp = now;
             true
         } else {
@@ -48,20 +48,20 @@ pub struct ConfigScheduler {
 }
 
 impl ConfigScheduler {
-    pub fn new() -> ConfigScheduler {
+    pub fn new(clock: &Clock) -> ConfigScheduler {
         ConfigScheduler {
-            last_poll_instant: Instant::now(),
-            last_poll_time: Local::now().time(),
+            last_poll_instant: clock.now(),
+            last_poll_time: clock.local_time(),
         }
     }
 
-    pub fn poll(&mut self, config: &mut Config) -> bool {
-        let timestamp = Instant::now();
+    pub fn poll(&mut self, config: &mut Config, clock: &Clock) -> bool {
+        let timestamp = clock.now();
         let poll_interval = Duration::new(1, 0);
         let mut changes = false;
         if timestamp > self.last_poll_instant + poll_interval {
             let last_poll_time = self.last_poll_time;
-            let time = Local::now().time();
+            let time = clock.local_time();
             if DailyPollInterval::new(last_poll_time, time).poll_config_changes(config) {
                 changes = true;
             }
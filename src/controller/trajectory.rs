@@ -0,0 +1,269 @@
+//! Playback of uploaded flyer trajectories, for repeatable camera moves
+
+use message::*;
+use vecmath::*;
+use config::Config;
+use controller::velocity::RateLimitedVelocity;
+use fygimbal::util::encoder_sub;
+
+pub struct TrajectoryPlayer {
+    trajectory: Option<Trajectory>,
+    path: Vec<TrajectoryWaypoint>,
+    tangents: Vec<Vector3<f32>>,
+    elapsed: f32,
+    velocity: RateLimitedVelocity,
+    status: Option<TrajectoryStatus>,
+}
+
+impl TrajectoryPlayer {
+    pub fn new() -> TrajectoryPlayer {
+        TrajectoryPlayer {
+            trajectory: None,
+            path: Vec::new(),
+            tangents: Vec::new(),
+            elapsed: 0.0,
+            velocity: RateLimitedVelocity::new(),
+            status: None,
+        }
+    }
+
+    pub fn upload(&mut self, trajectory: Trajectory) {
+        self.trajectory = Some(trajectory);
+        self.stop();
+    }
+
+    /// Begin playback from the flyer's current position
+    pub fn start(&mut self, position: Vector3<f32>) {
+        self.stop();
+        if let Some(ref trajectory) = self.trajectory {
+            if trajectory.waypoints[0].time > 0.0 {
+                // Leave from wherever we are now, arriving at the first waypoint on time
+                self.path.push(TrajectoryWaypoint {
+                    time: 0.0,
+                    position,
+                    gimbal_angles: None,
+                });
+            }
+            self.path.extend(trajectory.waypoints.iter().cloned());
+        }
+        self.tangents = path_tangents(&self.path);
+    }
+
+    pub fn stop(&mut self) {
+        self.path.clear();
+        self.tangents.clear();
+        self.elapsed = 0.0;
+        self.velocity = RateLimitedVelocity::new();
+        self.status = None;
+    }
+
+    pub fn velocity(&self) -> Vector3<f32> {
+        self.velocity.get()
+    }
+
+    pub fn status(&self) -> Option<TrajectoryStatus> {
+        self.status.clone()
+    }
+
+    pub fn gimbal_target(&self) -> Option<Vector2<i16>> {
+        match self.status {
+            None => None,
+            Some(ref status) => status.gimbal_target,
+        }
+    }
+
+    /// Advance playback by one tick. Position feedback is only used when we have a pose estimate.
+    pub fn tick(&mut self, config: &Config, position: Option<Vector3<f32>>) {
+        if self.path.is_empty() {
+            return;
+        }
+
+        let duration = self.path[self.path.len() - 1].time;
+        let (target_position, target_velocity, gimbal_target) = self.sample(self.elapsed);
+
+        let feedback = match position {
+            None => [0.0; 3],
+            Some(position) => vec3_scale(vec3_sub(target_position, position), config.params.trajectory_position_gain),
+        };
+        let v = vec3_add(target_velocity, feedback);
+        let v_len = vec3_len(v);
+        let v_limit = config.params.trajectory_velocity_limit_m_per_sec;
+        let v = if v_len > v_limit { vec3_scale(v, v_limit / v_len) } else { v };
+        self.velocity.tick_jerk_limited(config, v);
+
+        self.status = Some(TrajectoryStatus {
+            elapsed: self.elapsed,
+            duration,
+            target_position,
+            target_velocity,
+            gimbal_target,
+            is_complete: self.elapsed >= duration,
+        });
+
+        self.elapsed = (self.elapsed + 1.0 / (TICK_HZ as f32)).min(duration);
+    }
+
+    fn sample(&self, time: f32) -> (Vector3<f32>, Vector3<f32>, Option<Vector2<i16>>) {
+        let last = self.path.len() - 1;
+        if self.path.len() == 1 || time >= self.path[last].time {
+            return (self.path[last].position, [0.0; 3], last_gimbal_angles(&self.path));
+        }
+
+        let mut index = 0;
+        while index + 1 < last && time >= self.path[index + 1].time {
+            index += 1;
+        }
+        let (p0, p1) = (&self.path[index], &self.path[index + 1]);
+        let h = p1.time - p0.time;
+        let s = ((time - p0.time) / h).max(0.0).min(1.0);
+        let (position, velocity) = hermite(p0.position, self.tangents[index], p1.position, self.tangents[index + 1], h, s);
+
+        let gimbal_angles = match (p0.gimbal_angles, p1.gimbal_angles) {
+            (Some(a0), Some(a1)) => Some([
+                a0[0].wrapping_add((encoder_sub(a1[0], a0[0]) as f32 * s).round() as i16),
+                a0[1].wrapping_add((encoder_sub(a1[1], a0[1]) as f32 * s).round() as i16),
+            ]),
+            (None, Some(a1)) => Some(a1),
+            (a0, None) => a0,
+        };
+
+        (position, velocity, gimbal_angles)
+    }
+}
+
+fn last_gimbal_angles(path: &Vec<TrajectoryWaypoint>) -> Option<Vector2<i16>> {
+    path.iter().rev().filter_map(|waypoint| waypoint.gimbal_angles).next()
+}
+
+fn path_tangents(path: &Vec<TrajectoryWaypoint>) -> Vec<Vector3<f32>> {
+    // Catmull-Rom tangents for non-uniform times, starting and ending at rest
+    (0 .. path.len()).map(|index| {
+        if index == 0 || index + 1 >= path.len() {
+            [0.0; 3]
+        } else {
+            let prev = &path[index - 1];
+            let next = &path[index + 1];
+            vec3_scale(vec3_sub(next.position, prev.position), 1.0 / (next.time - prev.time))
+        }
+    }).collect()
+}
+
+fn hermite(p0: Vector3<f32>, m0: Vector3<f32>, p1: Vector3<f32>, m1: Vector3<f32>, h: f32, s: f32) -> (Vector3<f32>, Vector3<f32>) {
+    // Cubic Hermite segment over an interval of length h, at normalized time s.
+    // Returns position and its time derivative.
+    let (s2, s3) = (s * s, s * s * s);
+    let position = vec3_add(
+        vec3_add(vec3_scale(p0, 2.0*s3 - 3.0*s2 + 1.0), vec3_scale(m0, h * (s3 - 2.0*s2 + s))),
+        vec3_add(vec3_scale(p1, -2.0*s3 + 3.0*s2), vec3_scale(m1, h * (s3 - s2))));
+    let velocity = vec3_add(
+        vec3_add(vec3_scale(p0, (6.0*s2 - 6.0*s) / h), vec3_scale(m0, 3.0*s2 - 4.0*s + 1.0)),
+        vec3_add(vec3_scale(p1, (-6.0*s2 + 6.0*s) / h), vec3_scale(m1, 3.0*s2 - 2.0*s)));
+    (position, velocity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::{test_config, assert_near};
+
+    fn waypoint(time: f32, position: Vector3<f32>, gimbal_angles: Option<Vector2<i16>>) -> TrajectoryWaypoint {
+        TrajectoryWaypoint { time, position, gimbal_angles }
+    }
+
+    #[test]
+    fn hermite_endpoints() {
+        let (p0, m0, p1, m1) = ([1.0, 2.0, 3.0], [0.5, 0.0, -1.0], [4.0, 0.0, 3.0], [0.0, 2.0, 0.0]);
+        assert_eq!(hermite(p0, m0, p1, m1, 2.0, 0.0), (p0, m0));
+        assert_eq!(hermite(p0, m0, p1, m1, 2.0, 1.0), (p1, m1));
+    }
+
+    #[test]
+    fn hermite_reproduces_a_line() {
+        // Tangents matching the chord slope make the cubic terms cancel
+        let (position, velocity) = hermite([0.0; 3], [1.0, 0.0, 0.0], [2.0, 0.0, 0.0], [1.0, 0.0, 0.0], 2.0, 0.5);
+        assert_near(position[0], 1.0, 1e-6);
+        assert_near(velocity[0], 1.0, 1e-6);
+        let (position, velocity) = hermite([0.0; 3], [1.0, 0.0, 0.0], [2.0, 0.0, 0.0], [1.0, 0.0, 0.0], 2.0, 0.25);
+        assert_near(position[0], 0.5, 1e-6);
+        assert_near(velocity[0], 1.0, 1e-6);
+    }
+
+    #[test]
+    fn hermite_rest_to_rest() {
+        // Smoothstep: halfway at the midpoint, where speed peaks at 3/2 the average
+        let (position, velocity) = hermite([0.0; 3], [0.0; 3], [0.0, 0.0, 4.0], [0.0; 3], 2.0, 0.5);
+        assert_near(position[2], 2.0, 1e-6);
+        assert_near(velocity[2], 3.0, 1e-6);
+    }
+
+    #[test]
+    fn catmull_rom_tangents() {
+        let path = vec![
+            waypoint(0.0, [0.0; 3], None),
+            waypoint(1.0, [1.0, 0.0, 0.0], None),
+            waypoint(3.0, [5.0, 3.0, 0.0], None),
+        ];
+        let tangents = path_tangents(&path);
+        assert_eq!(tangents[0], [0.0; 3]);
+        assert_near(tangents[1][0], 5.0 / 3.0, 1e-6);
+        assert_near(tangents[1][1], 1.0, 1e-6);
+        assert_eq!(tangents[2], [0.0; 3]);
+    }
+
+    #[test]
+    fn sample_interpolates_position_and_gimbal() {
+        let mut player = TrajectoryPlayer::new();
+        player.upload(Trajectory { waypoints: vec![
+            waypoint(0.0, [0.0, 0.0, 5.0], Some([0, 100])),
+            waypoint(2.0, [2.0, 0.0, 5.0], Some([1000, -100])),
+        ]});
+        player.start([9.0, 9.0, 9.0]);
+
+        let (position, velocity, gimbal) = player.sample(1.0);
+        assert_near(position[0], 1.0, 1e-6);
+        assert_near(position[2], 5.0, 1e-6);
+        assert_near(velocity[0], 1.5, 1e-6);
+        assert_eq!(gimbal, Some([500, 0]));
+
+        let (position, velocity, gimbal) = player.sample(10.0);
+        assert_eq!(position, [2.0, 0.0, 5.0]);
+        assert_eq!(velocity, [0.0; 3]);
+        assert_eq!(gimbal, Some([1000, -100]));
+    }
+
+    #[test]
+    fn gimbal_takes_the_short_way_around() {
+        let mut player = TrajectoryPlayer::new();
+        player.upload(Trajectory { waypoints: vec![
+            waypoint(0.0, [0.0; 3], Some([2000, 0])),
+            waypoint(1.0, [0.0; 3], Some([-2000, 0])),
+        ]});
+        player.start([0.0; 3]);
+        // 2000 to -2000 is 96 counts forward through the wrap at 2048, not 4000 back
+        assert_eq!(player.sample(0.5).2, Some([2048, 0]));
+    }
+
+    #[test]
+    fn start_leaves_from_current_position() {
+        let config = test_config(ControllerMode::Halted);
+        let mut player = TrajectoryPlayer::new();
+        player.upload(Trajectory { waypoints: vec![ waypoint(2.0, [0.0, 0.0, 5.0], None) ]});
+        player.start([4.0, 0.0, 5.0]);
+        assert_eq!(player.sample(0.0).0, [4.0, 0.0, 5.0]);
+
+        player.tick(&config, None);
+        let status = player.status().unwrap();
+        assert_eq!(status.elapsed, 0.0);
+        assert_eq!(status.duration, 2.0);
+        assert!(!status.is_complete);
+
+        // A few extra ticks, so rounding in the accumulated time can't leave us just short
+        for _ in 0 .. 2 * TICK_HZ + 10 {
+            player.tick(&config, None);
+        }
+        let status = player.status().unwrap();
+        assert!(status.is_complete);
+        assert_eq!(status.target_position, [0.0, 0.0, 5.0]);
+    }
+}
@@ -3,13 +3,15 @@ use vecmath::*;
 use config::Config;
 
 pub struct RateLimitedVelocity {
-    vec: Vector3<f32>
+    vec: Vector3<f32>,
+    accel: Vector3<f32>,
 }
 
 impl RateLimitedVelocity {
     pub fn new() -> RateLimitedVelocity {
         RateLimitedVelocity {
-            vec: [0.0, 0.0, 0.0]
+            vec: [0.0, 0.0, 0.0],
+            accel: [0.0, 0.0, 0.0],
         }
     }
 
@@ -24,9 +26,97 @@ impl RateLimitedVelocity {
             diff
         };
         self.vec = vec3_add(self.vec, clipped);
+        self.accel = vec3_scale(clipped, 1.0 / dt);
+    }
+
+    pub fn tick_jerk_limited(self: &mut RateLimitedVelocity, config: &Config, target: Vector3<f32>) {
+        // Acceleration slews at the jerk limit, and it's also kept low enough
+        // that it can ramp back to zero by the time we reach the target velocity.
+        let dt = 1.0 / (TICK_HZ as f32);
+        let jerk_limit = config.params.jerk_limit_m_per_sec3;
+        let diff = vec3_sub(target, self.vec);
+        let len = vec3_len(diff);
+        let accel_target = if len > 0.0 {
+            let accel_len = config.params.accel_limit_m_per_sec2.min((2.0 * jerk_limit * len).sqrt());
+            vec3_scale(diff, accel_len / len)
+        } else {
+            [0.0, 0.0, 0.0]
+        };
+
+        let accel_diff = vec3_sub(accel_target, self.accel);
+        let accel_diff_len = vec3_len(accel_diff);
+        let jerk_per_tick = jerk_limit * dt;
+        let accel_step = if accel_diff_len > jerk_per_tick {
+            vec3_scale(accel_diff, jerk_per_tick / accel_diff_len)
+        } else {
+            accel_diff
+        };
+        self.accel = vec3_add(self.accel, accel_step);
+
+        let step = vec3_scale(self.accel, dt);
+        if vec3_len(step) >= len {
+            // Arrive exactly, without overshooting
+            self.vec = target;
+            self.accel = [0.0, 0.0, 0.0];
+        } else {
+            self.vec = vec3_add(self.vec, step);
+        }
     }
 
     pub fn get(self: &RateLimitedVelocity) -> Vector3<f32> {
         self.vec
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::{test_config, assert_near};
+
+    #[test]
+    fn jerk_limited_first_tick() {
+        // Acceleration starts from zero, so it can only grow by one tick of jerk
+        let config = test_config(ControllerMode::Halted);
+        let mut velocity = RateLimitedVelocity::new();
+        velocity.tick_jerk_limited(&config, [1.0, 0.0, 0.0]);
+        let dt = 1.0 / (TICK_HZ as f32);
+        assert_near(velocity.accel[0], config.params.jerk_limit_m_per_sec3 * dt, 1e-7);
+        assert_near(velocity.get()[0], config.params.jerk_limit_m_per_sec3 * dt * dt, 1e-9);
+        assert_eq!(velocity.get()[1], 0.0);
+        assert_eq!(velocity.get()[2], 0.0);
+    }
+
+    #[test]
+    fn jerk_limited_reaches_target_within_limits() {
+        let config = test_config(ControllerMode::Halted);
+        let dt = 1.0 / (TICK_HZ as f32);
+        let jerk_per_tick = config.params.jerk_limit_m_per_sec3 * dt;
+        let accel_limit = config.params.accel_limit_m_per_sec2;
+        let mut velocity = RateLimitedVelocity::new();
+        let mut previous = velocity.get();
+        let mut previous_accel = velocity.accel;
+        let mut ticks = 0;
+
+        while velocity.get() != [1.0, 0.0, 0.0] {
+            velocity.tick_jerk_limited(&config, [1.0, 0.0, 0.0]);
+            ticks += 1;
+            assert!(ticks < 3 * TICK_HZ, "Never arrived, at {:?}", velocity.get());
+
+            let v = velocity.get();
+            assert!(v[0] >= previous[0] && v[0] <= 1.0, "Overshot or reversed, {:?}", v);
+            assert!(vec3_len(velocity.accel) <= accel_limit + 1e-6);
+            if v != [1.0, 0.0, 0.0] {
+                assert!(vec3_len(vec3_sub(velocity.accel, previous_accel)) <= jerk_per_tick + 1e-6);
+            }
+            previous = v;
+            previous_accel = velocity.accel;
+        }
+
+        // Ramping up and down at the jerk limit each takes accel/jerk, with a plateau between
+        let ramp_sec = accel_limit / config.params.jerk_limit_m_per_sec3;
+        let expected_sec = 1.0 / accel_limit + ramp_sec;
+        assert_near(ticks as f32 * dt, expected_sec, 0.1);
+        assert_eq!(velocity.accel, [0.0, 0.0, 0.0]);
+    }
+}
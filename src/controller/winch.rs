@@ -8,6 +8,8 @@ pub struct WinchController {
     pub mech_status: MechStatus,
     id: usize,
     last_winch_status: Option<(WinchStatus, Instant)>,
+    /// Packet loss over the last NetworkStatus interval
+    link_loss: f32,
     quantized_position_target: i32,
     fract_position_target: f32,
     pwm_period: f32,
@@ -29,6 +31,7 @@ impl WinchController {
         WinchController {
             id,
             last_winch_status: None,
+            link_loss: 0.0,
             quantized_position_target: 0,
             fract_position_target: 0.0,
             pwm_period: 0.0,
@@ -56,11 +59,11 @@ impl WinchController {
         }
     }
 
-    pub fn update(&mut self, config: &Config, cal: &WinchCalibration, status: &WinchStatus) {
+    pub fn update(&mut self, config: &Config, cal: &WinchCalibration, status: &WinchStatus, now: Instant) {
         if config.mode == ControllerMode::Halted
             || self.was_motor_shutoff(status)
             || self.was_tick_discontinuity(status) {
-            self.reset(status);
+            self.reset(status, now);
         }
 
         let distance_traveled_m = match self.last_winch_status {
@@ -84,7 +87,7 @@ impl WinchController {
         self.pwm_period = self.pwm_period.max(pwm_period_min).min(pwm_period_max);
 
         self.mech_status = MechStatus::new(status);
-        self.last_winch_status = Some((status.clone(), Instant::now()));
+        self.last_winch_status = Some((status.clone(), now));
     }
 
     pub fn make_command(&self, config: &Config, cal: &WinchCalibration, status: &WinchStatus) -> WinchCommand {
@@ -173,17 +176,27 @@ impl WinchController {
         }
     }
 
-    pub fn is_status_recent(&self, config: &Config) -> bool {
+    pub fn is_status_recent(&self, config: &Config, now: Instant) -> bool {
         let deadline = Duration::from_millis(config.params.winch_watchdog_millis);
         match self.last_winch_status {
             None => false,
-            Some((_, timestamp)) => timestamp + deadline > Instant::now(),
+            Some((_, timestamp)) => timestamp + deadline > now,
         }
     }
 
-    fn reset(&mut self, status: &WinchStatus) {
+    pub fn link_status_update(&mut self, link: &LinkStatus) {
+        self.link_loss = link.loss;
+    }
+
+    /// Statuses can keep arriving often enough for the watchdog's deadline on a link
+    /// that's still losing a large share of them
+    pub fn is_link_healthy(&self, config: &Config) -> bool {
+        self.link_loss <= config.params.winch_watchdog_max_loss
+    }
+
+    fn reset(&mut self, status: &WinchStatus, now: Instant) {
         // Initialize assumed winch state from this packet
-        self.last_winch_status = Some((status.clone(), Instant::now()));
+        self.last_winch_status = Some((status.clone(), now));
         self.quantized_position_target = status.sensors.position;
         self.fract_position_target = 0.0;
     }
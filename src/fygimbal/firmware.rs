@@ -0,0 +1,446 @@
+//! Firmware updates through the gimbal's bootloader. Right after power-on each MCU waits
+//! briefly for a request to stay in boot mode; we keep asking until one answers, then write
+//! the image for each MCU in turn. Every request waits for its own acknowledgement and is
+//! sent again if none arrives. NEXT_MCU hands the link off to the following MCU's
+//! bootloader, and on the last MCU it starts the new firmware.
+//!
+//! The block framing here is our own reading of the bootloader, not yet confirmed against
+//! the vendor's updater, so updates only run with gimbal_firmware configured.
+
+use message::*;
+use config::GimbalFirmwareConfig;
+use std::io;
+use std::io::Read;
+use std::fs::File;
+use std::time::{Duration, Instant};
+use fygimbal::framing::{GimbalPacket, GimbalFraming};
+use fygimbal::protocol::{bootloader_cmd, pack, unpack, NUM_AXES};
+
+/// Bytes of firmware image per WRITE_BLOCK packet
+const BLOCK_SIZE : usize = 256;
+
+/// How long to keep asking for boot mode while the operator power cycles the gimbal
+const BOOT_WAIT_MILLIS : u64 = 30000;
+
+const ACK_TIMEOUT_MILLIS : u64 = 200;
+const MAX_ATTEMPTS : u32 = 10;
+
+/// Bootloader protocol versions this was written against
+const BOOTLOADER_VERSIONS : &[u16] = &[ 3 ];
+
+/// What the controller asks of the poller, with the images it already read
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirmwareCommand {
+    Start(Vec<Vec<u8>>),
+    Cancel,
+}
+
+/// Read the configured image for each MCU, refusing unless updates are configured at all
+pub fn load_images(config: Option<&GimbalFirmwareConfig>) -> Result<Vec<Vec<u8>>, String> {
+    let config = match config {
+        Some(config) => config,
+        None => return Err("Gimbal firmware updates aren't enabled in the config".into()),
+    };
+    if config.image_paths.len() != NUM_AXES {
+        return Err(format!("Need {} firmware images, one per MCU", NUM_AXES));
+    }
+    let mut images = Vec::new();
+    for path in config.image_paths.iter() {
+        let mut image = Vec::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_end(&mut image)) {
+            return Err(format!("Can't read firmware image {}, {}", path, e));
+        }
+        if image.is_empty() {
+            return Err(format!("Firmware image {} is empty", path));
+        }
+        images.push(image);
+    }
+    Ok(images)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    InterruptBoot,
+    Version,
+    WriteBlock(usize),
+    NextMcu,
+}
+
+pub struct FirmwareUpdate {
+    images: Vec<Vec<u8>>,
+    stage: Stage,
+    started: Instant,
+    /// When the current request was last sent, and how many times
+    outstanding: Option<(Instant, u32)>,
+    status: GimbalFirmwareStatus,
+}
+
+impl FirmwareUpdate {
+    pub fn new(images: Vec<Vec<u8>>, now: Instant) -> FirmwareUpdate {
+        let total_blocks = block_count(&images[0]);
+        FirmwareUpdate {
+            images,
+            stage: Stage::InterruptBoot,
+            started: now,
+            outstanding: None,
+            status: GimbalFirmwareStatus {
+                phase: GimbalFirmwarePhase::WaitingForBootloader,
+                mcu: 0,
+                bootloader_version: None,
+                block: 0,
+                total_blocks,
+                retries: 0,
+                error: None,
+            },
+        }
+    }
+
+    pub fn status(&self) -> GimbalFirmwareStatus {
+        self.status.clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match self.status.phase {
+            GimbalFirmwarePhase::Finished | GimbalFirmwarePhase::Failed => true,
+            _ => false,
+        }
+    }
+
+    pub fn fail(&mut self, error: String) {
+        self.status.phase = GimbalFirmwarePhase::Failed;
+        self.status.error = Some(error);
+    }
+
+    /// Send the current request if it hasn't been sent yet or its acknowledgement is overdue
+    pub fn poll(&mut self, writer: &mut io::Write, now: Instant) -> io::Result<()> {
+        if self.is_finished() {
+            return Ok(());
+        }
+        let attempts = match self.outstanding {
+            None => 0,
+            Some((timestamp, attempts)) => {
+                if now < timestamp + Duration::from_millis(ACK_TIMEOUT_MILLIS) {
+                    return Ok(());
+                }
+                attempts
+            }
+        };
+
+        if self.stage == Stage::InterruptBoot {
+            if now > self.started + Duration::from_millis(BOOT_WAIT_MILLIS) {
+                self.fail("No answer from the bootloader, power cycle the gimbal after starting an update".into());
+                return Ok(());
+            }
+        } else if attempts >= MAX_ATTEMPTS {
+            self.fail(format!("No acknowledgement from MCU {} after {} attempts", self.status.mcu, attempts));
+            return Ok(());
+        } else if attempts > 0 {
+            self.status.retries += 1;
+        }
+
+        self.request().write_to(writer)?;
+        self.outstanding = Some((now, attempts + 1));
+        Ok(())
+    }
+
+    pub fn packet_received(&mut self, packet: &GimbalPacket) {
+        if packet.framing != GimbalFraming::Bootloader || self.is_finished() {
+            return;
+        }
+        let next = match (self.stage, packet.command) {
+
+            (Stage::InterruptBoot, bootloader_cmd::INTERRUPT_BOOT) => {
+                self.status.phase = GimbalFirmwarePhase::Writing;
+                Stage::Version
+            },
+
+            (Stage::Version, bootloader_cmd::VERSION) => {
+                let version = unpack::bootloader_u16(packet).ok();
+                self.status.bootloader_version = version;
+                match version {
+                    Some(version) if BOOTLOADER_VERSIONS.contains(&version) => Stage::WriteBlock(0),
+                    Some(version) => return self.fail(format!("MCU {} has unsupported bootloader version {}", self.status.mcu, version)),
+                    None => return self.fail(format!("MCU {} didn't report its bootloader version", self.status.mcu)),
+                }
+            },
+
+            (Stage::WriteBlock(block), bootloader_cmd::WRITE_BLOCK_ACK) => {
+                match unpack::bootloader_u16(packet) {
+                    Ok(acked) if acked as usize == block => (),
+                    // Late acknowledgement for an earlier attempt
+                    _ => return,
+                }
+                self.status.block = block + 1;
+                if block + 1 < self.status.total_blocks {
+                    Stage::WriteBlock(block + 1)
+                } else {
+                    Stage::NextMcu
+                }
+            },
+
+            (Stage::NextMcu, bootloader_cmd::NEXT_MCU_ACK) => {
+                let mcu = self.status.mcu as usize + 1;
+                if mcu >= self.images.len() {
+                    self.status.phase = GimbalFirmwarePhase::Finished;
+                    Stage::NextMcu
+                } else {
+                    self.status.mcu = mcu as u8;
+                    self.status.block = 0;
+                    self.status.total_blocks = block_count(&self.images[mcu]);
+                    Stage::Version
+                }
+            },
+
+            _ => return,
+        };
+        self.stage = next;
+        self.outstanding = None;
+    }
+
+    fn request(&self) -> GimbalPacket {
+        let mcu = self.status.mcu;
+        match self.stage {
+            Stage::InterruptBoot => pack::bootloader(mcu, bootloader_cmd::INTERRUPT_BOOT),
+            Stage::Version => pack::bootloader(mcu, bootloader_cmd::VERSION),
+            Stage::NextMcu => pack::bootloader(mcu, bootloader_cmd::NEXT_MCU),
+            Stage::WriteBlock(block) => {
+                let image = &self.images[mcu as usize];
+                let start = block * BLOCK_SIZE;
+                let end = (start + BLOCK_SIZE).min(image.len());
+                pack::write_block(mcu, block as u16, &image[start .. end])
+            },
+        }
+    }
+}
+
+fn block_count(image: &[u8]) -> usize {
+    (image.len() + BLOCK_SIZE - 1) / BLOCK_SIZE
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use simulator::SimGimbal;
+    use fygimbal::PacketReceiver;
+    use fygimbal::protocol::{pack, cmd};
+    use serde_yaml;
+    use std::env;
+    use std::io::Write;
+
+    /// Carries packets between an update and a simulated gimbal, with time standing still
+    /// unless the test moves it
+    struct Link {
+        gimbal: SimGimbal,
+        now: Instant,
+        requests: Vec<GimbalPacket>,
+    }
+
+    impl Link {
+        fn new() -> Link {
+            let config : Config = serde_yaml::from_str(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/config.yaml"))).unwrap();
+            Link {
+                gimbal: SimGimbal::new(&config),
+                now: Instant::now(),
+                requests: Vec::new(),
+            }
+        }
+
+        /// Poll the update once, returning the gimbal's answers without delivering them
+        fn send(&mut self, update: &mut FirmwareUpdate) -> Vec<GimbalPacket> {
+            let mut request_bytes = Vec::new();
+            update.poll(&mut request_bytes, self.now).unwrap();
+            self.requests.extend(decode(&request_bytes));
+            let mut response_bytes = Vec::new();
+            self.gimbal.received(&request_bytes, &mut response_bytes);
+            decode(&response_bytes)
+        }
+
+        fn exchange(&mut self, update: &mut FirmwareUpdate) {
+            for packet in self.send(update) {
+                update.packet_received(&packet);
+            }
+        }
+
+        fn wait_for_ack_timeout(&mut self) {
+            self.now += Duration::from_millis(ACK_TIMEOUT_MILLIS + 1);
+        }
+
+        fn count(&self, command: u8) -> usize {
+            self.requests.iter().filter(|packet| packet.command == command).count()
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<GimbalPacket> {
+        let mut receiver = PacketReceiver::new();
+        receiver.write(bytes).unwrap();
+        receiver.collect()
+    }
+
+    fn images() -> Vec<Vec<u8>> {
+        vec![
+            (0 .. 600).map(|i| i as u8).collect(),
+            vec![0xaa; BLOCK_SIZE],
+            vec![0x55],
+        ]
+    }
+
+    fn start(link: &Link) -> FirmwareUpdate {
+        FirmwareUpdate::new(images(), link.now)
+    }
+
+    #[test]
+    fn writes_every_mcu_in_turn() {
+        let mut link = Link::new();
+        let mut update = start(&link);
+        for _ in 0 .. 20 {
+            link.exchange(&mut update);
+        }
+
+        let status = update.status();
+        assert_eq!(status.phase, GimbalFirmwarePhase::Finished);
+        assert_eq!(status.mcu, 2);
+        assert_eq!(status.bootloader_version, Some(3));
+        assert_eq!(status.retries, 0);
+
+        let sequence : Vec<(u8, u8)> = link.requests.iter().map(|packet| (packet.command, packet.target)).collect();
+        assert_eq!(sequence, vec![
+            (bootloader_cmd::INTERRUPT_BOOT, 0),
+            (bootloader_cmd::VERSION, 0),
+            (bootloader_cmd::WRITE_BLOCK, 0),
+            (bootloader_cmd::WRITE_BLOCK, 0),
+            (bootloader_cmd::WRITE_BLOCK, 0),
+            (bootloader_cmd::NEXT_MCU, 0),
+            (bootloader_cmd::VERSION, 1),
+            (bootloader_cmd::WRITE_BLOCK, 1),
+            (bootloader_cmd::NEXT_MCU, 1),
+            (bootloader_cmd::VERSION, 2),
+            (bootloader_cmd::WRITE_BLOCK, 2),
+            (bootloader_cmd::NEXT_MCU, 2),
+        ]);
+
+        // Each block carries its number followed by the next slice of that MCU's image
+        for (mcu, image) in images().iter().enumerate() {
+            let mut written = Vec::new();
+            for (block, packet) in link.requests.iter().filter(|packet| {
+                packet.command == bootloader_cmd::WRITE_BLOCK && packet.target as usize == mcu
+            }).enumerate() {
+                assert_eq!(unpack::bootloader_u16(packet).unwrap() as usize, block);
+                written.extend_from_slice(&packet.data[2..]);
+            }
+            assert_eq!(&written, image);
+        }
+
+        // The gimbal is out of its bootloader and answering normal requests again
+        let mut request = Vec::new();
+        pack::get_value(0, 0).write_to(&mut request).unwrap();
+        let mut response = Vec::new();
+        link.gimbal.received(&request, &mut response);
+        let responses = decode(&response);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].command, cmd::GET_VALUE);
+    }
+
+    #[test]
+    fn resends_after_lost_ack() {
+        let mut link = Link::new();
+        let mut update = start(&link);
+        link.exchange(&mut update);
+        link.exchange(&mut update);
+        assert_eq!(update.status().phase, GimbalFirmwarePhase::Writing);
+
+        // The gimbal stores block 0 but its acknowledgement never arrives
+        assert_eq!(link.send(&mut update).len(), 1);
+        assert_eq!(update.status().block, 0);
+
+        // Nothing more goes out until the acknowledgement is overdue
+        link.exchange(&mut update);
+        assert_eq!(link.count(bootloader_cmd::WRITE_BLOCK), 1);
+        link.wait_for_ack_timeout();
+        link.exchange(&mut update);
+        assert_eq!(link.count(bootloader_cmd::WRITE_BLOCK), 2);
+        assert_eq!(update.status().block, 1);
+        assert_eq!(update.status().retries, 1);
+
+        for _ in 0 .. 20 {
+            link.exchange(&mut update);
+        }
+        assert_eq!(update.status().phase, GimbalFirmwarePhase::Finished);
+        assert_eq!(update.status().retries, 1);
+    }
+
+    #[test]
+    fn fails_when_retries_run_out() {
+        let mut link = Link::new();
+        let mut update = start(&link);
+        link.exchange(&mut update);
+
+        // Bootloader answered, then went silent
+        for _ in 0 .. 2 * MAX_ATTEMPTS {
+            link.send(&mut update);
+            link.wait_for_ack_timeout();
+        }
+        let status = update.status();
+        assert_eq!(status.phase, GimbalFirmwarePhase::Failed);
+        assert!(status.error.is_some());
+        assert_eq!(status.retries, MAX_ATTEMPTS - 1);
+        assert_eq!(link.count(bootloader_cmd::VERSION), MAX_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn refuses_unsupported_bootloader_version() {
+        let mut link = Link::new();
+        let mut update = start(&link);
+        link.exchange(&mut update);
+
+        let mut responses = link.send(&mut update);
+        assert_eq!(responses[0].command, bootloader_cmd::VERSION);
+        responses[0].data = vec![2, 0];
+        update.packet_received(&responses[0]);
+
+        let status = update.status();
+        assert_eq!(status.phase, GimbalFirmwarePhase::Failed);
+        assert_eq!(status.bootloader_version, Some(2));
+        assert!(status.error.unwrap().contains("version"));
+
+        // Nothing gets written to a bootloader we don't understand
+        link.wait_for_ack_timeout();
+        link.exchange(&mut update);
+        assert_eq!(link.count(bootloader_cmd::WRITE_BLOCK), 0);
+    }
+
+    #[test]
+    fn gives_up_waiting_for_bootloader() {
+        let mut link = Link::new();
+        let mut update = start(&link);
+        let mut silent = Vec::new();
+        let deadline = link.now + Duration::from_millis(BOOT_WAIT_MILLIS);
+        while link.now <= deadline {
+            update.poll(&mut silent, link.now).unwrap();
+            assert_eq!(update.status().phase, GimbalFirmwarePhase::WaitingForBootloader);
+            link.wait_for_ack_timeout();
+        }
+        update.poll(&mut silent, link.now).unwrap();
+        assert_eq!(update.status().phase, GimbalFirmwarePhase::Failed);
+    }
+
+    #[test]
+    fn images_load_only_when_configured() {
+        assert!(load_images(None).unwrap_err().contains("enabled"));
+
+        let paths : Vec<String> = images().iter().enumerate().map(|(mcu, image)| {
+            let path = env::temp_dir().join(format!("tucoflyer-firmware-test-{}.bin", mcu));
+            File::create(&path).unwrap().write_all(image).unwrap();
+            path.to_string_lossy().into_owned()
+        }).collect();
+        let config = GimbalFirmwareConfig { image_paths: paths.clone() };
+        assert_eq!(load_images(Some(&config)).unwrap(), images());
+
+        let config = GimbalFirmwareConfig { image_paths: paths[.. 2].to_vec() };
+        assert!(load_images(Some(&config)).is_err());
+        let mut missing = paths.clone();
+        missing[1].push_str(".missing");
+        assert!(load_images(Some(&GimbalFirmwareConfig { image_paths: missing })).unwrap_err().contains(".missing"));
+    }
+}
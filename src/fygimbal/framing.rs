@@ -1,6 +1,7 @@
 use std::io;
-use std::io::{Cursor, Write};
-use byteorder::{ByteOrder, LittleEndian, WriteBytesExt, ReadBytesExt};
+use std::io::Write;
+use std::mem;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use crc16;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -11,12 +12,32 @@ pub struct GimbalPacket {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
 pub enum GimbalFraming {
     Bootloader,
     Normal,
 }
 
+/// A received packet whose data still lives in the PacketReceiver's buffer
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GimbalPacketView<'a> {
+    pub framing: GimbalFraming,
+    pub command: u8,
+    pub target: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> GimbalPacketView<'a> {
+    pub fn to_packet(&self) -> GimbalPacket {
+        GimbalPacket {
+            framing: self.framing,
+            command: self.command,
+            target: self.target,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
 impl GimbalPacket {
     pub fn write_to(&self, wr: &mut io::Write) -> io::Result<()> {
         wr.write(&self.framing.to_bytes())?;
@@ -26,6 +47,15 @@ impl GimbalPacket {
         Ok(())
     }
 
+    pub fn view(&self) -> GimbalPacketView {
+        GimbalPacketView {
+            framing: self.framing,
+            command: self.command,
+            target: self.target,
+            data: &self.data,
+        }
+    }
+
     fn write_header(&self, wr: &mut io::Write) -> io::Result<()> {
         wr.write_u8(self.target)?;
         wr.write_u8(self.command)?;
@@ -39,46 +69,6 @@ impl GimbalPacket {
         body.write(&self.data).unwrap();
         self.framing.crc_bytes(&body)
     }
-
-    fn parse(buffer: &[u8]) -> (&[u8], Option<GimbalPacket>) {
-        let (remainder, framing) = GimbalFraming::parse(buffer);
-        match framing {
-            None => (remainder, None),
-            Some(framing) => {
-                if remainder.len() <= 2 {
-                    // Wait for full header
-                    (buffer, None)
-                } else {
-                    let (header, remainder) = remainder.split_at(2);
-                    let target = header[0];
-                    let command = header[1];
-                    let (remainder, length) = framing.parse_length(remainder);
-                    match length {
-                        None => (buffer, None),
-                        Some(length) => {
-                            let length_with_crc = length + 2;
-
-                            if remainder.len() < length_with_crc {
-                                // Wait for more data
-                                (buffer, None)
-                            } else {
-                                let (data, remainder) = remainder.split_at(length_with_crc);
-                                let (data, stored_crc) = data.split_at(length);
-                                let data = data.to_vec();
-                                let packet = GimbalPacket { framing, command, target, data };
-                                if packet.crc_bytes() == stored_crc {
-                                    (remainder, Some(packet))
-                                } else {
-                                    // Ignore whole packet, bad CRC
-                                    (remainder, None)
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
 }
 
 impl GimbalFraming {
@@ -89,36 +79,22 @@ impl GimbalFraming {
         }
     }
 
-    fn from_bytes(bytes: &[u8]) -> Option<GimbalFraming> {
-        if bytes.len() >= 2 {
-            if bytes[0..2] == GimbalFraming::Bootloader.to_bytes() {
-                Some(GimbalFraming::Bootloader)
-            } else if bytes[0..2] == GimbalFraming::Normal.to_bytes() {
-                Some(GimbalFraming::Normal)
-            } else {
-                None
-            }
+    fn from_bytes(first: u8, second: u8) -> Option<GimbalFraming> {
+        if [first, second] == GimbalFraming::Bootloader.to_bytes() {
+            Some(GimbalFraming::Bootloader)
+        } else if [first, second] == GimbalFraming::Normal.to_bytes() {
+            Some(GimbalFraming::Normal)
         } else {
             None
         }
     }
 
-    fn parse(buffer: &[u8]) -> (&[u8], Option<GimbalFraming>) {
-        if buffer.len() >= 2 {
-            // Look for framing sequence at each byte offset
-            for ignored in 0..(buffer.len() - 2) {
-                if let Some(result) = GimbalFraming::from_bytes(&buffer[ignored..]) {
-                    return (&buffer[ignored + 2 ..], Some(result));
-                }
-            }
-        }
-
-        if buffer.len() >= 1 {
-            // Discard junk but save the last byte, in case it's part of an incomplete framing
-            return (&buffer[buffer.len()-1..], None);
+    /// Framing sequence, target, command, and the length field
+    fn header_len(&self) -> usize {
+        match self {
+            &GimbalFraming::Bootloader => 6,
+            &GimbalFraming::Normal => 5,
         }
-
-        (buffer, None)
     }
 
     fn write_length(&self, wr: &mut io::Write, len: usize) -> io::Result<()> {
@@ -135,24 +111,6 @@ impl GimbalFraming {
         Ok(())
     }
 
-    fn parse_length<'a>(&self, buffer: &'a [u8]) -> (&'a [u8], Option<usize>) {
-        let (offset, result) = {
-            let mut cursor = Cursor::new(buffer);
-            let result = match self {
-                &GimbalFraming::Bootloader => match cursor.read_u16::<LittleEndian>() {
-                    Err(_) => None,
-                    Ok(len) => Some(len as usize),
-                },
-                &GimbalFraming::Normal => match cursor.read_u8() {
-                    Err(_) => None,
-                    Ok(len) => Some(len as usize),
-                },
-            };
-            (cursor.position() as usize, result)
-        };
-        (&buffer[offset..], result)
-    }
-
     fn calculate_crc(&self, data: &[u8]) -> u16 {
         match self {
             &GimbalFraming::Bootloader => crc16::State::<crc16::CCITT_FALSE>::calculate(data),
@@ -167,22 +125,177 @@ impl GimbalFraming {
     }
 }
 
+/// Largest amount of unparsed data we hold. Packets longer than this can't be received,
+/// which is far beyond anything the gimbal or its bootloader sends.
+const RECEIVE_BUFFER_SIZE : usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReceiverStats {
+    pub packets: u64,
+    pub crc_errors: u32,
+    /// Times we had to skip bytes to find the start of a packet
+    pub resyncs: u32,
+    /// Bytes skipped while looking for the start of a packet, including failed packets
+    pub discarded_bytes: u64,
+    /// Bytes lost because they arrived faster than packets were taken out
+    pub overflowed_bytes: u64,
+}
+
+/// Streaming packet decoder. Received bytes go into a fixed ring buffer, and packets are
+/// handed out as views into that buffer, so steady-state receiving doesn't allocate.
 #[derive(Debug)]
 pub struct PacketReceiver {
-    buffer: Vec<u8>,
+    ring: Box<[u8]>,
+    /// Same size as the ring, for straightening it out when a packet wraps around the end
+    scratch: Box<[u8]>,
+    start: usize,
+    len: usize,
+    stats: ReceiverStats,
 }
 
 impl PacketReceiver {
     pub fn new() -> PacketReceiver {
-        PacketReceiver { 
-            buffer: Vec::new(),
+        PacketReceiver {
+            ring: vec![0; RECEIVE_BUFFER_SIZE].into_boxed_slice(),
+            scratch: vec![0; RECEIVE_BUFFER_SIZE].into_boxed_slice(),
+            start: 0,
+            len: 0,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReceiverStats {
+        self.stats
+    }
+
+    /// The next complete packet, valid until more data is written
+    pub fn next_view(&mut self) -> Option<GimbalPacketView> {
+        let (framing, target, command, offset, length) = self.find_packet()?;
+        Some(GimbalPacketView {
+            framing,
+            target,
+            command,
+            data: &self.ring[offset .. offset + length],
+        })
+    }
+
+    /// Consumes the next good packet, returning its header and where its data sits in the ring
+    fn find_packet(&mut self) -> Option<(GimbalFraming, u8, u8, usize, usize)> {
+        loop {
+            let framing = self.find_framing()?;
+            let header_len = framing.header_len();
+            if self.len < header_len {
+                // Wait for full header
+                return None;
+            }
+            let length = match framing {
+                GimbalFraming::Bootloader => self.at(4) as usize | (self.at(5) as usize) << 8,
+                GimbalFraming::Normal => self.at(4) as usize,
+            };
+            let total = header_len + length + 2;
+            if total > self.ring.len() {
+                // Can't be a real packet, look for another framing sequence past this one
+                self.discard(2);
+                continue;
+            }
+            if self.len < total {
+                // Wait for more data
+                return None;
+            }
+
+            if self.start + total > self.ring.len() {
+                self.make_contiguous();
+            }
+            let start = self.start;
+            let crc_ok = {
+                let body = &self.ring[start + 2 .. start + header_len + length];
+                let stored_crc = &self.ring[start + header_len + length .. start + total];
+                framing.crc_bytes(body) == stored_crc
+            };
+            if !crc_ok {
+                // Ignore whole packet, bad CRC
+                self.stats.crc_errors += 1;
+                self.discard(total);
+                continue;
+            }
+
+            let target = self.ring[start + 2];
+            let command = self.ring[start + 3];
+            self.consume(total);
+            self.stats.packets += 1;
+            return Some((framing, target, command, start + header_len, length));
+        }
+    }
+
+    /// Skip ahead to the next framing sequence
+    fn find_framing(&mut self) -> Option<GimbalFraming> {
+        let mut offset = 0;
+        while offset + 1 < self.len {
+            if let Some(framing) = GimbalFraming::from_bytes(self.at(offset), self.at(offset + 1)) {
+                self.discard(offset);
+                return Some(framing);
+            }
+            offset += 1;
         }
+
+        // Discard junk but save the last byte, in case it's part of an incomplete framing
+        let junk = self.len.saturating_sub(1);
+        self.discard(junk);
+        None
+    }
+
+    fn at(&self, offset: usize) -> u8 {
+        self.ring[(self.start + offset) % self.ring.len()]
+    }
+
+    fn consume(&mut self, count: usize) {
+        assert!(count <= self.len);
+        self.start = (self.start + count) % self.ring.len();
+        self.len -= count;
+    }
+
+    fn discard(&mut self, count: usize) {
+        if count > 0 {
+            self.stats.resyncs += 1;
+            self.stats.discarded_bytes += count as u64;
+            self.consume(count);
+        }
+    }
+
+    /// Move the unparsed data to the beginning of the ring
+    fn make_contiguous(&mut self) {
+        let capacity = self.ring.len();
+        let first = self.len.min(capacity - self.start);
+        self.scratch[.. first].copy_from_slice(&self.ring[self.start .. self.start + first]);
+        self.scratch[first .. self.len].copy_from_slice(&self.ring[.. self.len - first]);
+        mem::swap(&mut self.ring, &mut self.scratch);
+        self.start = 0;
+    }
+
+    #[cfg(test)]
+    fn pending(&self) -> Vec<u8> {
+        (0 .. self.len).map(|offset| self.at(offset)).collect()
     }
 }
 
 impl io::Write for PacketReceiver {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.buffer.write(buf)
+        let capacity = self.ring.len();
+
+        // Make room by dropping the oldest data, there's no sense in blocking the receiver
+        let kept = if buf.len() > capacity { &buf[buf.len() - capacity ..] } else { buf };
+        let excess = (self.len + kept.len()).saturating_sub(capacity);
+        if excess > 0 {
+            self.consume(excess);
+        }
+        self.stats.overflowed_bytes += (excess + buf.len() - kept.len()) as u64;
+
+        let end = (self.start + self.len) % capacity;
+        let first = kept.len().min(capacity - end);
+        self.ring[end .. end + first].copy_from_slice(&kept[.. first]);
+        self.ring[.. kept.len() - first].copy_from_slice(&kept[first ..]);
+        self.len += kept.len();
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -194,18 +307,14 @@ impl Iterator for PacketReceiver {
     type Item = GimbalPacket;
 
     fn next(&mut self) -> Option<GimbalPacket> {
-        let (buffer, packet) = {
-            let (remainder, packet) = GimbalPacket::parse(&self.buffer);
-            (remainder.to_vec(), packet)
-        };
-        self.buffer = buffer;
-        packet
+        self.next_view().map(|view| view.to_packet())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::{Rng, SeedableRng, XorShiftRng};
 
     #[test]
     fn encode_boot_cmd01() {
@@ -230,7 +339,7 @@ mod tests {
             target: 0,
             data: vec![]
         }));
-        assert_eq!(recv.buffer, vec![0x55, 0xaa]);
+        assert_eq!(recv.pending(), vec![0x55, 0xaa]);
     }
 
     #[test]
@@ -238,7 +347,8 @@ mod tests {
         let mut recv = PacketReceiver::new();
         recv.write(&[0x12, 0x34, 0x55, 0xaa, 0x00, 0x01, 0x00, 0x00, 0xff, 0xb3, 0x99, 0xaa]).unwrap();
         assert_eq!(recv.next(), None);
-        assert_eq!(recv.buffer, vec![0x99, 0xaa]);
+        assert_eq!(recv.pending(), vec![0xaa]);
+        assert_eq!(recv.stats().crc_errors, 1);
     }
 
     #[test]
@@ -264,7 +374,126 @@ mod tests {
             target: 0,
             data: vec![0x65, 0x00, 0x2c, 0x01]
         }));
-        assert_eq!(recv.buffer, vec![0x00, 0x00, 0x00]);
+        assert_eq!(recv.pending(), vec![0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn decode_view_in_place() {
+        let mut recv = PacketReceiver::new();
+        recv.write(&[0xa5, 0x5a, 0x00, 0x08, 0x04, 0x65, 0x00, 0x2c, 0x01, 0x79, 0x32]).unwrap();
+        assert_eq!(recv.next_view(), Some(GimbalPacketView {
+            framing: GimbalFraming::Normal,
+            command: 8,
+            target: 0,
+            data: &[0x65, 0x00, 0x2c, 0x01]
+        }));
+        assert_eq!(recv.next_view(), None);
+    }
+
+    /// Never a framing byte, so junk and data made of these can't look like a packet start
+    fn random_byte(rng: &mut XorShiftRng) -> u8 {
+        loop {
+            let byte = rng.gen::<u8>();
+            if byte != 0x55 && byte != 0xa5 {
+                return byte;
+            }
+        }
     }
 
+    fn random_packet(rng: &mut XorShiftRng) -> GimbalPacket {
+        let framing = if rng.gen() { GimbalFraming::Bootloader } else { GimbalFraming::Normal };
+        let length = rng.gen_range(0, 40);
+        GimbalPacket {
+            framing,
+            command: rng.gen(),
+            target: rng.gen(),
+            data: (0 .. length).map(|_| random_byte(rng)).collect(),
+        }
+    }
+
+    /// Feed the stream in random sized pieces, collecting packets as they complete
+    fn receive_in_pieces(rng: &mut XorShiftRng, recv: &mut PacketReceiver, stream: &[u8]) -> Vec<GimbalPacket> {
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while offset < stream.len() {
+            let end = (offset + rng.gen_range(1, 64)).min(stream.len());
+            recv.write(&stream[offset .. end]).unwrap();
+            while let Some(packet) = recv.next() {
+                packets.push(packet);
+            }
+            offset = end;
+        }
+        packets
+    }
+
+    #[test]
+    fn split_packets_with_junk() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        for _ in 0 .. 100 {
+            let mut recv = PacketReceiver::new();
+            let mut stream = Vec::new();
+            let mut junk = 0;
+            let packets : Vec<GimbalPacket> = (0 .. rng.gen_range(1, 200)).map(|_| random_packet(&mut rng)).collect();
+            for packet in &packets {
+                for _ in 0 .. rng.gen_range(0, 4) {
+                    stream.push(random_byte(&mut rng));
+                    junk += 1;
+                }
+                packet.write_to(&mut stream).unwrap();
+            }
+
+            // Long enough that the ring wraps around many times
+            assert_eq!(receive_in_pieces(&mut rng, &mut recv, &stream), packets);
+            let stats = recv.stats();
+            assert_eq!(stats.packets, packets.len() as u64);
+            assert_eq!(stats.crc_errors, 0);
+            assert_eq!(stats.discarded_bytes, junk);
+            assert_eq!(stats.overflowed_bytes, 0);
+            assert_eq!(recv.pending(), vec![]);
+        }
+    }
+
+    #[test]
+    fn corrupted_byte_loses_only_whole_packets() {
+        let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+        for _ in 0 .. 100 {
+            let mut recv = PacketReceiver::new();
+            let mut stream = Vec::new();
+            let packets : Vec<GimbalPacket> = (0 .. rng.gen_range(1, 50)).map(|_| random_packet(&mut rng)).collect();
+            for packet in &packets {
+                packet.write_to(&mut stream).unwrap();
+            }
+            let index = rng.gen_range(0, stream.len());
+            let replacement = random_byte(&mut rng);
+            if stream[index] == replacement {
+                continue;
+            }
+            stream[index] = replacement;
+
+            // Whatever comes out must be intact, in order, and missing at least the damaged packet
+            let received = receive_in_pieces(&mut rng, &mut recv, &stream);
+            assert!(received.len() < packets.len());
+            let mut remaining = packets.iter();
+            for packet in &received {
+                assert!(remaining.any(|original| original == packet));
+            }
+        }
+    }
+
+    #[test]
+    fn overflow_drops_oldest_data() {
+        let mut recv = PacketReceiver::new();
+        recv.write(&vec![0; RECEIVE_BUFFER_SIZE + 100]).unwrap();
+        let packet = GimbalPacket {
+            framing: GimbalFraming::Normal,
+            command: 8,
+            target: 0,
+            data: vec![0x65, 0x00, 0x2c, 0x01]
+        };
+        let mut stream = Vec::new();
+        packet.write_to(&mut stream).unwrap();
+        recv.write(&stream).unwrap();
+        assert_eq!(recv.next(), Some(packet));
+        assert_eq!(recv.stats().overflowed_bytes, 100 + stream.len() as u64);
+    }
 }
@@ -1,8 +1,11 @@
-mod poller;
-pub use self::poller::*;
-
-mod framing;
-pub use self::framing::{GimbalPacket, GimbalFraming};
-
-pub mod protocol;
-pub mod util;
+mod poller;
+pub use self::poller::*;
+
+mod framing;
+mod firmware;
+pub use self::firmware::{FirmwareCommand, load_images};
+pub use self::framing::{GimbalPacket, GimbalPacketView, GimbalFraming, PacketReceiver, ReceiverStats};
+
+pub mod protocol;
+pub mod registry;
+pub mod util;
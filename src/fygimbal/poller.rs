@@ -5,19 +5,52 @@ use std::io;
 use std::mem;
 use std::io::Write;
 use std::sync::mpsc;
-use fygimbal::framing::{GimbalPacket, GimbalFraming, PacketReceiver};
+use fygimbal::framing::{GimbalPacket, GimbalPacketView, GimbalFraming, PacketReceiver};
 use controller::ControllerPort;
 use fygimbal::protocol;
+use fygimbal::registry;
+use fygimbal::firmware::{FirmwareUpdate, FirmwareCommand};
 
 const MAX_PACKETS_PER_READ_BATCH : usize = 2;
 const MAX_PACKET_LENGTH : usize = 16;
 const MAX_CONTINUOUS_POLL_MILLIS : u64 = 500;
 
+/// Reads are given up on after a few smoothed round trips, within these bounds
+const MIN_RESPONSE_TIMEOUT_MILLIS : u64 = 10;
+const RESPONSE_TIMEOUT_ROUND_TRIPS : f32 = 4.0;
+
+/// Smoothing for round trip time and loss, per read
+const LINK_FILTER_RATE : f32 = 0.05;
+
+const STATS_INTERVAL_MILLIS : u64 = 1000;
+
+/// Rate requests achieving less than this fraction of their target are reported as lagging
+const LAGGING_FRACTION : f32 = 0.8;
+
+/// A due poll ranks one priority higher for each interval this long it has waited, so an
+/// oversubscribed link still gets around to every request
+const PRIORITY_AGING_MILLIS : u64 = 250;
+
+/// A read that goes unanswered is sent again, up to this many times in total
+const MAX_READ_ATTEMPTS : u32 = 3;
+
+/// Persistent values are written again until they read back as written, up to this many times
+const MAX_WRITE_ATTEMPTS : u32 = 3;
+
+/// After giving up on a read its reply may still arrive, and replies don't say which read
+/// they answer, so we wait this many response timeouts before sending a different read
+const SETTLE_RESPONSE_TIMEOUTS : u32 = 2;
+
+const DEGRADED_CONSECUTIVE_TIMEOUTS : u32 = 3;
+const DEGRADED_LOSS : f32 = 0.2;
+const DOWN_CONSECUTIVE_TIMEOUTS : u32 = 20;
+
 #[derive(Debug, Clone)]
 pub struct GimbalPort {
     packets: mpsc::SyncSender<GimbalPacket>,
     writes: mpsc::SyncSender<GimbalValueData>,
     requests: mpsc::SyncSender<Vec<GimbalValueRequest>>,
+    firmware: mpsc::SyncSender<FirmwareCommand>,
 }
 
 impl GimbalPort {
@@ -62,6 +95,12 @@ impl GimbalPort {
         }
     }
 
+    pub fn firmware_command(&self, command: FirmwareCommand) {
+        if self.firmware.try_send(command).is_err() {
+            println!("GimbalPort dropping firmware command!");
+        }
+    }
+
     pub fn request_once(&self, index: u8, target: u8) {
         self.request_values(vec![
             GimbalValueRequest {
@@ -71,6 +110,15 @@ impl GimbalPort {
         ]);
     }
 
+    pub fn request_rate(&self, index: u8, target: u8, rate: GimbalPollRate) {
+        self.request_values(vec![
+            GimbalValueRequest {
+                scope: GimbalRequestScope::Rate(rate),
+                addr: GimbalValueAddress { index, target }
+            }
+        ]);
+    }
+
     pub fn request_continuous(&self, index: u8, target: u8) {
         self.request_values(vec![
             GimbalValueRequest {
@@ -83,8 +131,100 @@ impl GimbalPort {
 
 struct ValueTrackerItem {
     value: Option<(Instant, i16)>,
-    request: Option<(Instant, GimbalRequestScope)>,
+    request: Option<TrackedRequest>,
     write: Option<i16>,
+    /// Kept across requests, so a renewed request doesn't poll early
+    last_polled: Option<Instant>,
+    /// Polls since the last stats interval
+    polls: u32,
+    /// Value written and not yet confirmed by reading it back, and the write attempts so far
+    verify: Option<(i16, u32)>,
+}
+
+/// What a value read tells us about an earlier write to the same address
+enum Readback {
+    Unchecked,
+    Matched,
+    Retrying,
+    Failed(i16),
+}
+
+enum Received {
+    /// Reply to a GET_VALUE, or None if it didn't hold a value
+    Value(Option<i16>),
+    Other(GimbalPacket),
+}
+
+impl Received {
+    fn from_view(view: &GimbalPacketView) -> Received {
+        if view.framing == GimbalFraming::Normal
+            && view.target == protocol::target::HOST
+            && view.command == protocol::cmd::GET_VALUE {
+            let value = protocol::unpack::get_value(view).ok();
+            Received::Value(if view.data.len() == 2 { value } else { None })
+        } else {
+            Received::Other(view.to_packet())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingRead {
+    addr: GimbalValueAddress,
+    attempts: u32,
+    sent: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TrackedRequest {
+    /// First asked for at this time, which is when a value never polled before is due
+    requested: Instant,
+    /// A one-time read is still owed, since this time
+    once: Option<Instant>,
+    /// Repeated polling, with an infinite rate for Continuous requests. Several requesters
+    /// of the same value get the highest rate and priority any of them asked for.
+    repeat: Option<(Instant, GimbalPollRate)>,
+}
+
+impl TrackedRequest {
+    fn new(timestamp: Instant) -> TrackedRequest {
+        TrackedRequest {
+            requested: timestamp,
+            once: None,
+            repeat: None,
+        }
+    }
+
+    /// Priority and the time this request became due, if it wants a poll now
+    fn due(&self, last_polled: Option<Instant>, now: Instant) -> Option<(GimbalRequestPriority, Instant)> {
+        let repeat = self.repeat.map(|(_, rate)| rate);
+        if let Some(timestamp) = self.once {
+            // One-time reads are due immediately, at least at normal priority
+            let priority = repeat.map_or(GimbalRequestPriority::Normal, |rate| rate.priority.max(GimbalRequestPriority::Normal));
+            return Some((priority, timestamp));
+        }
+        let rate = repeat?;
+        let due = match last_polled {
+            None => self.requested,
+            Some(timestamp) => {
+                // Continuous requests have an infinite rate, and are due right away
+                let period_millis = if rate.hz > 0.0 { (1000.0 / rate.hz).min(u32::max_value() as f32) } else { 0.0 };
+                timestamp + Duration::from_millis(period_millis as u64)
+            }
+        };
+        if due <= now {
+            Some((rate.priority, due))
+        } else {
+            None
+        }
+    }
+}
+
+/// Rank of a due poll, its priority raised by one level per PRIORITY_AGING_MILLIS spent waiting
+fn aged_priority(priority: GimbalRequestPriority, due: Instant, now: Instant) -> u64 {
+    let waited = if now > due { now - due } else { Duration::from_millis(0) };
+    let waited_millis = waited.as_secs() * 1000 + waited.subsec_nanos() as u64 / 1_000_000;
+    priority as u64 + waited_millis / PRIORITY_AGING_MILLIS
 }
 
 struct ValueTracker {
@@ -103,6 +243,9 @@ impl ValueTracker {
                     value: None,
                     request: None,
                     write: None,
+                    last_polled: None,
+                    polls: 0,
+                    verify: None,
                 }
             }).collect()
         }
@@ -133,11 +276,43 @@ impl ValueTracker {
 
     fn store_request(&mut self, timestamp: Instant, req: GimbalValueRequest) {
         if let Some(index) = ValueTracker::addr_index(&req.addr) {
-            self.items[index].request = match self.items[index].request {
-                None => Some((timestamp, req.scope)),
-                Some((_, GimbalRequestScope::Continuous)) => Some((timestamp, GimbalRequestScope::Continuous)),
-                Some((_, GimbalRequestScope::Once)) => Some((timestamp, req.scope)),
+            let mut tracked = self.items[index].request.unwrap_or(TrackedRequest::new(timestamp));
+            let rate = match req.scope {
+                GimbalRequestScope::Once => {
+                    tracked.once = Some(tracked.once.unwrap_or(timestamp));
+                    None
+                },
+                GimbalRequestScope::Continuous => Some(GimbalPollRate {
+                    hz: ::std::f32::INFINITY,
+                    priority: GimbalRequestPriority::Normal,
+                }),
+                GimbalRequestScope::Rate(rate) => Some(rate),
             };
+            if let Some(rate) = rate {
+                tracked.repeat = Some((timestamp, match tracked.repeat {
+                    None => rate,
+                    Some((_, prev)) => GimbalPollRate {
+                        hz: prev.hz.max(rate.hz),
+                        priority: prev.priority.max(rate.priority),
+                    },
+                }));
+            }
+            self.items[index].request = Some(tracked);
+        }
+    }
+
+    fn expire_requests(&mut self, now: Instant) {
+        for item in self.items.iter_mut() {
+            if let Some(ref mut tracked) = item.request {
+                if let Some((renewed, _)) = tracked.repeat {
+                    if now > renewed + Duration::from_millis(MAX_CONTINUOUS_POLL_MILLIS) {
+                        tracked.repeat = None;
+                    }
+                }
+            }
+            if item.request.map_or(false, |tracked| tracked.once.is_none() && tracked.repeat.is_none()) {
+                item.request = None;
+            }
         }
     }
 
@@ -162,42 +337,184 @@ impl ValueTracker {
         None
     }
 
-    fn next_request(&mut self) -> Option<GimbalValueAddress> {
-        for _ in 0..self.items.len() {
-            let index = self.request_index;
-            self.request_index = (self.request_index + 1) % self.items.len();
-            match self.items[index].request {
+    /// Persistent values are read back after each write, and the write only completes once
+    /// the value matches. Returns true if the write is waiting on a readback.
+    fn write_sent(&mut self, data: &GimbalValueData, now: Instant) -> bool {
+        let persistent = registry::lookup(data.addr.index).map_or(false, |info| info.persistent);
+        match (persistent, ValueTracker::addr_index(&data.addr)) {
+            (true, Some(index)) => {
+                let item = &mut self.items[index];
+                let attempts = match item.verify {
+                    Some((value, attempts)) if value == data.value => attempts + 1,
+                    _ => 1,
+                };
+                item.verify = Some((data.value, attempts));
+                let mut tracked = item.request.unwrap_or(TrackedRequest::new(now));
+                tracked.once = Some(tracked.once.unwrap_or(now));
+                item.request = Some(tracked);
+                true
+            },
+            _ => false,
+        }
+    }
 
-                None => (),
+    fn check_readback(&mut self, data: &GimbalValueData) -> Readback {
+        let item = match ValueTracker::addr_index(&data.addr) {
+            Some(index) => &mut self.items[index],
+            None => return Readback::Unchecked,
+        };
+        let (written, attempts) = match item.verify {
+            Some(verify) => verify,
+            None => return Readback::Unchecked,
+        };
+        if item.write.is_some() {
+            // A newer write is on its way, check that one instead
+            Readback::Unchecked
+        } else if data.value == written {
+            item.verify = None;
+            Readback::Matched
+        } else if attempts < MAX_WRITE_ATTEMPTS {
+            item.write = Some(written);
+            Readback::Retrying
+        } else {
+            item.verify = None;
+            Readback::Failed(written)
+        }
+    }
 
-                Some((_, GimbalRequestScope::Once)) => {
-                    self.items[index].request = None;
-                    return Some(ValueTracker::index_addr(index));
+    /// Highest priority value that's due after aging, breaking ties by whichever has waited longest
+    fn next_request(&mut self, now: Instant) -> Option<GimbalValueAddress> {
+        self.expire_requests(now);
+        let mut best : Option<(usize, u64, Instant)> = None;
+        for offset in 0..self.items.len() {
+            // Start after the last pick, so equal candidates take turns
+            let index = (self.request_index + offset) % self.items.len();
+            let item = &self.items[index];
+            if let Some((priority, due)) = item.request.and_then(|tracked| tracked.due(item.last_polled, now)) {
+                let rank = aged_priority(priority, due, now);
+                let better = match best {
+                    None => true,
+                    Some((_, best_rank, best_due)) => rank > best_rank || (rank == best_rank && due < best_due),
+                };
+                if better {
+                    best = Some((index, rank, due));
                 }
+            }
+        }
 
-                Some((ts, GimbalRequestScope::Continuous)) => {
-                    if Instant::now() > ts + Duration::from_millis(MAX_CONTINUOUS_POLL_MILLIS) {
-                        self.items[index].request = None;
-                    } else {
-                        return Some(ValueTracker::index_addr(index));
+        let (index, _, _) = best?;
+        self.request_index = (index + 1) % self.items.len();
+        let item = &mut self.items[index];
+        item.last_polled = Some(now);
+        item.polls += 1;
+        if let Some(ref mut tracked) = item.request {
+            tracked.once = None;
+        }
+        Some(ValueTracker::index_addr(index))
+    }
+
+    /// Summarize and reset the per-value poll counts
+    fn request_stats(&mut self, interval_sec: f32) -> (f32, usize, Vec<GimbalValueAddress>) {
+        let mut requested_hz = 0.0;
+        let mut active_requests = 0;
+        let mut lagging = Vec::new();
+        for (index, item) in self.items.iter_mut().enumerate() {
+            if let Some((_, rate)) = item.request.and_then(|tracked| tracked.repeat) {
+                active_requests += 1;
+                if rate.hz.is_finite() {
+                    requested_hz += rate.hz;
+                    if (item.polls as f32) < rate.hz * interval_sec * LAGGING_FRACTION {
+                        lagging.push(ValueTracker::index_addr(index));
                     }
                 }
             }
+            item.polls = 0;
+        }
+        (requested_hz, active_requests, lagging)
+    }
+}
+
+/// Round trip and loss measurements for reads, plus traffic counts for the stats interval
+struct LinkMonitor {
+    read_sent: Option<Instant>,
+    round_trip_sec: f32,
+    loss: f32,
+    reads: u32,
+    writes: u32,
+    interval_start: Instant,
+}
+
+impl LinkMonitor {
+    fn new() -> LinkMonitor {
+        LinkMonitor {
+            read_sent: None,
+            // Start from the fixed timeout, and let measurements bring it down
+            round_trip_sec: GimbalPoller::read_timeout().subsec_nanos() as f32 * 1e-9 / RESPONSE_TIMEOUT_ROUND_TRIPS,
+            loss: 0.0,
+            reads: 0,
+            writes: 0,
+            interval_start: Instant::now(),
+        }
+    }
+
+    fn read_sent(&mut self, now: Instant) {
+        self.read_sent = Some(now);
+        self.reads += 1;
+    }
+
+    fn read_answered(&mut self, now: Instant) {
+        if let Some(sent) = self.read_sent.take() {
+            let elapsed = now - sent;
+            let rtt = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 * 1e-9;
+            self.round_trip_sec += (rtt - self.round_trip_sec) * LINK_FILTER_RATE;
+            self.loss -= self.loss * LINK_FILTER_RATE;
+        }
+    }
+
+    fn read_lost(&mut self) {
+        if self.read_sent.take().is_some() {
+            self.loss += (1.0 - self.loss) * LINK_FILTER_RATE;
+        }
+    }
+
+    fn response_timeout(&self) -> Duration {
+        let millis = (self.round_trip_sec * RESPONSE_TIMEOUT_ROUND_TRIPS * 1000.0) as u64;
+        Duration::from_millis(millis.max(MIN_RESPONSE_TIMEOUT_MILLIS)).min(GimbalPoller::read_timeout())
+    }
+
+    fn read_budget_hz(&self) -> f32 {
+        if self.round_trip_sec > 0.0 {
+            (1.0 - self.loss) / self.round_trip_sec
+        } else {
+            0.0
         }
-        None
     }
 }
 
+/// The protocol has no sequence numbers, so we keep at most one read in flight and match
+/// each GET_VALUE reply to it. Until it's answered or times out, batches carry only writes.
+/// An unanswered read is retried at the same address, where a
+/// late reply is still a correct answer; only after giving up do we wait for the link to
+/// go quiet before asking about anything else.
 pub struct GimbalPoller {
     receiver: PacketReceiver,
     batch_timestamp: Instant,
-    pending_read: Option<GimbalValueAddress>,
+    pending_read: Option<PendingRead>,
+    retry_read: Option<PendingRead>,
+    settle_until: Option<Instant>,
     value_tracker: ValueTracker,
+    link: LinkMonitor,
+    link_status: GimbalLinkStatus,
+    /// The last write to the link failed
+    io_failing: bool,
+    firmware: Option<FirmwareUpdate>,
+    firmware_status: Option<GimbalFirmwareStatus>,
 
     port: GimbalPort,
     packets: mpsc::Receiver<GimbalPacket>,
     writes: mpsc::Receiver<GimbalValueData>,
     requests: mpsc::Receiver<Vec<GimbalValueRequest>>,
+    firmware_commands: mpsc::Receiver<FirmwareCommand>,
 }
 
 impl GimbalPoller {
@@ -205,21 +522,44 @@ impl GimbalPoller {
         let packets = mpsc::sync_channel(256);
         let writes = mpsc::sync_channel(256);
         let requests = mpsc::sync_channel(256);
+        let firmware = mpsc::sync_channel(4);
 
         GimbalPoller {
             receiver: PacketReceiver::new(),
             batch_timestamp: Instant::now(),
             pending_read: None,
+            retry_read: None,
+            settle_until: None,
             value_tracker: ValueTracker::new(),
+            link: LinkMonitor::new(),
+            link_status: GimbalLinkStatus {
+                state: GimbalLinkState::Ok,
+                crc_errors: 0,
+                resyncs: 0,
+                discarded_bytes: 0,
+                malformed_replies: 0,
+                late_replies: 0,
+                read_timeouts: 0,
+                read_retries: 0,
+                write_retries: 0,
+                write_failures: 0,
+                io_errors: 0,
+                consecutive_timeouts: 0,
+            },
+            io_failing: false,
+            firmware: None,
+            firmware_status: None,
 
             port: GimbalPort {
                 packets: packets.0,
                 writes: writes.0,
                 requests: requests.0,
+                firmware: firmware.0,
             },
             packets: packets.1,
             writes: writes.1,
             requests: requests.1,
+            firmware_commands: firmware.1,
         }
     }
 
@@ -231,53 +571,246 @@ impl GimbalPoller {
         self.port.clone()
     }
 
+    /// Smoothed read round trip in milliseconds, and fraction of reads lost
+    pub fn link_quality(&self) -> (f32, f32) {
+        (self.link.round_trip_sec * 1000.0, self.link.loss)
+    }
+
     pub fn received(&mut self, msg: &[u8], writer: &mut io::Write, controller: &ControllerPort) {
         let mut received_anything = false;
-        self.receiver.write(msg).unwrap();
-        while let Some(packet) = self.receiver.next() {
-            self.handle_packet(packet, controller);
+        if let Err(e) = self.receiver.write_all(msg) {
+            // The receive buffer drops old data rather than failing, but if it ever does fail
+            // that's a link error to count, not a reason to stop receiving
+            println!("Error buffering data from gimbal, {}", e);
+            self.link_status.io_errors += 1;
+        }
+        loop {
+            // Value replies are decoded in place, only other packets get copied out
+            let received = match self.receiver.next_view() {
+                Some(view) => Received::from_view(&view),
+                None => break,
+            };
+            match received {
+                Received::Value(value) => self.value_reply(value, controller),
+                Received::Other(packet) => self.handle_packet(packet, controller),
+            }
             received_anything = true;
         }
         if received_anything {
             self.send_next_batch(writer, controller);
         }
+        self.publish_link_status(false, controller);
     }
 
     fn handle_packet(&mut self, packet: GimbalPacket, controller: &ControllerPort) {
-        if packet.framing == GimbalFraming::Normal {
-            if packet.target == protocol::target::HOST {
-                if packet.command == protocol::cmd::GET_VALUE {
-                    if let Some(addr) = mem::replace(&mut self.pending_read, None) {
-                        if let Ok(value) = protocol::unpack::get_value(&packet) {
-
-                            let data = GimbalValueData { addr, value };
-                            let msg = Message::GimbalValue(data.clone(), GimbalValueOp::ReadComplete);
-                            let tsm = msg.timestamp();
-
-                            self.value_tracker.store_value(tsm.timestamp, data);
-                            controller.send(tsm);
-                            return;
-                        }
-                    }
-                }
+        if packet.framing == GimbalFraming::Bootloader {
+            if let Some(ref mut update) = self.firmware {
+                update.packet_received(&packet);
+                return;
             }
         }
         controller.send(Message::UnhandledGimbalPacket(packet).timestamp());
     }
 
+    fn value_reply(&mut self, value: Option<i16>, controller: &ControllerPort) {
+        let value = match value {
+            Some(value) => value,
+            None => {
+                // The read stays pending, for a good reply or a retry when it times out
+                self.link_status.malformed_replies += 1;
+                return;
+            }
+        };
+        let addr = match self.pending_read.take() {
+            Some(pending) => pending.addr,
+            None => {
+                // Answers a read we already gave up on
+                self.link_status.late_replies += 1;
+                return;
+            }
+        };
+        self.link.read_answered(Instant::now());
+        self.link_status.consecutive_timeouts = 0;
+
+        let data = GimbalValueData { addr, value };
+        let msg = Message::GimbalValue(data.clone(), GimbalValueOp::ReadComplete);
+        let tsm = msg.timestamp();
+        self.value_tracker.store_value(tsm.timestamp, data.clone());
+        controller.send(tsm);
+
+        match self.value_tracker.check_readback(&data) {
+            Readback::Unchecked => (),
+            Readback::Matched => {
+                controller.send(Message::GimbalValue(data, GimbalValueOp::WriteComplete).timestamp());
+            },
+            Readback::Retrying => {
+                self.link_status.write_retries += 1;
+            },
+            Readback::Failed(written) => {
+                self.link_status.write_failures += 1;
+                let data = GimbalValueData { addr: data.addr, value: written };
+                controller.send(Message::GimbalValue(data, GimbalValueOp::WriteFailed).timestamp());
+            },
+        }
+    }
+
     pub fn check_for_timeout(&mut self, writer: &mut io::Write, controller: &ControllerPort) {
-        if self.batch_timestamp + GimbalPoller::read_timeout() < Instant::now() {
+        let now = Instant::now();
+        if now > self.link.interval_start + Duration::from_millis(STATS_INTERVAL_MILLIS) {
+            self.publish_stats(now, controller);
+        }
+        // Batches of writes go out while a read is pending, without extending its deadline
+        let timed_out = match self.pending_read {
+            Some(ref pending) => pending.sent + self.link.response_timeout() < now,
+            None => self.batch_timestamp + GimbalPoller::read_timeout() < now,
+        };
+        if timed_out {
+            if let Some(pending) = self.pending_read.take() {
+                self.read_timed_out(pending, now);
+            }
             self.send_next_batch(writer, controller);
         }
+        self.publish_link_status(false, controller);
+    }
+
+    fn read_timed_out(&mut self, pending: PendingRead, now: Instant) {
+        self.link.read_lost();
+        self.link_status.read_timeouts += 1;
+        self.link_status.consecutive_timeouts += 1;
+        if pending.attempts < MAX_READ_ATTEMPTS {
+            self.retry_read = Some(pending);
+        } else {
+            self.settle_until = Some(now + self.link.response_timeout() * SETTLE_RESPONSE_TIMEOUTS);
+        }
+    }
+
+    fn link_state(&self) -> GimbalLinkState {
+        let timeouts = self.link_status.consecutive_timeouts;
+        if self.io_failing || timeouts >= DOWN_CONSECUTIVE_TIMEOUTS {
+            GimbalLinkState::Down
+        } else if timeouts >= DEGRADED_CONSECUTIVE_TIMEOUTS || self.link.loss > DEGRADED_LOSS {
+            GimbalLinkState::Degraded
+        } else {
+            GimbalLinkState::Ok
+        }
+    }
+
+    /// Counters go out with the periodic stats, state changes go out right away
+    fn publish_link_status(&mut self, force: bool, controller: &ControllerPort) {
+        let state = self.link_state();
+        if force || state != self.link_status.state {
+            let stats = self.receiver.stats();
+            self.link_status.state = state;
+            self.link_status.crc_errors = stats.crc_errors;
+            self.link_status.resyncs = stats.resyncs;
+            self.link_status.discarded_bytes = stats.discarded_bytes + stats.overflowed_bytes;
+            controller.send(Message::GimbalLinkStatus(self.link_status.clone()).timestamp());
+        }
+    }
+
+    fn publish_stats(&mut self, now: Instant, controller: &ControllerPort) {
+        let elapsed = now - self.link.interval_start;
+        let interval_sec = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 * 1e-9;
+        let (requested_hz, active_requests, lagging) = self.value_tracker.request_stats(interval_sec);
+        let stats = GimbalPollerStats {
+            round_trip_ms: self.link.round_trip_sec * 1000.0,
+            loss: self.link.loss,
+            read_budget_hz: self.link.read_budget_hz(),
+            reads_per_sec: self.link.reads as f32 / interval_sec,
+            writes_per_sec: self.link.writes as f32 / interval_sec,
+            requested_hz,
+            active_requests,
+            lagging,
+        };
+        self.link.reads = 0;
+        self.link.writes = 0;
+        self.link.interval_start = now;
+        controller.send(Message::GimbalPollerStats(stats).timestamp());
+        self.publish_link_status(true, controller);
     }
 
     fn send_next_batch(&mut self, writer: &mut io::Write, controller: &ControllerPort) {
         self.drain_command_queues();
         self.batch_timestamp = Instant::now();
-        self.write_next_batch(&mut io::BufWriter::new(writer), controller).expect("Failed writing to gimbal");
+        if self.firmware.is_some() {
+            self.firmware_batch(writer, controller);
+        } else {
+            let result = {
+                let mut buffered = io::BufWriter::new(writer);
+                self.write_next_batch(&mut buffered, controller).and_then(|_| buffered.flush())
+            };
+            if let Err(e) = result {
+                // Whatever we were waiting on never made it out
+                if self.pending_read.take().is_some() {
+                    self.link.read_lost();
+                }
+                self.write_failed(e);
+            } else {
+                self.io_failing = false;
+            }
+        }
+    }
+
+    fn write_failed(&mut self, e: io::Error) {
+        if !self.io_failing {
+            println!("Error writing to gimbal, {}", e);
+        }
+        self.link_status.io_errors += 1;
+        self.io_failing = true;
+    }
+
+    fn firmware_batch(&mut self, writer: &mut io::Write, controller: &ControllerPort) {
+        // Normal traffic would only confuse the bootloader, and values are polled again afterward
+        for _ in self.packets.try_iter() {}
+
+        let (status, result) = match self.firmware {
+            None => return,
+            Some(ref mut update) => {
+                let result = {
+                    let mut buffered = io::BufWriter::new(writer);
+                    update.poll(&mut buffered, self.batch_timestamp).and_then(|_| buffered.flush())
+                };
+                if let Err(ref e) = result {
+                    update.fail(format!("Error writing to gimbal, {}", e));
+                }
+                (update.status(), result)
+            }
+        };
+        match result {
+            Ok(()) => self.io_failing = false,
+            Err(e) => self.write_failed(e),
+        }
+        if self.firmware_status.as_ref() != Some(&status) {
+            controller.send(Message::GimbalFirmwareStatus(status.clone()).timestamp());
+            self.firmware_status = Some(status);
+        }
+        if self.firmware.as_ref().map_or(false, |update| update.is_finished()) {
+            self.firmware = None;
+        }
+    }
+
+    fn firmware_command(&mut self, command: FirmwareCommand) {
+        match command {
+            FirmwareCommand::Start(images) => {
+                if self.firmware.is_some() {
+                    println!("Ignoring gimbal firmware update, one is already in progress");
+                } else {
+                    self.firmware = Some(FirmwareUpdate::new(images, Instant::now()));
+                }
+            },
+            FirmwareCommand::Cancel => {
+                if let Some(ref mut update) = self.firmware {
+                    update.fail("Cancelled".into());
+                }
+            },
+        }
     }
 
     fn drain_command_queues(&mut self) {
+        let firmware_commands : Vec<FirmwareCommand> = self.firmware_commands.try_iter().collect();
+        for command in firmware_commands {
+            self.firmware_command(command);
+        }
         for data in self.writes.try_iter() {
             self.value_tracker.store_write(data);
         }
@@ -301,22 +834,38 @@ impl GimbalPoller {
                 }
             }
             else if let Some(data) = self.value_tracker.next_write() {
-                let msg = Message::GimbalValue(data.clone(), GimbalValueOp::WriteComplete);
                 let packet = protocol::pack::set_value(data.addr.target, data.addr.index, data.value);
                 packet.write_to(writer)?;
                 packets_sent += 1;
-                controller.send(msg.timestamp());
+                self.link.writes += 1;
+                if !self.value_tracker.write_sent(&data, self.batch_timestamp) {
+                    controller.send(Message::GimbalValue(data, GimbalValueOp::WriteComplete).timestamp());
+                }
             }
             else {
                 break;
             }
         }
 
-        // The last packet should be a read, so we can wait for the response
+        // The last packet should be a read, so we can wait for the response. With one
+        // already in flight, another would leave us unable to tell their replies apart.
         assert!(packets_sent <= MAX_PACKETS_PER_READ_BATCH - 1);
-        if let Some(addr) = self.value_tracker.next_request() {
-            let packet = protocol::pack::get_value(addr.target, addr.index);
-            self.pending_read = Some(addr);
+        let now = Instant::now();
+        if self.pending_read.is_some() || self.settle_until.map_or(false, |until| now < until) {
+            return Ok(packets_sent);
+        }
+        self.settle_until = None;
+        let next_read = match self.retry_read.take() {
+            Some(retry) => {
+                self.link_status.read_retries += 1;
+                Some(PendingRead { addr: retry.addr, attempts: retry.attempts + 1, sent: now })
+            },
+            None => self.value_tracker.next_request(now).map(|addr| PendingRead { addr, attempts: 1, sent: now }),
+        };
+        if let Some(pending) = next_read {
+            let packet = protocol::pack::get_value(pending.addr.target, pending.addr.index);
+            self.pending_read = Some(pending);
+            self.link.read_sent(now);
             packet.write_to(writer)?;
             packets_sent += 1;
         }
@@ -324,3 +873,162 @@ impl GimbalPoller {
         Ok(packets_sent)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::GimbalRequestPriority::*;
+    use controller::tests::TestPort;
+
+    fn addr(index: u8) -> GimbalValueAddress {
+        GimbalValueAddress { index, target: protocol::target::YAW }
+    }
+
+    fn rate_request(index: u8, hz: f32, priority: GimbalRequestPriority) -> GimbalValueRequest {
+        GimbalValueRequest {
+            addr: addr(index),
+            scope: GimbalRequestScope::Rate(GimbalPollRate { hz, priority }),
+        }
+    }
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn rate_request_is_due_once_per_period() {
+        let t0 = Instant::now();
+        let mut tracked = TrackedRequest::new(t0);
+        tracked.repeat = Some((t0, GimbalPollRate { hz: 10.0, priority: Low }));
+        // Never polled, so due since it was first requested
+        assert_eq!(tracked.due(None, t0 + millis(30)), Some((Low, t0)));
+        assert_eq!(tracked.due(Some(t0), t0 + millis(99)), None);
+        assert_eq!(tracked.due(Some(t0), t0 + millis(150)), Some((Low, t0 + millis(100))));
+    }
+
+    #[test]
+    fn once_is_due_right_away_at_normal_priority_or_better() {
+        let t0 = Instant::now();
+        let mut tracked = TrackedRequest::new(t0);
+        tracked.once = Some(t0 + millis(5));
+        assert_eq!(tracked.due(Some(t0), t0 + millis(5)), Some((Normal, t0 + millis(5))));
+        tracked.repeat = Some((t0, GimbalPollRate { hz: 1.0, priority: Low }));
+        assert_eq!(tracked.due(Some(t0), t0 + millis(5)), Some((Normal, t0 + millis(5))));
+        tracked.repeat = Some((t0, GimbalPollRate { hz: 1.0, priority: High }));
+        assert_eq!(tracked.due(Some(t0), t0 + millis(5)), Some((High, t0 + millis(5))));
+    }
+
+    #[test]
+    fn continuous_is_due_right_after_each_poll() {
+        let t0 = Instant::now();
+        let mut tracker = ValueTracker::new();
+        tracker.store_request(t0, GimbalValueRequest { addr: addr(1), scope: GimbalRequestScope::Continuous });
+        let tracked = tracker.items[ValueTracker::addr_index(&addr(1)).unwrap()].request.unwrap();
+        assert_eq!(tracked.due(Some(t0), t0), Some((Normal, t0)));
+    }
+
+    #[test]
+    fn aging_raises_priority_one_level_per_interval() {
+        let t0 = Instant::now();
+        assert_eq!(aged_priority(Low, t0, t0), 0);
+        assert_eq!(aged_priority(Normal, t0, t0 + millis(249)), 1);
+        assert_eq!(aged_priority(Normal, t0, t0 + millis(250)), 2);
+        assert_eq!(aged_priority(Low, t0, t0 + millis(1000)), 4);
+        // Polls that aren't due yet haven't waited at all
+        assert_eq!(aged_priority(High, t0 + millis(10), t0), 2);
+    }
+
+    #[test]
+    fn higher_priority_polls_first() {
+        let t0 = Instant::now();
+        let mut tracker = ValueTracker::new();
+        tracker.store_request(t0, rate_request(77, 10.0, Low));
+        tracker.store_request(t0, rate_request(78, 10.0, Normal));
+        tracker.store_request(t0, rate_request(79, 10.0, High));
+        assert_eq!(tracker.next_request(t0), Some(addr(79)));
+        assert_eq!(tracker.next_request(t0), Some(addr(78)));
+        assert_eq!(tracker.next_request(t0), Some(addr(77)));
+        assert_eq!(tracker.next_request(t0), None);
+        assert_eq!(tracker.next_request(t0 + millis(100)), Some(addr(79)));
+    }
+
+    #[test]
+    fn waiting_polls_are_not_starved_by_an_oversubscribed_link() {
+        let t0 = Instant::now();
+        let mut tracker = ValueTracker::new();
+        let mut requests : Vec<GimbalValueRequest> = (10 .. 14).map(|index| rate_request(index, 1000.0, High)).collect();
+        requests.push(rate_request(20, 10.0, Normal));
+        requests.push(rate_request(30, 1.0, Low));
+
+        // One read every 5 ms for a second, with 4 kHz of High priority polls asked for
+        let mut polls = vec![0; protocol::NUM_VALUES];
+        for step in 0 .. 200 {
+            let now = t0 + millis(step * 5);
+            for req in requests.iter() {
+                tracker.store_request(now, req.clone());
+            }
+            polls[tracker.next_request(now).unwrap().index as usize] += 1;
+        }
+
+        // Strict priority would never get past the High requests. With aging, the Normal
+        // request waits 250 ms after each due time and the Low one waits 500 ms.
+        assert_eq!(polls[20], 3);
+        assert_eq!(polls[30], 1);
+        for index in 10 .. 14 {
+            assert_eq!(polls[index], 49);
+        }
+    }
+
+    fn from_gimbal(command: u8, data: Vec<u8>) -> Vec<u8> {
+        let packet = GimbalPacket { framing: GimbalFraming::Normal, command, target: protocol::target::HOST, data };
+        let mut buf = Vec::new();
+        packet.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    /// Indices of the values read by packets we sent
+    fn reads_sent(sent: &[u8]) -> Vec<u8> {
+        let mut receiver = PacketReceiver::new();
+        receiver.write_all(sent).unwrap();
+        let mut indices = Vec::new();
+        while let Some(view) = receiver.next_view() {
+            if view.command == protocol::cmd::GET_VALUE {
+                indices.push(view.data[0]);
+            }
+        }
+        indices
+    }
+
+    #[test]
+    fn malformed_reply_keeps_its_read_in_flight() {
+        let test_port = TestPort::new();
+        let controller = &test_port.port;
+        let mut poller = GimbalPoller::new();
+        poller.port().request_once(10, protocol::target::YAW);
+        poller.port().request_once(20, protocol::target::YAW);
+
+        let mut sent = Vec::new();
+        poller.send_next_batch(&mut sent, controller);
+        let first = reads_sent(&sent);
+        assert_eq!(first.len(), 1);
+
+        // Neither a bad reply nor an unrelated packet may start another read
+        sent.clear();
+        poller.received(&from_gimbal(protocol::cmd::GET_VALUE, vec![ 0x34 ]), &mut sent, controller);
+        poller.received(&from_gimbal(protocol::cmd::SAVE_VALUES, vec![ 0 ]), &mut sent, controller);
+        assert_eq!(reads_sent(&sent), vec![]);
+        assert_eq!(poller.link_status.malformed_replies, 1);
+
+        // So the good reply that follows still belongs to the first read
+        poller.received(&from_gimbal(protocol::cmd::GET_VALUE, vec![ 0x34, 0x12 ]), &mut sent, controller);
+        let second = reads_sent(&sent);
+        assert_eq!(second.len(), 1);
+        assert!(second[0] != first[0]);
+        let values : Vec<GimbalValueData> = test_port.messages().into_iter().filter_map(|msg| match msg {
+            Message::GimbalValue(data, GimbalValueOp::ReadComplete) => Some(data),
+            _ => None,
+        }).collect();
+        assert_eq!(values, vec![ GimbalValueData { addr: addr(first[0]), value: 0x1234 } ]);
+        assert_eq!(poller.link_status.late_replies, 0);
+    }
+}
@@ -1,7 +1,6 @@
 pub const NUM_AXES : usize = 3;
 pub const NUM_VALUES : usize = 128;
 
-#[allow(dead_code)]
 pub mod bootloader_cmd {
     pub const VERSION : u8 = 0x00;
     pub const INTERRUPT_BOOT : u8 = 0x01;
@@ -180,17 +179,106 @@ pub mod pack {
             data: vec![value],
         }
     }
+
+    /// The IMU measures gyro drift while the gimbal holds still, and acknowledges when done
+    pub fn capture_gyro_drift() -> GimbalPacket {
+        GimbalPacket {
+            framing: GimbalFraming::Normal,
+            command: super::cmd::CAPTURE_GYRO_DRIFT_COMPENSATION,
+            target: super::target::IMU_ADJACENT,
+            data: Vec::new(),
+        }
+    }
+
+    /// Store the current encoder angle in one of the MCU's calibration angle slots
+    pub fn capture_calibration_angle(target: u8, num: u8) -> GimbalPacket {
+        GimbalPacket {
+            framing: GimbalFraming::Normal,
+            command: super::cmd::CAPTURE_CALIBRATION_ANGLE,
+            target,
+            data: vec![num],
+        }
+    }
+
+    /// Ask the IMU for the accelerometer corrections that would zero its current tilt
+    pub fn get_accel_corrections() -> GimbalPacket {
+        GimbalPacket {
+            framing: GimbalFraming::Normal,
+            command: super::cmd::GET_ACCEL_CORRECTIONS,
+            target: super::target::IMU_ADJACENT,
+            data: Vec::new(),
+        }
+    }
+
+    /// Ask one MCU to store its current values in flash
+    pub fn save_values(target: u8) -> GimbalPacket {
+        GimbalPacket {
+            framing: GimbalFraming::Normal,
+            command: super::cmd::SAVE_VALUES,
+            target,
+            data: vec![0],
+        }
+    }
+
+    pub fn bootloader(target: u8, command: u8) -> GimbalPacket {
+        GimbalPacket {
+            framing: GimbalFraming::Bootloader,
+            command,
+            target,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn write_block(target: u8, block: u16, contents: &[u8]) -> GimbalPacket {
+        let mut data = Vec::new();
+        data.write_u16::<LittleEndian>(block).unwrap();
+        data.extend_from_slice(contents);
+        GimbalPacket {
+            framing: GimbalFraming::Bootloader,
+            command: super::bootloader_cmd::WRITE_BLOCK,
+            target,
+            data,
+        }
+    }
 }
 
 pub mod unpack {
     use std::io::{Result, Cursor};
-    use fygimbal::framing::{GimbalFraming, GimbalPacket};
+    use fygimbal::framing::{GimbalFraming, GimbalPacket, GimbalPacketView};
     use byteorder::{ReadBytesExt, LittleEndian};
 
-    pub fn get_value(packet: &GimbalPacket) -> Result<i16> {
+    /// Decoded straight from the receive buffer, since these are nearly all of our traffic
+    pub fn get_value(packet: &GimbalPacketView) -> Result<i16> {
         assert!(packet.framing == GimbalFraming::Normal);
         assert!(packet.command == super::cmd::GET_VALUE);
-        let mut reader = Cursor::new(&packet.data);
+        let mut reader = Cursor::new(packet.data);
         reader.read_i16::<LittleEndian>()
     }
+
+    /// The MCU acknowledging a SAVE_VALUES request
+    pub fn save_values(packet: &GimbalPacket) -> Result<u8> {
+        assert!(packet.framing == GimbalFraming::Normal);
+        assert!(packet.command == super::cmd::SAVE_VALUES);
+        let mut reader = Cursor::new(&packet.data);
+        reader.read_u8()
+    }
+
+    /// Corrections for each IMU axis, indexed by imu_axis
+    pub fn accel_corrections(packet: &GimbalPacket) -> Result<[i16; 3]> {
+        assert!(packet.framing == GimbalFraming::Normal);
+        assert!(packet.command == super::cmd::GET_ACCEL_CORRECTIONS);
+        let mut reader = Cursor::new(&packet.data);
+        Ok([
+            reader.read_i16::<LittleEndian>()?,
+            reader.read_i16::<LittleEndian>()?,
+            reader.read_i16::<LittleEndian>()?,
+        ])
+    }
+
+    /// Leading u16 of a bootloader packet, the version or the acknowledged block number
+    pub fn bootloader_u16(packet: &GimbalPacket) -> Result<u16> {
+        assert!(packet.framing == GimbalFraming::Bootloader);
+        let mut reader = Cursor::new(&packet.data);
+        reader.read_u16::<LittleEndian>()
+    }
 }
@@ -0,0 +1,173 @@
+//! What we know about each slot in the gimbal's value space: its name, which MCUs have it,
+//! how raw counts map to units, whether writes make sense, and whether SAVE_VALUES keeps it.
+//! Slots missing from the table are still readable and writable, since much of the value
+//! space hasn't been identified yet.
+
+use message::GimbalValueData;
+use fygimbal::protocol::{values, NUM_AXES, NUM_VALUES};
+use self::GimbalValueAccess::*;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GimbalValueAccess {
+    ReadOnly,
+    Writable,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct GimbalValueInfo {
+    pub index: u8,
+    pub name: &'static str,
+    /// Whether the yaw, roll and pitch MCUs each have this value
+    pub axes: [bool; NUM_AXES],
+    /// Units per raw count
+    pub scale: f32,
+    pub unit: &'static str,
+    pub access: GimbalValueAccess,
+    /// Stored in flash by SAVE_VALUES, and kept across power cycles
+    pub persistent: bool,
+}
+
+impl GimbalValueInfo {
+    pub fn applies_to(&self, target: u8) -> bool {
+        self.axes.get(target as usize).cloned().unwrap_or(false)
+    }
+
+    pub fn scaled(&self, raw: i16) -> f32 {
+        raw as f32 * self.scale
+    }
+}
+
+const ALL_AXES : [bool; NUM_AXES] = [ true, true, true ];
+const IMU_ONLY : [bool; NUM_AXES] = [ false, false, true ];
+
+const DEGREES_PER_COUNT : f32 = 360.0 / 4096.0;
+
+macro_rules! slot {
+    ($index:ident, $axes:expr, $scale:expr, $unit:expr, $access:expr, $persistent:expr) => {
+        GimbalValueInfo {
+            index: values::$index,
+            name: stringify!($index),
+            axes: $axes,
+            scale: $scale,
+            unit: $unit,
+            access: $access,
+            persistent: $persistent,
+        }
+    }
+}
+
+pub const REGISTRY : &[GimbalValueInfo] = &[
+    slot!(CHECKSUM,                                       ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_ERROR_FLAGS,                              ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_STATUS_FLAGS,                             ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(CONTROLLER_VELOCITY_INPUT,                      ALL_AXES, 1.0, "", Writable, false),
+    slot!(GYRO_ANGULAR_RATE,                              ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_FILTERED_CURRENT_TARGET,                  ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_FILTERED_CURRENT,                         ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(SUPPLY_VOLTAGE,                                 IMU_ONLY, 0.1, "V", ReadOnly, false),
+    slot!(FOLLOW_INTEGRATOR,                              ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(STABILIZER_VELOCITY_INPUT,                      ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_MODE,                                     ALL_AXES, 1.0, "", Writable, false),
+    slot!(MOTOR_ANGLE_OFFSET,                             ALL_AXES, DEGREES_PER_COUNT, "deg", ReadOnly, false),
+    slot!(MOTOR_ANGLE,                                    ALL_AXES, DEGREES_PER_COUNT, "deg", ReadOnly, false),
+    slot!(ENCODER_ANGLE,                                  ALL_AXES, DEGREES_PER_COUNT, "deg", ReadOnly, false),
+    slot!(MOTOR_SHUTDOWN_ERROR_FLAGS,                     ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_CURRENT_TARGET,                           ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_CURRENT,                                  ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(FOLLOW_INTEGRATOR_OFFSET,                       ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(FOLLOW_ANGLE_ERROR,                             ALL_AXES, DEGREES_PER_COUNT, "deg", ReadOnly, false),
+    slot!(CALIBRATION_ANGLE_0_CENTER,                     ALL_AXES, DEGREES_PER_COUNT, "deg", Writable, true),
+    slot!(MANUAL_MOTOR_CURRENT_INPUT,                     ALL_AXES, 1.0, "", Writable, false),
+    slot!(SIMULATED_ANGLE_OSCILLATOR_RATE,                ALL_AXES, 1.0, "", Writable, false),
+    slot!(MANUAL_MOTOR_VELOCITY_INPUT,                    ALL_AXES, 1.0, "", Writable, false),
+    slot!(CALIBRATION_GYRO_DRIFT_WAS_SET_AT_STARTUP_FLAG, IMU_ONLY, 1.0, "", ReadOnly, false),
+    slot!(CALIBRATION_GYRO_DRIFT_Z,                       IMU_ONLY, 1.0, "", Writable, true),
+    slot!(CALIBRATION_GYRO_DRIFT_X,                       IMU_ONLY, 1.0, "", Writable, true),
+    slot!(CALIBRATION_GYRO_DRIFT_Y,                       IMU_ONLY, 1.0, "", Writable, true),
+    slot!(CALIBRATION_ACCEL_OFFSET_Z,                     IMU_ONLY, 1.0, "", Writable, true),
+    slot!(CALIBRATION_ACCEL_OFFSET_X,                     IMU_ONLY, 1.0, "", Writable, true),
+    slot!(CALIBRATION_ACCEL_OFFSET_Y,                     IMU_ONLY, 1.0, "", Writable, true),
+    slot!(FOLLOW_ENABLE_FLAG,                             ALL_AXES, 1.0, "", Writable, true),
+    slot!(CALIBRATION_ANGLE_1_MOTOR,                      ALL_AXES, DEGREES_PER_COUNT, "deg", Writable, true),
+    slot!(FOLLOW_RATE,                                    ALL_AXES, 1.0, "", Writable, true),
+    slot!(JOYSTICK_MODE,                                  ALL_AXES, 1.0, "", Writable, true),
+    slot!(IMU_TYPE,                                       IMU_ONLY, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_CURRENT_ADC1,                             ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(MOTOR_CURRENT_ADC2,                             ALL_AXES, 1.0, "", ReadOnly, false),
+    slot!(FIRMWARE_VERSION,                               ALL_AXES, 1.0, "", ReadOnly, false),
+];
+
+pub fn lookup(index: u8) -> Option<&'static GimbalValueInfo> {
+    REGISTRY.iter().find(|info| info.index == index)
+}
+
+/// Raw value in the slot's units, or unscaled if the slot isn't in the registry
+pub fn scaled(index: u8, raw: i16) -> f32 {
+    match lookup(index) {
+        Some(info) => info.scaled(raw),
+        None => raw as f32,
+    }
+}
+
+/// Refuse writes that can't do what the sender intended
+pub fn validate_write(data: &GimbalValueData) -> Result<(), String> {
+    let addr = &data.addr;
+    if addr.index as usize >= NUM_VALUES || addr.target as usize >= NUM_AXES {
+        return Err(format!("No gimbal value {} on axis {}", addr.index, addr.target));
+    }
+    match lookup(addr.index) {
+        None => Ok(()),
+        Some(info) if !info.applies_to(addr.target) => Err(format!("Gimbal value {} doesn't exist on axis {}", info.name, addr.target)),
+        Some(info) if info.access == ReadOnly => Err(format!("Gimbal value {} is read-only", info.name)),
+        Some(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::GimbalValueAddress;
+    use fygimbal::protocol::target;
+
+    fn write(target: u8, index: u8) -> GimbalValueData {
+        GimbalValueData { addr: GimbalValueAddress { target, index }, value: 0 }
+    }
+
+    #[test]
+    fn indices_are_unique() {
+        for (i, info) in REGISTRY.iter().enumerate() {
+            assert!((info.index as usize) < NUM_VALUES);
+            assert!(REGISTRY[.. i].iter().all(|other| other.index != info.index), "{} is listed twice", info.name);
+        }
+    }
+
+    #[test]
+    fn out_of_range_writes_refused() {
+        assert!(validate_write(&write(target::YAW, NUM_VALUES as u8)).is_err());
+        assert!(validate_write(&write(target::HOST, values::FOLLOW_RATE)).is_err());
+    }
+
+    #[test]
+    fn imu_only_slots_refused_on_other_axes() {
+        assert!(validate_write(&write(target::IMU_ADJACENT, values::CALIBRATION_GYRO_DRIFT_Z)).is_ok());
+        assert!(validate_write(&write(target::YAW, values::CALIBRATION_GYRO_DRIFT_Z)).is_err());
+        assert!(validate_write(&write(target::ROLL, values::CALIBRATION_ACCEL_OFFSET_X)).is_err());
+    }
+
+    #[test]
+    fn read_only_slots_refused() {
+        assert_eq!(validate_write(&write(target::YAW, values::ENCODER_ANGLE)), Err("Gimbal value ENCODER_ANGLE is read-only".into()));
+        assert!(validate_write(&write(target::PITCH, values::SUPPLY_VOLTAGE)).is_err());
+        assert!(validate_write(&write(target::YAW, values::CALIBRATION_ANGLE_0_CENTER)).is_ok());
+    }
+
+    #[test]
+    fn unknown_slots_pass_through() {
+        let unknown = (0 .. NUM_VALUES as u8).find(|&index| lookup(index).is_none()).unwrap();
+        for axis in 0 .. NUM_AXES as u8 {
+            assert!(validate_write(&write(axis, unknown)).is_ok());
+        }
+        assert_eq!(scaled(unknown, -5), -5.0);
+        assert_eq!(scaled(values::SUPPLY_VOLTAGE, 120), 12.0);
+    }
+}
@@ -4,6 +4,7 @@ use message::{Command, Message, ManualControlAxis, CameraOutput};
 use controller::ControllerPort;
 use config::{SharedConfigFile, ControllerMode};
 use gilrs::{Event, Button, Axis, Gilrs};
+use supervisor;
 use std::thread;
 use std::time::Duration;
 
@@ -105,10 +106,11 @@ fn send_complete(c: &ControllerPort, state: &State) {
     }
 }
 
-pub fn start(config: &SharedConfigFile, c: &ControllerPort) {
+pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
     let config = config.clone();
-    let c = c.clone();
-    thread::Builder::new().name("Gamepad".into()).spawn(move || {
+    let c = controller.clone();
+    // Critical, a failure could leave the bot following a stick that's been let go
+    supervisor::spawn("Gamepad", true, controller, move || {
         let mut gil = Gilrs::new();
         let mut state = State::new();
 
@@ -156,5 +158,5 @@ pub fn start(config: &SharedConfigFile, c: &ControllerPort) {
 
             thread::sleep(Duration::from_millis(10));
         }
-    }).unwrap();
+    });
 }
@@ -1,7 +1,7 @@
 use controller::ControllerPort;
 use config::{Config, SharedConfigFile, ControllerMode};
 use message::{TimestampedMessage, Message, Command, ManualControlAxis};
-use std::thread;
+use supervisor;
 use std::sync::mpsc::sync_channel;
 use std::mem;
 use std::env;
@@ -20,6 +20,7 @@ struct MetricSampler {
     min_interval: Duration,
     winch_ts: Vec<Instant>,
     flyer_ts: Instant,
+    flyer_pose_ts: Instant,
     flush_ts: Instant,
     gimbal_control_ts: Instant,
     object_detector_ts: Instant,
@@ -37,6 +38,7 @@ impl MetricSampler {
             min_interval: Duration::new(0, (1e9 / max_sample_hz) as u32),
             winch_ts: range(0, num_winches).map(|_| now).collect(),
             flyer_ts: now,
+            flyer_pose_ts: now,
             object_detector_ts: now,
             region_tracker_ts: now,
             flush_ts: now,
@@ -47,13 +49,22 @@ impl MetricSampler {
         }
     }
 
+    fn resize(&mut self, num_winches: usize) {
+        let now = Instant::now();
+        self.winch_ts.truncate(num_winches);
+        while self.winch_ts.len() < num_winches {
+            self.winch_ts.push(now);
+        }
+    }
+
     fn handle_message(&mut self, points: &mut Vec<Point>, config: &mut Config, tsm: &TimestampedMessage) {
         *self.message_counts.entry("message").or_insert(0) += 1;
         match &tsm.message {
 
             &Message::WinchStatus(id, ref status) => {
                 *self.message_counts.entry("winch_status").or_insert(0) += 1;
-                if tsm.timestamp >= self.winch_ts[id] + self.min_interval {
+                // Statuses already in flight when a winch was removed from the config
+                if id < self.winch_ts.len() && tsm.timestamp >= self.winch_ts[id] + self.min_interval {
                     self.winch_ts[id] = tsm.timestamp;
                     let cal = &config.winches[id].calibration;
 
@@ -78,6 +89,54 @@ impl MetricSampler {
                 }
             },
 
+            &Message::BotModuleStatus(ref status) => {
+                *self.message_counts.entry("bot_module_status").or_insert(0) += 1;
+                let mut p = Point::new("bot.module");
+                p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                p.add_tag("module", Value::String(format!("{:?}", status.module)));
+                p.add_field("state", Value::String(format!("{:?}", status.state)));
+                p.add_field("refused_codes", Value::Integer(status.refused_codes.len() as i64));
+                p.add_field("refused_messages", Value::Integer(status.refused_messages as i64));
+                p.add_field("malformed_messages", Value::Integer(status.malformed_messages as i64));
+                points.push(p);
+            },
+
+            &Message::SubsystemFault(ref fault) => {
+                *self.message_counts.entry("subsystem_fault").or_insert(0) += 1;
+                let mut p = Point::new("subsystem.fault");
+                p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                p.add_tag("subsystem", Value::String(fault.subsystem.clone()));
+                p.add_field("error", Value::String(fault.error.clone()));
+                p.add_field("failures", Value::Integer(fault.failures as i64));
+                p.add_field("critical", Value::Boolean(fault.critical));
+                points.push(p);
+            },
+
+            &Message::NetworkStatus(ref status) => {
+                *self.message_counts.entry("network_status").or_insert(0) += 1;
+                let mut links = vec![ ("flyer".to_owned(), &status.flyer), ("gimbal".to_owned(), &status.gimbal) ];
+                for (id, link) in status.winches.iter().enumerate() {
+                    links.push((format!("winch{}", id), link));
+                }
+                for (module, link) in links {
+                    let mut p = Point::new("network.link");
+                    p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                    p.add_tag("module", Value::String(module));
+                    p.add_field("packets_per_sec", Value::Float(link.packets_per_sec.into()));
+                    p.add_field("loss", Value::Float(link.loss.into()));
+                    if let Some(latency_ms) = link.latency_ms {
+                        p.add_field("latency_ms", Value::Float(latency_ms.into()));
+                    }
+                    p.add_field("malformed", Value::Integer(link.malformed as i64));
+                    p.add_field("refused", Value::Integer(link.refused as i64));
+                    points.push(p);
+                }
+                let mut p = Point::new("network.unknown");
+                p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                p.add_field("packets", Value::Integer(status.unknown_packets as i64));
+                points.push(p);
+            },
+
             &Message::FlyerSensors(ref status) => {
                 *self.message_counts.entry("flyer_sensors").or_insert(0) += 1;
                 if tsm.timestamp >= self.flyer_ts + self.min_interval {
@@ -107,6 +166,27 @@ impl MetricSampler {
                 }
             },
 
+            &Message::FlyerPose(ref pose) => {
+                *self.message_counts.entry("flyer_pose").or_insert(0) += 1;
+                if tsm.timestamp >= self.flyer_pose_ts + self.min_interval {
+                    self.flyer_pose_ts = tsm.timestamp;
+
+                    let mut p = Point::new("flyer.pose");
+                    p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                    p.add_field("position.x", Value::Float(pose.position[0].into()));
+                    p.add_field("position.y", Value::Float(pose.position[1].into()));
+                    p.add_field("position.z", Value::Float(pose.position[2].into()));
+                    p.add_field("velocity.x", Value::Float(pose.velocity[0].into()));
+                    p.add_field("velocity.y", Value::Float(pose.velocity[1].into()));
+                    p.add_field("velocity.z", Value::Float(pose.velocity[2].into()));
+                    p.add_field("position_variance.x", Value::Float(pose.position_variance[0].into()));
+                    p.add_field("position_variance.y", Value::Float(pose.position_variance[1].into()));
+                    p.add_field("position_variance.z", Value::Float(pose.position_variance[2].into()));
+                    p.add_field("num_ropes", Value::Integer(pose.num_ropes as i64));
+                    points.push(p);
+                }
+            },
+
             &Message::GimbalControlStatus(ref status) => {
                 *self.message_counts.entry("gimbal_control_status").or_insert(0) += 1;
                 if tsm.timestamp >= self.gimbal_control_ts + self.min_interval {
@@ -140,6 +220,7 @@ impl MetricSampler {
             &Message::ConfigIsCurrent(ref new_config) => {
                 *self.message_counts.entry("config_is_current").or_insert(0) += 1;
                 *config = new_config.clone();
+                self.resize(config.winches.len());
                 let json_config = serde_json::to_value(new_config).unwrap();
                 let mut p = Point::new("config");
                 p.add_timestamp(self.sync.to_millis(tsm.timestamp));
@@ -153,6 +234,7 @@ impl MetricSampler {
                     ("mode.normal", &ControllerMode::Normal),
                     ("mode.manual_flyer", &ControllerMode::ManualFlyer),
                     ("mode.manual_winch", &ControllerMode::ManualWinch(0)),
+                    ("mode.trajectory", &ControllerMode::Trajectory),
                 ];
                 for &(name, value) in modes.iter() {
                     let is_current = mem::discriminant(&config.mode) == mem::discriminant(value);
@@ -174,6 +256,39 @@ impl MetricSampler {
                 *self.message_counts.entry("unhandled_gimbal_packet").or_insert(0) += 1;
             },
 
+            &Message::GimbalPollerStats(ref stats) => {
+                *self.message_counts.entry("gimbal_poller_stats").or_insert(0) += 1;
+                let mut p = Point::new("gimbal.poller");
+                p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                p.add_field("round_trip_ms", Value::Float(stats.round_trip_ms.into()));
+                p.add_field("loss", Value::Float(stats.loss.into()));
+                p.add_field("read_budget_hz", Value::Float(stats.read_budget_hz.into()));
+                p.add_field("reads_per_sec", Value::Float(stats.reads_per_sec.into()));
+                p.add_field("writes_per_sec", Value::Float(stats.writes_per_sec.into()));
+                p.add_field("requested_hz", Value::Float(stats.requested_hz.into()));
+                p.add_field("active_requests", Value::Integer(stats.active_requests as i64));
+                p.add_field("lagging", Value::Integer(stats.lagging.len() as i64));
+                points.push(p);
+            },
+
+            &Message::GimbalLinkStatus(ref status) => {
+                *self.message_counts.entry("gimbal_link_status").or_insert(0) += 1;
+                let mut p = Point::new("gimbal.link");
+                p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                p.add_field("state", Value::String(format!("{:?}", status.state)));
+                p.add_field("crc_errors", Value::Integer(status.crc_errors as i64));
+                p.add_field("resyncs", Value::Integer(status.resyncs as i64));
+                p.add_field("discarded_bytes", Value::Integer(status.discarded_bytes as i64));
+                p.add_field("malformed_replies", Value::Integer(status.malformed_replies as i64));
+                p.add_field("late_replies", Value::Integer(status.late_replies as i64));
+                p.add_field("read_timeouts", Value::Integer(status.read_timeouts as i64));
+                p.add_field("read_retries", Value::Integer(status.read_retries as i64));
+                p.add_field("write_retries", Value::Integer(status.write_retries as i64));
+                p.add_field("write_failures", Value::Integer(status.write_failures as i64));
+                p.add_field("io_errors", Value::Integer(status.io_errors as i64));
+                points.push(p);
+            },
+
             &Message::CameraOverlayScene(ref rects) => {
                 *self.message_counts.entry("camera_overlay_scene").or_insert(0) += 1;
                 *self.message_counts.entry("camera_overlay_scene.rects").or_insert(0) += rects.len() as u64;
@@ -183,6 +298,47 @@ impl MetricSampler {
                 *self.message_counts.entry("camera_init_tracked_region").or_insert(0) += 1;
             },
 
+            &Message::TrajectoryStatus(_) => {
+                *self.message_counts.entry("trajectory_status").or_insert(0) += 1;
+            },
+
+            &Message::GeofenceStatus(ref status) => {
+                *self.message_counts.entry("geofence_status").or_insert(0) += 1;
+                let mut p = Point::new("geofence.status");
+                p.add_timestamp(self.sync.to_millis(tsm.timestamp));
+                p.add_field("violations", Value::Integer(status.violations.len() as i64));
+                p.add_field("slowdowns", Value::Integer(status.slowdowns.len() as i64));
+                points.push(p);
+            },
+
+            &Message::WinchCalibrationStatus(_) => {
+                *self.message_counts.entry("winch_calibration_status").or_insert(0) += 1;
+            },
+
+            &Message::AnchorSurveyStatus(_) => {
+                *self.message_counts.entry("anchor_survey_status").or_insert(0) += 1;
+            },
+
+            &Message::WinchAutotuneStatus(_) => {
+                *self.message_counts.entry("winch_autotune_status").or_insert(0) += 1;
+            },
+
+            &Message::GimbalTuningStatus(_) => {
+                *self.message_counts.entry("gimbal_tuning_status").or_insert(0) += 1;
+            },
+
+            &Message::GimbalFirmwareStatus(_) => {
+                *self.message_counts.entry("gimbal_firmware_status").or_insert(0) += 1;
+            },
+
+            &Message::GimbalParamStatus(_) => {
+                *self.message_counts.entry("gimbal_param_status").or_insert(0) += 1;
+            },
+
+            &Message::GimbalCalibrationStatus(_) => {
+                *self.message_counts.entry("gimbal_calibration_status").or_insert(0) += 1;
+            },
+
             &Message::Command(ref cmd) => {
                 *self.message_counts.entry("command").or_insert(0) += 1;
                 match cmd {
@@ -256,6 +412,22 @@ impl MetricSampler {
                        *self.message_counts.entry("gimbal_motor_enable").or_insert(0) += 1;
                     },
 
+                    &Command::GimbalSetAngle(_) => {
+                       *self.message_counts.entry("gimbal_set_angle").or_insert(0) += 1;
+                    },
+
+                    &Command::GimbalSlewTo(_) => {
+                       *self.message_counts.entry("gimbal_slew_to").or_insert(0) += 1;
+                    },
+
+                    &Command::GimbalReleaseAngle => {
+                       *self.message_counts.entry("gimbal_release_angle").or_insert(0) += 1;
+                    },
+
+                    &Command::GimbalFirmware(_) => {
+                       *self.message_counts.entry("gimbal_firmware").or_insert(0) += 1;
+                    },
+
                     &Command::GimbalPacket(_) => {
                        *self.message_counts.entry("gimbal_packet").or_insert(0) += 1;
                     },
@@ -267,6 +439,34 @@ impl MetricSampler {
                     &Command::GimbalValueRequests(_) => {
                        *self.message_counts.entry("gimbal_value_requests").or_insert(0) += 1;
                     },
+
+                    &Command::TrajectoryUpload(_) => {
+                       *self.message_counts.entry("trajectory_upload").or_insert(0) += 1;
+                    },
+
+                    &Command::WinchCalibration(_) => {
+                       *self.message_counts.entry("winch_calibration").or_insert(0) += 1;
+                    },
+
+                    &Command::AnchorSurvey(_) => {
+                       *self.message_counts.entry("anchor_survey").or_insert(0) += 1;
+                    },
+
+                    &Command::WinchAutotune(_) => {
+                       *self.message_counts.entry("winch_autotune").or_insert(0) += 1;
+                    },
+
+                    &Command::GimbalTuning(_) => {
+                       *self.message_counts.entry("gimbal_tuning").or_insert(0) += 1;
+                    },
+
+                    &Command::GimbalParams(_) => {
+                       *self.message_counts.entry("gimbal_params").or_insert(0) += 1;
+                    },
+
+                    &Command::GimbalCalibration(_) => {
+                       *self.message_counts.entry("gimbal_calibration").or_insert(0) += 1;
+                    },
                 }
             }
         }
@@ -356,7 +556,7 @@ pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
     if let Some(metrics_config) = config.metrics.clone() {
         let database = metrics_config.database;
         let batch_size = metrics_config.batch_size;
-        let controller = controller.clone();
+        let bus_controller = controller.clone();
         let mut sampler = MetricSampler::new(metrics_config.max_sample_hz, config.winches.len());
 
         let client = influx_db_client::Client::new(&metrics_config.influxdb_host, &database);
@@ -368,12 +568,12 @@ pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
 
         let (batch_sender, batch_receiver) = sync_channel(16);
 
-        thread::Builder::new().name("Metrics sampler".into()).spawn(move || {
-            let mut bus_receiver = controller.add_rx();
+        supervisor::spawn("Metrics sampler", false, controller, move || {
+            let mut bus_receiver = bus_controller.add_rx()?;
             let mut points = Vec::new();
 
             loop {
-                let msg = bus_receiver.recv().unwrap();
+                let msg = bus_receiver.recv().map_err(|_| "Message bus closed".to_owned())?;
                 sampler.handle_message(&mut points, &mut config, &msg);
 
                 if points.len() >= batch_size {
@@ -384,11 +584,11 @@ pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
                     }
                 }
             }
-        }).unwrap();
+        });
 
-        thread::Builder::new().name("Metrics transmitter".into()).spawn(move || {
+        supervisor::spawn("Metrics transmitter", false, controller, move || {
             loop {
-                let batch = batch_receiver.recv().unwrap();
+                let batch = batch_receiver.recv().map_err(|_| "Metrics sampler is gone".to_owned())?;
                 let batch = influx_db_client::Points::create_new(batch);
                 let precision = Some(influx_db_client::Precision::Milliseconds);
 
@@ -396,7 +596,7 @@ pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
                     Ok(_) => (),
 
                     Err(influx_db_client::Error::DataBaseDoesNotExist(_)) => {
-                        client.create_database(&database).expect("Failed to create metrics database");
+                        client.create_database(&database).map_err(|e| format!("Failed to create metrics database, {:?}", e))?;
                     },
 
                     err => {
@@ -404,6 +604,6 @@ pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
                     },
                 };
             }
-        }).unwrap();
+        });
     }
 }
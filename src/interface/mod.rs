@@ -1,3 +1,5 @@
 pub mod gamepad;
 pub mod web;
 pub mod metrics;
+pub mod recorder;
+pub mod replay;
@@ -0,0 +1,35 @@
+//! Records everything on the controller's message bus to disk, for later analysis and replay
+
+use controller::ControllerPort;
+use config::SharedConfigFile;
+use message::Message;
+use buslog::BusLogWriter;
+use std::fs;
+use std::path::Path;
+use supervisor;
+use chrono::Local;
+
+pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
+    let config = config.get_latest();
+    if let Some(recorder_config) = config.recorder.clone() {
+        let bus_controller = controller.clone();
+
+        // Each restart begins a new log, so a failed one is left intact
+        supervisor::spawn("Bus recorder", false, controller, move || {
+            let directory = Path::new(&recorder_config.directory);
+            fs::create_dir_all(directory).map_err(|e| format!("Failed to create recording directory, {}", e))?;
+            let path = directory.join(Local::now().format("bus-%Y%m%d-%H%M%S.log").to_string());
+            let mut log = BusLogWriter::create(&path).map_err(|e| format!("Failed to create bus log, {}", e))?;
+            println!("Recording message bus to {}", path.display());
+
+            // Replay starts from the configuration we had at the beginning
+            let mut bus_receiver = bus_controller.add_rx()?;
+            log.write(&Message::ConfigIsCurrent(config.clone()).timestamp()).map_err(|e| format!("Failed to write bus log, {}", e))?;
+
+            loop {
+                let msg = bus_receiver.recv().map_err(|_| "Message bus closed".to_owned())?;
+                log.write(&msg).map_err(|e| format!("Failed to write bus log, {}", e))?;
+            }
+        });
+    }
+}
@@ -0,0 +1,158 @@
+//! Feeds a recorded message bus log back into a controller
+
+use controller::ControllerPort;
+use config::Config;
+use message::Message;
+use serde_json::Value;
+use buslog::{BusLogReader, micros_to_duration};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Instant;
+use supervisor;
+
+/// Configuration for a controller that replays a log, based on the recorded configuration if
+/// we have one. The controller still runs its control loops, but nothing it sends can reach
+/// real hardware.
+pub fn replay_config(local: &Config, recorded: Option<&Config>) -> Config {
+    let mut config = match recorded {
+        Some(recorded) => recorded.clone(),
+        None => local.clone(),
+    };
+    let ephemeral : SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let discard : SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+    config.web = local.web.clone();
+    config.metrics = None;
+    config.recorder = None;
+    config.controller_addr = ephemeral;
+    config.flyer_addr = discard;
+    for winch in config.winches.iter_mut() {
+        winch.addr = discard;
+    }
+    config
+}
+
+/// Start replaying in the background. Speeds above 1.0 replay faster than real time.
+pub fn start(mut log: BusLogReader, speed: f32, controller: &ControllerPort) {
+    let port = controller.clone();
+    let start = Instant::now();
+    let mut first_micros = None;
+
+    // After a restart, carry on from the next record on the same timeline
+    supervisor::spawn("Bus replay", false, controller, move || {
+        while let Some(record) = log.next() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    println!("Stopping replay, {}", e);
+                    return Ok(());
+                }
+            };
+            let message = match replay_input(record.message) {
+                Some(message) => message,
+                None => continue,
+            };
+
+            let first = *first_micros.get_or_insert(record.micros);
+            let elapsed_micros = record.micros.saturating_sub(first);
+            let due = start + micros_to_duration((elapsed_micros as f64 / speed as f64) as u64);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+
+            port.send(message.timestamp());
+        }
+        println!("Replay complete");
+        Ok(())
+    });
+}
+
+/// Settings chosen by replay_config, which recorded config edits must not change
+const REPLAY_OVERRIDES : &[&str] = &[ "controller_addr", "flyer_addr", "web", "metrics", "recorder" ];
+
+/// The recorded message to send the controller again, if any. Config edits lose anything that
+/// replay_config overrides, so a recorded edit can't point us back at the real bot.
+fn replay_input(msg: Message) -> Option<Message> {
+    match msg {
+        Message::UpdateConfig(mut updates) => {
+            strip_replay_overrides(&mut updates);
+            Some(Message::UpdateConfig(updates))
+        },
+        msg => if is_controller_input(&msg) { Some(msg) } else { None },
+    }
+}
+
+fn strip_replay_overrides(updates: &mut Value) {
+    if let Value::Object(ref mut updates) = *updates {
+        for key in REPLAY_OVERRIDES {
+            updates.remove(*key);
+        }
+        // Winches update as an array, or as a map keyed by index
+        let winches : Vec<&mut Value> = match updates.get_mut("winches") {
+            Some(&mut Value::Array(ref mut winches)) => winches.iter_mut().collect(),
+            Some(&mut Value::Object(ref mut winches)) => winches.values_mut().collect(),
+            _ => Vec::new(),
+        };
+        for winch in winches {
+            if let Value::Object(ref mut winch) = *winch {
+                winch.remove("addr");
+            }
+        }
+    }
+}
+
+fn is_controller_input(msg: &Message) -> bool {
+    // Everything else on the bus came from the controller itself, and it will generate those again
+    match msg {
+        &Message::Command(_) => true,
+        &Message::UpdateConfig(_) => true,
+        &Message::WinchStatus(..) => true,
+        &Message::FlyerSensors(_) => true,
+        &Message::GimbalValue(..) => true,
+        &Message::UnhandledGimbalPacket(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::tests::test_config;
+    use config::ControllerMode;
+    use serde_json;
+
+    #[test]
+    fn recorded_config_edits_keep_replay_addresses() {
+        let config = replay_config(&test_config(ControllerMode::Halted), None);
+        let recorded : Value = serde_json::from_str(r#"{
+            "controller_addr": "10.32.0.1:9024",
+            "flyer_addr": "10.32.0.8:9024",
+            "winches": [ { "addr": "10.32.0.10:9024", "calibration": { "kg_force_per_count": 1.0 } }, null ],
+            "mode": "Normal"
+        }"#).unwrap();
+        let updates = match replay_input(Message::UpdateConfig(recorded)) {
+            Some(Message::UpdateConfig(updates)) => updates,
+            other => panic!("Unexpected replay input {:?}", other),
+        };
+        let merged = config.merge(updates).unwrap();
+        assert_eq!(merged.controller_addr, config.controller_addr);
+        assert_eq!(merged.flyer_addr, config.flyer_addr);
+        assert_eq!(merged.winches[0].addr, config.winches[0].addr);
+        assert_eq!(merged.winches[0].calibration.kg_force_per_count, 1.0);
+        assert_eq!(merged.mode, ControllerMode::Normal);
+
+        // Updates keyed by winch index too
+        let recorded : Value = serde_json::from_str(r#"{ "winches": { "1": { "addr": "10.32.0.11:9024" } } }"#).unwrap();
+        let updates = match replay_input(Message::UpdateConfig(recorded)) {
+            Some(Message::UpdateConfig(updates)) => updates,
+            other => panic!("Unexpected replay input {:?}", other),
+        };
+        assert_eq!(config.merge(updates).unwrap().winches[1].addr, config.winches[1].addr);
+    }
+
+    #[test]
+    fn controller_output_is_not_replayed() {
+        assert!(replay_input(Message::ConfigIsCurrent(test_config(ControllerMode::Halted))).is_none());
+    }
+}
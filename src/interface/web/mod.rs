@@ -15,7 +15,7 @@ pub fn start(config: &SharedConfigFile, controller: &ControllerPort) {
     let secret_key = auth::make_random_string();
     let connect_string = make_connect_string(&web_config.http_uri(&secret_key, 0));
 
-    http::start(&web_config);
+    http::start(&web_config, controller);
     ws::start(controller, config, secret_key.clone());
 
     store_connect_string(&connect_string, &web_config.connection_file_path).expect("can't write to connection info file");
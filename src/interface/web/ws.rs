@@ -1,4 +1,4 @@
-use message::{Message, Command, TimestampedMessage};
+use message::{Message, Command, TimestampedMessage, GimbalValueAddress, GimbalValueRequest};
 use controller::ControllerPort;
 use config::SharedConfigFile;
 use serde_json::{to_string, from_str, to_value, Value};
@@ -10,7 +10,9 @@ use std::thread;
 use std::time::{Duration, Instant};
 use std::mem;
 use websocket;
+use supervisor;
 use interface::web::auth;
+use fygimbal::registry::{self, GimbalValueInfo};
 
 // All times in milliseconds
 const MIN_BATCH_PERIOD : f64 = 2.0;
@@ -19,12 +21,18 @@ const MAX_SEND_LATENCY : f64 = 400.0;
 const PING_INTERVAL : f64 = 100.0;
 const PING_TIMEOUT : f64 = 10000.0;
 
+/// Distinct gimbal values each client may poll, counting every address it asked for within
+/// the window, which outlasts the poller's own request expiry
+const MAX_CLIENT_GIMBAL_POLLS : usize = 48;
+const CLIENT_GIMBAL_POLL_WINDOW_MILLIS : u64 = 1000;
+
 #[derive(Serialize, Clone, Debug)]
 enum MessageToClient {
     Stream(Vec<LocalTimestampedMessage>),
     Auth(AuthChallenge),
     AuthStatus(bool),
     Error(ClientError),
+    GimbalValueRegistry(&'static [GimbalValueInfo]),
 }
 
 type Subscription = HashSet<String>;
@@ -48,24 +56,38 @@ enum ErrorCode {
     ParseFailed,
     AuthRequired,
     UpdateConfigFailed,
+    InvalidCommand,
     RequestQueueFull,
 }
 
 pub fn start(controller: &ControllerPort, config: &SharedConfigFile, secret_key: String) {
-    let controller = controller.clone();
+    let connection_controller = controller.clone();
     let config = config.clone();
     let addr = config.get_latest().web.ws_bind_addr();
-    let server = websocket::sync::Server::bind(addr).expect("failed to bind to WebSocket server port");
+    let mut server = websocket::sync::Server::bind(addr).expect("failed to bind to WebSocket server port");
 
-    thread::Builder::new().name("Websocket Server".into()).spawn(move || {
-        for request in server.filter_map(Result::ok) {
+    supervisor::spawn("Websocket Server", false, controller, move || {
+        for request in server.by_ref().filter_map(Result::ok) {
             let secret_key = secret_key.clone();
-            let controller = controller.clone();
+            let controller = connection_controller.clone();
             let config = config.clone();
             thread::Builder::new().name("Websocket Connection".into()).spawn(move || {
                 // Per-connection thread
 
-                let (receiver, sender) = request.accept().unwrap().split().unwrap();
+                let client = match request.accept() {
+                    Ok(client) => client,
+                    Err((_, e)) => {
+                        println!("Failed to accept websocket connection, {}", e);
+                        return;
+                    }
+                };
+                let (receiver, sender) = match client.split() {
+                    Ok(halves) => halves,
+                    Err(e) => {
+                        println!("Failed to set up websocket connection, {}", e);
+                        return;
+                    }
+                };
                 let mut default_subscription = HashSet::new();
                 let client_info = ClientInfo::new(secret_key);
 
@@ -75,21 +97,33 @@ pub fn start(controller: &ControllerPort, config: &SharedConfigFile, secret_key:
                 // Start authentication by offering the client a challenge
                 send_port.direct.send(MessageToClient::Auth(client_info.challenge.clone())).unwrap();
 
+                // Names and units for gimbal values, which never change while we're running
+                send_port.direct.send(MessageToClient::GimbalValueRegistry(registry::REGISTRY)).unwrap();
+
                 // Send the first config state this client will see
                 let config_is_current = Message::ConfigIsCurrent(config.get_latest());
                 default_subscription.insert(name_for_message_type(&config_is_current));
                 send_port.stream.send(config_is_current.timestamp()).unwrap();
 
                 // Start the message pump that reads from controller's Bus and writes to 'send_port'
-                let subscription_sender = start_ws_bus_receiver(&client_info, &controller, &send_port);
+                let subscription_sender = match start_ws_bus_receiver(&client_info, &controller, &send_port) {
+                    Ok(subscription_sender) => subscription_sender,
+                    Err(e) => {
+                        println!("Websocket connection can't follow the message bus, {}", e);
+                        client_info.flags.kill();
+                        return;
+                    }
+                };
                 subscription_sender.send(default_subscription).unwrap();
 
                 // Now handle incoming messages
-                let handler = MessageHandler { client_info, send_port, controller, config, subscription_sender };
+                let gimbal_polls = Mutex::new(ClientGimbalPolls::new());
+                let handler = MessageHandler { client_info, send_port, controller, config, subscription_sender, gimbal_polls };
                 handler.receive(receiver);
-            }).unwrap();
+            }).map_err(|e| format!("Failed to start websocket connection thread, {}", e))?;
         }
-    }).unwrap();
+        Err("Websocket server stopped accepting connections".into())
+    });
 }
 
 fn name_for_message_type(msg: &Message) -> String {
@@ -102,13 +136,28 @@ fn name_for_message_type(msg: &Message) -> String {
     panic!("Unexpected serialization format in name_for_message_type");
 }
 
-fn start_ws_bus_receiver(client_info: &ClientInfo, controller: &ControllerPort, send_port: &MessageSendPort) -> mpsc::SyncSender<Subscription> {
+fn validate_command(command: &Command) -> Result<(), String> {
+    // Catch the errors we can before they reach the controller, so they can be reported to this client
+    match command {
+        &Command::TrajectoryUpload(ref trajectory) => trajectory.validate(),
+        &Command::WinchCalibration(ref command) => command.validate(),
+        &Command::AnchorSurvey(ref command) => command.validate(),
+        &Command::GimbalSetAngle(ref target) => target.validate(),
+        &Command::GimbalSlewTo(ref slew) => slew.validate(),
+        &Command::GimbalParams(ref command) => command.validate(),
+        &Command::GimbalValueWrite(ref data) => registry::validate_write(data),
+        &Command::GimbalValueRequests(ref reqs) => reqs.iter().map(|req| req.validate()).collect(),
+        _ => Ok(()),
+    }
+}
+
+fn start_ws_bus_receiver(client_info: &ClientInfo, controller: &ControllerPort, send_port: &MessageSendPort) -> Result<mpsc::SyncSender<Subscription>, String> {
     // This thread just shuttles messages from the (fast, must not block)
     // internal message bus to the per-connection batching fifo buffer,
     // while filtering them against a subscription list. Returns a channel
     // for new subscription list updates.
 
-    let mut bus_receiver = controller.add_rx();
+    let mut bus_receiver = controller.add_rx()?;
     let send_port = send_port.clone();
     let client_flags = client_info.flags.clone();
     let (subscription_sender, subscription_reader) = mpsc::sync_channel(16);
@@ -147,9 +196,9 @@ fn start_ws_bus_receiver(client_info: &ClientInfo, controller: &ControllerPort,
             }
         }
         client_flags.kill();
-    }).unwrap();
+    }).map_err(|e| format!("Failed to start websocket bus receiver, {}", e))?;
 
-    subscription_sender
+    Ok(subscription_sender)
 }
 
 #[derive(Clone)]
@@ -373,6 +422,7 @@ struct MessageHandler {
     controller: ControllerPort,
     config: SharedConfigFile,
     subscription_sender: mpsc::SyncSender<Subscription>,
+    gimbal_polls: Mutex<ClientGimbalPolls>,
 }
 
 impl MessageHandler {
@@ -450,12 +500,24 @@ impl MessageHandler {
     fn handle_command(&self, command: Command) -> ClientResult {
         if !self.client_info.flags.is_authenticated() {
             Err(ClientError { code: ErrorCode::AuthRequired, message: None })
+        } else if let Err(e) = validate_command(&command).and_then(|_| self.limit_gimbal_polls(&command)) {
+            Err(ClientError { code: ErrorCode::InvalidCommand, message: Some(e) })
         } else {
             self.controller.send(Message::Command(command).timestamp());
             Ok(None)
         }
     }
 
+    fn limit_gimbal_polls(&self, command: &Command) -> Result<(), String> {
+        match command {
+            &Command::GimbalValueRequests(ref reqs) => match self.gimbal_polls.lock() {
+                Ok(mut polls) => polls.admit(reqs, Instant::now()),
+                Err(_) => Err("Gimbal poll limit unavailable".into()),
+            },
+            _ => Ok(()),
+        }
+    }
+
     fn handle_subscription(&self, subs: Subscription) -> ClientResult {
         match self.subscription_sender.send(subs) {
             Ok(_) => Ok(None),
@@ -486,3 +548,59 @@ impl MessageHandler {
         }
     }
 }
+
+/// Gimbal values one client asked to poll recently, so no single client can crowd the link
+struct ClientGimbalPolls {
+    last_requested: HashMap<GimbalValueAddress, Instant>,
+}
+
+impl ClientGimbalPolls {
+    fn new() -> ClientGimbalPolls {
+        ClientGimbalPolls {
+            last_requested: HashMap::new(),
+        }
+    }
+
+    fn admit(&mut self, reqs: &[GimbalValueRequest], now: Instant) -> Result<(), String> {
+        let window = Duration::from_millis(CLIENT_GIMBAL_POLL_WINDOW_MILLIS);
+        self.last_requested.retain(|_, timestamp| now < *timestamp + window);
+        let polled = {
+            let mut addrs : HashSet<&GimbalValueAddress> = self.last_requested.keys().collect();
+            addrs.extend(reqs.iter().map(|req| &req.addr));
+            addrs.len()
+        };
+        if polled > MAX_CLIENT_GIMBAL_POLLS {
+            return Err(format!("Each client may poll at most {} gimbal values at a time", MAX_CLIENT_GIMBAL_POLLS));
+        }
+        for req in reqs.iter() {
+            self.last_requested.insert(req.addr.clone(), now);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::GimbalRequestScope;
+
+    fn requests(indices: ::std::ops::Range<u8>) -> Vec<GimbalValueRequest> {
+        indices.map(|index| GimbalValueRequest {
+            addr: GimbalValueAddress { target: 0, index },
+            scope: GimbalRequestScope::Once,
+        }).collect()
+    }
+
+    #[test]
+    fn client_gimbal_polls_are_capped_until_they_expire() {
+        let t0 = Instant::now();
+        let mut polls = ClientGimbalPolls::new();
+        assert!(polls.admit(&requests(0 .. 40), t0).is_ok());
+        // Renewing the same values doesn't count against the limit
+        assert!(polls.admit(&requests(0 .. 40), t0 + Duration::from_millis(100)).is_ok());
+        assert!(polls.admit(&requests(40 .. 48), t0 + Duration::from_millis(200)).is_ok());
+        assert!(polls.admit(&requests(48 .. 49), t0 + Duration::from_millis(300)).is_err());
+        // Once the first 40 go unrenewed for the window, there's room again
+        assert!(polls.admit(&requests(48 .. 80), t0 + Duration::from_millis(1150)).is_ok());
+    }
+}
@@ -2,6 +2,8 @@ use std::io::Write;
 use std::thread;
 use std::time::{Duration, Instant};
 use botcomm::BotSocket;
+use controller::ControllerPort;
+use supervisor;
 use led::format::write_apa102_pixel;
 use led::shader::{Shader, LightEnvironment, PixelMapping};
 use led::models::LEDModel;
@@ -15,17 +17,18 @@ pub struct LightAnimator {
 }
 
 impl LightAnimator {
-    pub fn start(config: &LightAnimatorConfig, socket: &BotSocket) -> LightAnimator {
+    pub fn start(config: &LightAnimatorConfig, socket: &BotSocket, controller: &ControllerPort) -> LightAnimator {
         let last_sent = None;
         let (env_sender, env_recv) = sync_channel(128);
         let socket = socket.try_clone().unwrap();
         let config = config.clone();
-        thread::Builder::new().name("LightAnimator".into()).spawn(move || {
-            let mut anim = AnimatorThread::new(config, &socket, env_recv);
+        // Lights don't move anything, so a restart needn't halt the bot
+        supervisor::spawn("LightAnimator", false, controller, move || {
+            let mut anim = AnimatorThread::new(config.clone(), &socket, &env_recv);
             loop {
                 anim.frame();
             }
-        }).unwrap();
+        });
         LightAnimator {
             env_sender,
             last_sent,
@@ -46,7 +49,8 @@ impl LightAnimator {
 struct AnimatorThread<'a> {
     config: LightAnimatorConfig,
     env: Option<LightEnvironment>,
-    recv: Receiver<LightEnvironment>,
+    recv: &'a Receiver<LightEnvironment>,
+    socket: &'a BotSocket,
     model: LEDModel<'a>,
     shader: Shader,
     interpolation_target: Option<LightEnvironment>,
@@ -54,11 +58,12 @@ struct AnimatorThread<'a> {
 }
 
 impl<'a> AnimatorThread<'a> {
-    fn new(config: LightAnimatorConfig, socket: &'a BotSocket, recv: Receiver<LightEnvironment>) -> AnimatorThread<'a> {
+    fn new(config: LightAnimatorConfig, socket: &'a BotSocket, recv: &'a Receiver<LightEnvironment>) -> AnimatorThread<'a> {
         AnimatorThread {
             config,
             recv,
-            model: LEDModel::new(socket),
+            socket,
+            model: LEDModel::new(socket, socket.num_winches()),
             shader: Shader::new(),
             env: None,
             last_frame_timestamp: None,
@@ -102,6 +107,10 @@ impl<'a> AnimatorThread<'a> {
         };
 
         if let Some(ref env) = self.env {
+            if env.winches.len() != self.model.num_winches {
+                // Winches were added or removed in the config
+                self.model = LEDModel::new(self.socket, env.winches.len());
+            }
             self.shader.step(env, 1.0 / self.config.frame_rate);
             for i in 0..self.model.vec.len() {
                 let buf = self.render(&self.model.vec[i].pixels, env);
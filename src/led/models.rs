@@ -61,10 +61,11 @@ pub struct LEDWriterMapping<'a> {
 #[derive(Debug)]
 pub struct LEDModel<'a> {
 	pub vec: Vec<LEDWriterMapping<'a>>,
+	pub num_winches: usize,
 }
 
 impl<'a> LEDModel<'a> {
-	pub fn new(socket: &'a BotSocket) -> LEDModel<'a> {
+	pub fn new(socket: &'a BotSocket, num_winches: usize) -> LEDModel<'a> {
 		let mut vec = Vec::new();
 
 		vec.push(LEDWriterMapping {
@@ -72,13 +73,13 @@ impl<'a> LEDModel<'a> {
 			pixels: flyer(),
 		});
 
-		for id in 0..socket.num_winches() {
+		for id in 0..num_winches {
 			vec.push(LEDWriterMapping {
 				writer: socket.winch_leds(id),
 				pixels: winch(id),
 			});
 		}
 
-		LEDModel { vec }
+		LEDModel { vec, num_winches }
 	}
 }
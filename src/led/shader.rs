@@ -114,14 +114,16 @@ impl Shader {
 	pub fn pixel(&self, env: &LightEnvironment, mapping: &PixelMapping) -> Vector3<f32> {
 		let c = match &mapping.usage {
 
-			&PixelUsage::Winch(id, loc, _x) => {
-				self.winch_pixel(&env.winches[id], env, loc)
+			// Pixels for winches that aren't configured stay dark
+			&PixelUsage::Winch(id, loc, _x) => match env.winches.get(id) {
+				Some(winch) => self.winch_pixel(winch, env, loc),
+				None => [0.0, 0.0, 0.0],
 			},
 
-			&PixelUsage::FlyerSaucer(id, loc) => {
-				let c = self.winch_pixel(&env.winches[id], env, loc);
-				vec3_scale(c, env.config.flyer_saucer_brightness)
-			}
+			&PixelUsage::FlyerSaucer(id, loc) => match env.winches.get(id) {
+				Some(winch) => vec3_scale(self.winch_pixel(winch, env, loc), env.config.flyer_saucer_brightness),
+				None => [0.0, 0.0, 0.0],
+			},
 
 			&PixelUsage::FlyerRing(angle, z) => {
 				let x = angle_normalize(angle - env.camera_yaw_angle);
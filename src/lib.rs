@@ -47,9 +47,19 @@ mod config;
 pub use config::*;
 
 mod controller;
-pub use controller::{Controller, ControllerPort};
+pub use controller::{Controller, ControllerPort, Clock, SystemClock, ManualClock};
 
 mod botcomm;
 pub use botcomm::BotSocket;
 
+mod netmonitor;
+mod botauth;
+mod supervisor;
+
+mod buslog;
+pub use buslog::{BusLogReader, BusLogWriter, BusLogRecord};
+
+mod simulator;
+pub use simulator::{Simulator, simulator_config};
+
 pub mod interface;
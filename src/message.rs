@@ -2,8 +2,10 @@ use vecmath::*;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::time::Instant;
-use config::{Config, ControllerMode};
+use std::net::SocketAddr;
+use config::{Config, ControllerMode, WinchCalibration};
 use fygimbal::GimbalPacket;
+use fygimbal::protocol::{NUM_AXES, NUM_VALUES};
 
 pub const TICK_HZ : u32 = 250;
 
@@ -21,6 +23,17 @@ pub enum Command {
     GimbalPacket(GimbalPacket),
     GimbalValueWrite(GimbalValueData),
     GimbalValueRequests(Vec<GimbalValueRequest>),
+    GimbalSetAngle(GimbalAngleTarget),
+    GimbalSlewTo(GimbalSlew),
+    GimbalReleaseAngle,
+    GimbalFirmware(GimbalFirmwareCommand),
+    TrajectoryUpload(Trajectory),
+    WinchCalibration(WinchCalibrationCommand),
+    AnchorSurvey(AnchorSurveyCommand),
+    WinchAutotune(WinchAutotuneCommand),
+    GimbalTuning(GimbalTuningCommand),
+    GimbalParams(GimbalParamCommand),
+    GimbalCalibration(GimbalCalibrationCommand),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -45,14 +58,29 @@ pub struct TimestampedMessage {
 pub enum Message {
     Command(Command),
     FlyerSensors(FlyerSensors),
+    FlyerPose(FlyerPose),
     WinchStatus(usize, WinchStatus),
+    BotModuleStatus(BotModuleStatus),
+    NetworkStatus(NetworkStatus),
+    SubsystemFault(SubsystemFault),
     UpdateConfig(Value),
     ConfigIsCurrent(Config),
     GimbalControlStatus(GimbalControlStatus),
     GimbalValue(GimbalValueData, GimbalValueOp),
     UnhandledGimbalPacket(GimbalPacket),
+    GimbalPollerStats(GimbalPollerStats),
+    GimbalLinkStatus(GimbalLinkStatus),
     CameraOverlayScene(Vec<OverlayRect>),
     CameraInitTrackedRegion(Vector4<f32>),
+    TrajectoryStatus(TrajectoryStatus),
+    GeofenceStatus(GeofenceStatus),
+    WinchCalibrationStatus(WinchCalibrationStatus),
+    AnchorSurveyStatus(AnchorSurveyStatus),
+    WinchAutotuneStatus(WinchAutotuneStatus),
+    GimbalTuningStatus(GimbalTuningStatus),
+    GimbalFirmwareStatus(GimbalFirmwareStatus),
+    GimbalParamStatus(GimbalParamStatus),
+    GimbalCalibrationStatus(GimbalCalibrationStatus),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
@@ -75,7 +103,7 @@ pub struct CameraOutputStatus {
     pub active_seconds: f64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct GimbalValueAddress {
     pub target: u8,
     pub index: u8,
@@ -93,16 +121,108 @@ pub struct GimbalValueRequest {
     pub scope: GimbalRequestScope,
 }
 
+/// Fastest poll a GimbalValueRequests command may ask for. The controller's own polls go
+/// straight to the GimbalPort, and only they may use High priority or Continuous scope.
+pub const MAX_REQUESTED_POLL_HZ : f32 = 20.0;
+
+impl GimbalValueRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.scope {
+            GimbalRequestScope::Rate(rate) if !(rate.hz.is_finite() && rate.hz > 0.0) => {
+                Err("Gimbal poll rate must be positive".into())
+            },
+            GimbalRequestScope::Rate(rate) if rate.hz > MAX_REQUESTED_POLL_HZ => {
+                Err(format!("Gimbal poll rate is limited to {} Hz", MAX_REQUESTED_POLL_HZ))
+            },
+            GimbalRequestScope::Rate(rate) if rate.priority == GimbalRequestPriority::High => {
+                Err("High priority gimbal polls are reserved for the controller".into())
+            },
+            GimbalRequestScope::Continuous => {
+                Err("Continuous gimbal polls are reserved for the controller, request a rate instead".into())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum GimbalRequestScope {
     Once,
+    /// Poll as often as the link allows at normal priority, until requests stop arriving
     Continuous,
+    /// Poll at a target rate, until requests stop arriving
+    Rate(GimbalPollRate),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GimbalPollRate {
+    pub hz: f32,
+    pub priority: GimbalRequestPriority,
+}
+
+/// Values that are due at the same time are polled in priority order, so higher priorities
+/// keep their rate when the link is oversubscribed. Polls left waiting rank higher the longer
+/// they wait, so lower priorities slow down rather than stop.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GimbalRequestPriority {
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalPollerStats {
+    /// Smoothed time between sending a read and receiving its response
+    pub round_trip_ms: f32,
+    /// Smoothed fraction of reads that went unanswered
+    pub loss: f32,
+    /// Reads per second the link can sustain, estimated from round trip time and loss
+    pub read_budget_hz: f32,
+    pub reads_per_sec: f32,
+    pub writes_per_sec: f32,
+    /// Sum of the target rates of active rate requests
+    pub requested_hz: f32,
+    pub active_requests: usize,
+    /// Rate requests that were polled well below their target rate
+    pub lagging: Vec<GimbalValueAddress>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum GimbalLinkState {
+    Ok,
+    /// Reads are going unanswered often enough that values may be stale
+    Degraded,
+    /// Nothing is getting through, or we can't write to the link at all
+    Down,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalLinkStatus {
+    pub state: GimbalLinkState,
+    /// Counts since the poller started
+    pub crc_errors: u32,
+    /// Times the receiver skipped bytes to find the next packet, and how many it skipped
+    pub resyncs: u32,
+    pub discarded_bytes: u64,
+    pub malformed_replies: u32,
+    pub late_replies: u32,
+    pub read_timeouts: u32,
+    pub read_retries: u32,
+    pub write_retries: u32,
+    pub write_failures: u32,
+    /// Sending to the gimbal or buffering what it sent us failed
+    pub io_errors: u32,
+    /// Reads in a row that went unanswered
+    pub consecutive_timeouts: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum GimbalValueOp {
     ReadComplete,
+    /// Sent, and for persistent values also confirmed by reading the value back
     WriteComplete,
+    /// The value read back never matched, even after retrying the write
+    WriteFailed,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -123,6 +243,219 @@ pub struct GimbalControlStatus {
     pub current_osc_detector: Vector3<f32>,
     pub current_peak_detector: Vector3<f32>,
     pub current_error_duration: f32,
+    pub angle_control: Option<GimbalAngleStatus>,
+    pub look_at: Option<GimbalLookAtStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalLookAtStatus {
+    pub point: Vector3<f32>,
+    /// Encoder angles aiming at the point, clamped to the configured limits
+    pub angles: Vector2<i16>,
+    /// False if the point is outside the limits and we're aiming as close as we can
+    pub reachable: bool,
+}
+
+/// Absolute gimbal angles to hold, overriding tracking until released
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalAngleTarget {
+    /// Encoder angles relative to center calibration, clamped to the configured limits
+    pub angles: Vector2<i16>,
+    /// Highest control rate used on the way, in the same units as config.gimbal.max_rate
+    pub max_rate: f32,
+    /// Encoder counts from the target on each axis that count as arrived
+    pub settle_tolerance: i16,
+}
+
+impl GimbalAngleTarget {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.max_rate.is_finite() && self.max_rate > 0.0) {
+            Err("Gimbal max_rate must be positive".into())
+        } else if self.settle_tolerance < 0 {
+            Err("Gimbal settle_tolerance can't be negative".into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Move to an angle target along a straight line, instead of as fast as the hold loop can
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalSlew {
+    pub target: GimbalAngleTarget,
+    /// Setpoint speed along the line, in encoder counts per second
+    pub speed: f32,
+}
+
+impl GimbalSlew {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.speed.is_finite() && self.speed > 0.0) {
+            Err("Gimbal slew speed must be positive".into())
+        } else {
+            self.target.validate()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GimbalFirmwareCommand {
+    /// Flash the images named in the gimbal_firmware config, which the controller reads
+    /// itself so they never travel over the bus. The gimbal must be power cycled after
+    /// starting, so its bootloader sees our request to stay in boot mode.
+    Start,
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GimbalFirmwarePhase {
+    WaitingForBootloader,
+    Writing,
+    Finished,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalFirmwareStatus {
+    pub phase: GimbalFirmwarePhase,
+    /// MCU being written, 0 through 2
+    pub mcu: u8,
+    pub bootloader_version: Option<u16>,
+    pub block: usize,
+    pub total_blocks: usize,
+    /// Requests sent again after going unanswered
+    pub retries: u32,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GimbalParamCommand {
+    /// Read every value from the gimbal and save them under a profile name
+    Snapshot(String),
+    /// Compare a saved profile with another one, or with the live gimbal if None
+    Diff(String, Option<String>),
+    /// Write the selected values from a saved profile, then save them to the gimbal's flash
+    Restore(String, Vec<GimbalValueAddress>),
+    ListProfiles,
+    Cancel,
+}
+
+impl GimbalParamCommand {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            &GimbalParamCommand::Snapshot(ref name) => validate_profile_name(name),
+            &GimbalParamCommand::Diff(ref from, ref to) => {
+                validate_profile_name(from)?;
+                match to {
+                    &Some(ref to) => validate_profile_name(to),
+                    &None => Ok(()),
+                }
+            },
+            &GimbalParamCommand::Restore(ref name, ref addrs) => {
+                validate_profile_name(name)?;
+                if addrs.is_empty() {
+                    Err("No gimbal values selected to restore".into())
+                } else if addrs.iter().any(|addr| addr.index as usize >= NUM_VALUES || addr.target as usize >= NUM_AXES) {
+                    Err("Gimbal value address out of range".into())
+                } else {
+                    Ok(())
+                }
+            },
+            &GimbalParamCommand::ListProfiles => Ok(()),
+            &GimbalParamCommand::Cancel => Ok(()),
+        }
+    }
+}
+
+/// Profiles are files in the profile directory, so names can't reach outside of it
+fn validate_profile_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.starts_with('.') || name.contains(|c: char| c == '/' || c == '\\') {
+        Err(format!("Bad gimbal profile name {:?}", name))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GimbalParamActivity {
+    Snapshot(String),
+    DiffLive(String),
+    Restore(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalParamStatus {
+    pub profiles: Vec<String>,
+    pub activity: Option<GimbalParamActivity>,
+    /// Values read or written so far by the current activity
+    pub progress: usize,
+    pub total: usize,
+    pub diff: Option<GimbalParamDiff>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalParamDiff {
+    pub from: String,
+    /// Profile compared against, or None for the live gimbal
+    pub to: Option<String>,
+    pub values: Vec<GimbalValueDiff>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalValueDiff {
+    pub addr: GimbalValueAddress,
+    pub from: Option<i16>,
+    pub to: Option<i16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GimbalCalibrationProcedure {
+    /// Measure gyro drift, with the gimbal resting perfectly still
+    GyroDrift,
+    /// Measure accelerometer offsets, with the IMU resting level
+    AccelOffsets,
+    /// Store each encoder's center angle, with the camera aimed straight ahead by hand
+    EncoderCenter,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GimbalCalibrationCommand {
+    Start(GimbalCalibrationProcedure),
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GimbalCalibrationPhase {
+    CheckingMotors,
+    ReadingBefore,
+    Capturing,
+    Verifying,
+    Saving,
+    Finished,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalCalibrationStatus {
+    pub procedure: GimbalCalibrationProcedure,
+    pub phase: GimbalCalibrationPhase,
+    pub values: Vec<GimbalCalibrationValue>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalCalibrationValue {
+    pub addr: GimbalValueAddress,
+    pub before: Option<i16>,
+    pub after: Option<i16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalAngleStatus {
+    pub target: GimbalAngleTarget,
+    /// Where the hold loop is aiming now, which moves toward the target during a slew
+    pub setpoint: Vector2<f32>,
+    pub settled: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -184,6 +517,294 @@ impl CameraDetectedObjects {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrajectoryWaypoint {
+    /// Seconds since the start of playback
+    pub time: f32,
+    /// Flyer location in meters, in the same coordinate system as the winch anchors
+    pub position: Vector3<f32>,
+    /// Optional gimbal encoder angles [yaw, pitch] to arrive at along with this waypoint
+    pub gimbal_angles: Option<Vector2<i16>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    pub waypoints: Vec<TrajectoryWaypoint>,
+}
+
+impl Trajectory {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.waypoints.is_empty() {
+            return Err("Trajectory has no waypoints".into());
+        }
+        let mut prev_time = 0.0;
+        for (index, waypoint) in self.waypoints.iter().enumerate() {
+            if !waypoint.time.is_finite() || waypoint.time < prev_time || (index > 0 && waypoint.time == prev_time) {
+                return Err(format!("Waypoint {} time must be increasing", index));
+            }
+            if !(waypoint.position[0].is_finite() && waypoint.position[1].is_finite() && waypoint.position[2].is_finite()) {
+                return Err(format!("Waypoint {} position must be finite", index));
+            }
+            prev_time = waypoint.time;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrajectoryStatus {
+    /// Seconds of playback so far
+    pub elapsed: f32,
+    /// Total length of the trajectory in seconds
+    pub duration: f32,
+    pub target_position: Vector3<f32>,
+    pub target_velocity: Vector3<f32>,
+    pub gimbal_target: Option<Vector2<i16>>,
+    pub is_complete: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeofenceBoundary {
+    Floor,
+    Ceiling,
+    Box,
+    Plane(usize),
+    AnchorHull(usize),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GeofenceStatus {
+    /// Boundaries the flyer is currently outside of
+    pub violations: Vec<GeofenceBoundary>,
+    /// Boundaries currently slowing down the commanded motion
+    pub slowdowns: Vec<GeofenceBoundary>,
+}
+
+/// Steps in the guided load cell and encoder calibration for one winch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum WinchCalibrationCommand {
+    /// Begin calibrating a winch, discarding any results not yet confirmed
+    Start(usize),
+    /// Average the load cell with nothing hanging from the rope
+    Tare,
+    /// Average the load cell with a known mass hanging from the rope, in kilograms
+    KnownMass(f32),
+    /// Remember the current encoder position as the start of a measured length of rope
+    MarkReel,
+    /// Length of rope reeled in or out since the mark, as measured by the operator, in meters
+    KnownReelLength(f32),
+    /// Save the proposed calibration to the config file
+    Confirm,
+    Cancel,
+}
+
+impl WinchCalibrationCommand {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            &WinchCalibrationCommand::KnownMass(kg) if !(kg.is_finite() && kg > 0.0) => Err("Known mass must be positive".into()),
+            &WinchCalibrationCommand::KnownReelLength(m) if !(m.is_finite() && m > 0.0) => Err("Reel length must be positive".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WinchMassSample {
+    pub kg: f32,
+    /// Average raw load cell reading
+    pub force_count: f32,
+    /// Known mass minus the mass predicted by the proposed calibration
+    pub residual_kg: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WinchCalibrationStatus {
+    pub id: usize,
+    /// False once the session has been confirmed or cancelled
+    pub active: bool,
+    /// Waiting on load cell readings for a tare or known mass
+    pub sampling: bool,
+    pub current: WinchCalibration,
+    pub proposed: WinchCalibration,
+    pub tare_count: Option<f32>,
+    pub masses: Vec<WinchMassSample>,
+    pub mass_rms_residual_kg: Option<f32>,
+    pub reel_mark: Option<i32>,
+    /// Encoder counts covered by the last measured length
+    pub reel_counts: Option<i32>,
+    /// Problem with the most recent command, if any
+    pub error: Option<String>,
+}
+
+/// Where the flyer is during one pose of an anchor survey, independent of the rope geometry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SurveyReference {
+    /// Flyer position measured by the operator
+    Position(Vector3<f32>),
+    /// Horizontal position measured by the operator, with height from the downward LIDAR
+    HorizontalWithLidarHeight(Vector2<f32>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AnchorSurveyCommand {
+    /// Begin a new survey, discarding any poses recorded so far
+    Start,
+    /// Record every winch's encoder position along with where the flyer is now
+    RecordPose(SurveyReference),
+    RemovePose(usize),
+    /// Fit anchor locations and rope offsets to the recorded poses
+    Solve,
+    /// Save the fitted anchor locations to the config file
+    Confirm,
+    Cancel,
+}
+
+impl AnchorSurveyCommand {
+    pub fn validate(&self) -> Result<(), String> {
+        let finite = match self {
+            &AnchorSurveyCommand::RecordPose(SurveyReference::Position(p)) => p.iter().all(|x| x.is_finite()),
+            &AnchorSurveyCommand::RecordPose(SurveyReference::HorizontalWithLidarHeight(p)) => p.iter().all(|x| x.is_finite()),
+            _ => true,
+        };
+        if finite { Ok(()) } else { Err("Survey position must be finite".into()) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnchorSurveyPose {
+    pub position: Vector3<f32>,
+    /// Encoder position of each winch, indexed by winch id
+    pub winch_counts: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnchorFit {
+    pub loc: Vector3<f32>,
+    /// Rope length at encoder position zero
+    pub rope_offset_m: f32,
+    /// Measured minus fitted rope length at each pose
+    pub residuals_m: Vec<f32>,
+    pub rms_residual_m: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnchorSurveyStatus {
+    /// False once the survey has been confirmed or cancelled
+    pub active: bool,
+    pub poses: Vec<AnchorSurveyPose>,
+    /// Anchor locations from the config file, for comparison
+    pub current_locs: Vec<Vector3<f32>>,
+    /// Fitted geometry for each winch, until the set of poses changes
+    pub fit: Option<Vec<AnchorFit>>,
+    /// Problem with the most recent command, if any
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum WinchAutotuneCommand {
+    /// Begin a relay feedback test on the winch under manual control
+    Start(usize),
+    Cancel,
+    /// Save one of the proposed gain sets to the config file
+    Confirm(WinchTuningRule),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WinchTuningRule {
+    ZieglerNichols,
+    TyreusLuyben,
+    NoOvershoot,
+}
+
+/// PID gains in the same units as BotParams, PWM per meter, meter-second, and meter per second
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WinchPIDProposal {
+    pub rule: WinchTuningRule,
+    pub pwm_gain_p: f32,
+    pub pwm_gain_i: f32,
+    pub pwm_gain_d: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WinchAutotuneStatus {
+    pub id: usize,
+    /// True while the relay test is driving the winch
+    pub running: bool,
+    pub cycles_completed: u32,
+    /// Relay gain at which the loop oscillates, in PWM per meter
+    pub ultimate_gain: Option<f32>,
+    pub ultimate_period_sec: Option<f32>,
+    pub amplitude_m: Option<f32>,
+    pub proposals: Vec<WinchPIDProposal>,
+    /// Why the test stopped early, or a problem with the most recent command
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GimbalTuningCommand {
+    /// Run the step and chirp test on one axis, replacing any earlier model for it
+    Start(GimbalTuningAxis),
+    Cancel,
+    /// Save the proposed gains to the config file
+    Confirm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GimbalTuningAxis {
+    Yaw,
+    Pitch,
+}
+
+impl GimbalTuningAxis {
+    pub fn index(self) -> usize {
+        match self {
+            GimbalTuningAxis::Yaw => 0,
+            GimbalTuningAxis::Pitch => 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GimbalTuningPhase {
+    Settle,
+    StepForward,
+    StepReverse,
+    Chirp,
+    Coast,
+}
+
+/// First order lag with dead time from control rate to encoder angle velocity
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalAxisModel {
+    /// Encoder counts per second at steady state, per unit of control rate
+    pub gain: f32,
+    pub time_constant_sec: f32,
+    pub delay_sec: f32,
+    /// How well the model reproduces the recorded angles, in encoder counts
+    pub rms_residual: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalTuningProposal {
+    pub hold_p_gain: f32,
+    pub hold_i_gain: f32,
+    pub limiter_gain: f32,
+    pub limiter_slowdown_extent: Vector2<f32>,
+    /// Simulated overshoot of each modeled axis after a hold step, in percent
+    pub hold_overshoot_percent: Vector2<Option<f32>>,
+    /// Simulated distance past the endstop after approaching it at max_rate, in encoder counts
+    pub limit_overshoot: Vector2<Option<f32>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GimbalTuningStatus {
+    /// Axis and phase of the test in progress, if any
+    pub testing: Option<(GimbalTuningAxis, GimbalTuningPhase)>,
+    pub models: Vector2<Option<GimbalAxisModel>>,
+    pub proposal: Option<GimbalTuningProposal>,
+    pub error: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct CameraDetectedObject {
     pub rect: Vector4<f32>,
@@ -254,6 +875,22 @@ pub struct FlyerSensors {
     pub imu: IMUTelemetry,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlyerPose {
+    /// Meters, in the same coordinate system as the winch anchor locations
+    pub position: Vector3<f32>,
+    /// Meters per second
+    pub velocity: Vector3<f32>,
+    /// Unit quaternion [w, x, y, z] from the flyer's IMU
+    pub orientation: Vector4<f32>,
+    /// Per-axis variance of the position estimate, in square meters
+    pub position_variance: Vector3<f32>,
+    /// Per-axis variance of the velocity estimate, in square meters per second squared
+    pub velocity_variance: Vector3<f32>,
+    /// Number of winches whose rope lengths contribute to the estimate
+    pub num_ropes: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ForceTelemetry {
     pub measure: i32,           // Uncalibrated, (+) = increasing tension
@@ -341,3 +978,121 @@ pub struct WinchStatus {
     pub sensors: WinchSensors,
     pub motor: WinchMotorControl
 }
+
+/// Sent to a bot module, asking which protocol version and message layouts it speaks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BotHello {
+    pub protocol_version: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BotMessageLayout {
+    pub code: u8,
+    pub layout_hash: u32,           // FNV-1a of the layout string the module was built with
+}
+
+/// A module's answer to BotHello
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BotCapabilities {
+    pub protocol_version: u16,
+    pub firmware_version: u32,
+    pub messages: Vec<BotMessageLayout>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BotModule {
+    Flyer,
+    Winch(usize),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BotModuleState {
+    /// Hasn't answered a hello, so we assume older firmware and accept everything
+    Unknown,
+    Compatible,
+    /// Same protocol version, but some messages are laid out differently and are refused
+    Mismatched,
+    /// Different protocol version, every message is refused
+    Incompatible,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BotModuleStatus {
+    pub module: BotModule,
+    pub state: BotModuleState,
+    pub capabilities: Option<BotCapabilities>,
+    /// Messages this module should speak that we won't send or accept
+    pub refused_codes: Vec<u8>,
+    /// Counts since startup of messages dropped from this module
+    pub refused_messages: u32,
+    pub malformed_messages: u32,
+}
+
+/// Traffic from one module over the last NetworkStatus interval
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkStatus {
+    pub packets_per_sec: f32,
+    /// Fraction of packets lost, from gaps in the module's packet counter, or for the
+    /// gimbal the poller's smoothed fraction of unanswered reads
+    pub loss: f32,
+    /// Smoothed loopback round trip, None until a probe has been answered
+    pub latency_ms: Option<f32>,
+    /// Time since the last packet arrived, None if nothing has arrived yet
+    pub silence_ms: Option<f32>,
+    pub malformed: u32,
+    pub refused: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkStatus {
+    pub flyer: LinkStatus,
+    pub gimbal: LinkStatus,
+    pub winches: Vec<LinkStatus>,
+    /// Packets from addresses that aren't any configured module, and a few of those addresses
+    pub unknown_packets: u32,
+    pub unknown_sources: Vec<SocketAddr>,
+}
+
+/// A supervised thread failed, and is being restarted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SubsystemFault {
+    /// Name of the failed thread
+    pub subsystem: String,
+    pub error: String,
+    /// Failures of this subsystem since startup, including this one
+    pub failures: u32,
+    /// Safety-critical subsystems halt the bot when they fail
+    pub critical: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_names_stay_in_their_directory() {
+        assert!(validate_profile_name("bench-2018.05").is_ok());
+        assert!(validate_profile_name("").is_err());
+        assert!(validate_profile_name(".").is_err());
+        assert!(validate_profile_name("..").is_err());
+        assert!(validate_profile_name(".hidden").is_err());
+        assert!(validate_profile_name("../escape").is_err());
+        assert!(validate_profile_name("nested/name").is_err());
+        assert!(validate_profile_name("/absolute").is_err());
+        assert!(validate_profile_name("back\\slash").is_err());
+    }
+
+    #[test]
+    fn gimbal_value_requests_leave_the_link_to_the_controller() {
+        let request = |scope| GimbalValueRequest { addr: GimbalValueAddress { target: 0, index: 0x26 }, scope };
+        let rate = |hz, priority| GimbalRequestScope::Rate(GimbalPollRate { hz, priority });
+        assert!(request(GimbalRequestScope::Once).validate().is_ok());
+        assert!(request(rate(MAX_REQUESTED_POLL_HZ, GimbalRequestPriority::Normal)).validate().is_ok());
+        assert!(request(rate(0.5, GimbalRequestPriority::Low)).validate().is_ok());
+        assert!(request(rate(0.0, GimbalRequestPriority::Normal)).validate().is_err());
+        assert!(request(rate(::std::f32::NAN, GimbalRequestPriority::Normal)).validate().is_err());
+        assert!(request(rate(MAX_REQUESTED_POLL_HZ * 2.0, GimbalRequestPriority::Normal)).validate().is_err());
+        assert!(request(rate(1.0, GimbalRequestPriority::High)).validate().is_err());
+        assert!(request(GimbalRequestScope::Continuous).validate().is_err());
+    }
+}
@@ -0,0 +1,319 @@
+//! Link quality for each bot module, measured from the traffic the BotReceiver sees.
+//! Winches and the flyer number their packets, so gaps in those counters tell us how many
+//! were lost on the way. Latency comes from loopback probes we send each module, and for
+//! the gimbal from the poller's own read timing.
+
+use message::*;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often to publish NetworkStatus and probe each module's latency
+const REPORT_INTERVAL_MILLIS : u64 = 1000;
+
+/// Counter jumps larger than this are a module restarting rather than lost packets
+const MAX_SEQUENCE_GAP : u32 = 1000;
+
+/// Smoothing for loopback round trip time, per probe
+const LATENCY_FILTER_RATE : f32 = 0.25;
+
+/// Unknown source addresses listed per report, so a flood can't grow the message without bound
+const MAX_UNKNOWN_SOURCES : usize = 8;
+
+fn millis(duration: Duration) -> f32 {
+    duration.as_secs() as f32 * 1e3 + duration.subsec_nanos() as f32 * 1e-6
+}
+
+struct LinkCounter {
+    packets: u32,
+    /// Packets carrying a sequence counter, and how many the counter says were sent
+    sequenced: u32,
+    expected: u32,
+    malformed: u32,
+    refused: u32,
+    last_sequence: Option<u32>,
+    last_packet: Option<Instant>,
+    latency_ms: Option<f32>,
+    probe: Option<(u32, Instant)>,
+}
+
+impl LinkCounter {
+    fn new() -> LinkCounter {
+        LinkCounter {
+            packets: 0,
+            sequenced: 0,
+            expected: 0,
+            malformed: 0,
+            refused: 0,
+            last_sequence: None,
+            last_packet: None,
+            latency_ms: None,
+            probe: None,
+        }
+    }
+
+    fn packet(&mut self, now: Instant) {
+        self.packets += 1;
+        self.last_packet = Some(now);
+    }
+
+    fn sequence(&mut self, sequence: u32) {
+        let gap = self.last_sequence.map_or(1, |last| sequence.wrapping_sub(last));
+        self.sequenced += 1;
+        self.expected += if gap >= 1 && gap <= MAX_SEQUENCE_GAP { gap } else { 1 };
+        self.last_sequence = Some(sequence);
+    }
+
+    fn probe_answered(&mut self, sequence: u32, now: Instant) {
+        if let Some((sent, timestamp)) = self.probe {
+            if sent == sequence {
+                let rtt = millis(now - timestamp);
+                self.latency_ms = Some(match self.latency_ms {
+                    None => rtt,
+                    Some(latency) => latency + (rtt - latency) * LATENCY_FILTER_RATE,
+                });
+                self.probe = None;
+            }
+        }
+    }
+
+    /// Summarize the interval that just ended and start counting a new one
+    fn report(&mut self, interval_sec: f32, now: Instant) -> LinkStatus {
+        let status = LinkStatus {
+            packets_per_sec: self.packets as f32 / interval_sec,
+            loss: if self.expected > 0 { 1.0 - self.sequenced as f32 / self.expected as f32 } else { 0.0 },
+            latency_ms: self.latency_ms,
+            silence_ms: self.last_packet.map(|timestamp| millis(now - timestamp)),
+            malformed: self.malformed,
+            refused: self.refused,
+        };
+        self.packets = 0;
+        self.sequenced = 0;
+        self.expected = 0;
+        self.malformed = 0;
+        self.refused = 0;
+        status
+    }
+}
+
+pub struct NetworkMonitor {
+    flyer: LinkCounter,
+    gimbal: LinkCounter,
+    winches: Vec<LinkCounter>,
+    unknown_packets: u32,
+    unknown_sources: Vec<SocketAddr>,
+    next_probe: u32,
+    last_report: Instant,
+}
+
+impl NetworkMonitor {
+    pub fn new(num_winches: usize, now: Instant) -> NetworkMonitor {
+        NetworkMonitor {
+            flyer: LinkCounter::new(),
+            gimbal: LinkCounter::new(),
+            winches: (0 .. num_winches).map(|_| LinkCounter::new()).collect(),
+            unknown_packets: 0,
+            unknown_sources: Vec::new(),
+            next_probe: 0,
+            last_report: now,
+        }
+    }
+
+    /// Follow the configured winch count, which can change at runtime
+    pub fn resize(&mut self, num_winches: usize) {
+        self.winches.truncate(num_winches);
+        while self.winches.len() < num_winches {
+            self.winches.push(LinkCounter::new());
+        }
+    }
+
+    fn link(&mut self, module: BotModule) -> Option<&mut LinkCounter> {
+        match module {
+            BotModule::Flyer => Some(&mut self.flyer),
+            BotModule::Winch(id) => self.winches.get_mut(id),
+        }
+    }
+
+    pub fn packet(&mut self, module: BotModule, now: Instant) {
+        if let Some(link) = self.link(module) {
+            link.packet(now);
+        }
+    }
+
+    /// A packet numbered by the module, for measuring loss
+    pub fn sequence(&mut self, module: BotModule, sequence: u32) {
+        if let Some(link) = self.link(module) {
+            link.sequence(sequence);
+        }
+    }
+
+    pub fn malformed(&mut self, module: BotModule) {
+        if let Some(link) = self.link(module) {
+            link.malformed += 1;
+        }
+    }
+
+    pub fn refused(&mut self, module: BotModule) {
+        if let Some(link) = self.link(module) {
+            link.refused += 1;
+        }
+    }
+
+    pub fn gimbal_packet(&mut self, now: Instant) {
+        self.gimbal.packet(now);
+    }
+
+    pub fn unknown_source(&mut self, addr: SocketAddr) {
+        self.unknown_packets += 1;
+        if self.unknown_sources.len() < MAX_UNKNOWN_SOURCES && !self.unknown_sources.contains(&addr) {
+            self.unknown_sources.push(addr);
+        }
+    }
+
+    /// Start a latency probe, returning the sequence number to send in a loopback packet
+    pub fn probe_sent(&mut self, module: BotModule, now: Instant) -> u32 {
+        let sequence = self.next_probe;
+        self.next_probe = self.next_probe.wrapping_add(1);
+        if let Some(link) = self.link(module) {
+            link.probe = Some((sequence, now));
+        }
+        sequence
+    }
+
+    pub fn probe_answered(&mut self, module: BotModule, sequence: u32, now: Instant) {
+        if let Some(link) = self.link(module) {
+            link.probe_answered(sequence, now);
+        }
+    }
+
+    pub fn report_due(&self, now: Instant) -> bool {
+        now > self.last_report + Duration::from_millis(REPORT_INTERVAL_MILLIS)
+    }
+
+    /// Summarize the interval since the last report. The gimbal's loss and round trip are
+    /// measured by its poller, which knows which of its reads went unanswered.
+    pub fn report(&mut self, gimbal_round_trip_ms: f32, gimbal_loss: f32, now: Instant) -> NetworkStatus {
+        let interval_sec = millis(now - self.last_report) * 1e-3;
+        self.last_report = now;
+
+        let mut gimbal = self.gimbal.report(interval_sec, now);
+        gimbal.loss = gimbal_loss;
+        gimbal.latency_ms = Some(gimbal_round_trip_ms);
+
+        let status = NetworkStatus {
+            flyer: self.flyer.report(interval_sec, now),
+            gimbal,
+            winches: self.winches.iter_mut().map(|link| link.report(interval_sec, now)).collect(),
+            unknown_packets: self.unknown_packets,
+            unknown_sources: self.unknown_sources.clone(),
+        };
+        self.unknown_packets = 0;
+        self.unknown_sources.clear();
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-3, "{} is not {}", value, expected);
+    }
+
+    fn assert_loss(link: &mut LinkCounter, expected: f32) {
+        assert_near(link.report(1.0, Instant::now()).loss, expected);
+    }
+
+    fn sequences(list: &[u32]) -> LinkCounter {
+        let mut link = LinkCounter::new();
+        for &sequence in list.iter() {
+            link.sequence(sequence);
+        }
+        link
+    }
+
+    #[test]
+    fn gaps_in_the_sequence_are_lost_packets() {
+        assert_loss(&mut sequences(&[ 1, 2, 3, 4 ]), 0.0);
+        // 3 and 4 went missing
+        assert_loss(&mut sequences(&[ 1, 2, 5 ]), 0.4);
+        // A gap right at the limit is still loss
+        assert_loss(&mut sequences(&[ 1, 1 + MAX_SEQUENCE_GAP ]), 1.0 - 2.0 / (1.0 + MAX_SEQUENCE_GAP as f32));
+    }
+
+    #[test]
+    fn sequence_wraps_around() {
+        assert_loss(&mut sequences(&[ u32::max_value() - 1, u32::max_value(), 0, 1 ]), 0.0);
+        // Only 0 is missing
+        assert_loss(&mut sequences(&[ u32::max_value() - 1, u32::max_value(), 1 ]), 0.25);
+    }
+
+    #[test]
+    fn restart_is_not_loss() {
+        // Counting from zero again, and a jump too far forward to be lost packets
+        assert_loss(&mut sequences(&[ 5000, 5001, 3, 4 ]), 0.0);
+        assert_loss(&mut sequences(&[ 5000, 5001, 5002 + MAX_SEQUENCE_GAP, 5003 + MAX_SEQUENCE_GAP ]), 0.0);
+    }
+
+    #[test]
+    fn loss_is_counted_per_report() {
+        let mut link = sequences(&[ 1, 3 ]);
+        assert_loss(&mut link, 1.0 - 2.0 / 3.0);
+        // The last sequence carries over, so the first packet of the next interval counts its gap
+        link.sequence(4);
+        link.sequence(5);
+        assert_loss(&mut link, 0.0);
+        link.sequence(8);
+        assert_loss(&mut link, 2.0 / 3.0);
+        // Nothing numbered arrived at all
+        assert_loss(&mut link, 0.0);
+    }
+
+    #[test]
+    fn probe_round_trip_is_smoothed() {
+        let t0 = Instant::now();
+        let mut link = LinkCounter::new();
+        link.probe = Some((7, t0));
+        // An answer to some other probe doesn't count
+        link.probe_answered(6, t0 + Duration::from_millis(5));
+        assert_eq!(link.latency_ms, None);
+        link.probe_answered(7, t0 + Duration::from_millis(20));
+        assert_near(link.latency_ms.unwrap(), 20.0);
+        // Answered probes are done, a duplicate answer changes nothing
+        link.probe_answered(7, t0 + Duration::from_millis(500));
+        assert_near(link.latency_ms.unwrap(), 20.0);
+
+        let t1 = t0 + Duration::from_secs(1);
+        link.probe = Some((8, t1));
+        link.probe_answered(8, t1 + Duration::from_millis(60));
+        assert_near(link.latency_ms.unwrap(), 20.0 + 40.0 * LATENCY_FILTER_RATE);
+    }
+
+    #[test]
+    fn report_summarizes_the_interval() {
+        let t0 = Instant::now();
+        let mut monitor = NetworkMonitor::new(2, t0);
+        let winch = BotModule::Winch(1);
+        for sequence in 0 .. 10 {
+            monitor.packet(winch, t0 + Duration::from_millis(sequence as u64 * 100));
+            monitor.sequence(winch, sequence * 2);
+        }
+        monitor.malformed(winch);
+        let probe = monitor.probe_sent(winch, t0);
+        monitor.probe_answered(winch, probe, t0 + Duration::from_millis(4));
+
+        let t1 = t0 + Duration::from_secs(2);
+        assert!(monitor.report_due(t1));
+        let status = monitor.report(3.0, 0.1, t1);
+        let link = &status.winches[1];
+        assert_near(link.packets_per_sec, 5.0);
+        assert_near(link.loss, 1.0 - 10.0 / 19.0);
+        assert_near(link.latency_ms.unwrap(), 4.0);
+        assert_near(link.silence_ms.unwrap(), 1100.0);
+        assert_eq!(link.malformed, 1);
+        assert_eq!(status.winches[0].silence_ms, None);
+        assert_eq!(status.gimbal.latency_ms, Some(3.0));
+        assert_eq!(status.gimbal.loss, 0.1);
+        assert!(!monitor.report_due(t1));
+    }
+}
@@ -0,0 +1,153 @@
+//! Simulated flyer: a point mass hanging on elastic ropes, with its IMU and LIDAR sensors
+
+use message::*;
+use vecmath::*;
+use config::Config;
+
+const FLYER_MASS_KG : f32 = 1.6;
+const GRAVITY_M_PER_SEC2 : f32 = 9.81;
+
+/// Rope stiffness is this divided by the rope's length
+const ROPE_STIFFNESS_N : f32 = 40000.0;
+const ROPE_DAMPING_N_PER_M_PER_SEC : f32 = 60.0;
+const AIR_DRAG_N_PER_M_PER_SEC : f32 = 0.8;
+
+/// BNO055 fixed point scale for unit quaternions
+const IMU_QUATERNION_ONE : f32 = 16384.0;
+
+/// Per-sensor LIDAR sample rate, as a divisor of the flyer sensor packet rate
+const LIDAR_PACKET_DIVISOR : u32 = 2;
+
+pub struct SimFlyer {
+    position: Vector3<f32>,
+    velocity: Vector3<f32>,
+    accel: Vector3<f32>,
+    /// Unstretched rope length at winch encoder position zero
+    rope_offsets_m: Vec<f32>,
+    tensions_kg: Vec<f32>,
+    packet_counter: u32,
+}
+
+impl SimFlyer {
+    pub fn new(config: &Config) -> SimFlyer {
+        let home = config.flyer_home_loc;
+        SimFlyer {
+            position: home,
+            velocity: [0.0; 3],
+            accel: [0.0; 3],
+            rope_offsets_m: config.winches.iter().map(|w| vec3_len(vec3_sub(w.loc, home))).collect(),
+            tensions_kg: config.winches.iter().map(|_| 0.0).collect(),
+            packet_counter: 0,
+        }
+    }
+
+    pub fn tension_kg(&self, id: usize) -> f32 {
+        self.tensions_kg[id]
+    }
+
+    /// Integrate motion over one physics step, given how much rope each winch has reeled in
+    pub fn step(&mut self, config: &Config, reeled_in_m: &[f32], dt: f32) {
+        let mut force = [0.0, 0.0, -FLYER_MASS_KG * GRAVITY_M_PER_SEC2];
+        force = vec3_sub(force, vec3_scale(self.velocity, AIR_DRAG_N_PER_M_PER_SEC));
+
+        for (id, winch) in config.winches.iter().enumerate() {
+            let rope = vec3_sub(winch.loc, self.position);
+            let length = vec3_len(rope);
+            let unstretched = self.rope_offsets_m[id] - reeled_in_m.get(id).cloned().unwrap_or(0.0);
+            let tension_n = if length > 0.0 && unstretched > 0.0 {
+                let direction = vec3_scale(rope, 1.0 / length);
+                let stretch = length - unstretched;
+                let stretch_rate = -vec3_dot(self.velocity, direction);
+                // Ropes only pull
+                let t = (stretch * ROPE_STIFFNESS_N / unstretched + stretch_rate * ROPE_DAMPING_N_PER_M_PER_SEC).max(0.0);
+                force = vec3_add(force, vec3_scale(direction, t));
+                t
+            } else {
+                0.0
+            };
+            self.tensions_kg[id] = tension_n / GRAVITY_M_PER_SEC2;
+        }
+
+        self.accel = vec3_scale(force, 1.0 / FLYER_MASS_KG);
+        self.velocity = vec3_add(self.velocity, vec3_scale(self.accel, dt));
+        self.position = vec3_add(self.position, vec3_scale(self.velocity, dt));
+
+        let floor_z = config.estimator.floor_z;
+        if self.position[2] < floor_z {
+            // Resting on the floor
+            self.position[2] = floor_z;
+            self.velocity = [0.0; 3];
+            self.accel = [0.0; 3];
+        }
+    }
+
+    /// Sensor packet as the flyer board would send it
+    pub fn sensors(&mut self, config: &Config) -> FlyerSensors {
+        self.packet_counter = self.packet_counter.wrapping_add(1);
+
+        let accel_scale = 1.0 / config.estimator.imu_accel_m_per_sec2_per_count;
+        let linear_accel = [
+            (self.accel[0] * accel_scale) as i16,
+            (self.accel[1] * accel_scale) as i16,
+            (self.accel[2] * accel_scale) as i16,
+        ];
+        let gravity = [ 0, 0, (GRAVITY_M_PER_SEC2 * accel_scale) as i16 ];
+
+        let mut ranges = [0; 4];
+        let mut counters = [0; 4];
+        let lidar_counter = self.packet_counter / LIDAR_PACKET_DIVISOR;
+        for (index, direction) in config.estimator.lidar_directions.iter().enumerate().take(ranges.len()) {
+            let range = self.lidar_range(config, *direction).min(config.estimator.lidar_max_range_m);
+            ranges[index] = (range / config.estimator.lidar_m_per_count) as u32;
+            counters[index] = lidar_counter;
+        }
+
+        FlyerSensors {
+            xband: XBandTelemetry {
+                edge_count: 0,
+                speed_measure: 0,
+                measure_count: 0,
+            },
+            lidar: LIDARTelemetry { ranges, counters },
+            analog: AnalogTelemetry {
+                values: [0; 8],
+                counter: self.packet_counter,
+            },
+            imu: IMUTelemetry {
+                accelerometer: vec3_add(linear_accel, gravity),
+                magnetometer: [0; 3],
+                gyroscope: [0; 3],
+                euler_angles: [0; 3],
+                quaternion: [ IMU_QUATERNION_ONE as i16, 0, 0, 0 ],
+                linear_accel,
+                gravity,
+                temperature: 25,
+                calib_stat: -1,
+                counter: self.packet_counter,
+            },
+        }
+    }
+
+    fn lidar_range(&self, config: &Config, direction: Vector3<f32>) -> f32 {
+        // The flyer is level, so body directions are world directions. The room is
+        // a box from the floor up to the anchors, just large enough to contain them.
+        let anchors = config.winches.iter().map(|w| w.loc);
+        let mut room_min = [ ::std::f32::MAX, ::std::f32::MAX, config.estimator.floor_z ];
+        let mut room_max = [ ::std::f32::MIN, ::std::f32::MIN, ::std::f32::MIN ];
+        for loc in anchors {
+            for axis in 0..3 {
+                room_min[axis] = room_min[axis].min(loc[axis]);
+                room_max[axis] = room_max[axis].max(loc[axis]);
+            }
+        }
+
+        let mut range = ::std::f32::MAX;
+        for axis in 0..3 {
+            let wall = if direction[axis] > 0.0 { room_max[axis] } else { room_min[axis] };
+            if direction[axis] != 0.0 {
+                range = range.min((wall - self.position[axis]) / direction[axis]);
+            }
+        }
+        range.max(0.0)
+    }
+}
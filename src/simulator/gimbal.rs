@@ -0,0 +1,261 @@
+//! Simulated FY gimbal, emulating its value space, the motion of the yaw and pitch motors,
+//! and enough of the bootloader to accept firmware updates
+
+use config::Config;
+use fygimbal::{GimbalPacket, GimbalFraming, PacketReceiver};
+use fygimbal::protocol::{cmd, bootloader_cmd, target, values, motor_status, NUM_AXES, NUM_VALUES};
+use std::io::{Cursor, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+/// Encoder counts per second, per unit of controller velocity input
+const ENCODER_RATE_PER_VELOCITY_INPUT : f32 = 0.25;
+
+const ENCODER_RANGE : i16 = 4096;
+
+/// Supply voltage in tenths of a volt
+const SUPPLY_VOLTAGE_DECIVOLTS : i16 = 120;
+
+const FIRMWARE_VERSION : i16 = 115;
+
+const BOOTLOADER_VERSION : u16 = 3;
+
+/// Firmware being received by the bootloader, MCU by MCU
+struct SimBootloader {
+    mcu: usize,
+    image: Vec<u8>,
+    next_block: u16,
+}
+
+pub struct SimGimbal {
+    receiver: PacketReceiver,
+    values: Vec<i16>,
+    encoder_fract: [f32; NUM_AXES],
+    bootloader: Option<SimBootloader>,
+}
+
+impl SimGimbal {
+    pub fn new(config: &Config) -> SimGimbal {
+        let mut gimbal = SimGimbal {
+            receiver: PacketReceiver::new(),
+            values: vec![0; NUM_VALUES * NUM_AXES],
+            encoder_fract: [0.0; NUM_AXES],
+            bootloader: None,
+        };
+
+        // Start out with the same persistent parameters the real gimbal would have
+        for (index, axis_values) in config.gimbal.values.iter() {
+            for (axis, value) in axis_values.iter().enumerate() {
+                if let &Some(value) = value {
+                    gimbal.set(axis as u8, *index, value);
+                }
+            }
+        }
+        gimbal.set(target::IMU_ADJACENT, values::SUPPLY_VOLTAGE, SUPPLY_VOLTAGE_DECIVOLTS);
+        gimbal.set(target::YAW, values::FIRMWARE_VERSION, FIRMWARE_VERSION);
+        for axis in 0 .. NUM_AXES {
+            // Motors come up powered, like they do on the real gimbal
+            gimbal.set(axis as u8, values::MOTOR_STATUS_FLAGS, motor_status::POWER_ON);
+        }
+        gimbal
+    }
+
+    fn slot(target: u8, index: u8) -> Option<usize> {
+        if (target as usize) < NUM_AXES && (index as usize) < NUM_VALUES {
+            Some(index as usize * NUM_AXES + target as usize)
+        } else {
+            None
+        }
+    }
+
+    fn get(&self, target: u8, index: u8) -> i16 {
+        match SimGimbal::slot(target, index) {
+            Some(slot) => self.values[slot],
+            None => 0,
+        }
+    }
+
+    fn set(&mut self, target: u8, index: u8, value: i16) {
+        if let Some(slot) = SimGimbal::slot(target, index) {
+            self.values[slot] = value;
+        }
+    }
+
+    /// Move the motors for one physics step
+    pub fn step(&mut self, dt: f32) {
+        for &axis in [target::YAW, target::PITCH].iter() {
+            let powered = (self.get(axis, values::MOTOR_STATUS_FLAGS) & motor_status::POWER_ON) != 0;
+            if powered {
+                let rate = self.get(axis, values::CONTROLLER_VELOCITY_INPUT) as f32 * ENCODER_RATE_PER_VELOCITY_INPUT;
+                let position = self.get(axis, values::ENCODER_ANGLE) as f32 + self.encoder_fract[axis as usize] + rate * dt;
+                let whole = position.floor();
+                self.encoder_fract[axis as usize] = position - whole;
+                let angle = (whole as i32).wrapping_rem(ENCODER_RANGE as i32);
+                let angle = if angle < 0 { angle + ENCODER_RANGE as i32 } else { angle };
+                self.set(axis, values::ENCODER_ANGLE, angle as i16);
+            }
+        }
+    }
+
+    /// Feed bytes from the serial link, writing any responses
+    pub fn received(&mut self, msg: &[u8], writer: &mut Write) {
+        self.receiver.write(msg).unwrap();
+        while let Some(packet) = self.receiver.next() {
+            let response = match packet.framing {
+                GimbalFraming::Bootloader => self.handle_bootloader_packet(&packet),
+                GimbalFraming::Normal if self.bootloader.is_none() => self.handle_packet(&packet),
+                GimbalFraming::Normal => None,
+            };
+            if let Some(response) = response {
+                response.write_to(writer).unwrap();
+            }
+        }
+    }
+
+    fn handle_bootloader_packet(&mut self, packet: &GimbalPacket) -> Option<GimbalPacket> {
+        let mut reader = Cursor::new(&packet.data);
+        let mut data = Vec::new();
+        let command = match packet.command {
+
+            bootloader_cmd::INTERRUPT_BOOT => {
+                // Act as if the gimbal had just been power cycled
+                if self.bootloader.is_none() {
+                    self.bootloader = Some(SimBootloader { mcu: 0, image: Vec::new(), next_block: 0 });
+                }
+                bootloader_cmd::INTERRUPT_BOOT
+            },
+
+            bootloader_cmd::VERSION => {
+                self.bootloader.as_ref()?;
+                data.write_u16::<LittleEndian>(BOOTLOADER_VERSION).unwrap();
+                bootloader_cmd::VERSION
+            },
+
+            bootloader_cmd::WRITE_BLOCK => {
+                let boot = self.bootloader.as_mut()?;
+                let block = reader.read_u16::<LittleEndian>().ok()?;
+                if block == boot.next_block {
+                    boot.image.extend_from_slice(&packet.data[2..]);
+                    boot.next_block = boot.next_block.wrapping_add(1);
+                } else if block > boot.next_block {
+                    // Missed a block, stay quiet so the host retries
+                    return None;
+                }
+                data.write_u16::<LittleEndian>(block).unwrap();
+                bootloader_cmd::WRITE_BLOCK_ACK
+            },
+
+            bootloader_cmd::NEXT_MCU => {
+                let finished = {
+                    let boot = self.bootloader.as_mut()?;
+                    println!("Simulated gimbal MCU {} received {} bytes of firmware", boot.mcu, boot.image.len());
+                    boot.mcu += 1;
+                    boot.image.clear();
+                    boot.next_block = 0;
+                    boot.mcu >= NUM_AXES
+                };
+                if finished {
+                    self.bootloader = None;
+                }
+                bootloader_cmd::NEXT_MCU_ACK
+            },
+
+            _ => return None,
+        };
+        Some(GimbalPacket {
+            framing: GimbalFraming::Bootloader,
+            command,
+            target: target::HOST,
+            data,
+        })
+    }
+
+    fn handle_packet(&mut self, packet: &GimbalPacket) -> Option<GimbalPacket> {
+        let mut reader = Cursor::new(&packet.data);
+        match packet.command {
+
+            cmd::GET_VALUE => {
+                let index = reader.read_u8().ok()?;
+                let mut data = Vec::new();
+                data.write_i16::<LittleEndian>(self.get(packet.target, index)).unwrap();
+                Some(GimbalPacket {
+                    framing: GimbalFraming::Normal,
+                    command: cmd::GET_VALUE,
+                    target: target::HOST,
+                    data,
+                })
+            },
+
+            cmd::SET_VALUE => {
+                let index = reader.read_u16::<LittleEndian>().ok()?;
+                let value = reader.read_i16::<LittleEndian>().ok()?;
+                self.set(packet.target, index as u8, value);
+                None
+            },
+
+            cmd::SET_ACCEL_CORRECTION => {
+                let axis = reader.read_u8().ok()?;
+                let value = reader.read_i16::<LittleEndian>().ok()?;
+                let index = values::CALIBRATION_ACCEL_OFFSET_Z.wrapping_add(axis);
+                self.set(target::IMU_ADJACENT, index, value);
+                None
+            },
+
+            cmd::CAPTURE_GYRO_DRIFT_COMPENSATION => {
+                // The simulated gyro has no drift to measure
+                for &index in [values::CALIBRATION_GYRO_DRIFT_Z, values::CALIBRATION_GYRO_DRIFT_X, values::CALIBRATION_GYRO_DRIFT_Y].iter() {
+                    self.set(target::IMU_ADJACENT, index, 0);
+                }
+                Some(GimbalPacket {
+                    framing: GimbalFraming::Normal,
+                    command: cmd::CAPTURE_GYRO_DRIFT_COMPENSATION,
+                    target: target::HOST,
+                    data: vec![0],
+                })
+            },
+
+            cmd::CAPTURE_CALIBRATION_ANGLE => {
+                let num = reader.read_u8().ok()?;
+                if num == 0 {
+                    let angle = self.get(packet.target, values::ENCODER_ANGLE);
+                    self.set(packet.target, values::CALIBRATION_ANGLE_0_CENTER, angle);
+                }
+                Some(GimbalPacket {
+                    framing: GimbalFraming::Normal,
+                    command: cmd::CAPTURE_CALIBRATION_ANGLE,
+                    target: target::HOST,
+                    data: vec![num],
+                })
+            },
+
+            cmd::GET_ACCEL_CORRECTIONS => {
+                // The simulated IMU sits perfectly level
+                Some(GimbalPacket {
+                    framing: GimbalFraming::Normal,
+                    command: cmd::GET_ACCEL_CORRECTIONS,
+                    target: target::HOST,
+                    data: vec![0; 6],
+                })
+            },
+
+            cmd::SAVE_VALUES => {
+                // Nothing here outlives the process, but acknowledge like the real MCU
+                Some(GimbalPacket {
+                    framing: GimbalFraming::Normal,
+                    command: cmd::SAVE_VALUES,
+                    target: target::HOST,
+                    data: vec![packet.target],
+                })
+            },
+
+            cmd::MOTOR_POWER => {
+                let enable = reader.read_u8().ok()?;
+                let flags = self.get(packet.target, values::MOTOR_STATUS_FLAGS);
+                let flags = if enable != 0 { flags | motor_status::POWER_ON } else { flags & !motor_status::POWER_ON };
+                self.set(packet.target, values::MOTOR_STATUS_FLAGS, flags);
+                None
+            },
+
+            _ => None,
+        }
+    }
+}
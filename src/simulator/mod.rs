@@ -0,0 +1,290 @@
+//! Stand-in for the bot hardware, answering on the winch and flyer addresses with the
+//! same UDP protocol the real boards speak. With simulator_config pointing both sides at
+//! loopback addresses, the controller can't tell the difference.
+
+mod winch;
+mod flyer;
+mod gimbal;
+
+use message::{TICK_HZ, BotModule};
+use config::{Config, BotAuthConfig};
+use botcomm;
+use botcomm::{MSG_LOOPBACK, MSG_GIMBAL, MSG_FLYER_SENSORS, MSG_LEDS, MSG_WINCH_STATUS, MSG_WINCH_COMMAND, MSG_HELLO, MSG_CAPABILITIES, MSG_AUTHENTICATED, MSG_AUTH_SESSION};
+use botauth::{BotKey, BotVerifier, requires_authentication};
+use self::winch::SimWinch;
+use self::flyer::SimFlyer;
+pub use self::gimbal::SimGimbal;
+use std::io;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use bincode;
+use rand;
+
+/// Physics steps per second
+const SIM_HZ : u32 = 1000;
+
+/// Flyer sensor packets per second
+const FLYER_SENSORS_HZ : u32 = 100;
+
+/// Give up on catching up to real time after falling this far behind
+const MAX_LAG_MILLIS : u64 = 100;
+
+/// Reported in answer to a hello, always built from the same message layouts as the controller
+const SIM_FIRMWARE_VERSION : u32 = 0;
+
+/// Loopback port for the controller, with the flyer and each winch on ports above it
+const SIM_BASE_PORT : u16 = 9024;
+
+/// Configuration for a controller and simulator talking over loopback, based on the usual
+/// one. Nothing either side sends can reach real hardware or outside services.
+pub fn simulator_config(local: &Config) -> Config {
+    let mut config = local.clone();
+    let loopback = |port: u16| -> SocketAddr { SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port) };
+
+    config.controller_addr = loopback(SIM_BASE_PORT);
+    config.flyer_addr = loopback(SIM_BASE_PORT + 4);
+    for (id, winch) in config.winches.iter_mut().enumerate() {
+        winch.addr = loopback(SIM_BASE_PORT + 6 + id as u16);
+    }
+    config.bot_auth = Some(BotAuthConfig { key_path: "bot-auth-sim.key".into() });
+    config.web.http_addr = loopback(8080);
+    config.web.ws_addr = loopback(8081);
+    config.web.open_browser = false;
+    config.metrics = None;
+    config.recorder = None;
+    config
+}
+
+pub struct Simulator {
+    config: Config,
+    winch_sockets: Vec<UdpSocket>,
+    flyer_socket: UdpSocket,
+    /// Each board checks signatures on its own, when bot_auth is configured, under a
+    /// nonce drawn as the simulator starts
+    winch_verifiers: Vec<Option<BotVerifier>>,
+    flyer_verifier: Option<BotVerifier>,
+    winches: Vec<SimWinch>,
+    flyer: SimFlyer,
+    gimbal: SimGimbal,
+    step_counter: u32,
+}
+
+impl Simulator {
+    pub fn new(config: &Config) -> io::Result<Simulator> {
+        let mut winch_sockets = Vec::new();
+        for winch in &config.winches {
+            winch_sockets.push(bind(winch.addr)?);
+        }
+        let flyer_socket = bind(config.flyer_addr)?;
+        let key = match config.bot_auth {
+            Some(ref auth) => Some(BotKey::load(auth)?),
+            None => None,
+        };
+        let verifier = || key.clone().map(|key| BotVerifier::new(key, rand::random()));
+
+        Ok(Simulator {
+            config: config.clone(),
+            winch_sockets,
+            flyer_socket,
+            winch_verifiers: config.winches.iter().map(|_| verifier()).collect(),
+            flyer_verifier: verifier(),
+            winches: config.winches.iter().map(SimWinch::new).collect(),
+            flyer: SimFlyer::new(config),
+            gimbal: SimGimbal::new(config),
+            step_counter: 0,
+        })
+    }
+
+    pub fn run(mut self) {
+        println!("Simulating {} winches and the flyer, talking to {}", self.winches.len(), self.config.controller_addr);
+        let step_duration = Duration::new(0, 1_000_000_000 / SIM_HZ);
+        let mut deadline = Instant::now();
+        loop {
+            self.poll().expect("Simulator networking failed");
+            deadline += step_duration;
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            } else if now - deadline > Duration::from_millis(MAX_LAG_MILLIS) {
+                deadline = now;
+            }
+        }
+    }
+
+    fn poll(&mut self) -> io::Result<()> {
+        self.receive()?;
+        self.step()
+    }
+
+    fn step(&mut self) -> io::Result<()> {
+        let dt = 1.0 / (SIM_HZ as f32);
+        self.step_counter = self.step_counter.wrapping_add(1);
+
+        for winch in self.winches.iter_mut() {
+            winch.step(dt);
+        }
+        let reeled_in_m : Vec<f32> = self.winches.iter().map(|w| w.reeled_in_m()).collect();
+        self.flyer.step(&self.config, &reeled_in_m, dt);
+        self.gimbal.step(dt);
+
+        if self.step_counter % (SIM_HZ / TICK_HZ) == 0 {
+            for id in 0 .. self.winches.len() {
+                self.winches[id].sense_tension(self.flyer.tension_kg(id));
+                let status = self.winches[id].control_tick();
+                send(&self.winch_sockets[id], &self.config.controller_addr, MSG_WINCH_STATUS, &status)?;
+            }
+        }
+
+        if self.step_counter % (SIM_HZ / FLYER_SENSORS_HZ) == 0 {
+            let sensors = self.flyer.sensors(&self.config);
+            send(&self.flyer_socket, &self.config.controller_addr, MSG_FLYER_SENSORS, &sensors)?;
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buf = [0; 2048];
+
+        for id in 0 .. self.winch_sockets.len() {
+            while let Some(num_bytes) = recv(&self.winch_sockets[id], &mut buf)? {
+                if num_bytes >= 1 {
+                    let (code, msg) = match authenticate(&mut self.winch_verifiers[id], buf[0], &buf[1..num_bytes]) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            println!("Simulated winch {} refused a packet: {}", id, e);
+                            continue;
+                        }
+                    };
+                    match code {
+                        MSG_WINCH_COMMAND => {
+                            if let Ok(command) = bincode::deserialize(msg) {
+                                self.winches[id].received_command(command);
+                            }
+                        },
+                        MSG_HELLO => {
+                            let caps = botcomm::capabilities(BotModule::Winch(id), SIM_FIRMWARE_VERSION);
+                            send(&self.winch_sockets[id], &self.config.controller_addr, MSG_CAPABILITIES, &caps)?;
+                            if let Some(ref verifier) = self.winch_verifiers[id] {
+                                send_bytes(&self.winch_sockets[id], &self.config.controller_addr, MSG_AUTH_SESSION, &verifier.session())?;
+                            }
+                        },
+                        MSG_LOOPBACK => send_bytes(&self.winch_sockets[id], &self.config.controller_addr, MSG_LOOPBACK, msg)?,
+                        MSG_LEDS => (),
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        while let Some(num_bytes) = recv(&self.flyer_socket, &mut buf)? {
+            if num_bytes >= 1 {
+                let (code, msg) = match authenticate(&mut self.flyer_verifier, buf[0], &buf[1..num_bytes]) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        println!("Simulated flyer refused a packet: {}", e);
+                        continue;
+                    }
+                };
+                match code {
+                    MSG_GIMBAL => {
+                        let mut response = Vec::new();
+                        self.gimbal.received(msg, &mut response);
+                        if !response.is_empty() {
+                            send_bytes(&self.flyer_socket, &self.config.controller_addr, MSG_GIMBAL, &response)?;
+                        }
+                    },
+                    MSG_HELLO => {
+                        let caps = botcomm::capabilities(BotModule::Flyer, SIM_FIRMWARE_VERSION);
+                        send(&self.flyer_socket, &self.config.controller_addr, MSG_CAPABILITIES, &caps)?;
+                        if let Some(ref verifier) = self.flyer_verifier {
+                            send_bytes(&self.flyer_socket, &self.config.controller_addr, MSG_AUTH_SESSION, &verifier.session())?;
+                        }
+                    },
+                    MSG_LOOPBACK => send_bytes(&self.flyer_socket, &self.config.controller_addr, MSG_LOOPBACK, msg)?,
+                    MSG_LEDS => (),
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Unwrap authenticated framing the way firmware with a key does, refusing unsigned
+/// copies of the messages that must be signed
+fn authenticate<'a>(verifier: &mut Option<BotVerifier>, code: u8, msg: &'a [u8]) -> Result<(u8, &'a [u8]), String> {
+    match *verifier {
+        None => Ok((code, msg)),
+        Some(ref mut verifier) => {
+            if code == MSG_AUTHENTICATED {
+                verifier.open(msg)
+            } else if requires_authentication(code) {
+                Err(format!("Message {:#04x} must be authenticated", code))
+            } else {
+                Ok((code, msg))
+            }
+        }
+    }
+}
+
+fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let udp = UdpSocket::bind(addr)?;
+    udp.set_nonblocking(true)?;
+    Ok(udp)
+}
+
+fn recv(udp: &UdpSocket, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    match udp.recv_from(buf) {
+        Ok((num_bytes, _)) => Ok(Some(num_bytes)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        // Nobody listening on the controller address yet
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn send_bytes(udp: &UdpSocket, addr: &SocketAddr, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut buf = vec![header];
+    buf.write(body)?;
+    match udp.send_to(&buf, &addr) {
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => Ok(()),
+    }
+}
+
+fn send<T: Serialize>(udp: &UdpSocket, addr: &SocketAddr, header: u8, body: &T) -> io::Result<()> {
+    let limit = bincode::Bounded(2048);
+    let bytes = bincode::serialize(body, limit).unwrap();
+    send_bytes(udp, addr, header, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ControllerMode;
+    use controller::tests::test_config;
+
+    #[test]
+    fn simulator_config_stays_on_loopback() {
+        let local = test_config(ControllerMode::Halted);
+        let config = simulator_config(&local);
+        let mut ports = vec![ config.controller_addr.port(), config.flyer_addr.port() ];
+        ports.extend(config.winches.iter().map(|winch| winch.addr.port()));
+        assert_eq!(ports, vec![ 9024, 9028, 9030, 9031, 9032, 9033 ]);
+        assert!(config.controller_addr.ip().is_loopback() && config.flyer_addr.ip().is_loopback());
+        assert!(config.winches.iter().all(|winch| winch.addr.ip().is_loopback()));
+        assert_eq!(config.metrics, None);
+        assert_eq!(config.recorder, None);
+
+        // Everything else comes from the usual configuration
+        assert_eq!(config.params, local.params);
+        assert_eq!(config.gimbal, local.gimbal);
+        assert_eq!(config.winches[0].calibration, local.winches[0].calibration);
+    }
+}
@@ -0,0 +1,239 @@
+//! Simulated winch board: motor, PID loop and load cell, following the firmware's control law
+
+use message::*;
+use config::{WinchConfig, WinchCalibration};
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+
+/// Firmware halts the motor when commands stop arriving for this long
+const COMMAND_TIMEOUT_MILLIS : u64 = 100;
+
+/// No-load rope speed at full PWM
+const MAX_SPEED_M_PER_SEC : f32 = 1.2;
+
+/// Rope tension that stalls the motor when reeling in at full PWM
+const STALL_TENSION_KG : f32 = 6.0;
+
+/// First-order lag between PWM and motor speed
+const MOTOR_TIME_CONSTANT_SEC : f32 = 0.04;
+
+/// Peak-to-peak noise added to raw load cell readings
+const FORCE_NOISE_COUNTS : f32 = 200.0;
+
+pub struct SimWinch {
+    calibration: WinchCalibration,
+    command: Option<(Instant, WinchCommand)>,
+    command_counter: u32,
+    tick_counter: u32,
+    /// Encoder position in counts; increasing counts reel in rope
+    position: f32,
+    /// Counts per second
+    velocity: f32,
+    last_position_target: i32,
+    force_measure: i32,
+    force_filtered: f32,
+    in_deadband: bool,
+    motor: WinchMotorControl,
+}
+
+impl SimWinch {
+    pub fn new(config: &WinchConfig) -> SimWinch {
+        SimWinch {
+            calibration: config.calibration.clone(),
+            command: None,
+            command_counter: 0,
+            tick_counter: 0,
+            position: 0.0,
+            velocity: 0.0,
+            last_position_target: 0,
+            force_measure: 0,
+            force_filtered: 0.0,
+            in_deadband: true,
+            motor: WinchMotorControl {
+                pwm: WinchPWMStatus {
+                    total: 0.0,
+                    p: 0.0,
+                    i: 0.0,
+                    d: 0.0,
+                    hz: 0.0,
+                    clocks: 0,
+                    period: 0,
+                    enabled: 0,
+                },
+                position_err: 0,
+                pos_err_filtered: 0.0,
+                pos_err_integral: 0.0,
+                vel_err_inst: 0.0,
+                vel_err_filtered: 0.0,
+            },
+        }
+    }
+
+    /// Length of rope reeled in since startup, in meters
+    pub fn reeled_in_m(&self) -> f32 {
+        self.calibration.dist_to_m(self.position)
+    }
+
+    pub fn received_command(&mut self, command: WinchCommand) {
+        self.command_counter = self.command_counter.wrapping_add(1);
+        self.command = Some((Instant::now(), command));
+    }
+
+    /// Advance the motor by one physics step
+    pub fn step(&mut self, dt: f32) {
+        let target_m_per_sec = MAX_SPEED_M_PER_SEC * self.motor.pwm.total;
+        let target = self.calibration.dist_from_m(target_m_per_sec);
+        self.velocity += (target - self.velocity) * (dt / MOTOR_TIME_CONSTANT_SEC).min(1.0);
+        self.position += self.velocity * dt;
+    }
+
+    /// Sample the load cell with the rope's current tension
+    pub fn sense_tension(&mut self, tension_kg: f32) {
+        let noise = (thread_rng().next_f32() - 0.5) * FORCE_NOISE_COUNTS;
+        self.force_measure = (self.calibration.force_from_kg(tension_kg) + noise).round() as i32;
+    }
+
+    /// One iteration of the firmware control loop, returning the status packet it would send
+    pub fn control_tick(&mut self) -> WinchStatus {
+        self.tick_counter = self.tick_counter.wrapping_add(1);
+
+        let timeout = Duration::from_millis(COMMAND_TIMEOUT_MILLIS);
+        let command = match self.command {
+            Some((timestamp, ref command)) if timestamp + timeout > Instant::now() => Some(command.clone()),
+            _ => None,
+        };
+
+        match command {
+            None => self.halt(),
+            Some(ref command) => {
+                self.force_filtered += (self.force_measure as f32 - self.force_filtered) * command.force.filter_param;
+                self.pid_tick(command);
+            },
+        }
+
+        WinchStatus {
+            command_counter: self.command_counter,
+            tick_counter: self.tick_counter,
+            command: match command {
+                Some(command) => command,
+                None => self.idle_command(),
+            },
+            sensors: WinchSensors {
+                force: ForceTelemetry {
+                    measure: self.force_measure,
+                    filtered: self.force_filtered,
+                    counter: self.tick_counter,
+                },
+                position: self.position.round() as i32,
+                velocity: self.velocity,
+            },
+            motor: self.motor.clone(),
+        }
+    }
+
+    fn halt(&mut self) {
+        self.force_filtered = self.force_measure as f32;
+        self.motor.pwm.total = 0.0;
+        self.motor.pwm.p = 0.0;
+        self.motor.pwm.i = 0.0;
+        self.motor.pwm.d = 0.0;
+        self.motor.pwm.enabled = 0;
+        self.motor.pos_err_integral = 0.0;
+        self.last_position_target = self.position.round() as i32;
+    }
+
+    fn pid_tick(&mut self, command: &WinchCommand) {
+        let dt = 1.0 / (TICK_HZ as f32);
+        let position = self.position.round() as i32;
+        let m = &mut self.motor;
+
+        let target_velocity = command.position.wrapping_sub(self.last_position_target) as f32 / dt;
+        self.last_position_target = command.position;
+
+        m.position_err = command.position.wrapping_sub(position);
+        m.pos_err_filtered += (m.position_err as f32 - m.pos_err_filtered) * command.pid.p_filter_param;
+        m.pos_err_integral = m.pos_err_integral * (1.0 - command.pid.i_decay_param) + m.position_err as f32 * dt;
+        m.vel_err_inst = target_velocity - self.velocity;
+        m.vel_err_filtered += (m.vel_err_inst - m.vel_err_filtered) * command.pid.d_filter_param;
+
+        m.pwm.p = command.pid.gain_p * m.pos_err_filtered;
+        m.pwm.i = command.pid.gain_i * m.pos_err_integral;
+        m.pwm.d = command.pid.gain_d * m.vel_err_filtered;
+        let mut pwm = (m.pwm.p + m.pwm.i + m.pwm.d).max(-1.0).min(1.0);
+
+        // Deadband with hysteresis, so we can rest without hunting
+        let db = &command.deadband;
+        let err = m.position_err.abs();
+        let speed = self.velocity.abs();
+        if self.in_deadband {
+            if err > db.position_center + db.position_width / 2 || speed > db.velocity_center + db.velocity_width / 2.0 {
+                self.in_deadband = false;
+            }
+        } else if err < db.position_center - db.position_width / 2 && speed < db.velocity_center - db.velocity_width / 2.0 {
+            self.in_deadband = true;
+        }
+        if self.in_deadband {
+            pwm = 0.0;
+        }
+
+        // Force limits only block motion in the direction that makes things worse
+        let force = &command.force;
+        if self.force_filtered > force.lockout_above || self.force_filtered < force.lockout_below {
+            pwm = 0.0;
+        } else if self.force_filtered > force.pos_motion_max {
+            pwm = pwm.min(0.0);
+        } else if self.force_filtered < force.neg_motion_min {
+            pwm = pwm.max(0.0);
+        }
+
+        if pwm.abs() < command.pwm.minimum {
+            pwm = 0.0;
+        } else {
+            pwm = (pwm + pwm.signum() * command.pwm.bias).max(-1.0).min(1.0);
+        }
+
+        // Reeling in against load slows the motor down, all the way to a stall
+        let tension_kg = self.calibration.force_to_kg(self.force_filtered).max(0.0);
+        if pwm > 0.0 {
+            pwm *= (1.0 - tension_kg / STALL_TENSION_KG).max(0.0);
+        }
+
+        m.pwm.total = pwm;
+        m.pwm.hz = command.pwm.hz;
+        m.pwm.period = if command.pwm.hz > 0.0 { (1e6 / command.pwm.hz).min(65535.0) as u16 } else { 0 };
+        m.pwm.clocks = (pwm * m.pwm.period as f32) as i16;
+        m.pwm.enabled = 1;
+    }
+
+    fn idle_command(&self) -> WinchCommand {
+        WinchCommand {
+            position: self.position.round() as i32,
+            force: ForceCommand {
+                filter_param: 1.0,
+                neg_motion_min: 0.0,
+                pos_motion_max: 0.0,
+                lockout_below: 0.0,
+                lockout_above: 0.0,
+            },
+            pid: PIDGains {
+                gain_p: 0.0,
+                gain_i: 0.0,
+                gain_d: 0.0,
+                p_filter_param: 1.0,
+                i_decay_param: 1.0,
+                d_filter_param: 1.0,
+            },
+            deadband: WinchDeadband {
+                position_center: 0,
+                position_width: 0,
+                velocity_center: 0.0,
+                velocity_width: 0.0,
+            },
+            pwm: WinchPWMCommand {
+                hz: 0.0,
+                minimum: 0.0,
+                bias: 0.0,
+            },
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Keeps worker threads running. A supervised thread runs its body again whenever it
+//! panics or returns an error, after reporting a SubsystemFault on the message bus.
+//! Safety-critical subsystems are marked as such in the fault, and the controller halts
+//! the bot when it sees one, since motion can't be trusted while they restart.
+
+use message::*;
+use controller::ControllerPort;
+use std::any::Any;
+use std::cmp::min;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wait before each restart, doubling with each failure in a row up to the maximum
+const RESTART_DELAY_MILLIS : u64 = 100;
+const MAX_RESTART_DELAY_MILLIS : u64 = 5000;
+
+/// Failures in a row reset after the body has run this long without failing
+const HEALTHY_RUN_MILLIS : u64 = 10000;
+
+pub fn panic_message(cause: &Box<Any + Send>) -> String {
+    if let Some(message) = cause.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = cause.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Panicked".into()
+    }
+}
+
+/// Failures of one subsystem, and how long to wait before restarting it
+struct Failures {
+    total: u32,
+    in_a_row: u32,
+}
+
+impl Failures {
+    fn new() -> Failures {
+        Failures {
+            total: 0,
+            in_a_row: 0,
+        }
+    }
+
+    /// Count a failure after the body ran for `run_time`, returning the delay before its restart
+    fn failed(&mut self, run_time: Duration) -> Duration {
+        if run_time > Duration::from_millis(HEALTHY_RUN_MILLIS) {
+            self.in_a_row = 0;
+        }
+        self.total += 1;
+        self.in_a_row += 1;
+        let delay = RESTART_DELAY_MILLIS << min(self.in_a_row - 1, 6);
+        Duration::from_millis(min(delay, MAX_RESTART_DELAY_MILLIS))
+    }
+}
+
+/// Start a named thread running `body` until it returns Ok, restarting it on failure
+pub fn spawn<F>(name: &'static str, critical: bool, controller: &ControllerPort, mut body: F)
+    where F: FnMut() -> Result<(), String> + Send + 'static
+{
+    let controller = controller.clone();
+    thread::Builder::new().name(name.into()).spawn(move || {
+        let mut failures = Failures::new();
+        loop {
+            let started = Instant::now();
+            let error = match panic::catch_unwind(AssertUnwindSafe(|| body())) {
+                Ok(Ok(())) => return,
+                Ok(Err(error)) => error,
+                Err(cause) => panic_message(&cause),
+            };
+            let delay = failures.failed(started.elapsed());
+
+            println!("{} failed, restarting: {}", name, error);
+            controller.send(Message::SubsystemFault(SubsystemFault {
+                subsystem: name.into(),
+                error,
+                failures: failures.total,
+                critical,
+            }).timestamp());
+            thread::sleep(delay);
+        }
+    }).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(duration: Duration) -> u64 {
+        duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+    }
+
+    #[test]
+    fn restart_delay_backs_off() {
+        let mut failures = Failures::new();
+        let delays : Vec<u64> = (0 .. 9).map(|_| millis(failures.failed(Duration::from_millis(1)))).collect();
+        assert_eq!(delays, vec![ 100, 200, 400, 800, 1600, 3200, 5000, 5000, 5000 ]);
+        assert_eq!(failures.total, 9);
+    }
+
+    #[test]
+    fn healthy_run_resets_back_off() {
+        let mut failures = Failures::new();
+        for _ in 0 .. 4 {
+            failures.failed(Duration::from_millis(1));
+        }
+        // Running exactly the healthy time isn't quite enough
+        assert_eq!(millis(failures.failed(Duration::from_millis(HEALTHY_RUN_MILLIS))), 1600);
+        assert_eq!(millis(failures.failed(Duration::from_millis(HEALTHY_RUN_MILLIS + 1))), 100);
+        assert_eq!(millis(failures.failed(Duration::from_millis(1))), 200);
+        // The total never resets, it goes out with each fault
+        assert_eq!(failures.total, 7);
+    }
+
+    #[test]
+    fn panic_messages() {
+        let cause : Box<Any + Send> = Box::new("static message");
+        assert_eq!(panic_message(&cause), "static message");
+        let cause : Box<Any + Send> = Box::new(format!("formatted {}", 5));
+        assert_eq!(panic_message(&cause), "formatted 5");
+        let cause : Box<Any + Send> = Box::new(5);
+        assert_eq!(panic_message(&cause), "Panicked");
+    }
+}
@@ -150,3 +150,16 @@ pub fn angle_normalize(a: f32) -> f32 {
 	let a = if a < -PI { a + TAU } else { a };
 	a
 }
+
+pub fn quat_normalized(q: Vector4<f32>) -> Vector4<f32> {
+	// Quaternions are [w, x, y, z]. A zero quaternion becomes the identity.
+	let len = vec4_len(q);
+	if len > 0.0 { vec4_scale(q, 1.0 / len) } else { [1.0, 0.0, 0.0, 0.0] }
+}
+
+pub fn quat_rotate_vec3(q: Vector4<f32>, v: Vector3<f32>) -> Vector3<f32> {
+	// v' = v + 2w(u x v) + 2u x (u x v), for unit quaternion q = [w, u]
+	let u = [q[1], q[2], q[3]];
+	let t = vec3_scale(vec3_cross(u, v), 2.0);
+	vec3_add(vec3_add(v, vec3_scale(t, q[0])), vec3_cross(u, t))
+}
@@ -135,12 +135,12 @@ class GimbalRow extends Component {
             return requests;
         };
         let req_once = requests_for_scope("Once");
-        let req_continuous = requests_for_scope("Continuous");
+        let req_fast = requests_for_scope({ Rate: { hz: 20.0, priority: "Normal" } });
 
         return <div className="GimbalRow">
             <span className="index">{ ("00" + index.toString(16)).slice(-2) }</span>
             {row}
-            <GimbalPollerToggle interval={300.0} requests={req_continuous}>cont</GimbalPollerToggle>
+            <GimbalPollerToggle interval={300.0} requests={req_fast}>20Hz</GimbalPollerToggle>
             <GimbalPollerToggle interval={100.0} requests={req_once}>100ms</GimbalPollerToggle>
             <GimbalPollerToggle interval={1000.0} requests={req_once}>1s</GimbalPollerToggle>
             <span className="GimbalPollerToggle">